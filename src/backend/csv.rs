@@ -0,0 +1,205 @@
+use crate::backend::{CompletionStatus, EstTime, Task, ToDo, TrackedTime};
+use chrono::prelude::{DateTime, Local};
+use std::str::FromStr;
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+/// What `/todo/export?format=csv` has in it when it isn't told otherwise
+pub const DEFAULT_COLUMNS: [Column; 8] = [
+    Column::Id,
+    Column::Name,
+    Column::Category,
+    Column::Tags,
+    Column::Priority,
+    Column::Due,
+    Column::Est,
+    Column::Complete,
+];
+
+/// Same for the report of finished tasks
+pub const REPORT_COLUMNS: [Column; 5] = [
+    Column::Name,
+    Column::Category,
+    Column::Finished,
+    Column::Est,
+    Column::Tracked,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Id,
+    Name,
+    Desc,
+    Category,
+    // Space separated
+    Tags,
+    Priority,
+    Due,
+    Created,
+    // When it was marked finished
+    Finished,
+    // Minutes, sub-tasks included
+    Est,
+    Tracked,
+    Complete,
+    Parent,
+}
+
+impl FromStr for Column {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &(s.trim().to_ascii_lowercase())[..] {
+            "id" => Ok(Column::Id),
+            "name" => Ok(Column::Name),
+            "desc" | "description" => Ok(Column::Desc),
+            "category" => Ok(Column::Category),
+            "tags" => Ok(Column::Tags),
+            "priority" => Ok(Column::Priority),
+            "due" => Ok(Column::Due),
+            "created" => Ok(Column::Created),
+            "finished" => Ok(Column::Finished),
+            "est" | "estimate" => Ok(Column::Est),
+            "tracked" => Ok(Column::Tracked),
+            "complete" => Ok(Column::Complete),
+            "parent" => Ok(Column::Parent),
+            _ => Err("Unknown column"),
+        }
+    }
+}
+
+impl Column {
+    pub fn header(self) -> &'static str {
+        match self {
+            Column::Id => "id",
+            Column::Name => "name",
+            Column::Desc => "desc",
+            Column::Category => "category",
+            Column::Tags => "tags",
+            Column::Priority => "priority",
+            Column::Due => "due",
+            Column::Created => "created",
+            Column::Finished => "finished",
+            Column::Est => "est",
+            Column::Tracked => "tracked",
+            Column::Complete => "complete",
+            Column::Parent => "parent",
+        }
+    }
+
+    pub fn value(self, task: &Task, todo: &ToDo) -> String {
+        let date = |date: Option<DateTime<Local>>| {
+            date.map(|date| date.format(DATE_FORMAT).to_string())
+                .unwrap_or_default()
+        };
+        match self {
+            Column::Id => task.get_id().to_string(),
+            Column::Name => task.get_name(),
+            Column::Desc => task.get_desc(),
+            Column::Category => task.get_category().unwrap_or_default(),
+            Column::Tags => {
+                let mut tags: Vec<String> = task.get_tags().into_iter().collect();
+                tags.sort();
+                tags.join(" ")
+            }
+            Column::Priority => task
+                .get_priority()
+                .map(|priority| format!("{:?}", priority))
+                .unwrap_or_default(),
+            Column::Due => date(task.get_due_date()),
+            Column::Created => date(Some(task.get_initial_date())),
+            Column::Finished => date(task.get_finished_at()),
+            Column::Est => task.est_time().to_string(),
+            Column::Tracked => task.tracked_time().to_string(),
+            Column::Complete => task.complete().to_string(),
+            Column::Parent => todo
+                .get_parent(task.get_id())
+                .map(|parent| parent.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Column names separated by commas, like `name,due,priority`
+pub fn parse_columns(columns: &str) -> Result<Vec<Column>, String> {
+    columns
+        .split(',')
+        .filter(|column| !column.trim().is_empty())
+        .map(|column| Column::from_str(column).map_err(|e| format!("{}: {}", e, column.trim())))
+        .collect()
+}
+
+/// A header row, then a row for each task
+pub fn export(tasks: &[&Task], todo: &ToDo, columns: &[Column]) -> String {
+    let mut rows = vec![columns
+        .iter()
+        .map(|column| column.header())
+        .collect::<Vec<&str>>()
+        .join(",")];
+    rows.extend(tasks.iter().map(|task| {
+        columns
+            .iter()
+            .map(|column| field(&column.value(task, todo)))
+            .collect::<Vec<String>>()
+            .join(",")
+    }));
+    rows.into_iter().map(|row| row + "\r\n").collect()
+}
+
+// Quoted if it has to be, with any quotes inside doubled
+fn field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export, parse_columns, Column, DEFAULT_COLUMNS};
+    use crate::backend::{Priority, Task, ToDo};
+    use chrono::prelude::{Local, TimeZone};
+
+    #[test]
+    fn csv() {
+        let mut parent = Task::new(
+            "Write \"report\", then send",
+            "Line one\nLine two",
+            Some(Local.ymd(2020, 10, 20).and_hms(17, 0, 0)),
+            30,
+            Some(Priority::High),
+            None,
+            Some("work".to_string()),
+        );
+        parent.set_tags(vec!["b".to_string(), "a".to_string()].into_iter().collect());
+        let child = Task::new("Proofread", "", None, 15, None, None, None);
+        let (parent_id, child_id) = (parent.get_id(), child.get_id());
+        let mut todo = ToDo::from_vec(vec![parent]);
+        todo.add_sub_task(parent_id, child).unwrap();
+
+        let columns = parse_columns("name, desc,due,priority,tags,est,parent").unwrap();
+        let tasks = vec![
+            todo.get_task(parent_id).unwrap(),
+            todo.get_task(child_id).unwrap(),
+        ];
+        let text = export(&tasks, &todo, &columns);
+        let rows: Vec<&str> = text.split("\r\n").collect();
+        assert_eq!(rows[0], "name,desc,due,priority,tags,est,parent");
+        assert_eq!(
+            rows[1],
+            "\"Write \"\"report\"\", then send\",\"Line one\nLine two\",2020-10-20 17:00,High,a b,45,"
+        );
+        assert_eq!(rows[2], format!("Proofread,,,,,15,{}", parent_id));
+
+        assert_eq!(
+            export(&[], &todo, &DEFAULT_COLUMNS),
+            "id,name,category,tags,priority,due,est,complete\r\n"
+        );
+        assert_eq!(parse_columns("Finished").unwrap(), vec![Column::Finished]);
+        assert_eq!(
+            parse_columns("name,size").unwrap_err(),
+            "Unknown column: size"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use crate::backend::{Task, ToDo};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+// Oldest records get dropped past this point so the log can't grow forever
+pub(crate) const MAX_RECORDS: usize = 1000;
+
+/// A single mutation of a ToDo, carrying enough to reverse it
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum Change {
+    Added {
+        task: Task,
+        parent: Option<Uuid>,
+    },
+    Removed {
+        task: Task,
+        parent: Option<Uuid>,
+        // (dependent, blocker) links that were dropped along with the task
+        blocking: Vec<(Uuid, Uuid)>,
+    },
+    Finished {
+        id: Uuid,
+        before: bool,
+        after: bool,
+    },
+    // The whole task as it was and as it became, sub-tasks and all
+    Replaced {
+        before: Task,
+        after: Task,
+    },
+}
+
+impl Change {
+    /// Makes the change again
+    pub fn apply(&self, todo: &mut ToDo) -> Result<(), &'static str> {
+        match self {
+            Change::Added { task, parent } => insert(todo, task.clone(), *parent),
+            Change::Removed { task, .. } => todo
+                .remove_task(task.get_id())
+                .map_err(|_| "Task no longer exists"),
+            Change::Finished { id, after, .. } => todo
+                .mark_finished(*id, Some(*after))
+                .map_err(|_| "Task no longer exists"),
+            Change::Replaced { after, .. } => todo
+                .replace_task(after.clone())
+                .map_err(|_| "Task no longer exists"),
+        }
+    }
+
+    /// Puts the ToDo back the way it was before the change
+    pub fn revert(&self, todo: &mut ToDo) -> Result<(), &'static str> {
+        match self {
+            Change::Added { task, .. } => todo
+                .remove_task(task.get_id())
+                .map_err(|_| "Task no longer exists"),
+            Change::Removed {
+                task,
+                parent,
+                blocking,
+            } => {
+                insert(todo, task.clone(), *parent)?;
+                for (dependent, blocker) in blocking {
+                    // Whatever was waiting on it may have been removed since
+                    let _ = todo.add_dependency(*dependent, *blocker);
+                }
+                Ok(())
+            }
+            Change::Finished { id, before, .. } => todo
+                .mark_finished(*id, Some(*before))
+                .map_err(|_| "Task no longer exists"),
+            Change::Replaced { before, .. } => todo
+                .replace_task(before.clone())
+                .map_err(|_| "Task no longer exists"),
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { task, .. } => write!(f, "Added {}", task.get_name()),
+            Change::Removed { task, .. } => write!(f, "Removed {}", task.get_name()),
+            Change::Finished { id, after, .. } => write!(
+                f,
+                "Marked {} {}",
+                id,
+                if *after { "finished" } else { "unfinished" }
+            ),
+            Change::Replaced { after, .. } => write!(f, "Updated {}", after.get_name()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChangeRecord {
+    pub time: DateTime<Local>,
+    pub change: Change,
+}
+
+/// One movement of the undo and redo stacks, so storage can journal them instead of writing out the whole log
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum Step {
+    Recorded(Vec<ChangeRecord>),
+    // The most recent change came off the done stack, and went onto the undone one if it could be reverted
+    Undone { reverted: bool },
+    Redone { applied: bool },
+}
+
+/// Log of the changes made to a ToDo, doubling as the undo and redo stacks
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct History {
+    done: Vec<ChangeRecord>,
+    undone: Vec<ChangeRecord>,
+    #[serde(skip)]
+    steps: Vec<Step>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            done: Vec::new(),
+            undone: Vec::new(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Puts a history back together from its two stacks, most recent last
+    pub fn from_stacks(done: Vec<ChangeRecord>, undone: Vec<ChangeRecord>) -> Self {
+        History {
+            done,
+            undone,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Moves the changes the ToDo has made into the log.
+    /// Anything that was undone can't be redone once something new happens
+    pub fn record(&mut self, todo: &mut ToDo) {
+        let changes = todo.take_changes();
+        if changes.is_empty() {
+            return;
+        }
+        let time = Local::now();
+        let records: Vec<ChangeRecord> = changes
+            .into_iter()
+            .map(|change| ChangeRecord { time, change })
+            .collect();
+        self.push_records(records.clone());
+        self.steps.push(Step::Recorded(records));
+    }
+
+    fn push_records(&mut self, records: Vec<ChangeRecord>) {
+        self.undone.clear();
+        self.done.extend(records);
+        if self.done.len() > MAX_RECORDS {
+            let extra = self.done.len() - MAX_RECORDS;
+            self.done.drain(..extra);
+        }
+    }
+
+    /// Reverts the most recent change, returning it.
+    /// A change that can't be reverted anymore is dropped from the log
+    pub fn undo(&mut self, todo: &mut ToDo) -> Result<ChangeRecord, &'static str> {
+        self.record(todo);
+        let record = self.done.pop().ok_or("Nothing to undo")?;
+        let result = record.change.revert(todo);
+        // Reverting goes through the normal ToDo calls, those changes aren't new history
+        todo.take_changes();
+        self.steps.push(Step::Undone {
+            reverted: result.is_ok(),
+        });
+        result?;
+        self.undone.push(record.clone());
+        Ok(record)
+    }
+
+    /// Re-applies the most recently undone change, returning it
+    pub fn redo(&mut self, todo: &mut ToDo) -> Result<ChangeRecord, &'static str> {
+        self.record(todo);
+        let record = self.undone.pop().ok_or("Nothing to redo")?;
+        let result = record.change.apply(todo);
+        todo.take_changes();
+        self.steps.push(Step::Redone {
+            applied: result.is_ok(),
+        });
+        result?;
+        self.done.push(record.clone());
+        Ok(record)
+    }
+
+    /// The steps taken since this was last called
+    pub fn take_steps(&mut self) -> Vec<Step> {
+        std::mem::take(&mut self.steps)
+    }
+
+    /// Repeats a step on the stacks alone, the ToDo it was taken on is left for the caller to restore
+    pub fn replay(&mut self, step: Step) {
+        match step {
+            Step::Recorded(records) => self.push_records(records),
+            Step::Undone { reverted } => {
+                if let (Some(record), true) = (self.done.pop(), reverted) {
+                    self.undone.push(record);
+                }
+            }
+            Step::Redone { applied } => {
+                if let (Some(record), true) = (self.undone.pop(), applied) {
+                    self.done.push(record);
+                }
+            }
+        }
+    }
+
+    pub fn get_done(&self) -> &[ChangeRecord] {
+        &self.done
+    }
+
+    pub fn get_undone(&self) -> &[ChangeRecord] {
+        &self.undone
+    }
+}
+
+fn insert(todo: &mut ToDo, task: Task, parent: Option<Uuid>) -> Result<(), &'static str> {
+    if todo.get_task(task.get_id()).is_some() {
+        return Err("Task already exists");
+    }
+    match parent {
+        Some(parent) => todo.add_sub_task(parent, task),
+        None => {
+            todo.add_task(task);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::History;
+    use crate::backend::{CompletionStatus, Task, ToDo};
+
+    #[test]
+    fn undo_redo() {
+        let blocker = Task::new("Blocker", "Blocker", None, 0, None, None, None);
+        let task = Task::new("Task", "Task", None, 0, None, None, None);
+        let (blocker_id, id) = (blocker.get_id(), task.get_id());
+        let mut todo = ToDo::new();
+        let mut history = History::new();
+        todo.add_task(blocker);
+        todo.add_task(task);
+        todo.add_dependency(id, blocker_id).unwrap();
+        todo.mark_finished(id, Some(true)).unwrap();
+        todo.remove_task(blocker_id).unwrap();
+        history.record(&mut todo);
+        assert_eq!(history.get_done().len(), 4);
+
+        history.undo(&mut todo).unwrap();
+        assert!(todo.get_task(blocker_id).is_some());
+        assert!(todo.is_blocked(id));
+        history.undo(&mut todo).unwrap();
+        assert!(!todo.get_task(id).unwrap().complete());
+        assert_eq!(history.get_undone().len(), 2);
+
+        history.redo(&mut todo).unwrap();
+        assert!(todo.get_task(id).unwrap().complete());
+        history.undo(&mut todo).unwrap();
+        history.undo(&mut todo).unwrap();
+        history.undo(&mut todo).unwrap();
+        assert_eq!(todo.num_tasks(), 0);
+        assert!(history.undo(&mut todo).is_err());
+
+        // Doing something new drops whatever could have been redone
+        todo.add_task(Task::new("New", "New", None, 0, None, None, None));
+        history.record(&mut todo);
+        assert!(history.get_undone().is_empty());
+        assert!(history.redo(&mut todo).is_err());
+
+        // The steps on their own rebuild the same stacks
+        let mut replayed = History::new();
+        for step in history.take_steps() {
+            replayed.replay(step);
+        }
+        assert_eq!(replayed.get_done().len(), history.get_done().len());
+        assert_eq!(replayed.get_undone().len(), history.get_undone().len());
+    }
+}
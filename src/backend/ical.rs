@@ -0,0 +1,588 @@
+use crate::backend::quick_add::at_local;
+use crate::backend::recurrence::parse_date_time;
+use crate::backend::{Priority, Recurrence, Task, ToDo};
+use chrono::prelude::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use uuid::Uuid;
+
+const PRODID: &str = "-//Desktopper//Desktopper//EN";
+const UTC_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+// TZIDs that are just another name for UTC
+const UTC_ZONES: [&str; 4] = ["UTC", "Etc/UTC", "GMT", "Etc/GMT"];
+// Content lines longer than this are folded onto the next
+const LINE_OCTETS: usize = 75;
+
+/// Writes out every task, sub-tasks included, as a VTODO in an iCalendar file.
+/// Sub-tasks are RELATED-TO their parent, which is how they find it again on the way back in
+pub fn export(todo: &ToDo) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:{}", PRODID),
+        "X-WR-CALNAME:Desktopper".to_string(),
+    ];
+    let stamp = Utc::now();
+    for task in todo.get_all_tasks() {
+        lines.extend(to_vtodo(task, todo.get_parent(task.get_id()), stamp));
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+/// A single task as the unfolded content lines of a VTODO,
+/// the estimate, sessions and blockers have nowhere to go
+pub fn to_vtodo(task: &Task, parent: Option<Uuid>, stamp: DateTime<Utc>) -> Vec<String> {
+    let mut lines = vec![
+        "BEGIN:VTODO".to_string(),
+        format!("UID:{}", task.get_id()),
+        format!("DTSTAMP:{}", utc(stamp)),
+        format!("CREATED:{}", utc(task.get_initial_date())),
+        format!("SUMMARY:{}", escape(&task.get_name())),
+    ];
+    if !task.get_desc().is_empty() {
+        lines.push(format!("DESCRIPTION:{}", escape(&task.get_desc())));
+    }
+    if let Some(due_date) = task.get_due_date() {
+        lines.push(format!("DUE:{}", utc(due_date)));
+    }
+    if let Some(priority) = task.get_priority() {
+        lines.push(format!("PRIORITY:{}", priority_number(priority)));
+    }
+    // The category goes first so it comes back as the category
+    let mut tags: Vec<String> = task.get_tags().into_iter().collect();
+    tags.sort();
+    let categories: Vec<String> = task
+        .get_category()
+        .into_iter()
+        .chain(tags)
+        .map(|category| escape(&category))
+        .collect();
+    if !categories.is_empty() {
+        lines.push(format!("CATEGORIES:{}", categories.join(",")));
+    }
+    // Sub-tasks have their own VTODO, so a parent is only as finished as it was marked
+    lines.push(
+        if task.is_done() {
+            "STATUS:COMPLETED"
+        } else {
+            "STATUS:NEEDS-ACTION"
+        }
+        .to_string(),
+    );
+    if let (true, Some(finished_at)) = (task.is_done(), task.get_finished_at()) {
+        lines.push(format!("COMPLETED:{}", utc(finished_at)));
+    }
+    if let Some(rule) = task.get_repeats() {
+        lines.extend(rule.to_string().lines().map(String::from));
+    }
+    if let Some(parent) = parent {
+        lines.push(format!("RELATED-TO:{}", parent));
+    }
+    lines.push("END:VTODO".to_string());
+    lines
+}
+
+/// Reads the VTODOs out of an iCalendar file, the rest of the calendar is skipped.
+///
+/// - PRIORITY 1-2 is Extreme, 3-4 High, 5 Medium and 6-9 Low
+/// - the first of the CATEGORIES is the category, the rest become tags
+/// - a date-only DUE is due at the end of that day
+/// - STATUS:COMPLETED marks it finished, COMPLETED is when
+/// - RRULE, along with DTSTART and EXDATE, is how it repeats
+/// - times are UTC or local, one with a TZID for any other zone is an error rather than being read as local
+///
+/// A VTODO RELATED-TO another one in the same file comes back as its sub-task,
+/// only the top level tasks are returned and they all get new ids
+pub fn parse(text: &str) -> Result<Vec<Task>, String> {
+    let mut parsed = vec![];
+    let mut current: Option<VTodo> = None;
+    // How deep we are in components inside the VTODO, i.e. VALARM
+    let mut nested = 0;
+    for (line_no, line) in unfold(text) {
+        let err = |e: &str| format!("Line {}: {}", line_no, e);
+        let (name, params, value) = split_line(&line).ok_or_else(|| err("Invalid content line"))?;
+        match (name.as_str(), current.as_mut()) {
+            ("BEGIN", None) if value.eq_ignore_ascii_case("VTODO") => {
+                current = Some(VTodo::default())
+            }
+            ("BEGIN", Some(_)) => nested += 1,
+            ("END", Some(_)) if nested > 0 => nested -= 1,
+            ("END", Some(_)) => {
+                let todo = current.take().unwrap();
+                parsed.push(todo.into_task().map_err(err)?);
+            }
+            (_, Some(todo)) if nested == 0 => todo.set(&name, &params, &value).map_err(err)?,
+            _ => {}
+        }
+    }
+    if current.is_some() {
+        return Err("VTODO is missing its END".to_string());
+    }
+
+    let uids: HashSet<String> = parsed
+        .iter()
+        .filter_map(|(uid, _, _)| uid.clone())
+        .collect();
+    let mut roots = vec![];
+    let mut children: HashMap<String, Vec<(Option<String>, Task)>> = HashMap::new();
+    for (uid, parent, task) in parsed {
+        match parent.filter(|parent| uids.contains(parent)) {
+            Some(parent) => children.entry(parent).or_default().push((uid, task)),
+            None => roots.push((uid, task)),
+        }
+    }
+    let mut tasks: Vec<Task> = roots
+        .into_iter()
+        .map(|(uid, task)| attach(uid, task, &mut children))
+        .collect();
+    // Anything left over is related in a loop, it goes at the top level rather than getting lost
+    tasks.extend(
+        children
+            .drain()
+            .flat_map(|(_, tasks)| tasks)
+            .map(|(_, task)| task),
+    );
+    Ok(tasks)
+}
+
+// What's been read of a VTODO so far
+#[derive(Default)]
+struct VTodo {
+    uid: Option<String>,
+    related_to: Option<String>,
+    summary: Option<String>,
+    description: String,
+    due: Option<DateTime<chrono::Local>>,
+    priority: Option<Priority>,
+    categories: Vec<String>,
+    completed: bool,
+    completed_at: Option<DateTime<chrono::Local>>,
+    // DTSTART, RRULE and EXDATE lines for the recurrence to parse together
+    recurrence: Vec<String>,
+}
+
+impl VTodo {
+    fn set(&mut self, name: &str, params: &[String], value: &str) -> Result<(), &'static str> {
+        match name {
+            "UID" => self.uid = Some(value.to_string()),
+            "SUMMARY" => self.summary = Some(unescape(value)),
+            "DESCRIPTION" => self.description = unescape(value),
+            "DUE" => {
+                let date_only = params.iter().any(|p| p.eq_ignore_ascii_case("VALUE=DATE"))
+                    || value.trim().len() == 8;
+                self.due = Some(if date_only {
+                    let day = NaiveDate::parse_from_str(value.trim(), "%Y%m%d")
+                        .map_err(|_| "Invalid due date")?;
+                    at_local(day, NaiveTime::from_hms(23, 59, 59))?
+                } else {
+                    parse_date_time(&honor_tzid(params, value)?)?
+                });
+            }
+            "PRIORITY" => {
+                self.priority = match u8::from_str(value.trim()).map_err(|_| "Invalid priority")? {
+                    0 => None,
+                    1..=2 => Some(Priority::Extreme),
+                    3..=4 => Some(Priority::High),
+                    5 => Some(Priority::Medium),
+                    6..=9 => Some(Priority::Low),
+                    _ => return Err("Invalid priority"),
+                }
+            }
+            "CATEGORIES" => self.categories.extend(
+                split_list(value)
+                    .into_iter()
+                    .map(|category| unescape(&category))
+                    .filter(|category| !category.is_empty()),
+            ),
+            "STATUS" => self.completed = value.trim().eq_ignore_ascii_case("COMPLETED"),
+            "COMPLETED" => {
+                self.completed = true;
+                self.completed_at = Some(parse_date_time(&honor_tzid(params, value)?)?);
+            }
+            "DTSTART" | "EXDATE" => {
+                let value = honor_tzid(params, value)?;
+                self.recurrence.push(format!("{}:{}", name, value))
+            }
+            "RRULE" => self.recurrence.push(format!("{}:{}", name, value)),
+            "RELATED-TO" => {
+                // Only the parent matters, not siblings or children
+                let is_parent = params.iter().all(|p| {
+                    !p.to_ascii_uppercase().starts_with("RELTYPE=")
+                        || p.eq_ignore_ascii_case("RELTYPE=PARENT")
+                });
+                if is_parent {
+                    self.related_to = Some(value.to_string());
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn into_task(self) -> Result<(Option<String>, Option<String>, Task), &'static str> {
+        let summary = self.summary.ok_or("VTODO needs a SUMMARY")?;
+        // A DTSTART on its own is just when the work starts, not a repeat
+        let repeat = if self.recurrence.iter().any(|line| line.starts_with("RRULE")) {
+            Some(Recurrence::from_str(&self.recurrence.join("\n"))?)
+        } else {
+            None
+        };
+        let mut categories = self.categories.into_iter();
+        let mut task = Task::new(
+            &summary,
+            &self.description,
+            self.due,
+            0,
+            self.priority,
+            repeat,
+            categories.next(),
+        );
+        task.set_tags(categories.collect());
+        task.set_done(self.completed);
+        task.set_finished_at(self.completed_at);
+        Ok((self.uid, self.related_to, task))
+    }
+}
+
+// The value with its TZID folded in, UTC times get a Z. There's no time zone database to look
+// other zones up in, so only UTC can be honored
+fn honor_tzid(params: &[String], value: &str) -> Result<String, &'static str> {
+    let tzid = params.iter().find_map(|param| {
+        let (name, zone) = param.split_at(param.find('=')?);
+        if name.eq_ignore_ascii_case("TZID") {
+            Some(&zone[1..])
+        } else {
+            None
+        }
+    });
+    match tzid {
+        None => Ok(value.to_string()),
+        Some(zone) if UTC_ZONES.iter().any(|utc| zone.eq_ignore_ascii_case(utc)) => {
+            let times: Vec<String> = value
+                .split(',')
+                .map(|time| {
+                    let time = time.trim();
+                    // Dates have no time of day to be in a zone
+                    if time.len() == 8 || time.ends_with('Z') || time.ends_with('z') {
+                        time.to_string()
+                    } else {
+                        format!("{}Z", time)
+                    }
+                })
+                .collect();
+            Ok(times.join(","))
+        }
+        Some(_) => Err("Only UTC and local times are supported, not other time zones"),
+    }
+}
+
+fn attach(
+    uid: Option<String>,
+    mut task: Task,
+    children: &mut HashMap<String, Vec<(Option<String>, Task)>>,
+) -> Task {
+    if let Some(sub_tasks) = uid.and_then(|uid| children.remove(&uid)) {
+        for (uid, sub_task) in sub_tasks {
+            task.add_sub_task(attach(uid, sub_task, children));
+        }
+    }
+    task
+}
+
+fn priority_number(priority: Priority) -> u8 {
+    match priority {
+        Priority::Extreme => 1,
+        Priority::High => 3,
+        Priority::Medium => 5,
+        Priority::Low => 7,
+    }
+}
+
+fn utc<Tz: TimeZone>(time: DateTime<Tz>) -> String {
+    time.with_timezone(&Utc).format(UTC_FORMAT).to_string()
+}
+
+// TEXT values escape backslashes, separators and line breaks
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ';' => out.push_str("\\;"),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// Splits on the commas that aren't escaped, leaving the escapes in
+fn split_list(value: &str) -> Vec<String> {
+    let mut items = vec![String::new()];
+    let mut escaped = false;
+    for c in value.chars() {
+        if c == ',' && !escaped {
+            items.push(String::new());
+        } else {
+            items.last_mut().unwrap().push(c);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    items
+}
+
+// Breaks the line up into ones of at most 75 octets, without splitting a character
+fn fold(line: &str) -> String {
+    let mut out = String::with_capacity(line.len() + 2);
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > LINE_OCTETS {
+            out.push_str("\r\n ");
+            octets = 1;
+        }
+        out.push(c);
+        octets += c.len_utf8();
+    }
+    out.push_str("\r\n");
+    out
+}
+
+// Joins folded lines back together, keeping the number of the line each one started on
+fn unfold(text: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        match (line.chars().next(), lines.last_mut()) {
+            (Some(' '), Some((_, last))) | (Some('\t'), Some((_, last))) => {
+                last.push_str(&line[1..])
+            }
+            _ if line.trim().is_empty() => {}
+            _ => lines.push((idx + 1, line.to_string())),
+        }
+    }
+    lines
+}
+
+// NAME;PARAM=a;PARAM="b;c":value, the name comes back upper cased
+fn split_line(line: &str) -> Option<(String, Vec<String>, String)> {
+    let mut quoted = false;
+    let mut parts = vec![String::new()];
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => parts.push(String::new()),
+            ':' if !quoted => {
+                let name = parts.remove(0).to_ascii_uppercase();
+                if name.is_empty() {
+                    return None;
+                }
+                return Some((name, parts, line[idx + 1..].to_string()));
+            }
+            _ => parts.last_mut().unwrap().push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape, export, fold, parse, unescape, unfold};
+    use crate::backend::{CompletionStatus, Priority, Recurrence, Task, ToDo};
+    use chrono::prelude::{Local, TimeZone, Utc};
+    use std::str::FromStr;
+
+    #[test]
+    fn ical() {
+        let mut todo = ToDo::new();
+        let mut parent = Task::new(
+            "Plan trip",
+            "Flights, hotel; then\nthe car",
+            Some(Local.ymd(2020, 10, 20).and_hms(17, 30, 0)),
+            0,
+            Some(Priority::High),
+            Some(Recurrence::from_str("FREQ=WEEKLY;COUNT=3").unwrap()),
+            Some("travel".to_string()),
+        );
+        parent.set_tags(
+            vec!["family".to_string(), "abroad".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let parent_id = parent.get_id();
+        todo.add_task(parent);
+        let child = Task::new("Book hotel", "", None, 0, None, None, None);
+        let child_id = child.get_id();
+        todo.add_sub_task(parent_id, child).unwrap();
+        todo.mark_finished(child_id, Some(true)).unwrap();
+        todo.add_task(Task::new(
+            "Other",
+            "",
+            None,
+            0,
+            Some(Priority::Low),
+            None,
+            None,
+        ));
+
+        let text = export(&todo);
+        assert!(text.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(text.contains("CATEGORIES:travel,abroad,family\r\n"));
+        assert!(text.contains(&format!("RELATED-TO:{}\r\n", parent_id)));
+        assert!(text.lines().all(|line| line.len() <= 75));
+
+        let tasks = parse(&text).unwrap();
+        assert_eq!(tasks.len(), 2);
+        let parent = tasks.iter().find(|t| t.get_name() == "Plan trip").unwrap();
+        assert_ne!(parent.get_id(), parent_id);
+        assert_eq!(parent.get_desc(), "Flights, hotel; then\nthe car");
+        assert_eq!(
+            parent.get_due_date(),
+            Some(Local.ymd(2020, 10, 20).and_hms(17, 30, 0))
+        );
+        assert_eq!(parent.get_priority(), Some(Priority::High));
+        assert_eq!(parent.get_category(), Some("travel".to_string()));
+        assert!(parent.has_tag("family") && parent.has_tag("abroad"));
+        assert_eq!(
+            parent.get_repeats().map(|rule| rule.to_string()),
+            todo.get_task(parent_id)
+                .unwrap()
+                .get_repeats()
+                .map(|rule| rule.to_string())
+        );
+        assert_eq!(parent.get_sub_tasks().len(), 1);
+        assert!(parent.get_sub_tasks()[0].complete());
+        assert!(!parent.is_done());
+    }
+
+    #[test]
+    fn ical_from_elsewhere() {
+        let text = "BEGIN:VCALENDAR\r\n\
+                    VERSION:2.0\r\n\
+                    BEGIN:VEVENT\r\n\
+                    SUMMARY:Not a task\r\n\
+                    END:VEVENT\r\n\
+                    BEGIN:VTODO\r\n\
+                    UID:a@example.com\r\n\
+                    SUMMARY:Renew pass\r\n\
+                    \x20port\r\n\
+                    DUE;VALUE=DATE:20201101\r\n\
+                    PRIORITY:2\r\n\
+                    CATEGORIES:Errands\\, misc,Home\r\n\
+                    BEGIN:VALARM\r\n\
+                    DESCRIPTION:Reminder\r\n\
+                    END:VALARM\r\n\
+                    END:VTODO\r\n\
+                    BEGIN:VTODO\r\n\
+                    SUMMARY;LANGUAGE=en:Photo\r\n\
+                    RELATED-TO;RELTYPE=PARENT:a@example.com\r\n\
+                    STATUS:COMPLETED\r\n\
+                    COMPLETED:20201020T120000Z\r\n\
+                    END:VTODO\r\n\
+                    END:VCALENDAR\r\n";
+        let tasks = parse(text).unwrap();
+        assert_eq!(tasks.len(), 1);
+        let task = &tasks[0];
+        assert_eq!(task.get_name(), "Renew passport");
+        assert_eq!(task.get_desc(), "");
+        assert_eq!(
+            task.get_due_date(),
+            Some(Local.ymd(2020, 11, 1).and_hms(23, 59, 59))
+        );
+        assert_eq!(task.get_priority(), Some(Priority::Extreme));
+        assert_eq!(task.get_category(), Some("Errands, misc".to_string()));
+        assert!(task.has_tag("Home"));
+        assert_eq!(task.get_sub_tasks()[0].get_name(), "Photo");
+        assert!(task.get_sub_tasks()[0].complete());
+        assert_eq!(
+            task.get_sub_tasks()[0].get_finished_at(),
+            Some(
+                Utc.ymd(2020, 10, 20)
+                    .and_hms(12, 0, 0)
+                    .with_timezone(&Local)
+            )
+        );
+    }
+
+    #[test]
+    fn ical_time_zones() {
+        let vtodo = |lines: &str| {
+            parse(&format!(
+                "BEGIN:VTODO\r\nSUMMARY:Standup\r\n{}END:VTODO\r\n",
+                lines
+            ))
+        };
+        let tasks = vtodo(
+            "DTSTART;TZID=UTC:20201019T090000\r\n\
+             RRULE:FREQ=DAILY\r\n\
+             EXDATE;TZID=Etc/UTC:20201020T090000,20201021T090000\r\n\
+             DUE;TZID=\"UTC\":20201019T093000\r\n",
+        )
+        .unwrap();
+        let task = &tasks[0];
+        let at = |day| {
+            Utc.ymd(2020, 10, day)
+                .and_hms(9, 0, 0)
+                .with_timezone(&Local)
+        };
+        assert_eq!(
+            task.get_due_date(),
+            Some(
+                Utc.ymd(2020, 10, 19)
+                    .and_hms(9, 30, 0)
+                    .with_timezone(&Local)
+            )
+        );
+        let repeat = task.get_repeats().unwrap();
+        assert_eq!(repeat.get_start(), Some(at(19)));
+        assert_eq!(repeat.get_exceptions(), vec![at(20), at(21)]);
+
+        // A zone there's no way to convert from isn't taken as local time
+        for lines in &[
+            "DTSTART;TZID=America/New_York:20201019T090000\r\nRRULE:FREQ=DAILY\r\n",
+            "RRULE:FREQ=DAILY\r\nEXDATE;TZID=Europe/Paris:20201020T090000\r\n",
+            "DUE;TZID=Asia/Tokyo:20201019T093000\r\n",
+        ] {
+            assert!(vtodo(lines).is_err(), "{} should fail", lines);
+        }
+    }
+
+    #[test]
+    fn ical_lines() {
+        let long = "DESCRIPTION:".to_string() + &"é".repeat(50);
+        let folded = fold(&long);
+        assert!(folded.split("\r\n").all(|line| line.len() <= 75));
+        assert_eq!(unfold(&folded), vec![(1, long)]);
+        assert_eq!(unescape(&escape("a\\b;c,d\ne")), "a\\b;c,d\ne");
+
+        assert!(parse("BEGIN:VTODO\nDUE:tomorrow\nEND:VTODO").is_err());
+        assert_eq!(
+            parse("BEGIN:VTODO\nDESCRIPTION:No summary\nEND:VTODO").unwrap_err(),
+            "Line 3: VTODO needs a SUMMARY"
+        );
+        assert_eq!(
+            parse("BEGIN:VTODO\nnot a property\n").unwrap_err(),
+            "Line 2: Invalid content line"
+        );
+        assert!(parse("BEGIN:VTODO\nSUMMARY:Open\n").is_err());
+    }
+}
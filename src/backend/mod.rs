@@ -2,17 +2,39 @@ use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::str::FromStr;
 
+pub mod csv;
+pub mod history;
+pub mod ical;
+pub mod query;
+pub mod quick_add;
+pub mod recurrence;
+pub mod schema;
+pub mod scoring;
+pub mod source;
+pub mod storage;
 pub mod tasks;
+pub mod taskwarrior;
 pub mod todo;
+pub mod todo_txt;
 pub mod trello_api;
+pub mod trello_sync;
+pub mod trello_webhook;
 
-pub use tasks::Task;
+pub use history::History;
+pub use query::Query;
+pub use recurrence::Recurrence;
+pub use tasks::{Task, TimeSession};
 pub use todo::ToDo;
 
 pub trait EstTime {
     fn est_time(&self) -> u32;
 }
 
+/// Minutes actually spent, as opposed to the estimate
+pub trait TrackedTime {
+    fn tracked_time(&self) -> u32;
+}
+
 pub trait CompletionStatus {
     fn complete(&self) -> bool;
     fn completion_status(&self) -> (u32, u32);
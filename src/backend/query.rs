@@ -0,0 +1,701 @@
+use crate::backend::quick_add::parse_minutes;
+use crate::backend::{CompletionStatus, EstTime, Priority, Task, ToDo, TrackedTime};
+use chrono::prelude::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use chrono::Duration;
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+
+/// A parsed search over tasks.
+///
+/// Terms are `field op value`, joined with `and`, `or` and `not` and grouped with parentheses.
+/// Terms next to each other are and-ed together, `not` binds tightest and `or` loosest.
+/// Values with spaces or operator characters in them go in double quotes.
+///
+/// - `name`, `desc`, `text` (name or description) and `id` take `:` for a case-insensitive contains,
+///   `=`/`!=` for case-insensitive equality and `~` for a regex match
+/// - `tag` and `category` (or `cat`) take the same, but `:` matches the whole tag or category.
+///   `category:none` matches tasks without one
+/// - `due`, `priority` (or `pri`), `est` and `tracked` take `=`, `!=`, `<`, `<=`, `>` and `>=`.
+///   Due dates are `2020-10-31`, `"2020-10-31 17:00:00"`, `now`, `today`, `tomorrow`, `yesterday`
+///   or an offset from now like `+3d`, `-2h`, `+1w` or `+30m`. A plain date covers the whole day.
+///   `due=none` and `priority=none` match tasks without one.
+///   Times are in minutes, and take the `1h30m` form as well
+/// - `is:done`, `is:blocked`, `is:overdue`, `is:snoozed`, `is:tracking` and `is:repeating`
+/// - a bare word or quoted string is the same as `text:word`
+///
+/// e.g. `(tag:home or cat:chores) and due<=+2d and not is:done`, `name~"^Pay" priority>=high`
+#[derive(Debug, Clone)]
+pub struct Query {
+    expr: Expr,
+    source: String,
+}
+
+impl Query {
+    pub fn matches(&self, task: &Task, todo: &ToDo) -> bool {
+        self.expr.matches(task, todo, Local::now())
+    }
+
+    /// Checks a task as if it were `now`
+    pub fn matches_at(&self, task: &Task, todo: &ToDo, now: DateTime<Local>) -> bool {
+        self.expr.matches(task, todo, now)
+    }
+
+    /// Every task in the list that matches, sub-tasks included
+    pub fn filter<'a>(&self, todo: &'a ToDo) -> Vec<&'a Task> {
+        let now = Local::now();
+        todo.get_all_tasks()
+            .into_iter()
+            .filter(|task| self.expr.matches(task, todo, now))
+            .collect()
+    }
+}
+
+impl FromStr for Query {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        if tokens.is_empty() {
+            return Err("Empty query");
+        }
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+            terms: 0,
+        };
+        let expr = parser.parse_or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err("Unexpected closing parenthesis");
+        }
+        Ok(Query {
+            expr,
+            source: s.trim().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Term(Predicate),
+}
+
+impl Expr {
+    fn matches(&self, task: &Task, todo: &ToDo, now: DateTime<Local>) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(task, todo, now) && b.matches(task, todo, now),
+            Expr::Or(a, b) => a.matches(task, todo, now) || b.matches(task, todo, now),
+            Expr::Not(a) => !a.matches(task, todo, now),
+            Expr::Term(predicate) => predicate.matches(task, todo, now),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Predicate {
+    Text(TextField, Text),
+    Tag(Text),
+    // None is for tasks without a category
+    Category(Option<Text>),
+    Due(Cmp, Option<DateValue>),
+    Priority(Cmp, Option<Priority>),
+    Est(Cmp, u32),
+    Tracked(Cmp, u32),
+    Is(Flag),
+}
+
+impl Predicate {
+    fn matches(&self, task: &Task, todo: &ToDo, now: DateTime<Local>) -> bool {
+        match self {
+            Predicate::Text(TextField::Any, text) => {
+                text.matches(&task.get_name()) || text.matches(&task.get_desc())
+            }
+            Predicate::Text(TextField::Name, text) => text.matches(&task.get_name()),
+            Predicate::Text(TextField::Desc, text) => text.matches(&task.get_desc()),
+            Predicate::Text(TextField::Id, text) => text.matches(&task.get_id().to_string()),
+            Predicate::Tag(text) => task.get_tags().iter().any(|tag| text.matches(tag)),
+            Predicate::Category(None) => task.get_category().is_none(),
+            Predicate::Category(Some(text)) => match task.get_category() {
+                Some(category) => text.matches(&category),
+                None => false,
+            },
+            Predicate::Due(_, None) => task.get_due_date().is_none(),
+            Predicate::Due(cmp, Some(value)) => match task.get_due_date() {
+                Some(due_date) => {
+                    let (start, end) = value.range(now);
+                    cmp.check(due_date, start, end)
+                }
+                None => false,
+            },
+            Predicate::Priority(_, None) => task.get_priority().is_none(),
+            Predicate::Priority(cmp, Some(priority)) => match task.get_priority() {
+                Some(task_priority) => cmp.check(task_priority, *priority, *priority),
+                None => false,
+            },
+            Predicate::Est(cmp, minutes) => cmp.check(task.est_time(), *minutes, *minutes),
+            Predicate::Tracked(cmp, minutes) => cmp.check(task.tracked_time(), *minutes, *minutes),
+            Predicate::Is(flag) => match flag {
+                Flag::Done => task.complete(),
+                Flag::Blocked => todo.is_blocked(task.get_id()),
+                Flag::Overdue => match task.get_due_date() {
+                    Some(due_date) => due_date < now && !task.complete(),
+                    None => false,
+                },
+                Flag::Snoozed => todo.is_snoozed(task.get_id()),
+                Flag::Tracking => task.is_tracking(),
+                Flag::Repeating => task.get_repeats().is_some(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TextField {
+    Any,
+    Name,
+    Desc,
+    Id,
+}
+
+#[derive(Debug, Clone)]
+enum Text {
+    // Both lowercased ahead of time
+    Contains(String),
+    Equals(String),
+    Regex(Regex),
+}
+
+impl Text {
+    fn matches(&self, s: &str) -> bool {
+        match self {
+            Text::Contains(value) => s.to_lowercase().contains(value.as_str()),
+            Text::Equals(value) => s.to_lowercase() == *value,
+            Text::Regex(regex) => regex.is_match(s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cmp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Cmp {
+    // Values can cover a range, like a whole day, `start` and `end` are both inclusive
+    fn check<T: PartialOrd>(self, value: T, start: T, end: T) -> bool {
+        match self {
+            Cmp::Eq => value >= start && value <= end,
+            Cmp::Lt => value < start,
+            Cmp::Le => value <= end,
+            Cmp::Gt => value > end,
+            Cmp::Ge => value >= start,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Flag {
+    Done,
+    Blocked,
+    Overdue,
+    Snoozed,
+    Tracking,
+    Repeating,
+}
+
+// Relative dates are worked out when the query runs, so saved queries stay current
+// Offsets are kept to a thousand years, which any due date fits in and now can always be moved by
+const MAX_OFFSET_MINUTES: i64 = 1000 * 366 * 24 * 60;
+
+#[derive(Debug, Clone)]
+enum DateValue {
+    Now,
+    Offset(Duration),
+    // Days from today
+    Day(i64),
+    Date(NaiveDate),
+    At(DateTime<Local>),
+}
+
+impl DateValue {
+    fn range(&self, now: DateTime<Local>) -> (DateTime<Local>, DateTime<Local>) {
+        let date = match self {
+            DateValue::Now => return (now, now),
+            DateValue::Offset(offset) => return (now + *offset, now + *offset),
+            DateValue::At(at) => return (*at, *at),
+            DateValue::Day(days) => now.date().naive_local() + Duration::days(*days),
+            DateValue::Date(date) => *date,
+        };
+        let start = Local
+            .from_local_datetime(&date.and_hms(0, 0, 0))
+            .earliest()
+            .unwrap_or(now);
+        (start, start + Duration::days(1) - Duration::nanoseconds(1))
+    }
+}
+
+impl FromStr for DateValue {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &(s.to_ascii_lowercase())[..] {
+            "now" => return Ok(DateValue::Now),
+            "today" => return Ok(DateValue::Day(0)),
+            "tomorrow" => return Ok(DateValue::Day(1)),
+            "yesterday" => return Ok(DateValue::Day(-1)),
+            _ => {}
+        }
+        let offset = match (s.strip_prefix('+'), s.strip_prefix('-')) {
+            (Some(rest), _) => Some((1, rest)),
+            (_, Some(rest)) => Some((-1, rest)),
+            _ => None,
+        };
+        if let Some((sign, rest)) = offset {
+            let mut chars = rest.chars();
+            let unit = chars.next_back();
+            let amount = chars
+                .as_str()
+                .parse::<i64>()
+                .map_err(|_| "Invalid date offset")?;
+            let minutes_per = match unit {
+                Some('m') => 1,
+                Some('h') => 60,
+                Some('d') => 60 * 24,
+                Some('w') => 60 * 24 * 7,
+                _ => return Err("Invalid date offset"),
+            };
+            return match amount.checked_mul(minutes_per) {
+                Some(minutes) if minutes.abs() <= MAX_OFFSET_MINUTES => {
+                    Ok(DateValue::Offset(Duration::minutes(sign * minutes)))
+                }
+                _ => Err("Date offset is too far out"),
+            };
+        }
+        if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(DateValue::Date(date));
+        }
+        if let Ok(at) = DateTime::parse_from_rfc3339(s) {
+            return Ok(DateValue::At(at.with_timezone(&Local)));
+        }
+        // The same format the rest of the api takes
+        ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+            .iter()
+            .filter_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+            .filter_map(|at| Local.from_local_datetime(&at).earliest())
+            .map(DateValue::At)
+            .next()
+            .ok_or("Invalid date")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Contains,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Regex,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Op(Op),
+    Word(String),
+    Quoted(String),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ':' => Token::Op(Op::Contains),
+            '~' => Token::Op(Op::Regex),
+            '=' => Token::Op(Op::Eq),
+            '!' if chars.peek() == Some(&'=') => {
+                chars.next();
+                Token::Op(Op::Ne)
+            }
+            '<' | '>' => {
+                let or_equal = chars.peek() == Some(&'=');
+                if or_equal {
+                    chars.next();
+                }
+                Token::Op(match (c, or_equal) {
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    (_, false) => Op::Gt,
+                    (_, true) => Op::Ge,
+                })
+            }
+            '"' => {
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => quoted.push(escaped),
+                            None => return Err("Unterminated quote"),
+                        },
+                        Some(c) => quoted.push(c),
+                        None => return Err("Unterminated quote"),
+                    }
+                }
+                Token::Quoted(quoted)
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "()\":~=<>".contains(next) {
+                        break;
+                    }
+                    // A lone ! is part of the word, only != splits it
+                    if next == '!' {
+                        let mut ahead = chars.clone();
+                        ahead.next();
+                        if ahead.peek() == Some(&'=') {
+                            break;
+                        }
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// Parsing and matching both recurse through the expression, these keep queries from running out of stack
+const MAX_DEPTH: usize = 32;
+const MAX_TERMS: usize = 256;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // How many parentheses and nots the parser is inside of
+    depth: usize,
+    terms: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn descend(&mut self) -> Result<(), &'static str> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("Query is nested too deeply");
+        }
+        Ok(())
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) => word.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, &'static str> {
+        let mut expr = self.parse_and()?;
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, &'static str> {
+        let mut expr = self.parse_not()?;
+        loop {
+            if self.peek_keyword("and") {
+                self.pos += 1;
+            } else if self.peek_keyword("or") {
+                break;
+            } else {
+                // Anything else that can start a term is and-ed on
+                match self.peek() {
+                    Some(Token::Open) | Some(Token::Word(_)) | Some(Token::Quoted(_)) => {}
+                    _ => break,
+                }
+            }
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, &'static str> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            self.descend()?;
+            let expr = self.parse_not()?;
+            self.depth -= 1;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+        self.parse_term()
+    }
+
+    fn parse_term(&mut self) -> Result<Expr, &'static str> {
+        self.terms += 1;
+        if self.terms > MAX_TERMS {
+            return Err("Query has too many terms");
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                self.descend()?;
+                let expr = self.parse_or()?;
+                self.depth -= 1;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("Missing closing parenthesis"),
+                }
+            }
+            Some(Token::Word(field)) => match self.peek() {
+                Some(Token::Op(op)) => {
+                    let op = *op;
+                    self.pos += 1;
+                    let value = match self.next() {
+                        Some(Token::Word(value)) | Some(Token::Quoted(value)) => value,
+                        _ => return Err("Missing value after operator"),
+                    };
+                    term(&field, op, &value)
+                }
+                _ => Ok(Expr::Term(Predicate::Text(
+                    TextField::Any,
+                    Text::Contains(field.to_lowercase()),
+                ))),
+            },
+            Some(Token::Quoted(text)) => Ok(Expr::Term(Predicate::Text(
+                TextField::Any,
+                Text::Contains(text.to_lowercase()),
+            ))),
+            Some(Token::Op(_)) => Err("Operator is missing a field"),
+            Some(Token::Close) => Err("Unexpected closing parenthesis"),
+            None => Err("Query ended early"),
+        }
+    }
+}
+
+fn term(field: &str, op: Op, value: &str) -> Result<Expr, &'static str> {
+    // != is just a negated =
+    let (op, negate) = match op {
+        Op::Ne => (Op::Eq, true),
+        op => (op, false),
+    };
+    let none = value.eq_ignore_ascii_case("none");
+    let predicate = match &(field.to_ascii_lowercase())[..] {
+        "text" => Predicate::Text(TextField::Any, text(op, value, false)?),
+        "name" => Predicate::Text(TextField::Name, text(op, value, false)?),
+        "desc" => Predicate::Text(TextField::Desc, text(op, value, false)?),
+        "id" => Predicate::Text(TextField::Id, text(op, value, false)?),
+        "tag" => Predicate::Tag(text(op, value, true)?),
+        "category" | "cat" => match (none, op) {
+            (true, Op::Contains) | (true, Op::Eq) => Predicate::Category(None),
+            _ => Predicate::Category(Some(text(op, value, true)?)),
+        },
+        "due" => match (none, cmp(op)?) {
+            (true, Cmp::Eq) => Predicate::Due(Cmp::Eq, None),
+            (true, _) => return Err("Only = and != work with none"),
+            (false, cmp) => Predicate::Due(cmp, Some(DateValue::from_str(value)?)),
+        },
+        "priority" | "pri" => match (none, cmp(op)?) {
+            (true, Cmp::Eq) => Predicate::Priority(Cmp::Eq, None),
+            (true, _) => return Err("Only = and != work with none"),
+            (false, cmp) => Predicate::Priority(cmp, Some(Priority::from_str(value)?)),
+        },
+        "est" => Predicate::Est(
+            cmp(op)?,
+            parse_minutes(value).ok_or("Invalid number of minutes")?,
+        ),
+        "tracked" => Predicate::Tracked(
+            cmp(op)?,
+            parse_minutes(value).ok_or("Invalid number of minutes")?,
+        ),
+        "is" => {
+            if op != Op::Contains && op != Op::Eq {
+                return Err("is only works with : and !=");
+            }
+            Predicate::Is(match &(value.to_ascii_lowercase())[..] {
+                "done" | "complete" => Flag::Done,
+                "blocked" => Flag::Blocked,
+                "overdue" => Flag::Overdue,
+                "snoozed" => Flag::Snoozed,
+                "tracking" => Flag::Tracking,
+                "repeating" => Flag::Repeating,
+                _ => return Err("Unknown is: flag"),
+            })
+        }
+        _ => return Err("Unknown field"),
+    };
+    let expr = Expr::Term(predicate);
+    Ok(if negate {
+        Expr::Not(Box::new(expr))
+    } else {
+        expr
+    })
+}
+
+// `whole` makes : match the entire value, for tags and categories
+fn text(op: Op, value: &str, whole: bool) -> Result<Text, &'static str> {
+    match op {
+        Op::Contains if !whole => Ok(Text::Contains(value.to_lowercase())),
+        Op::Contains | Op::Eq => Ok(Text::Equals(value.to_lowercase())),
+        Op::Regex => Regex::new(value)
+            .map(Text::Regex)
+            .map_err(|_| "Invalid regex"),
+        _ => Err("Text only works with :, =, != and ~"),
+    }
+}
+
+fn cmp(op: Op) -> Result<Cmp, &'static str> {
+    match op {
+        Op::Contains | Op::Eq => Ok(Cmp::Eq),
+        Op::Lt => Ok(Cmp::Lt),
+        Op::Le => Ok(Cmp::Le),
+        Op::Gt => Ok(Cmp::Gt),
+        Op::Ge => Ok(Cmp::Ge),
+        _ => Err("Regex only works on text fields"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Query;
+    use crate::backend::{Priority, Task, ToDo};
+    use chrono::prelude::{Local, TimeZone};
+    use std::str::FromStr;
+
+    fn names(query: &str, todo: &ToDo) -> Vec<String> {
+        let now = Local.ymd(2020, 10, 14).and_hms(12, 0, 0);
+        let query = Query::from_str(query).unwrap();
+        let mut names: Vec<String> = todo
+            .get_all_tasks()
+            .into_iter()
+            .filter(|task| query.matches_at(task, todo, now))
+            .map(|task| task.get_name())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn query() {
+        let mut rent = Task::new(
+            "Pay rent",
+            "Transfer to the landlord",
+            Some(Local.ymd(2020, 10, 16).and_hms(9, 0, 0)),
+            10,
+            Some(Priority::High),
+            None,
+            Some("bills".to_string()),
+        );
+        rent.set_tags(vec!["home".to_string()].into_iter().collect());
+        let mut dishes = Task::new(
+            "Dishes",
+            "Before the weekend",
+            Some(Local.ymd(2020, 10, 13).and_hms(20, 0, 0)),
+            30,
+            Some(Priority::Low),
+            None,
+            None,
+        );
+        dishes.set_tags(
+            vec!["home".to_string(), "chores".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let report = Task::new("Report", "Quarterly numbers", None, 120, None, None, None);
+        let report_id = report.get_id();
+        let mut todo = ToDo::from_vec(vec![rent, dishes, report]);
+        todo.mark_finished(report_id, Some(true)).unwrap();
+
+        assert_eq!(names("RENT", &todo), vec!["Pay rent"]);
+        assert_eq!(names("desc:weekend", &todo), vec!["Dishes"]);
+        assert_eq!(names(r#"name~"^P""#, &todo), vec!["Pay rent"]);
+        assert_eq!(
+            names("tag:home and not tag:chores", &todo),
+            vec!["Pay rent"]
+        );
+        assert_eq!(
+            names("tag=chores or is:done", &todo),
+            vec!["Dishes", "Report"]
+        );
+        assert_eq!(names("cat:none", &todo), vec!["Dishes", "Report"]);
+        assert_eq!(names("cat!=none", &todo), vec!["Pay rent"]);
+        assert_eq!(names("priority>=medium", &todo), vec!["Pay rent"]);
+        assert_eq!(names("pri=none", &todo), vec!["Report"]);
+        assert_eq!(names("est>=30 est<1h", &todo), vec!["Dishes"]);
+        assert_eq!(names("due=2020-10-16", &todo), vec!["Pay rent"]);
+        assert_eq!(names("due<=+2d", &todo), vec!["Dishes", "Pay rent"]);
+        assert_eq!(names("due<today", &todo), vec!["Dishes"]);
+        assert_eq!(names("is:overdue", &todo), vec!["Dishes"]);
+        assert_eq!(
+            names("(tag:home or est>1h) and not (is:done or due<now)", &todo),
+            vec!["Pay rent"]
+        );
+        assert_eq!(
+            names(r#"due>"2020-10-16 08:00:00" or due=none"#, &todo),
+            vec!["Pay rent", "Report"]
+        );
+    }
+
+    #[test]
+    fn query_errors() {
+        for query in &[
+            "",
+            "(tag:home",
+            "tag:home)",
+            "name:",
+            "color:red",
+            "due~today",
+            "name<b",
+            "due:someday",
+            "priority>none",
+            "is:purple",
+            r#"name~"(""#,
+            r#"name:"open"#,
+            "due<+1é",
+            "due>-é",
+            "due<+99999999999999w",
+            "est>99999999h",
+        ] {
+            assert!(Query::from_str(query).is_err(), "{} should fail", query);
+        }
+        assert!(Query::from_str(&format!("{}a{}", "(".repeat(5000), ")".repeat(5000))).is_err());
+        assert!(Query::from_str(&format!("{}a", "not ".repeat(5000))).is_err());
+        assert!(Query::from_str(&"a ".repeat(5000)).is_err());
+        assert!(Query::from_str("((not a) or not (b and c))").is_ok());
+    }
+}
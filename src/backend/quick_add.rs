@@ -0,0 +1,323 @@
+use crate::backend::recurrence::Frequency;
+use crate::backend::{Priority, Recurrence, Task};
+use chrono::prelude::{DateTime, Local, NaiveDate, NaiveTime, TimeZone, Weekday};
+use chrono::{Datelike, Duration};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+const WORK_WEEK: [Weekday; 5] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+];
+
+/// Turns a single line into a task, e.g. `Pay rent fri 9am !high #home ~30m every month`.
+///
+/// - `!high` sets the priority, `#home` adds a tag and `@bills` sets the category
+/// - `~30m`, `~2h` or `~1h30m` sets the estimate
+/// - `today`, `tomorrow`, a weekday or `2020-10-31` sets the due date, `9am`, `9:30pm`, `17:00` or `noon` the time.
+///   A date on its own is due at the end of the day, a time on its own is the next time it comes around
+/// - `daily`, `every 2 weeks`, `every friday` or `every weekday` sets the repeat
+/// - everything after a lone `--` is the description
+///
+/// Whatever is left over makes up the name.
+pub fn parse(input: &str, now: DateTime<Local>) -> Result<Task, &'static str> {
+    let tokens: Vec<&str> = input.split_whitespace().collect();
+    let mut name: Vec<&str> = vec![];
+    let mut desc = String::new();
+    let mut date: Option<NaiveDate> = None;
+    let mut time: Option<NaiveTime> = None;
+    let mut priority: Option<Priority> = None;
+    let mut category: Option<String> = None;
+    let mut est_minutes: Option<u32> = None;
+    let mut repeat: Option<Recurrence> = None;
+    // First day of an `every <weekday>` rule, used when there's no due date
+    let mut repeat_day: Option<Weekday> = None;
+    let mut tags = HashSet::new();
+
+    let today = now.date().naive_local();
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        let lower = token.to_ascii_lowercase();
+        i += 1;
+        if token == "--" {
+            desc = tokens[i..].join(" ");
+            break;
+        } else if token.len() > 1 && token.starts_with('!') {
+            set_once(
+                &mut priority,
+                Priority::from_str(&token[1..])?,
+                "Priority given more than once",
+            )?;
+        } else if token.len() > 1 && token.starts_with('#') {
+            tags.insert(token[1..].to_string());
+        } else if token.len() > 1 && token.starts_with('@') {
+            set_once(
+                &mut category,
+                token[1..].to_string(),
+                "Category given more than once",
+            )?;
+        } else if token.len() > 1 && token.starts_with('~') {
+            let minutes = parse_minutes(&lower[1..]).ok_or("Invalid estimate")?;
+            set_once(&mut est_minutes, minutes, "Estimate given more than once")?;
+        } else if let Some(day) = parse_date(&lower, today) {
+            set_once(&mut date, day, "Due date given more than once")?;
+        } else if let Some(at) = parse_time(&lower) {
+            set_once(&mut time, at, "Due time given more than once")?;
+        } else if let Some(freq) = parse_frequency(&lower) {
+            set_once(
+                &mut repeat,
+                Recurrence::new(freq, 1),
+                "Repeat given more than once",
+            )?;
+        } else if let (true, Some((rule, first_day, used))) =
+            (lower == "every", parse_every(&tokens[i..]))
+        {
+            set_once(&mut repeat, rule, "Repeat given more than once")?;
+            repeat_day = first_day;
+            i += used;
+        } else {
+            name.push(token);
+        }
+    }
+
+    if name.is_empty() {
+        return Err("Task needs a name");
+    }
+    // Repeats count from the due date, so they need one to start from
+    if let (None, Some(day)) = (date, repeat_day) {
+        date = Some(next_weekday(today, day));
+    } else if let (None, None, Some(_)) = (date, time, &repeat) {
+        date = Some(today);
+    }
+    let due_date = match (date, time) {
+        (Some(date), time) => Some(at_local(
+            date,
+            time.unwrap_or_else(|| NaiveTime::from_hms(23, 59, 59)),
+        )?),
+        (None, Some(time)) => {
+            let due_date = at_local(today, time)?;
+            Some(if due_date > now {
+                due_date
+            } else {
+                at_local(today + Duration::days(1), time)?
+            })
+        }
+        (None, None) => None,
+    };
+
+    let mut task = Task::new(
+        name.join(" ").as_str(),
+        desc.as_str(),
+        due_date,
+        est_minutes.unwrap_or(0),
+        priority,
+        repeat,
+        category,
+    );
+    task.set_tags(tags);
+    Ok(task)
+}
+
+fn set_once<T>(slot: &mut Option<T>, value: T, err: &'static str) -> Result<(), &'static str> {
+    if slot.is_some() {
+        return Err(err);
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+// 30m, 2h, 1h30m, or a bare number of minutes
+pub(crate) fn parse_minutes(s: &str) -> Option<u32> {
+    if let Ok(minutes) = s.parse::<u32>() {
+        return Some(minutes);
+    }
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        let minutes = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue;
+            }
+            'h' => std::mem::take(&mut number)
+                .parse::<u32>()
+                .ok()?
+                .checked_mul(60)?,
+            'm' => std::mem::take(&mut number).parse::<u32>().ok()?,
+            _ => return None,
+        };
+        total = total.checked_add(minutes)?;
+    }
+    if number.is_empty() {
+        Some(total)
+    } else {
+        None
+    }
+}
+
+fn parse_date(s: &str, today: NaiveDate) -> Option<NaiveDate> {
+    match s {
+        "today" => Some(today),
+        "tomorrow" => Some(today + Duration::days(1)),
+        _ => match Weekday::from_str(s) {
+            Ok(day) => Some(next_weekday(today, day)),
+            Err(_) => NaiveDate::parse_from_str(s, "%Y-%m-%d").ok(),
+        },
+    }
+}
+
+// 9am, 9:30pm, 17:00 or noon
+fn parse_time(s: &str) -> Option<NaiveTime> {
+    if s == "noon" {
+        return Some(NaiveTime::from_hms(12, 0, 0));
+    }
+    let (clock, offset) = if s.ends_with("am") {
+        (&s[..s.len() - 2], Some(0))
+    } else if s.ends_with("pm") {
+        (&s[..s.len() - 2], Some(12))
+    } else {
+        (s, None)
+    };
+    let mut parts = clock.splitn(2, ':');
+    let hour = parts.next()?.parse::<u32>().ok()?;
+    let minute = match parts.next() {
+        Some(minute) if minute.len() == 2 => minute.parse::<u32>().ok()?,
+        Some(_) => return None,
+        // A bare number is only a time with am/pm after it
+        None if offset.is_some() => 0,
+        None => return None,
+    };
+    let hour = match offset {
+        Some(offset) if (1..=12).contains(&hour) => hour % 12 + offset,
+        Some(_) => return None,
+        None => hour,
+    };
+    NaiveTime::from_hms_opt(hour, minute, 0)
+}
+
+fn parse_frequency(s: &str) -> Option<Frequency> {
+    match s {
+        "daily" => Some(Frequency::Daily),
+        "weekly" => Some(Frequency::Weekly),
+        "monthly" => Some(Frequency::Monthly),
+        "yearly" | "annually" => Some(Frequency::Yearly),
+        _ => None,
+    }
+}
+
+// What follows an `every`: `day`, `2 weeks`, `friday`, `mon,thu` or `weekday`.
+// Returns the rule, the first weekday it lands on if it's day based, and how many tokens it took
+fn parse_every(tokens: &[&str]) -> Option<(Recurrence, Option<Weekday>, usize)> {
+    let first = tokens.first()?.to_ascii_lowercase();
+    if first == "weekday" {
+        return Some((Recurrence::on_weekdays(WORK_WEEK.to_vec()), None, 1));
+    }
+    let days: Option<Vec<Weekday>> = first
+        .split(',')
+        .map(|day| Weekday::from_str(day).ok())
+        .collect();
+    if let Some(days) = days {
+        let first_day = days.first().copied();
+        return Some((Recurrence::on_weekdays(days), first_day, 1));
+    }
+    let (interval, unit, used) = match first.parse::<u32>() {
+        Ok(interval) => (interval, tokens.get(1)?.to_ascii_lowercase(), 2),
+        Err(_) => (1, first, 1),
+    };
+    let freq = match unit.trim_end_matches('s') {
+        "day" => Frequency::Daily,
+        "week" => Frequency::Weekly,
+        "month" => Frequency::Monthly,
+        "year" => Frequency::Yearly,
+        _ => return None,
+    };
+    Some((Recurrence::new(freq, interval), None, used))
+}
+
+// The next time `day` comes around, today included
+fn next_weekday(today: NaiveDate, day: Weekday) -> NaiveDate {
+    let ahead = (7 + day.num_days_from_monday() - today.weekday().num_days_from_monday()) % 7;
+    today + Duration::days(ahead as i64)
+}
+
+pub(crate) fn at_local(date: NaiveDate, time: NaiveTime) -> Result<DateTime<Local>, &'static str> {
+    Local
+        .from_local_datetime(&date.and_time(time))
+        .single()
+        .ok_or("Due date doesn't exist in the local time zone")
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+    use crate::backend::recurrence::Frequency;
+    use crate::backend::{EstTime, Priority};
+    use chrono::prelude::{Local, TimeZone};
+
+    #[test]
+    fn quick_add() {
+        // A Wednesday
+        let now = Local.ymd(2020, 10, 14).and_hms(12, 0, 0);
+        let task = parse("Pay rent fri 9am !high #home ~30m every month", now).unwrap();
+        assert_eq!(task.get_name(), "Pay rent");
+        assert_eq!(
+            task.get_due_date(),
+            Some(Local.ymd(2020, 10, 16).and_hms(9, 0, 0))
+        );
+        assert_eq!(task.get_priority(), Some(Priority::High));
+        assert!(task.has_tag("home"));
+        assert_eq!(task.est_time(), 30);
+        let rule = task.get_repeats().unwrap();
+        assert_eq!(rule.get_freq(), Frequency::Monthly);
+        assert_eq!(rule.get_interval(), 1);
+
+        let task = parse("Read @books ~1h30m tomorrow -- chapter 3", now).unwrap();
+        assert_eq!(task.get_category(), Some("books".to_string()));
+        assert_eq!(task.est_time(), 90);
+        assert_eq!(
+            task.get_due_date(),
+            Some(Local.ymd(2020, 10, 15).and_hms(23, 59, 59))
+        );
+        assert_eq!(task.get_desc(), "chapter 3");
+
+        // Already past today, so it's tomorrow's
+        let task = parse("Call the bank 11am", now).unwrap();
+        assert_eq!(
+            task.get_due_date(),
+            Some(Local.ymd(2020, 10, 15).and_hms(11, 0, 0))
+        );
+
+        let task = parse("Water plants every 2 weeks", now).unwrap();
+        assert_eq!(task.get_repeats().unwrap().get_interval(), 2);
+        assert_eq!(
+            task.get_due_date(),
+            Some(Local.ymd(2020, 10, 14).and_hms(23, 59, 59))
+        );
+        let task = parse("Trash every monday 7:30pm", now).unwrap();
+        assert_eq!(
+            task.get_due_date(),
+            Some(Local.ymd(2020, 10, 19).and_hms(19, 30, 0))
+        );
+        // Words that only look like part of the syntax stay in the name
+        let task = parse("Plan every detail", now).unwrap();
+        assert_eq!(task.get_name(), "Plan every detail");
+        assert!(task.get_repeats().is_none());
+    }
+
+    #[test]
+    fn quick_add_errors() {
+        let now = Local::now();
+        assert!(parse("", now).is_err());
+        assert!(parse("!high #home", now).is_err());
+        assert!(parse("Dentist today tomorrow", now).is_err());
+        assert!(parse("Dentist !urgent", now).is_err());
+        assert!(parse("Dentist ~soon", now).is_err());
+        // Too many minutes to count
+        assert!(parse("Dentist ~99999999h", now).is_err());
+        assert!(parse("Dentist ~4294967295m1m", now).is_err());
+    }
+}
@@ -0,0 +1,615 @@
+use chrono::prelude::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use chrono::{Datelike, Duration};
+use serde::de::{Deserialize, Deserializer, Error, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+// Stops the search for the next occurrence from spinning forever on rules that can't happen (Feb 30th)
+const MAX_PERIODS: i64 = 10_000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl FromStr for Frequency {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &(s.to_ascii_uppercase())[..] {
+            "DAILY" => Ok(Frequency::Daily),
+            "WEEKLY" => Ok(Frequency::Weekly),
+            "MONTHLY" => Ok(Frequency::Monthly),
+            "YEARLY" => Ok(Frequency::Yearly),
+            _ => Err("Invalid or unsupported FREQ"),
+        }
+    }
+}
+
+impl fmt::Display for Frequency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+            Frequency::Yearly => "YEARLY",
+        })
+    }
+}
+
+/// An RFC 5545 recurrence rule, along with the DTSTART it's anchored to and any EXDATE exceptions.
+///
+/// The string form is made up of iCalendar content lines, e.g.
+/// `DTSTART:20200925T170000\nRRULE:FREQ=MONTHLY;BYDAY=-1FR\nEXDATE:20201225T170000`.
+/// A bare `FREQ=...` rule is accepted as well.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recurrence {
+    freq: Frequency,
+    interval: u32,
+    // Ordinal (i.e. the -1 in -1FR) and the day
+    by_day: Vec<(Option<i32>, Weekday)>,
+    by_month_day: Vec<i32>,
+    by_month: Vec<u32>,
+    count: Option<u32>,
+    until: Option<DateTime<Local>>,
+    start: Option<DateTime<Local>>,
+    exceptions: Vec<DateTime<Local>>,
+}
+
+impl Recurrence {
+    pub fn new(freq: Frequency, interval: u32) -> Self {
+        Recurrence {
+            freq,
+            interval: interval.max(1),
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+            by_month: Vec::new(),
+            count: None,
+            until: None,
+            start: None,
+            exceptions: Vec::new(),
+        }
+    }
+
+    /// Repeat every week on the given days, what tasks used to be limited to
+    pub fn on_weekdays(days: Vec<Weekday>) -> Self {
+        let mut rule = Recurrence::new(Frequency::Weekly, 1);
+        rule.by_day = days.into_iter().map(|day| (None, day)).collect();
+        rule
+    }
+
+    pub fn get_freq(&self) -> Frequency {
+        self.freq
+    }
+
+    pub fn get_interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// The days the rule is limited to, with their ordinal if it has one
+    pub fn get_by_day(&self) -> Vec<(Option<i32>, Weekday)> {
+        self.by_day.clone()
+    }
+
+    pub fn get_until(&self) -> Option<DateTime<Local>> {
+        self.until
+    }
+
+    pub fn get_start(&self) -> Option<DateTime<Local>> {
+        self.start
+    }
+
+    pub fn set_start(&mut self, start: DateTime<Local>) {
+        self.start = Some(start)
+    }
+
+    pub fn get_exceptions(&self) -> Vec<DateTime<Local>> {
+        self.exceptions.clone()
+    }
+
+    /// Skip a single occurrence of the rule
+    pub fn add_exception(&mut self, date: DateTime<Local>) {
+        self.exceptions.push(date)
+    }
+
+    /// Finds the first occurrence strictly after `after`.
+    /// Returns None if the rule has no start or has run out of occurrences (COUNT/UNTIL)
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = self.start?;
+        let start_date = start.naive_local().date();
+        let mut seen = 0;
+        for period in 0..MAX_PERIODS {
+            let occurrences = self
+                .period_dates(start_date, period)
+                .into_iter()
+                .filter_map(|date| {
+                    Local
+                        .from_local_datetime(&date.and_time(start.time()))
+                        .earliest()
+                })
+                .filter(|occurrence| *occurrence >= start);
+            for occurrence in occurrences {
+                if let Some(until) = self.until {
+                    if occurrence > until {
+                        return None;
+                    }
+                }
+                // Excluded dates still count towards COUNT
+                seen += 1;
+                if let Some(count) = self.count {
+                    if seen > count {
+                        return None;
+                    }
+                }
+                if occurrence > after && !self.exceptions.contains(&occurrence) {
+                    return Some(occurrence);
+                }
+            }
+        }
+        None
+    }
+
+    // All of the days in the nth period after the start that satisfy the rule, in order
+    fn period_dates(&self, start: NaiveDate, period: i64) -> Vec<NaiveDate> {
+        let step = period * self.interval as i64;
+        let days: Vec<NaiveDate> = match self.freq {
+            Frequency::Daily => vec![start + Duration::days(step)],
+            Frequency::Weekly => {
+                let monday = start - Duration::days(start.weekday().num_days_from_monday() as i64)
+                    + Duration::weeks(step);
+                (0..7).map(|i| monday + Duration::days(i)).collect()
+            }
+            Frequency::Monthly => {
+                let months = start.year() as i64 * 12 + start.month0() as i64 + step;
+                let year = (months / 12) as i32;
+                let month = (months % 12) as u32 + 1;
+                (1..=days_in_month(year, month))
+                    .map(|day| NaiveDate::from_ymd(year, month, day))
+                    .collect()
+            }
+            Frequency::Yearly => {
+                let year = start.year() + step as i32;
+                (1..=days_in_year(year))
+                    .map(|day| NaiveDate::from_yo(year, day))
+                    .collect()
+            }
+        };
+        days.into_iter()
+            .filter(|day| self.matches(*day, start))
+            .collect()
+    }
+
+    fn matches(&self, day: NaiveDate, start: NaiveDate) -> bool {
+        if !self.by_month.is_empty() && !self.by_month.contains(&day.month()) {
+            return false;
+        }
+        match self.freq {
+            Frequency::Daily => self.matches_month_day(day) && self.matches_weekday(day, false),
+            Frequency::Weekly => {
+                if self.by_day.is_empty() {
+                    day.weekday() == start.weekday()
+                } else {
+                    self.matches_weekday(day, false)
+                }
+            }
+            Frequency::Monthly => {
+                if self.by_month_day.is_empty() && self.by_day.is_empty() {
+                    day.day() == start.day()
+                } else {
+                    self.matches_month_day(day) && self.matches_weekday(day, false)
+                }
+            }
+            Frequency::Yearly => {
+                if self.by_month_day.is_empty() && self.by_day.is_empty() {
+                    // Without BYMONTH it's the anniversary of the start
+                    (!self.by_month.is_empty() || day.month() == start.month())
+                        && day.day() == start.day()
+                } else {
+                    // Ordinals are within the year unless we've been limited to certain months
+                    self.matches_month_day(day)
+                        && self.matches_weekday(day, self.by_month.is_empty())
+                }
+            }
+        }
+    }
+
+    fn matches_month_day(&self, day: NaiveDate) -> bool {
+        let last = days_in_month(day.year(), day.month()) as i32;
+        self.by_month_day.is_empty()
+            || self.by_month_day.iter().any(|month_day| {
+                if *month_day > 0 {
+                    day.day() as i32 == *month_day
+                } else {
+                    day.day() as i32 == last + 1 + *month_day
+                }
+            })
+    }
+
+    fn matches_weekday(&self, day: NaiveDate, yearly: bool) -> bool {
+        let (pos, len) = if yearly {
+            (day.ordinal() as i32, days_in_year(day.year()) as i32)
+        } else {
+            (
+                day.day() as i32,
+                days_in_month(day.year(), day.month()) as i32,
+            )
+        };
+        let nth = (pos - 1) / 7 + 1;
+        let nth_from_end = -((len - pos) / 7 + 1);
+        self.by_day.is_empty()
+            || self.by_day.iter().any(|(ordinal, weekday)| {
+                *weekday == day.weekday()
+                    && match ordinal {
+                        Some(ordinal) => *ordinal == nth || *ordinal == nth_from_end,
+                        None => true,
+                    }
+            })
+    }
+
+    fn parse_rule(value: &str) -> Result<Self, &'static str> {
+        let mut freq = None;
+        let mut rule = Recurrence::new(Frequency::Daily, 1);
+        for part in value.split(';').filter(|part| !part.is_empty()) {
+            let mut pair = part.splitn(2, '=');
+            let key = pair.next().unwrap().trim().to_ascii_uppercase();
+            let value = pair
+                .next()
+                .ok_or("Recurrence rule part is missing a value")?;
+            match key.as_str() {
+                "FREQ" => freq = Some(Frequency::from_str(value)?),
+                "INTERVAL" => {
+                    rule.interval = match u32::from_str(value) {
+                        Ok(interval) if interval > 0 => interval,
+                        _ => return Err("INTERVAL must be a positive number"),
+                    }
+                }
+                "COUNT" => rule.count = Some(u32::from_str(value).map_err(|_| "Invalid COUNT")?),
+                "UNTIL" => {
+                    let until = parse_date_time(value)?;
+                    // A plain date includes the whole day
+                    rule.until = Some(if value.trim().len() == 8 {
+                        until + Duration::days(1) - Duration::seconds(1)
+                    } else {
+                        until
+                    });
+                }
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        rule.by_day.push(parse_by_day(day)?);
+                    }
+                }
+                "BYMONTHDAY" => {
+                    for day in value.split(',') {
+                        match i32::from_str(day) {
+                            Ok(day) if day != 0 && day.abs() <= 31 => rule.by_month_day.push(day),
+                            _ => return Err("Invalid BYMONTHDAY"),
+                        }
+                    }
+                }
+                "BYMONTH" => {
+                    for month in value.split(',') {
+                        match u32::from_str(month) {
+                            Ok(month) if month >= 1 && month <= 12 => rule.by_month.push(month),
+                            _ => return Err("Invalid BYMONTH"),
+                        }
+                    }
+                }
+                // Weeks always start on Monday here
+                "WKST" => {}
+                _ => return Err("Unsupported recurrence rule part"),
+            }
+        }
+        if rule.count.is_some() && rule.until.is_some() {
+            return Err("COUNT and UNTIL can't both be set");
+        }
+        rule.freq = freq.ok_or("Recurrence rule is missing FREQ")?;
+        Ok(rule)
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rule = None;
+        let mut start = None;
+        let mut exceptions = Vec::new();
+        for line in s
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+        {
+            let (name, value) = match line.find(':') {
+                Some(idx) => (line[..idx].to_ascii_uppercase(), &line[idx + 1..]),
+                None => ("RRULE".to_string(), line),
+            };
+            // Drop any parameters, i.e. DTSTART;TZID=...
+            match name.split(';').next().unwrap() {
+                "RRULE" => rule = Some(Recurrence::parse_rule(value)?),
+                "DTSTART" => start = Some(parse_date_time(value)?),
+                "EXDATE" => {
+                    for date in value.split(',') {
+                        exceptions.push(parse_date_time(date)?);
+                    }
+                }
+                _ => return Err("Unsupported recurrence property"),
+            }
+        }
+        let mut rule = rule.ok_or("Missing RRULE")?;
+        rule.start = start;
+        rule.exceptions = exceptions;
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |items: Vec<String>| items.join(",");
+        if let Some(start) = self.start {
+            writeln!(f, "DTSTART:{}", start.format("%Y%m%dT%H%M%S"))?;
+        }
+        write!(f, "RRULE:FREQ={}", self.freq)?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        if let Some(until) = self.until {
+            write!(
+                f,
+                ";UNTIL={}",
+                until.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ")
+            )?;
+        }
+        if !self.by_month.is_empty() {
+            let months = self.by_month.iter().map(|m| m.to_string()).collect();
+            write!(f, ";BYMONTH={}", join(months))?;
+        }
+        if !self.by_month_day.is_empty() {
+            let days = self.by_month_day.iter().map(|d| d.to_string()).collect();
+            write!(f, ";BYMONTHDAY={}", join(days))?;
+        }
+        if !self.by_day.is_empty() {
+            let days = self
+                .by_day
+                .iter()
+                .map(|(ordinal, day)| match ordinal {
+                    Some(ordinal) => format!("{}{}", ordinal, weekday_code(*day)),
+                    None => weekday_code(*day).to_string(),
+                })
+                .collect();
+            write!(f, ";BYDAY={}", join(days))?;
+        }
+        if !self.exceptions.is_empty() {
+            let dates = self
+                .exceptions
+                .iter()
+                .map(|date| date.format("%Y%m%dT%H%M%S").to_string())
+                .collect();
+            write!(f, "\nEXDATE:{}", join(dates))?;
+        }
+        Ok(())
+    }
+}
+
+// Stored as the rule string, older files that only have a list of weekdays get converted
+impl<'de> Deserialize<'de> for Recurrence {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RecurrenceVisitor;
+
+        impl<'de> Visitor<'de> for RecurrenceVisitor {
+            type Value = Recurrence;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an RRULE string or a list of weekdays")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Recurrence, E>
+            where
+                E: Error,
+            {
+                Recurrence::from_str(value).map_err(E::custom)
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Recurrence, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut days = Vec::new();
+                while let Some(day) = seq.next_element::<Weekday>()? {
+                    days.push(day);
+                }
+                Ok(Recurrence::on_weekdays(days))
+            }
+        }
+
+        deserializer.deserialize_any(RecurrenceVisitor)
+    }
+}
+
+impl Serialize for Recurrence {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    let next = if month == 12 {
+        NaiveDate::from_ymd(year + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd(year, month + 1, 1)
+    };
+    next.pred().day()
+}
+
+fn days_in_year(year: i32) -> u32 {
+    NaiveDate::from_ymd(year, 12, 31).ordinal()
+}
+
+fn weekday_code(day: Weekday) -> &'static str {
+    match day {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+fn parse_by_day(value: &str) -> Result<(Option<i32>, Weekday), &'static str> {
+    let value = value.trim();
+    if value.len() < 2 || !value.is_char_boundary(value.len() - 2) {
+        return Err("Invalid BYDAY");
+    }
+    let (ordinal, code) = value.split_at(value.len() - 2);
+    let day = match &(code.to_ascii_uppercase())[..] {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return Err("Invalid BYDAY"),
+    };
+    if ordinal.is_empty() {
+        Ok((None, day))
+    } else {
+        match i32::from_str(ordinal.trim_start_matches('+')) {
+            Ok(ordinal) if ordinal != 0 && ordinal.abs() <= 53 => Ok((Some(ordinal), day)),
+            _ => Err("Invalid BYDAY"),
+        }
+    }
+}
+
+// Handles the DATE, local DATE-TIME, and UTC DATE-TIME forms
+pub(crate) fn parse_date_time(value: &str) -> Result<DateTime<Local>, &'static str> {
+    let value = value.trim();
+    if value.len() == 8 {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| "Invalid date")?;
+        return Local
+            .from_local_datetime(&date.and_hms(0, 0, 0))
+            .earliest()
+            .ok_or("Invalid date");
+    }
+    if value.ends_with('Z') || value.ends_with('z') {
+        let time = NaiveDateTime::parse_from_str(&value[..value.len() - 1], "%Y%m%dT%H%M%S")
+            .map_err(|_| "Invalid date-time")?;
+        Ok(Utc.from_utc_datetime(&time).with_timezone(&Local))
+    } else {
+        let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+            .map_err(|_| "Invalid date-time")?;
+        Local
+            .from_local_datetime(&time)
+            .earliest()
+            .ok_or("Invalid date-time")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Recurrence;
+    use chrono::{DateTime, Local, TimeZone};
+    use std::str::FromStr;
+
+    fn local(y: i32, m: u32, d: u32, h: u32) -> DateTime<Local> {
+        Local.ymd(y, m, d).and_hms(h, 0, 0)
+    }
+
+    fn occurrences(rule: &Recurrence, n: usize) -> Vec<DateTime<Local>> {
+        let mut dates = Vec::new();
+        let mut cur = rule.get_start().unwrap() - chrono::Duration::seconds(1);
+        while let Some(next) = rule.next_after(cur) {
+            dates.push(next);
+            cur = next;
+            if dates.len() == n {
+                break;
+            }
+        }
+        dates
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = "DTSTART:20200925T170000\nRRULE:FREQ=MONTHLY;INTERVAL=2;COUNT=5;BYDAY=-1FR\nEXDATE:20201127T170000";
+        let rule = Recurrence::from_str(text).unwrap();
+        assert_eq!(rule.to_string(), text);
+        let json = serde_json::to_string(&rule).unwrap();
+        assert_eq!(serde_json::from_str::<Recurrence>(&json).unwrap(), rule);
+    }
+
+    #[test]
+    fn legacy_weekdays() {
+        let rule: Recurrence = serde_json::from_str(r#"["Mon", "Fri"]"#).unwrap();
+        assert_eq!(rule.to_string(), "RRULE:FREQ=WEEKLY;BYDAY=MO,FR");
+    }
+
+    #[test]
+    fn last_friday_of_month() {
+        let rule =
+            Recurrence::from_str("DTSTART:20200925T170000\nRRULE:FREQ=MONTHLY;BYDAY=-1FR").unwrap();
+        assert_eq!(
+            occurrences(&rule, 3),
+            vec![
+                local(2020, 9, 25, 17),
+                local(2020, 10, 30, 17),
+                local(2020, 11, 27, 17)
+            ]
+        );
+    }
+
+    #[test]
+    fn biweekly() {
+        let rule =
+            Recurrence::from_str("DTSTART:20200904T100000\nRRULE:FREQ=WEEKLY;INTERVAL=2").unwrap();
+        assert_eq!(
+            occurrences(&rule, 3),
+            vec![
+                local(2020, 9, 4, 10),
+                local(2020, 9, 18, 10),
+                local(2020, 10, 2, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn month_day_count_and_exceptions() {
+        let rule = Recurrence::from_str(
+            "DTSTART:20200131T090000\nRRULE:FREQ=MONTHLY;BYMONTHDAY=-1;COUNT=3\nEXDATE:20200229T090000",
+        )
+        .unwrap();
+        assert_eq!(
+            occurrences(&rule, 10),
+            vec![local(2020, 1, 31, 9), local(2020, 3, 31, 9)]
+        );
+    }
+
+    #[test]
+    fn yearly_until() {
+        let rule =
+            Recurrence::from_str("DTSTART:20200101T080000\nRRULE:FREQ=YEARLY;UNTIL=20221231")
+                .unwrap();
+        assert_eq!(occurrences(&rule, 10).len(), 3);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(Recurrence::from_str("FREQ=HOURLY").is_err());
+        assert!(Recurrence::from_str("FREQ=DAILY;COUNT=2;UNTIL=20200101").is_err());
+        assert!(Recurrence::from_str("INTERVAL=2").is_err());
+        assert!(Recurrence::from_str("FREQ=MONTHLY;BYDAY=0FR").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use serde_json::{json, Value};
+use std::fmt;
+use std::path::Path;
+
+/// Version of the saved document this build writes.
+///
+/// - 0: just the list, `{"tasks": [...]}`, with its history in `<stem>_history.json` next to it
+/// - 1: a snapshot for the journal to build on, `{"seq", "todo", "history"}`
+/// - 2: the same, marked with its `version`
+pub const CURRENT_VERSION: u64 = 2;
+
+// Each one takes a document from the version at its index to the one after
+const MIGRATIONS: [fn(Value, &Path) -> anyhow::Result<Value>; CURRENT_VERSION as usize] =
+    [into_snapshot, add_version];
+
+/// A document saved by a newer build, which this one can't safely read or write over
+#[derive(Debug)]
+pub struct NewerVersion(pub u64);
+
+impl fmt::Display for NewerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "saved as version {}, this build only understands up to {}",
+            self.0, CURRENT_VERSION
+        )
+    }
+}
+
+impl std::error::Error for NewerVersion {}
+
+/// The version a document was saved as, the ones from before there was a version are told apart by their shape
+pub fn version(doc: &Value) -> anyhow::Result<u64> {
+    if let Some(version) = doc.get("version") {
+        version
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("version isn't a number"))
+    } else if doc.get("todo").is_some() {
+        Ok(1)
+    } else if doc.get("tasks").is_some() {
+        Ok(0)
+    } else {
+        Err(anyhow::anyhow!("not a saved todo list"))
+    }
+}
+
+/// Upgrades a document read from `path` to the current version one step at a time,
+/// returning it along with the version it started at
+pub fn migrate(mut doc: Value, path: &Path) -> anyhow::Result<(Value, u64)> {
+    let from = version(&doc)?;
+    if from > CURRENT_VERSION {
+        return Err(NewerVersion(from).into());
+    }
+    for migration in &MIGRATIONS[from as usize..] {
+        doc = migration(doc, path)?;
+    }
+    Ok((doc, from))
+}
+
+// 0 -> 1, pulls the history in from its own file
+fn into_snapshot(doc: Value, path: &Path) -> anyhow::Result<Value> {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let history =
+        match std::fs::read_to_string(path.with_file_name(format!("{}_history.json", stem))) {
+            Ok(history) => serde_json::from_str(&history)?,
+            Err(_) => json!({"done": [], "undone": []}),
+        };
+    Ok(json!({"seq": 0, "todo": doc, "history": history}))
+}
+
+// 1 -> 2
+fn add_version(mut doc: Value, _path: &Path) -> anyhow::Result<Value> {
+    doc["version"] = json!(2);
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{migrate, version, NewerVersion, CURRENT_VERSION};
+    use serde_json::json;
+    use std::path::Path;
+
+    #[test]
+    fn migrations() {
+        let path = Path::new("/nonexistent/todo.json");
+        let (doc, from) = migrate(json!({"tasks": []}), path).unwrap();
+        assert_eq!(from, 0);
+        assert_eq!(version(&doc).unwrap(), CURRENT_VERSION);
+        assert_eq!(doc["seq"], json!(0));
+        assert_eq!(doc["history"]["done"], json!([]));
+
+        let (doc, from) = migrate(
+            json!({"seq": 4, "todo": {"tasks": []}, "history": {"done": [], "undone": []}}),
+            path,
+        )
+        .unwrap();
+        assert_eq!(from, 1);
+        assert_eq!(doc["seq"], json!(4));
+
+        // Already current, nothing to do
+        let (same, from) = migrate(doc.clone(), path).unwrap();
+        assert_eq!(from, CURRENT_VERSION);
+        assert_eq!(same, doc);
+
+        let newer = migrate(json!({"version": CURRENT_VERSION + 1}), path).unwrap_err();
+        assert!(newer.downcast_ref::<NewerVersion>().is_some());
+        assert!(migrate(json!({"something": "else"}), path).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::backend::{CompletionStatus, EstTime, Task, ToDo, TrackedTime};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+// Points for each step up in priority
+const PRIORITY_WEIGHT: i32 = 20;
+// Base points for being overdue, plus one for each hour past due up to a cap
+const OVERDUE_POINTS: i32 = 100;
+const MAX_OVERDUE_HOURS: i32 = 72;
+// Points for a due date that's right now, dropping one for every couple of hours until it's due
+const DUE_POINTS: i32 = 80;
+// Points for a task that fills the whole available time
+const FIT_POINTS: i32 = 20;
+// Taken off when there isn't enough time to finish the task
+const NO_FIT_PENALTY: i32 = 60;
+
+#[derive(Serialize, Debug)]
+pub struct Ranked<'a> {
+    pub score: i32,
+    pub task: &'a Task,
+}
+
+/// How good of a pick a task is to work on next, higher is better.
+/// `available_minutes` is how much time there is to work with, if known
+pub fn score(task: &Task, available_minutes: Option<u32>, now: DateTime<Local>) -> i32 {
+    let mut score = task
+        .get_priority()
+        .map_or(0, |priority| priority.val() * PRIORITY_WEIGHT);
+
+    if let Some(due_date) = task.get_due_date() {
+        let hours_left = due_date.signed_duration_since(now).num_hours() as i32;
+        score += if due_date < now {
+            OVERDUE_POINTS + (-hours_left).min(MAX_OVERDUE_HOURS)
+        } else {
+            (DUE_POINTS - hours_left / 2).max(0)
+        };
+    }
+
+    // Time already put in doesn't need to fit anymore
+    let remaining = task.est_time().saturating_sub(task.tracked_time());
+    if let (Some(available), true) = (available_minutes, remaining > 0) {
+        score += if remaining > available {
+            -NO_FIT_PENALTY
+        } else {
+            // The closer it comes to filling the time, the better
+            (FIT_POINTS * remaining as i32) / available.max(1) as i32
+        };
+    }
+    score
+}
+
+/// Scores everything that can be worked on right now, best first.
+/// Tasks with open sub-tasks are left out, the sub-tasks get ranked instead
+pub fn rank(todo: &ToDo, available_minutes: Option<u32>) -> Vec<Ranked> {
+    let now = Local::now();
+    let mut ranked: Vec<Ranked> = todo
+        .get_startable()
+        .into_iter()
+        .filter(|task| {
+            task.get_sub_tasks()
+                .iter()
+                .all(|sub_task| sub_task.complete())
+        })
+        .map(|task| Ranked {
+            score: score(task, available_minutes, now),
+            task,
+        })
+        .collect();
+    // Ties go to whatever is due first, then to the id to keep it stable
+    ranked.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then_with(|| match (a.task.get_due_date(), b.task.get_due_date()) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => std::cmp::Ordering::Equal,
+            })
+            .then_with(|| a.task.get_id().cmp(&b.task.get_id()))
+    });
+    ranked
+}
+
+/// The single best task to pick up next
+pub fn next(todo: &ToDo, available_minutes: Option<u32>) -> Option<&Task> {
+    rank(todo, available_minutes)
+        .into_iter()
+        .next()
+        .map(|ranked| ranked.task)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{next, rank, score};
+    use crate::backend::{Priority, Task, ToDo};
+    use chrono::{Duration, Local};
+
+    #[test]
+    fn scoring() {
+        let now = Local::now();
+        let low = Task::new("Low", "Low", None, 0, Some(Priority::Low), None, None);
+        let high = Task::new("High", "High", None, 0, Some(Priority::High), None, None);
+        assert!(score(&high, None, now) > score(&low, None, now));
+
+        let soon = Task::new(
+            "Soon",
+            "Soon",
+            Some(now + Duration::hours(2)),
+            0,
+            None,
+            None,
+            None,
+        );
+        let later = Task::new(
+            "Later",
+            "Later",
+            Some(now + Duration::days(3)),
+            0,
+            None,
+            None,
+            None,
+        );
+        let overdue = Task::new(
+            "Late",
+            "Late",
+            Some(now - Duration::hours(2)),
+            0,
+            None,
+            None,
+            None,
+        );
+        assert!(score(&soon, None, now) > score(&later, None, now));
+        assert!(score(&overdue, None, now) > score(&soon, None, now));
+
+        let long = Task::new("Long", "Long", None, 120, None, None, None);
+        let short = Task::new("Short", "Short", None, 25, None, None, None);
+        assert!(score(&short, Some(30), now) > score(&long, Some(30), now));
+        assert!(score(&long, Some(150), now) > score(&short, Some(150), now));
+    }
+
+    #[test]
+    fn ranking() {
+        let parent = Task::new(
+            "Parent",
+            "Parent",
+            None,
+            0,
+            Some(Priority::Extreme),
+            None,
+            None,
+        );
+        let child = Task::new("Child", "Child", None, 0, None, None, None);
+        let other = Task::new(
+            "Other",
+            "Other",
+            None,
+            0,
+            Some(Priority::Medium),
+            None,
+            None,
+        );
+        let (parent_id, child_id) = (parent.get_id(), child.get_id());
+        let mut todo = ToDo::from_vec(vec![parent, other]);
+        assert_eq!(next(&todo, None).unwrap().get_id(), parent_id);
+
+        // Work on the pieces rather than the whole
+        todo.add_sub_task(parent_id, child).unwrap();
+        let ranked = rank(&todo, None);
+        assert_eq!(ranked.len(), 2);
+        assert!(ranked
+            .iter()
+            .all(|ranked| ranked.task.get_id() != parent_id));
+
+        todo.mark_finished(child_id, Some(true)).unwrap();
+        assert_eq!(next(&todo, None).unwrap().get_name(), "Other");
+        assert!(rank(&todo, None)
+            .iter()
+            .all(|ranked| ranked.task.get_id() != child_id));
+    }
+}
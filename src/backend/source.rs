@@ -0,0 +1,733 @@
+use crate::backend::storage::{Storage, StorageConfig};
+use crate::backend::trello_api::{CardUpdate, TrelloApi, TrelloError, ID};
+use crate::backend::{taskwarrior, Task, ToDo};
+use chrono::{Local, Utc};
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+/// Where a task was merged in from, the source's name and the task's id over there
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Origin {
+    pub source: String,
+    pub id: String,
+}
+
+/// What a source had that changed since the cursor it was asked about
+#[derive(Debug, Default)]
+pub struct Fetched {
+    // Keyed by their ids in the source
+    pub tasks: Vec<(String, Task)>,
+    // Where to carry on from next time, None if the source can only hand over everything
+    pub cursor: Option<String>,
+}
+
+/// What a merge did to the ToDo
+#[derive(Debug, Default, PartialEq)]
+pub struct Merged {
+    pub added: Vec<Uuid>,
+    pub updated: Vec<Uuid>,
+    pub removed: Vec<Uuid>,
+}
+
+impl Merged {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Somewhere else tasks come from, for `ToDo::merge_from` or a `SourceSync`
+pub trait TaskSource: Send {
+    /// Unique among the sources, it's what the tasks' origins are told apart by
+    fn name(&self) -> String;
+
+    /// Ids of every task the source has now
+    fn list(&mut self) -> Result<Vec<String>, String>;
+
+    /// The tasks that changed since the cursor, every one of them without a cursor
+    fn fetch(&mut self, cursor: Option<&str>) -> Result<Fetched, String>;
+
+    /// Sends back tasks from this source that were changed here, by their origins.
+    /// Sources that can't be written to leave this alone and their copy wins the next time it changes
+    fn push(&mut self, _tasks: &[Task]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Keeps merging one source into a ToDo, pushing back what changed here in between.
+/// Each round is `changed`, `push`, `fetch` and `merge` so the ToDo doesn't have to stay locked while the source is slow.
+/// Only tasks that were around for the last round are pushed, so nothing goes back on the first one
+pub struct SourceSync {
+    source: Box<dyn TaskSource>,
+    cursor: Option<String>,
+    // The source's tasks as of the last round, to tell which ones were changed here since
+    seen: HashMap<Uuid, serde_json::Value>,
+}
+
+impl SourceSync {
+    pub fn new(source: Box<dyn TaskSource>) -> Self {
+        SourceSync {
+            source,
+            cursor: None,
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn get_name(&self) -> String {
+        self.source.name()
+    }
+
+    /// The source's tasks that were changed here since the last round
+    pub fn changed(&self, todo: &ToDo) -> Vec<Task> {
+        self.seen
+            .iter()
+            .filter_map(|(id, seen)| {
+                let task = todo.get_task(*id)?;
+                match serde_json::to_value(task) {
+                    Ok(now) if now != *seen => Some(task.clone()),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    pub fn push(&mut self, tasks: &[Task]) -> Result<(), String> {
+        if tasks.is_empty() {
+            return Ok(());
+        }
+        self.source.push(tasks)?;
+        // Anything edited after this shows up as changed again, rather than being taken for what was just pushed
+        for task in tasks {
+            if let Ok(value) = serde_json::to_value(task) {
+                self.seen.insert(task.get_id(), value);
+            }
+        }
+        Ok(())
+    }
+
+    /// What changed in the source since the last round, and every id it has unless nothing did
+    pub fn fetch(&mut self) -> Result<(Fetched, Option<Vec<String>>), String> {
+        let fetched = self.source.fetch(self.cursor.as_deref())?;
+        if fetched.cursor.is_some() && fetched.cursor == self.cursor {
+            return Ok((fetched, None));
+        }
+        let listed = self.source.list()?;
+        Ok((fetched, Some(listed)))
+    }
+
+    pub fn merge(
+        &mut self,
+        todo: &mut ToDo,
+        fetched: Fetched,
+        listed: Option<Vec<String>>,
+    ) -> Merged {
+        let name = self.source.name();
+        // Edited here since the push, the source's copy would undo that so it's left to go up next round
+        let edited: HashMap<Uuid, serde_json::Value> = self
+            .changed(todo)
+            .iter()
+            .filter_map(|task| Some((task.get_id(), self.seen.get(&task.get_id())?.clone())))
+            .collect();
+        let kept: HashSet<String> = edited
+            .keys()
+            .filter_map(|id| Some(todo.get_task(*id)?.get_origin()?.id.clone()))
+            .collect();
+        let tasks = fetched
+            .tasks
+            .into_iter()
+            .filter(|(source_id, _)| !kept.contains(source_id))
+            .collect();
+        let merged = todo.merge(&name, tasks, listed.as_deref());
+        self.cursor = fetched.cursor;
+        self.seen = todo
+            .get_all_tasks()
+            .into_iter()
+            .filter(|task| {
+                task.get_origin()
+                    .map_or(false, |origin| origin.source == name)
+            })
+            .filter_map(|task| Some((task.get_id(), serde_json::to_value(task).ok()?)))
+            .collect();
+        self.seen.extend(edited);
+        merged
+    }
+}
+
+/// One `[[sources]]` entry in the api_server's config, by its `kind`
+/// ```toml
+/// [[sources]]
+/// kind = "file"
+/// location = "https://example.com/tasks.json"
+///
+/// [[sources]]
+/// kind = "storage"
+/// storage = { backend = "sqlite", path = "/home/pi/work.db" }
+///
+/// [[sources]]
+/// kind = "trello"
+/// board = "your_board_id_here"
+/// ```
+/// Trello's key and token come from TRELLO_KEY and TRELLO_TOKEN when they're left out
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SourceConfig {
+    File {
+        location: String,
+    },
+    Storage {
+        storage: StorageConfig,
+    },
+    Trello {
+        board: String,
+        key: Option<String>,
+        token: Option<String>,
+        base_url: Option<String>,
+    },
+}
+
+impl SourceConfig {
+    pub fn open(&self) -> anyhow::Result<Box<dyn TaskSource>> {
+        Ok(match self {
+            SourceConfig::File { location } => Box::new(FileSource::new(location)),
+            SourceConfig::Storage { storage } => {
+                let name = match storage {
+                    StorageConfig::Json { path }
+                    | StorageConfig::Sqlite { path }
+                    | StorageConfig::Checklist { path } => path.clone(),
+                    StorageConfig::Memory => "memory".to_string(),
+                };
+                Box::new(StorageSource::new(&name, storage.open()?))
+            }
+            SourceConfig::Trello {
+                board,
+                key,
+                token,
+                base_url,
+            } => {
+                let api = match (key, token) {
+                    (Some(key), Some(token)) => TrelloApi::with_credentials(key, token),
+                    _ => TrelloApi::new(),
+                };
+                let api = match base_url {
+                    Some(base_url) => api.with_base_url(base_url),
+                    None => api,
+                };
+                Box::new(TrelloSource::new(api, board))
+            }
+        })
+    }
+}
+
+/// Another store of tasks, like a second JSON file or sqlite database.
+/// Its top level tasks come over whole, sub-tasks and all
+pub struct StorageSource {
+    name: String,
+    storage: Box<dyn Storage>,
+}
+
+impl StorageSource {
+    pub fn new(name: &str, storage: Box<dyn Storage>) -> Self {
+        StorageSource {
+            name: name.to_string(),
+            storage,
+        }
+    }
+
+    fn load(&mut self) -> Result<ToDo, String> {
+        self.storage
+            .load()
+            .map(|(todo, _)| todo)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl TaskSource for StorageSource {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn list(&mut self) -> Result<Vec<String>, String> {
+        Ok(self
+            .load()?
+            .get_root_tasks()
+            .into_iter()
+            .map(|task| task.get_id().to_string())
+            .collect())
+    }
+
+    // A storage can't say what changed, but loading one is cheap enough
+    fn fetch(&mut self, _cursor: Option<&str>) -> Result<Fetched, String> {
+        Ok(Fetched {
+            tasks: self
+                .load()?
+                .get_root_tasks()
+                .into_iter()
+                .map(|task| (task.get_id().to_string(), task.clone()))
+                .collect(),
+            cursor: None,
+        })
+    }
+
+    fn push(&mut self, tasks: &[Task]) -> Result<(), String> {
+        let (mut todo, history) = self.storage.load().map_err(|e| e.to_string())?;
+        for task in tasks {
+            let id = match task.get_origin().map(|origin| Uuid::parse_str(&origin.id)) {
+                Some(Ok(id)) => id,
+                _ => continue,
+            };
+            // Removed over there since, which wins
+            let existing = match todo.get_task(id) {
+                Some(existing) => existing,
+                None => continue,
+            };
+            let mut task = task.clone();
+            task.set_id(id);
+            task.set_origin(existing.get_origin().cloned());
+            todo.replace_task(task)?;
+        }
+        self.storage
+            .save(&todo, &history, &[])
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// A JSON array of tasks in a file or at an http(s) url, in the api's own format or a Taskwarrior export.
+/// They're told apart by their ids, so those have to stay the same between reads.
+/// The cursor is the file's modified time, or the url's ETag or Last-Modified
+pub struct FileSource {
+    location: String,
+    client: Client,
+}
+
+impl FileSource {
+    pub fn new(location: &str) -> Self {
+        FileSource {
+            location: location.to_string(),
+            client: Client::new(),
+        }
+    }
+
+    // The contents and their cursor, None if they haven't changed since the cursor
+    fn read(&self, cursor: Option<&str>) -> Result<Option<(String, Option<String>)>, String> {
+        if self.location.starts_with("http://") || self.location.starts_with("https://") {
+            let mut request = self.client.get(&self.location);
+            match cursor {
+                Some(etag) if etag.starts_with("etag:") => {
+                    request = request.header("If-None-Match", &etag[5..]);
+                }
+                Some(modified) if modified.starts_with("modified:") => {
+                    request = request.header("If-Modified-Since", &modified[9..]);
+                }
+                _ => {}
+            }
+            let resp = request.send().map_err(|e| e.to_string())?;
+            if resp.status() == StatusCode::NOT_MODIFIED {
+                return Ok(None);
+            }
+            if !resp.status().is_success() {
+                return Err(format!("{} answered {}", self.location, resp.status()));
+            }
+            let header = |name: &str| {
+                resp.headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from)
+            };
+            let cursor = header("etag")
+                .map(|etag| format!("etag:{}", etag))
+                .or_else(|| {
+                    header("last-modified").map(|modified| format!("modified:{}", modified))
+                });
+            let text = resp.text().map_err(|e| e.to_string())?;
+            Ok(Some((text, cursor)))
+        } else {
+            let modified = std::fs::metadata(&self.location)
+                .and_then(|metadata| metadata.modified())
+                .map_err(|e| format!("{}: {}", self.location, e))?;
+            let modified = modified
+                .duration_since(UNIX_EPOCH)
+                .map(|since| format!("{}.{:09}", since.as_secs(), since.subsec_nanos()))
+                .ok();
+            if modified.is_some() && modified.as_deref() == cursor {
+                return Ok(None);
+            }
+            let text = std::fs::read_to_string(&self.location)
+                .map_err(|e| format!("{}: {}", self.location, e))?;
+            Ok(Some((text, modified)))
+        }
+    }
+}
+
+fn parse_tasks(text: &str) -> Result<Vec<Task>, String> {
+    match serde_json::from_str::<Vec<Task>>(text) {
+        Ok(tasks) => Ok(tasks),
+        Err(e) => taskwarrior::parse(text).map_err(|_| format!("Not a list of tasks: {}", e)),
+    }
+}
+
+impl TaskSource for FileSource {
+    fn name(&self) -> String {
+        self.location.clone()
+    }
+
+    fn list(&mut self) -> Result<Vec<String>, String> {
+        match self.read(None)? {
+            Some((text, _)) => Ok(parse_tasks(&text)?
+                .iter()
+                .map(|task| task.get_id().to_string())
+                .collect()),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn fetch(&mut self, cursor: Option<&str>) -> Result<Fetched, String> {
+        match self.read(cursor)? {
+            Some((text, cursor)) => Ok(Fetched {
+                tasks: parse_tasks(&text)?
+                    .into_iter()
+                    .map(|task| (task.get_id().to_string(), task))
+                    .collect(),
+                cursor,
+            }),
+            None => Ok(Fetched {
+                tasks: vec![],
+                cursor: cursor.map(String::from),
+            }),
+        }
+    }
+}
+
+/// The cards on a Trello board's open lists, in categories named after their lists.
+/// Only the name, due date and whether it's due-complete go back up.
+/// `TrelloSync` mirrors checklists and moves between lists too, use one or the other for a board
+pub struct TrelloSource {
+    api: TrelloApi,
+    board_id: String,
+}
+
+impl TrelloSource {
+    pub fn new(api: TrelloApi, board_id: &str) -> Self {
+        TrelloSource {
+            api,
+            board_id: board_id.to_string(),
+        }
+    }
+
+    fn cards(&self) -> Result<Vec<(String, Task)>, TrelloError> {
+        let mut tasks = vec![];
+        for list in self.api.get_lists(&self.board_id)? {
+            if list.is_closed() {
+                continue;
+            }
+            for card in self.api.get_cards(list.get_id())? {
+                let mut task = Task::new(
+                    card.get_name(),
+                    "",
+                    card.get_due().map(|due| due.with_timezone(&Local)),
+                    0,
+                    None,
+                    None,
+                    Some(list.get_name().to_string()),
+                );
+                task.set_done(card.is_due_complete());
+                tasks.push((card.get_id().to_string(), task));
+            }
+        }
+        Ok(tasks)
+    }
+}
+
+impl TaskSource for TrelloSource {
+    fn name(&self) -> String {
+        format!("trello:{}", self.board_id)
+    }
+
+    fn list(&mut self) -> Result<Vec<String>, String> {
+        Ok(self
+            .cards()
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    // The board's last activity is the cursor, nothing's fetched until it moves
+    fn fetch(&mut self, cursor: Option<&str>) -> Result<Fetched, String> {
+        let activity = self
+            .api
+            .get_last_activity(&self.board_id)
+            .map_err(|e| e.to_string())?;
+        let tasks = if Some(activity.as_str()) == cursor {
+            vec![]
+        } else {
+            self.cards().map_err(|e| e.to_string())?
+        };
+        Ok(Fetched {
+            tasks,
+            cursor: Some(activity),
+        })
+    }
+
+    fn push(&mut self, tasks: &[Task]) -> Result<(), String> {
+        for task in tasks {
+            let card_id = match task.get_origin() {
+                Some(origin) => origin.id.as_str(),
+                None => continue,
+            };
+            let update = CardUpdate {
+                name: Some(task.get_name()),
+                due: Some(task.get_due_date().map(|due| due.with_timezone(&Utc))),
+                due_complete: Some(task.is_done()),
+                ..CardUpdate::default()
+            };
+            match self.api.update_card(card_id, &update) {
+                // Archived or deleted since, the next fetch takes it off
+                Ok(_) | Err(TrelloError::NotFound) => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Fetched, FileSource, SourceConfig, SourceSync, StorageSource, TaskSource};
+    use crate::backend::storage::{Memory, Storage};
+    use crate::backend::{EstTime, History, Task, ToDo};
+    use std::collections::HashSet;
+
+    // Hands over whatever's in it, with a cursor that moves when it's changed
+    struct Fixed {
+        tasks: Vec<(String, Task)>,
+        version: u32,
+    }
+
+    impl TaskSource for Fixed {
+        fn name(&self) -> String {
+            "fixed".to_string()
+        }
+
+        fn list(&mut self) -> Result<Vec<String>, String> {
+            Ok(self.tasks.iter().map(|(id, _)| id.clone()).collect())
+        }
+
+        fn fetch(&mut self, cursor: Option<&str>) -> Result<Fetched, String> {
+            let version = self.version.to_string();
+            Ok(Fetched {
+                tasks: if cursor == Some(version.as_str()) {
+                    vec![]
+                } else {
+                    self.tasks.clone()
+                },
+                cursor: Some(version),
+            })
+        }
+    }
+
+    fn task(name: &str) -> Task {
+        Task::new(name, "", None, 0, None, None, Some("Work".to_string()))
+    }
+
+    fn find<'a>(todo: &'a ToDo, id: &str) -> Option<&'a Task> {
+        todo.get_all_tasks().into_iter().find(|task| {
+            task.get_origin()
+                .map_or(false, |origin| origin.source == "fixed" && origin.id == id)
+        })
+    }
+
+    #[test]
+    fn source_merge() {
+        let mut review = task("Review");
+        review.add_sub_task(Task::new("Read the diff", "", None, 20, None, None, None));
+        let mut source = Fixed {
+            tasks: vec![("1".to_string(), review), ("2".to_string(), task("Deploy"))],
+            version: 0,
+        };
+        let mut todo = ToDo::from_vec(vec![task("Local")]);
+        let (merged, cursor) = todo.merge_from(&mut source, None).unwrap();
+        assert_eq!(merged.added.len(), 2);
+        assert_eq!(cursor.as_deref(), Some("0"));
+        assert_eq!(todo.num_tasks(), 4);
+        let review = find(&todo, "1").unwrap().get_id();
+        let read = todo.get_task(review).unwrap().get_sub_tasks()[0].get_id();
+
+        // What's only tracked here stays put
+        let mut local = todo.get_task(review).unwrap().clone();
+        let mut estimate = Task::new("", "", None, 45, None, None, None);
+        estimate.add_sub_task(local.get_sub_tasks()[0].clone());
+        local.carry_over(&estimate);
+        local.set_origin(find(&todo, "1").unwrap().get_origin().cloned());
+        todo.replace_task(local).unwrap();
+        todo.add_tag(review, "team").unwrap();
+        todo.start_timer(read).unwrap();
+        todo.stop_timer(read).unwrap();
+
+        // Nothing changed there
+        let (merged, cursor) = todo.merge_from(&mut source, cursor.as_deref()).unwrap();
+        assert!(merged.is_empty());
+
+        // The source's sub-tasks come over, when it has some
+        let mut changed = task("Review the PR");
+        changed.add_sub_task(Task::new(
+            "Read the whole diff",
+            "",
+            None,
+            25,
+            None,
+            None,
+            None,
+        ));
+        changed.add_sub_task(task("Approve"));
+        source.tasks[0].1 = changed;
+        source.tasks.remove(1);
+        source.version += 1;
+        let (merged, _) = todo.merge_from(&mut source, cursor.as_deref()).unwrap();
+        assert_eq!(merged.updated, vec![review]);
+        assert_eq!(merged.removed.len(), 1);
+        let review = todo.get_task(review).unwrap();
+        assert_eq!(review.get_name(), "Review the PR");
+        assert_eq!(review.est_time(), 70);
+        assert!(review.has_tag("team"));
+        let sub_tasks = review.get_sub_tasks();
+        assert_eq!(sub_tasks.len(), 2);
+        assert_eq!(sub_tasks[0].get_id(), read);
+        assert_eq!(sub_tasks[0].get_name(), "Read the whole diff");
+        assert_eq!(sub_tasks[0].get_sessions().len(), 1);
+        assert_eq!(
+            todo.get_parent(sub_tasks[1].get_id()),
+            Some(review.get_id())
+        );
+        assert!(find(&todo, "2").is_none());
+        assert_eq!(todo.num_tasks(), 4);
+    }
+
+    #[test]
+    fn file_source() {
+        let dir = std::env::temp_dir().join(format!("desktopper-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tasks.json");
+        let tasks = vec![task("Review"), task("Deploy")];
+        std::fs::write(&path, serde_json::to_string(&tasks).unwrap()).unwrap();
+
+        let mut source = FileSource::new(path.to_str().unwrap());
+        let fetched = source.fetch(None).unwrap();
+        assert_eq!(fetched.tasks.len(), 2);
+        assert_eq!(fetched.tasks[0].0, tasks[0].get_id().to_string());
+        let cursor = fetched.cursor.unwrap();
+        assert!(source.fetch(Some(&cursor)).unwrap().tasks.is_empty());
+        assert_eq!(source.fetch(Some("0.000000000")).unwrap().tasks.len(), 2);
+        assert_eq!(source.list().unwrap().len(), 2);
+
+        // A Taskwarrior export
+        std::fs::write(
+            &path,
+            r#"[{"uuid": "5c5d5c0b-8ab1-4e5e-a3a0-9a6f1f1a7c11", "description": "Water plants",
+                 "status": "pending", "entry": "20201019T120000Z"}]"#,
+        )
+        .unwrap();
+        let fetched = source.fetch(None).unwrap();
+        assert_eq!(fetched.tasks[0].0, "5c5d5c0b-8ab1-4e5e-a3a0-9a6f1f1a7c11");
+        assert_eq!(fetched.tasks[0].1.get_name(), "Water plants");
+
+        std::fs::write(&path, "Water plants").unwrap();
+        assert!(source.fetch(None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn storage_source() {
+        let work = ToDo::from_vec(vec![task("Review"), task("Deploy")]);
+        let review = work.get_root_tasks()[0].clone();
+        let mut storage = Memory::new();
+        storage.save(&work, &History::new(), &[]).unwrap();
+        let mut sync = SourceSync::new(Box::new(StorageSource::new("work", Box::new(storage))));
+
+        let mut todo = ToDo::new();
+        let (fetched, listed) = sync.fetch().unwrap();
+        let merged = sync.merge(&mut todo, fetched, listed);
+        assert_eq!(merged.added.len(), 2);
+        // Same ids as over there, there's nothing else with them here
+        assert!(todo.get_task(review.get_id()).is_some());
+        assert!(sync.changed(&todo).is_empty());
+
+        todo.mark_finished(review.get_id(), Some(true)).unwrap();
+        let changed = sync.changed(&todo);
+        assert_eq!(changed.len(), 1);
+        sync.push(&changed).unwrap();
+        let (fetched, listed) = sync.fetch().unwrap();
+        // Renamed while the source was being fetched, the fetched copy doesn't undo it
+        let mut renamed = todo.get_task(review.get_id()).unwrap().clone();
+        renamed.set_name("Review the PR");
+        todo.replace_task(renamed).unwrap();
+        assert!(sync.merge(&mut todo, fetched, listed).is_empty());
+        assert_eq!(
+            todo.get_task(review.get_id()).unwrap().get_name(),
+            "Review the PR"
+        );
+        let changed = sync.changed(&todo);
+        assert_eq!(changed.len(), 1);
+        sync.push(&changed).unwrap();
+        let (fetched, listed) = sync.fetch().unwrap();
+        assert!(sync.merge(&mut todo, fetched, listed).is_empty());
+        assert!(sync.changed(&todo).is_empty());
+
+        // Made its way over there
+        let (fetched, _) = sync.fetch().unwrap();
+        let pushed = fetched
+            .tasks
+            .iter()
+            .find(|(id, _)| *id == review.get_id().to_string())
+            .unwrap();
+        assert!(pushed.1.is_done());
+        assert_eq!(pushed.1.get_name(), "Review the PR");
+        assert!(pushed.1.get_origin().is_none());
+
+        // Merging a store into the list it was copied from makes copies
+        let mut storage = Memory::new();
+        storage.save(&todo, &History::new(), &[]).unwrap();
+        let mut copy = StorageSource::new("copy", Box::new(storage));
+        let (merged, _) = todo.merge_from(&mut copy, None).unwrap();
+        assert_eq!(merged.added.len(), 2);
+        let ids: HashSet<_> = todo.get_ids().into_iter().collect();
+        assert_eq!(ids.len(), 4);
+    }
+
+    #[test]
+    fn source_config() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            sources: Vec<SourceConfig>,
+        }
+        let config: Config = toml::from_str(
+            r#"
+            [[sources]]
+            kind = "file"
+            location = "https://example.com/tasks.json"
+
+            [[sources]]
+            kind = "storage"
+            storage = { backend = "memory" }
+
+            [[sources]]
+            kind = "trello"
+            board = "b1"
+            key = "k"
+            token = "t"
+            "#,
+        )
+        .unwrap();
+        let names: Vec<String> = config
+            .sources
+            .iter()
+            .map(|source| source.open().unwrap().name())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["https://example.com/tasks.json", "memory", "trello:b1"]
+        );
+    }
+}
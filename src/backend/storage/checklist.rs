@@ -0,0 +1,810 @@
+use super::{with_suffix, Storage};
+use crate::backend::history::Step;
+use crate::backend::quick_add::at_local;
+use crate::backend::{History, Priority, Task, ToDo};
+use chrono::prelude::{NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const DATE_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M";
+// What a sub-task line gets indented by past its parent
+const INDENT: &str = "  ";
+
+/// Markdown or Org, decided by the file's extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecklistFormat {
+    Markdown,
+    Org,
+}
+
+impl ChecklistFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("org") => ChecklistFormat::Org,
+            _ => ChecklistFormat::Markdown,
+        }
+    }
+
+    // Hidden when the file is rendered, a comment in Markdown and an export snippet in Org
+    fn marker(self, id: Uuid) -> String {
+        match self {
+            ChecklistFormat::Markdown => format!("<!-- id:{} -->", id),
+            ChecklistFormat::Org => format!("@@id:{}@@", id),
+        }
+    }
+}
+
+/// Tasks kept as checkbox lines in a Markdown or Org file that can be edited by hand, e.g.
+/// ```markdown
+/// # Home
+/// - [ ] Pay rent due:2020-11-01 !high @home #bills <!-- id:6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e01 -->
+///   - [x] Find the checkbook
+/// ```
+///
+/// - `[ ]` is still to do, `[x]` is finished
+/// - `due:2020-11-01` is due at the end of that day, `due:2020-11-01T17:00` at that time
+/// - `!high` is the priority, `@home` the category and every `#bills` a tag
+/// - lines indented under a task are its sub-tasks
+/// - a `\` in front of a word keeps it in the name, like `Fix \#42`
+/// - spaces in a category or tag are written as `_`, like `@work_stuff`, and an `_` that's really there as `\_`
+///
+/// Lines without an id get one the next time the list is saved, anything that isn't a checkbox line is left alone.
+/// Saving only rewrites the lines of the tasks that changed, and if only the checkbox did just that.
+///
+/// What doesn't fit on a line, like the description, repeats, tracked time and the history,
+/// is kept in `<file>.state` next to it
+pub struct Checklist {
+    path: PathBuf,
+    state_path: PathBuf,
+    format: ChecklistFormat,
+    lines: Vec<Line>,
+    // The file as it was last read or written, to tell when something else has changed it
+    contents: String,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+enum Line {
+    Text(String),
+    Task(TaskLine),
+}
+
+#[derive(Debug, Clone)]
+struct TaskLine {
+    id: Uuid,
+    parent: Option<Uuid>,
+    // Exactly as it is in the file
+    text: String,
+    indent: String,
+    bullet: String,
+    // What the task looked like on the line, to tell if it's changed since
+    shown: String,
+}
+
+#[derive(Deserialize, Default)]
+struct State {
+    #[serde(default)]
+    history: History,
+    // Everything about each task that's in the file, without its sub-tasks
+    #[serde(default)]
+    tasks: Vec<Task>,
+}
+
+#[derive(Serialize)]
+struct StateRef<'a> {
+    history: &'a History,
+    tasks: Vec<Task>,
+}
+
+impl Checklist {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        Checklist {
+            state_path: with_suffix(&path, ".state"),
+            format: ChecklistFormat::from_path(&path),
+            path,
+            lines: vec![],
+            contents: String::new(),
+            modified: None,
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    // The lines of the file for the list as it is now, in the same order as they already were
+    fn lines_for(&self, todo: &ToDo) -> Vec<Line> {
+        let mut lines = vec![];
+        let mut written = HashSet::new();
+        for line in self.lines.iter() {
+            match line {
+                Line::Text(_) => lines.push(line.clone()),
+                Line::Task(line) => match todo.get_task(line.id) {
+                    // Moved tasks get written again under their new parent
+                    Some(task) if todo.get_parent(line.id) == line.parent => {
+                        written.insert(line.id);
+                        lines.push(Line::Task(self.update(line, task)));
+                    }
+                    _ => {}
+                },
+            }
+        }
+
+        // New tasks go under their parent after whatever's already there, or at the end of the file
+        let mut roots = todo.get_root_tasks();
+        roots.sort_by_key(|task| task.get_initial_date());
+        for task in roots.into_iter().flat_map(|root| root.walk()) {
+            if written.contains(&task.get_id()) {
+                continue;
+            }
+            let parent = todo.get_parent(task.get_id());
+            let (idx, indent) = match parent.and_then(|parent| find(&lines, parent)) {
+                Some(idx) => {
+                    let indent = match &lines[idx] {
+                        Line::Task(line) => line.indent.clone() + INDENT,
+                        Line::Text(_) => unreachable!(),
+                    };
+                    (end_of_children(&lines, idx), indent)
+                }
+                None => (lines.len(), String::new()),
+            };
+            let shown = show(task);
+            lines.insert(
+                idx,
+                Line::Task(TaskLine {
+                    id: task.get_id(),
+                    parent,
+                    text: format!(
+                        "{}- {} {}",
+                        indent,
+                        shown,
+                        self.format.marker(task.get_id())
+                    ),
+                    indent,
+                    bullet: "-".to_string(),
+                    shown,
+                }),
+            );
+            written.insert(task.get_id());
+        }
+        lines
+    }
+
+    // The line for a task that's already in the file, left as it was if the task hasn't changed
+    fn update(&self, line: &TaskLine, task: &Task) -> TaskLine {
+        let shown = show(task);
+        let text = if shown == line.shown {
+            strip_marker(&line.text).0
+        } else if flip(&shown) == line.shown {
+            toggle_checkbox(&strip_marker(&line.text).0, task.is_done())
+        } else {
+            format!("{}{} {}", line.indent, line.bullet, shown)
+        };
+        TaskLine {
+            text: format!("{} {}", text.trim_end(), self.format.marker(line.id)),
+            shown,
+            ..line.clone()
+        }
+    }
+
+    fn read_state(&self) -> anyhow::Result<State> {
+        match std::fs::read_to_string(&self.state_path) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Storage for Checklist {
+    fn load(&mut self) -> anyhow::Result<(ToDo, History)> {
+        let state = self.read_state()?;
+        let modified = self.modified();
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let known: HashMap<Uuid, Task> = state
+            .tasks
+            .into_iter()
+            .map(|task| (task.get_id(), task))
+            .collect();
+        let (lines, todo) = parse(&contents, &known);
+        self.lines = lines;
+        self.contents = contents;
+        self.modified = modified;
+        Ok((todo, state.history))
+    }
+
+    fn save(&mut self, todo: &ToDo, history: &History, _steps: &[Step]) -> anyhow::Result<()> {
+        if self.changed_elsewhere() {
+            return Err(anyhow::anyhow!(
+                "{} was changed by something else, it has to be loaded again before it can be saved over",
+                self.path.display()
+            ));
+        }
+        let lines = self.lines_for(todo);
+        let mut contents: String = lines
+            .iter()
+            .map(|line| match line {
+                Line::Text(text) => text.as_str(),
+                Line::Task(line) => line.text.as_str(),
+            })
+            .collect::<Vec<&str>>()
+            .join("\n");
+        if !contents.is_empty() {
+            contents.push('\n');
+        }
+
+        let tasks: Vec<Task> = todo
+            .get_all_tasks()
+            .into_iter()
+            .map(|task| {
+                let mut task = task.clone();
+                for id in task
+                    .get_sub_tasks()
+                    .iter()
+                    .map(Task::get_id)
+                    .collect::<Vec<_>>()
+                {
+                    task.remove_sub_task(id);
+                }
+                task
+            })
+            .collect();
+        // The state first, the file is what's checked for changes and should only look saved once both are
+        write_atomically(
+            &self.state_path,
+            serde_json::to_string(&StateRef { history, tasks })?.as_bytes(),
+        )?;
+        if contents != self.contents {
+            write_atomically(&self.path, contents.as_bytes())?;
+        }
+        self.lines = lines;
+        self.contents = contents;
+        self.modified = self.modified();
+        Ok(())
+    }
+
+    fn changed_elsewhere(&mut self) -> bool {
+        let modified = self.modified();
+        if modified == self.modified {
+            return false;
+        }
+        match std::fs::read_to_string(&self.path) {
+            // Touched but not changed
+            Ok(contents) if contents == self.contents => {
+                self.modified = modified;
+                false
+            }
+            Ok(_) => true,
+            // Editors that save by renaming leave it missing for a moment, it's not worth dropping everything over
+            Err(_) => false,
+        }
+    }
+}
+
+// The lines of a file and the list they make up, filling in what isn't on them from `known`
+fn parse(contents: &str, known: &HashMap<Uuid, Task>) -> (Vec<Line>, ToDo) {
+    let mut lines = vec![];
+    let mut tasks: Vec<Option<Task>> = vec![];
+    let mut children: Vec<Vec<usize>> = vec![];
+    let mut roots = vec![];
+    // Index into `tasks` and indent width of each task line above that could still be a parent
+    let mut parents: Vec<(usize, usize)> = vec![];
+    let mut seen = HashSet::new();
+    for text in contents.lines() {
+        let (indent, bullet, done, rest) = match split_checkbox(text) {
+            Some(split) => split,
+            None => {
+                lines.push(Line::Text(text.to_string()));
+                continue;
+            }
+        };
+        let (rest, id) = strip_marker(rest);
+        // Copied lines keep the id they were copied from, only the first one gets to use it
+        let id = match id {
+            Some(id) if seen.insert(id) => id,
+            _ => Uuid::new_v4(),
+        };
+        let mut task = parse_task(&rest, done, known.get(&id));
+        task.set_id(id);
+
+        let width = indent_width(indent);
+        while parents.last().map_or(false, |(_, parent)| *parent >= width) {
+            parents.pop();
+        }
+        let idx = tasks.len();
+        let parent = match parents.last() {
+            Some((parent, _)) => {
+                children[*parent].push(idx);
+                tasks[*parent].as_ref().map(Task::get_id)
+            }
+            None => {
+                roots.push(idx);
+                None
+            }
+        };
+        parents.push((idx, width));
+        lines.push(Line::Task(TaskLine {
+            id,
+            parent,
+            text: text.to_string(),
+            indent: indent.to_string(),
+            bullet: bullet.to_string(),
+            shown: show(&task),
+        }));
+        tasks.push(Some(task));
+        children.push(vec![]);
+    }
+    let roots = roots
+        .into_iter()
+        .map(|idx| attach(idx, &mut tasks, &children))
+        .collect();
+    (lines, ToDo::from_vec(roots))
+}
+
+fn attach(idx: usize, tasks: &mut Vec<Option<Task>>, children: &[Vec<usize>]) -> Task {
+    let mut task = tasks[idx].take().unwrap();
+    for child in children[idx].iter() {
+        task.add_sub_task(attach(*child, tasks, children));
+    }
+    task
+}
+
+// The part after the checkbox as a task, anything that doesn't look right as metadata is part of the name
+fn parse_task(rest: &str, done: bool, known: Option<&Task>) -> Task {
+    let mut name = vec![];
+    let mut due_date = None;
+    let mut priority = None;
+    let mut category = None;
+    let mut tags = HashSet::new();
+    for word in rest.split_whitespace() {
+        if let (Some(due), None) = (word.strip_prefix("due:").and_then(parse_due), due_date) {
+            due_date = Some(due);
+        } else if let (Some(Ok(level)), None) =
+            (word.strip_prefix('!').map(Priority::from_str), priority)
+        {
+            priority = Some(level);
+        } else if let (true, None) = (word.len() > 1 && word.starts_with('@'), &category) {
+            category = Some(with_spaces(&word[1..]));
+        } else if word.len() > 1 && word.starts_with('#') {
+            tags.insert(with_spaces(&word[1..]));
+        } else if let Some(escaped) = word.strip_prefix('\\').filter(|rest| needs_escape(rest)) {
+            name.push(escaped);
+        } else {
+            name.push(word);
+        }
+    }
+
+    let mut task = Task::new(
+        &name.join(" "),
+        &known.map(Task::get_desc).unwrap_or_default(),
+        due_date,
+        0,
+        priority,
+        known.and_then(Task::get_repeats),
+        category,
+    );
+    if let Some(known) = known {
+        task.carry_over(known);
+        task.set_initial_date(known.get_initial_date());
+        task.set_not_before(known.get_not_before());
+    }
+    task.set_tags(tags);
+    task.set_done(done);
+    // Checked by hand since it was saved is finished as of now
+    if let (true, Some(known)) = (done, known.filter(|known| known.is_done())) {
+        task.set_finished_at(known.get_finished_at());
+    }
+    task
+}
+
+fn parse_due(value: &str) -> Option<chrono::DateTime<chrono::Local>> {
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, DATE_TIME_FORMAT) {
+        at_local(time.date(), time.time()).ok()
+    } else {
+        let day = NaiveDate::parse_from_str(value, DATE_FORMAT).ok()?;
+        at_local(day, NaiveTime::from_hms(23, 59, 59)).ok()
+    }
+}
+
+// What's on a task's line, after the bullet and before the id
+fn show(task: &Task) -> String {
+    let name: Vec<String> = task
+        .get_name()
+        .split_whitespace()
+        .map(|word| {
+            if needs_escape(word) {
+                format!("\\{}", word)
+            } else {
+                word.to_string()
+            }
+        })
+        .collect();
+    let mut words = vec![
+        if task.is_done() { "[x]" } else { "[ ]" }.to_string(),
+        name.join(" "),
+    ];
+    if let Some(due_date) = task.get_due_date() {
+        // Due at the end of the day is the same as no time at all
+        if due_date.time() >= NaiveTime::from_hms(23, 59, 59) {
+            words.push(format!("due:{}", due_date.format(DATE_FORMAT)));
+        } else {
+            words.push(format!("due:{}", due_date.format(DATE_TIME_FORMAT)));
+        }
+    }
+    if let Some(priority) = task.get_priority() {
+        words.push(format!("!{:?}", priority).to_ascii_lowercase());
+    }
+    if let Some(category) = task.get_category() {
+        words.push(format!("@{}", no_spaces(&category)));
+    }
+    let mut tags: Vec<String> = task.get_tags().into_iter().collect();
+    tags.sort();
+    words.extend(tags.iter().map(|tag| format!("#{}", no_spaces(tag))));
+    words.join(" ")
+}
+
+// Words in a name that would be read back as metadata, or as one of these escaped, get a \ in front of them
+fn needs_escape(word: &str) -> bool {
+    match word.strip_prefix('\\') {
+        Some(rest) => needs_escape(rest),
+        None => ["#", "@", "!", "due:"]
+            .iter()
+            .any(|prefix| word.starts_with(prefix)),
+    }
+}
+
+// Categories and tags end at the first space, so spaces go in as _ and anything that could be mistaken for one is escaped
+fn no_spaces(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '\\' | '_' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_whitespace() => out.push('_'),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn with_spaces(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            '_' => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// The same line with the other checkbox
+fn flip(shown: &str) -> String {
+    match (shown.strip_prefix("[ ]"), shown.strip_prefix("[x]")) {
+        (Some(rest), _) => format!("[x]{}", rest),
+        (_, Some(rest)) => format!("[ ]{}", rest),
+        _ => shown.to_string(),
+    }
+}
+
+fn toggle_checkbox(text: &str, done: bool) -> String {
+    match text.find('[') {
+        Some(idx) => format!(
+            "{}{}{}",
+            &text[..idx],
+            if done { "[x]" } else { "[ ]" },
+            &text[idx + 3..]
+        ),
+        None => text.to_string(),
+    }
+}
+
+// `  - [x] rest`, into the indent, the bullet, whether it's checked and the rest.
+// Bullets are -, + or *, or a number followed by . or )
+fn split_checkbox(line: &str) -> Option<(&str, &str, bool, &str)> {
+    let trimmed = line.trim_start();
+    let indent = &line[..line.len() - trimmed.len()];
+    let bullet_len = match trimmed.chars().next()? {
+        '-' | '+' | '*' => 1,
+        c if c.is_ascii_digit() => {
+            let digits = trimmed.find(|c: char| !c.is_ascii_digit())?;
+            match trimmed[digits..].chars().next()? {
+                '.' | ')' => digits + 1,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let bullet = &trimmed[..bullet_len];
+    let after = &trimmed[bullet_len..];
+    let checkbox = after.trim_start();
+    if checkbox.len() == after.len() || checkbox.len() < 3 {
+        return None;
+    }
+    let done = match &checkbox[..3] {
+        "[ ]" | "[-]" => false,
+        "[x]" | "[X]" => true,
+        _ => return None,
+    };
+    let rest = &checkbox[3..];
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some((indent, bullet, done, rest.trim()))
+}
+
+// The text without its id marker, and the id if there was one
+fn strip_marker(text: &str) -> (String, Option<Uuid>) {
+    for (start, end) in &[("<!-- id:", "-->"), ("@@id:", "@@")] {
+        if let Some(idx) = text.find(start) {
+            let after = &text[idx + start.len()..];
+            if let Some(len) = after.find(end) {
+                if let Ok(id) = Uuid::parse_str(after[..len].trim()) {
+                    let rest = format!("{}{}", &text[..idx], &after[len + end.len()..]);
+                    return (rest.trim_end().to_string(), Some(id));
+                }
+            }
+        }
+    }
+    (text.to_string(), None)
+}
+
+// Tabs count as 4 spaces
+fn indent_width(indent: &str) -> usize {
+    indent.chars().map(|c| if c == '\t' { 4 } else { 1 }).sum()
+}
+
+fn find(lines: &[Line], id: Uuid) -> Option<usize> {
+    lines.iter().position(|line| match line {
+        Line::Task(line) => line.id == id,
+        Line::Text(_) => false,
+    })
+}
+
+// Just past the last line of everything indented under the task at `idx`
+fn end_of_children(lines: &[Line], idx: usize) -> usize {
+    let width = match &lines[idx] {
+        Line::Task(line) => indent_width(&line.indent),
+        Line::Text(_) => return idx + 1,
+    };
+    let mut end = idx + 1;
+    while let Some(Line::Task(line)) = lines.get(end) {
+        if indent_width(&line.indent) <= width {
+            break;
+        }
+        end += 1;
+    }
+    end
+}
+
+// Written next to where it's going and renamed over it, so nothing ever sees half of it
+fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_checkbox, Checklist};
+    use crate::backend::storage::{temp_dir, Storage};
+    use crate::backend::{History, Priority, Task, ToDo};
+    use chrono::prelude::{Local, TimeZone};
+    use std::collections::HashSet;
+
+    fn save(storage: &mut Checklist, todo: &mut ToDo, history: &mut History) {
+        history.record(todo);
+        let steps = history.take_steps();
+        storage.save(todo, history, &steps).unwrap();
+    }
+
+    #[test]
+    fn checklist() {
+        let dir = temp_dir();
+        let path = dir.join("todo.md");
+        std::fs::write(
+            &path,
+            "# Home\n\
+             \n\
+             * [ ]   Pay rent due:2020-11-01 !high @home #bills\n\
+             \t1. [X] Find the checkbook due:2020-10-30T17:00\n\
+             - [ ] Call mom #phone\n\
+             Some notes\n",
+        )
+        .unwrap();
+        let mut storage = Checklist::new(&path);
+        let (mut todo, mut history) = storage.load().unwrap();
+        assert_eq!(todo.num_tasks(), 3);
+        let rent = todo
+            .get_root_tasks()
+            .into_iter()
+            .find(|task| task.get_name() == "Pay rent")
+            .unwrap()
+            .clone();
+        assert_eq!(
+            rent.get_due_date(),
+            Some(Local.ymd(2020, 11, 1).and_hms(23, 59, 59))
+        );
+        assert_eq!(rent.get_priority(), Some(Priority::High));
+        assert_eq!(rent.get_category(), Some("home".to_string()));
+        assert!(rent.has_tag("bills"));
+        let checkbook = &rent.get_sub_tasks()[0];
+        assert!(checkbook.is_done());
+        assert_eq!(
+            checkbook.get_due_date(),
+            Some(Local.ymd(2020, 10, 30).and_hms(17, 0, 0))
+        );
+
+        // Only the finished task's checkbox changes, and everything gets an id
+        let mom = todo
+            .get_root_tasks()
+            .into_iter()
+            .find(|task| task.get_name() == "Call mom")
+            .unwrap()
+            .get_id();
+        todo.mark_finished(mom, Some(true)).unwrap();
+        todo.add_sub_task(
+            rent.get_id(),
+            Task::new("Stamp", "Forever stamps", None, 15, None, None, None),
+        )
+        .unwrap();
+        save(&mut storage, &mut todo, &mut history);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(&lines[..2], &["# Home", ""]);
+        assert!(lines[2].starts_with("* [ ]   Pay rent due:2020-11-01 !high @home #bills <!-- id:"));
+        assert!(lines[3].starts_with("\t1. [X] Find the checkbook due:2020-10-30T17:00 <!-- id:"));
+        assert!(lines[4].starts_with("  - [ ] Stamp <!-- id:"));
+        assert!(lines[5].starts_with("- [x] Call mom #phone <!-- id:"));
+        assert_eq!(lines[6], "Some notes");
+
+        // The ids and whatever isn't on the lines come back
+        let (loaded, loaded_history) = Checklist::new(&path).load().unwrap();
+        assert_eq!(loaded, todo);
+        assert_eq!(loaded_history.get_done().len(), history.get_done().len());
+        let stamp =
+            loaded.get_task(todo.get_task(rent.get_id()).unwrap().get_sub_tasks()[1].get_id());
+        assert_eq!(stamp.unwrap().get_desc(), "Forever stamps");
+        assert_eq!(
+            loaded.get_task(mom).unwrap().get_finished_at(),
+            todo.get_task(mom).unwrap().get_finished_at()
+        );
+
+        // Changes made by hand are picked up, and stop it saving over them
+        assert!(!storage.changed_elsewhere());
+        std::fs::write(&path, contents.replace("Call mom", "Call dad")).unwrap();
+        assert!(storage.changed_elsewhere());
+        assert!(storage.save(&todo, &history, &[]).is_err());
+        let (todo, _) = storage.load().unwrap();
+        assert_eq!(todo.get_task(mom).unwrap().get_name(), "Call dad");
+        assert!(!storage.changed_elsewhere());
+
+        // Removing a task drops its line and everything under it
+        let mut todo = todo;
+        todo.remove_task(rent.get_id()).unwrap();
+        storage.save(&todo, &history, &[]).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checklist_escapes() {
+        let dir = temp_dir();
+        let path = dir.join("todo.md");
+        let mut storage = Checklist::new(&path);
+        let (mut todo, mut history) = storage.load().unwrap();
+        let mut names = vec![
+            "Fix #42 regression",
+            "Email @bob !high",
+            "Read due:2020-11-01",
+            r"Keep \#this",
+        ];
+        for name in names.iter() {
+            todo.add_task(Task::new(name, "", None, 0, None, None, None));
+        }
+        save(&mut storage, &mut todo, &mut history);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(r"- [ ] Fix \#42 regression <!-- id:"));
+        assert!(contents.contains(r"- [ ] Keep \\#this <!-- id:"));
+
+        // Hand written ones are still metadata
+        std::fs::write(&path, contents + "- [ ] Call @home #phone\n").unwrap();
+        let (loaded, _) = Checklist::new(&path).load().unwrap();
+        let mut loaded_names: Vec<String> = loaded
+            .get_root_tasks()
+            .into_iter()
+            .filter(|task| task.get_tags().is_empty())
+            .map(|task| {
+                assert_eq!(task.get_category(), None);
+                assert_eq!(task.get_priority(), None);
+                assert_eq!(task.get_due_date(), None);
+                task.get_name()
+            })
+            .collect();
+        loaded_names.sort();
+        names.sort();
+        assert_eq!(loaded_names, names);
+        assert_eq!(loaded.num_tasks(), 5);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checklist_spaces() {
+        let dir = temp_dir();
+        let path = dir.join("todo.md");
+        let mut storage = Checklist::new(&path);
+        let (mut todo, mut history) = storage.load().unwrap();
+        let mut task = Task::new(
+            "Plan",
+            "",
+            None,
+            0,
+            None,
+            None,
+            Some("Work stuff".to_string()),
+        );
+        let tags: HashSet<String> = vec!["long term".to_string(), "snake_case".to_string()]
+            .into_iter()
+            .collect();
+        task.set_tags(tags.clone());
+        todo.add_task(task);
+        save(&mut storage, &mut todo, &mut history);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(r"- [ ] Plan @Work_stuff #long_term #snake\_case <!-- id:"));
+
+        let (loaded, _) = Checklist::new(&path).load().unwrap();
+        let task = loaded.get_root_tasks()[0];
+        assert_eq!(task.get_name(), "Plan");
+        assert_eq!(task.get_category(), Some("Work stuff".to_string()));
+        assert_eq!(task.get_tags(), tags);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checklist_org() {
+        let dir = temp_dir();
+        let path = dir.join("todo.org");
+        std::fs::write(&path, "* Errands\n- [ ] Groceries\n  - [-] Milk\n").unwrap();
+        let mut storage = Checklist::new(&path);
+        let (mut todo, mut history) = storage.load().unwrap();
+        assert_eq!(todo.get_root_tasks().len(), 1);
+        assert_eq!(todo.num_tasks(), 2);
+        save(&mut storage, &mut todo, &mut history);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("* Errands\n- [ ] Groceries @@id:"));
+        assert!(contents.contains("\n  - [-] Milk @@id:"));
+
+        assert!(split_checkbox("* Errands").is_none());
+        assert!(split_checkbox("-[ ] No space").is_none());
+        assert!(split_checkbox("- [ ]No space").is_none());
+        assert!(split_checkbox("- [y] Not a checkbox").is_none());
+        assert_eq!(
+            split_checkbox("  12) [x] Numbered"),
+            Some(("  ", "12)", true, "Numbered"))
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,664 @@
+use crate::backend::history::Step;
+use crate::backend::schema;
+use crate::backend::{CompletionStatus, EstTime, History, Priority, Task, ToDo};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+mod checklist;
+mod sqlite;
+
+pub use checklist::{Checklist, ChecklistFormat};
+pub use sqlite::Sqlite;
+
+// Saves to journal before they get folded into a new snapshot
+const COMPACT_AFTER: usize = 100;
+// Older snapshots kept as todo.json.1, todo.json.2, ...
+const BACKUPS: usize = 3;
+
+/// Somewhere to keep the todo list and its history between runs
+pub trait Storage: Send {
+    /// Reads back whatever was last saved, an empty list and history if nothing has been yet
+    fn load(&mut self) -> anyhow::Result<(ToDo, History)>;
+
+    /// `steps` are how the history moved since the last save, for storages that only write what changed
+    fn save(&mut self, todo: &ToDo, history: &History, steps: &[Step]) -> anyhow::Result<()>;
+
+    /// Ids of the tasks matching `filter` as of the last save,
+    /// None if this storage can't search and the tasks need to be checked in memory instead
+    fn search(&mut self, _filter: &TaskFilter) -> anyhow::Result<Option<Vec<Uuid>>> {
+        Ok(None)
+    }
+
+    /// Whether something else has changed what's saved since it was last loaded or saved,
+    /// for the storages that can be edited by hand
+    fn changed_elsewhere(&mut self) -> bool {
+        false
+    }
+}
+
+/// The plain field filters of `/todo/search`, each one that's set has to match
+#[derive(Debug, Default, Clone)]
+pub struct TaskFilter {
+    pub name: Option<String>,
+    pub desc: Option<String>,
+    pub due_after: Option<DateTime<Local>>,
+    pub due_before: Option<DateTime<Local>>,
+    pub est_low: Option<u32>,
+    pub est_high: Option<u32>,
+    // Only narrows things down to the complete tasks, false lets everything through
+    pub complete: Option<bool>,
+    pub priority_low: Option<Priority>,
+    pub priority_high: Option<Priority>,
+    // Some(None) for the tasks without a category
+    pub category: Option<Option<String>>,
+    pub tags_any: Vec<String>,
+    pub tags_all: Vec<String>,
+}
+
+impl TaskFilter {
+    pub fn matches(&self, task: &Task) -> bool {
+        // Tasks without a due date or priority sort before any that have one
+        self.name
+            .as_ref()
+            .map_or(true, |name| task.get_name().contains(name.as_str()))
+            && self
+                .desc
+                .as_ref()
+                .map_or(true, |desc| task.get_desc().contains(desc.as_str()))
+            && self
+                .due_after
+                .map_or(true, |due_date| task.get_due_date() >= Some(due_date))
+            && self
+                .due_before
+                .map_or(true, |due_date| task.get_due_date() <= Some(due_date))
+            && self.est_low.map_or(true, |est| task.est_time() >= est)
+            && self.est_high.map_or(true, |est| task.est_time() <= est)
+            && (self.complete != Some(true) || task.complete())
+            && self
+                .priority_low
+                .map_or(true, |priority| task.get_priority() >= Some(priority))
+            && self
+                .priority_high
+                .map_or(true, |priority| task.get_priority() <= Some(priority))
+            && self
+                .category
+                .as_ref()
+                .map_or(true, |category| &task.get_category() == category)
+            && (self.tags_any.is_empty() || self.tags_any.iter().any(|tag| task.has_tag(tag)))
+            && self.tags_all.iter().all(|tag| task.has_tag(tag))
+    }
+}
+
+/// Which storage to use, from the `[storage]` section of the config, e.g.
+/// ```toml
+/// [storage]
+/// backend = "sqlite"
+/// path = "/etc/desktopper/todo.db"
+/// ```
+/// `checklist` keeps the tasks in a Markdown or Org file, by its extension
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Json { path: String },
+    Sqlite { path: String },
+    Checklist { path: String },
+    Memory,
+}
+
+impl StorageConfig {
+    pub fn open(&self) -> anyhow::Result<Box<dyn Storage>> {
+        Ok(match self {
+            StorageConfig::Json { path } => Box::new(JsonFile::new(path)),
+            StorageConfig::Sqlite { path } => Box::new(Sqlite::open(path)?),
+            StorageConfig::Checklist { path } => Box::new(Checklist::new(path)),
+            StorageConfig::Memory => Box::new(Memory::new()),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    // The last journal entry that's already part of it
+    seq: u64,
+    todo: ToDo,
+    history: History,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u64,
+    seq: u64,
+    todo: &'a ToDo,
+    history: &'a History,
+}
+
+/// One save, only the top level tasks that changed and how the history moved
+#[derive(Deserialize, Serialize)]
+struct JournalEntry {
+    seq: u64,
+    #[serde(default)]
+    put: Vec<Task>,
+    #[serde(default)]
+    removed: Vec<Uuid>,
+    #[serde(default)]
+    steps: Vec<Step>,
+}
+
+/// A snapshot of the list and its history in a JSON file, with every save since appended to `<stem>.journal` next to it.
+///
+/// Snapshots are written to a temporary file and renamed over the old one, so a crash leaves either the old or the new one whole.
+/// A save cut off part way through the journal is only ever the last line, and only that save is lost.
+/// The journal is folded into a new snapshot every so often, keeping the last few snapshots as backups along with the journals
+/// that follow on from them
+pub struct JsonFile {
+    path: PathBuf,
+    journal_path: PathBuf,
+    journal: Option<File>,
+    seq: u64,
+    entries: usize,
+    // Each top level task as it was last saved, to find the ones that changed
+    saved: HashMap<Uuid, String>,
+}
+
+impl JsonFile {
+    /// `todo.json` keeps its journal in `todo.journal`
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        JsonFile {
+            journal_path: path.with_extension("journal"),
+            path,
+            journal: None,
+            seq: 0,
+            entries: 0,
+            saved: HashMap::new(),
+        }
+    }
+
+    fn backup_path(&self, backup: usize) -> PathBuf {
+        with_suffix(&self.path, &format!(".{}", backup))
+    }
+
+    // The saves from when that backup was the snapshot up until the next one
+    fn journal_backup_path(&self, backup: usize) -> PathBuf {
+        with_suffix(&self.journal_path, &format!(".{}", backup))
+    }
+
+    // The snapshot to start from, whether it needs to be written out again straight away,
+    // and which backup it came from, 0 being the snapshot itself
+    fn read_snapshot(&self) -> anyhow::Result<(Snapshot, bool, usize)> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok((
+                    Snapshot {
+                        seq: 0,
+                        todo: ToDo::new(),
+                        history: History::new(),
+                    },
+                    false,
+                    0,
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match self.parse_snapshot(&contents) {
+            Ok((snapshot, version)) if version < schema::CURRENT_VERSION => {
+                // Kept as it was in case the upgrade gets something wrong
+                let original = with_suffix(&self.path, &format!(".v{}.bak", version));
+                if !original.exists() {
+                    std::fs::copy(&self.path, &original)?;
+                }
+                info!(
+                    "Upgrading {} from version {}, the original is kept at {}",
+                    self.path.display(),
+                    version,
+                    original.display()
+                );
+                return Ok((snapshot, true, 0));
+            }
+            Ok((snapshot, _)) => return Ok((snapshot, false, 0)),
+            // Falling back to a backup would end up saving over the newer list
+            Err(e) if e.downcast_ref::<schema::NewerVersion>().is_some() => return Err(e),
+            Err(e) => warn!("{} is unreadable: {}", self.path.display(), e),
+        }
+        for backup in 1..=BACKUPS {
+            let path = self.backup_path(backup);
+            if let Ok((snapshot, _)) = std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|contents| self.parse_snapshot(&contents))
+            {
+                warn!("Starting from {} instead", path.display());
+                return Ok((snapshot, true, backup));
+            }
+        }
+        Err(anyhow::anyhow!(
+            "{} and its backups are unreadable",
+            self.path.display()
+        ))
+    }
+
+    // Along with the version it was saved as
+    fn parse_snapshot(&self, contents: &str) -> anyhow::Result<(Snapshot, u64)> {
+        let (doc, version) = schema::migrate(serde_json::from_str(contents)?, &self.path)?;
+        Ok((serde_json::from_value(doc)?, version))
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> anyhow::Result<()> {
+        if self.journal.is_none() {
+            self.journal = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.journal_path)?,
+            );
+        }
+        let journal = self.journal.as_mut().unwrap();
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        journal.write_all(line.as_bytes())?;
+        journal.sync_data()?;
+        Ok(())
+    }
+
+    /// Writes everything out as a new snapshot and starts an empty journal
+    fn compact(&mut self, todo: &ToDo, history: &History) -> anyhow::Result<()> {
+        let tmp_path = with_suffix(&self.path, ".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(
+            &mut writer,
+            &SnapshotRef {
+                version: schema::CURRENT_VERSION,
+                seq: self.seq,
+                todo,
+                history,
+            },
+        )?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        for backup in (1..BACKUPS).rev() {
+            // Not every backup exists yet
+            let _ = std::fs::rename(self.backup_path(backup), self.backup_path(backup + 1));
+            let _ = std::fs::rename(
+                self.journal_backup_path(backup),
+                self.journal_backup_path(backup + 1),
+            );
+        }
+        if self.path.exists() {
+            std::fs::copy(&self.path, self.backup_path(1))?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent().and_then(|dir| File::open(dir).ok()) {
+            dir.sync_all()?;
+        }
+
+        // Anything left in the journal if this gets cut off is at or before the snapshot's seq, and gets skipped.
+        // Otherwise it goes along with the old snapshot, to bring that up to date if it's ever needed
+        if self.journal_path.exists() {
+            std::fs::rename(&self.journal_path, self.journal_backup_path(1))?;
+        }
+        self.journal = Some(File::create(&self.journal_path)?);
+        self.entries = 0;
+        self.saved = saved_roots(todo)?;
+        Ok(())
+    }
+}
+
+impl Storage for JsonFile {
+    fn load(&mut self) -> anyhow::Result<(ToDo, History)> {
+        let (snapshot, mut rewrite, backup) = self.read_snapshot()?;
+        let Snapshot {
+            seq,
+            mut todo,
+            mut history,
+        } = snapshot;
+        self.seq = seq;
+
+        let mut roots: HashMap<Uuid, Task> = todo
+            .get_root_tasks()
+            .into_iter()
+            .map(|task| (task.get_id(), task.clone()))
+            .collect();
+        // A backup is brought up to date by the journals kept with the backups after it, oldest first
+        let journals: Vec<PathBuf> = (1..=backup)
+            .rev()
+            .map(|backup| self.journal_backup_path(backup))
+            .chain(std::iter::once(self.journal_path.clone()))
+            .collect();
+        let mut replayed = 0;
+        for journal_path in journals {
+            let file = match File::open(&journal_path) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            for line in BufReader::new(file).lines() {
+                // Anything in the journal gets folded in, so the next save doesn't land after a torn line
+                rewrite = true;
+                let entry: JournalEntry = match line
+                    .map_err(anyhow::Error::from)
+                    .and_then(|line| Ok(serde_json::from_str(&line)?))
+                {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("Stopping at a bad journal entry: {}", e);
+                        break;
+                    }
+                };
+                if entry.seq <= self.seq {
+                    continue;
+                }
+                if entry.seq > self.seq + 1 {
+                    // The saves in between went into a snapshot that's been lost, carrying on would drop them quietly
+                    return Err(anyhow::anyhow!(
+                        "{} picks up at save {} but the list only goes up to save {}",
+                        journal_path.display(),
+                        entry.seq,
+                        self.seq
+                    ));
+                }
+                for id in entry.removed {
+                    roots.remove(&id);
+                }
+                for task in entry.put {
+                    roots.insert(task.get_id(), task);
+                }
+                for step in entry.steps {
+                    history.replay(step);
+                }
+                self.seq = entry.seq;
+                replayed += 1;
+            }
+        }
+        if replayed > 0 {
+            info!("Replayed {} journal entries", replayed);
+            todo = ToDo::from_vec(roots.into_iter().map(|(_, task)| task).collect());
+        }
+
+        if rewrite {
+            self.compact(&todo, &history)?;
+        } else {
+            self.saved = saved_roots(&todo)?;
+        }
+        Ok((todo, history))
+    }
+
+    fn save(&mut self, todo: &ToDo, history: &History, steps: &[Step]) -> anyhow::Result<()> {
+        if self.entries >= COMPACT_AFTER {
+            return self.compact(todo, history);
+        }
+        let roots = saved_roots(todo)?;
+        let put: Vec<Task> = todo
+            .get_root_tasks()
+            .into_iter()
+            .filter(|task| self.saved.get(&task.get_id()) != roots.get(&task.get_id()))
+            .cloned()
+            .collect();
+        let removed: Vec<Uuid> = self
+            .saved
+            .keys()
+            .filter(|id| !roots.contains_key(id))
+            .copied()
+            .collect();
+        if put.is_empty() && removed.is_empty() && steps.is_empty() {
+            return Ok(());
+        }
+
+        let entry = JournalEntry {
+            seq: self.seq + 1,
+            put,
+            removed,
+            steps: steps.to_vec(),
+        };
+        if let Err(e) = self.append(&entry) {
+            // How much of it made it in is anyone's guess, a new snapshot covers it either way
+            warn!("Unable to append to the journal, compacting instead: {}", e);
+            return self.compact(todo, history);
+        }
+        self.seq = entry.seq;
+        self.entries += 1;
+        self.saved = roots;
+        Ok(())
+    }
+}
+
+fn saved_roots(todo: &ToDo) -> anyhow::Result<HashMap<Uuid, String>> {
+    todo.get_root_tasks()
+        .into_iter()
+        .map(|task| Ok((task.get_id(), serde_json::to_string(task)?)))
+        .collect()
+}
+
+// todo.json -> todo.json.1, unlike with_extension which would replace the .json
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path.as_os_str());
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Keeps nothing past the end of the process, for tests and trying things out
+pub struct Memory {
+    // Kept serialized so every load hands back a fresh copy, same as the other storages
+    todo: Option<String>,
+    history: Option<String>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory {
+            todo: None,
+            history: None,
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+impl Storage for Memory {
+    fn load(&mut self) -> anyhow::Result<(ToDo, History)> {
+        let todo = match &self.todo {
+            Some(todo) => serde_json::from_str(todo)?,
+            None => ToDo::new(),
+        };
+        let history = match &self.history {
+            Some(history) => serde_json::from_str(history)?,
+            None => History::new(),
+        };
+        Ok((todo, history))
+    }
+
+    fn save(&mut self, todo: &ToDo, history: &History, _steps: &[Step]) -> anyhow::Result<()> {
+        self.todo = Some(serde_json::to_string(todo)?);
+        self.history = Some(serde_json::to_string(history)?);
+        Ok(())
+    }
+}
+
+/// A fresh directory for a test to keep its files in
+#[cfg(test)]
+pub(crate) fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("desktopper-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod test {
+    use super::{temp_dir, Checklist, JsonFile, Memory, Sqlite, Storage, COMPACT_AFTER};
+    use crate::backend::{History, Task, ToDo};
+    use std::io::Write;
+
+    fn round_trip(storage: &mut dyn Storage) {
+        let (todo, history) = storage.load().unwrap();
+        assert_eq!(todo.num_tasks(), 0);
+        assert!(history.get_done().is_empty());
+
+        let parent = Task::new("Parent", "Parent", None, 0, None, None, None);
+        let child = Task::new("Child", "Child", None, 0, None, None, None);
+        let (parent_id, child_id) = (parent.get_id(), child.get_id());
+        let mut todo = ToDo::new();
+        let mut history = History::new();
+        todo.add_task(parent);
+        todo.add_sub_task(parent_id, child).unwrap();
+        history.record(&mut todo);
+        let steps = history.take_steps();
+        storage.save(&todo, &history, &steps).unwrap();
+
+        let (loaded, loaded_history) = storage.load().unwrap();
+        assert_eq!(loaded, todo);
+        assert_eq!(loaded.get_parent(child_id), Some(parent_id));
+        assert_eq!(loaded_history.get_done().len(), 2);
+
+        // Saving again replaces what was there
+        todo.remove_task(parent_id).unwrap();
+        let steps = history.take_steps();
+        storage.save(&todo, &history, &steps).unwrap();
+        assert_eq!(storage.load().unwrap().0.num_tasks(), 0);
+    }
+
+    fn add(todo: &mut ToDo, history: &mut History, storage: &mut JsonFile, name: &str) {
+        todo.add_task(Task::new(name, name, None, 0, None, None, None));
+        history.record(todo);
+        let steps = history.take_steps();
+        storage.save(todo, history, &steps).unwrap();
+    }
+
+    #[test]
+    fn memory() {
+        round_trip(&mut Memory::new());
+    }
+
+    #[test]
+    fn sqlite() {
+        round_trip(&mut Sqlite::in_memory().unwrap());
+    }
+
+    #[test]
+    fn json_file() {
+        let dir = temp_dir();
+        round_trip(&mut JsonFile::new(dir.join("todo.json")));
+        assert!(dir.join("todo.journal").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checklist_file() {
+        let dir = temp_dir();
+        round_trip(&mut Checklist::new(dir.join("todo.md")));
+        assert!(dir.join("todo.md.state").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn journal_replay() {
+        let dir = temp_dir();
+        let path = dir.join("todo.json");
+        let mut storage = JsonFile::new(&path);
+        let (mut todo, mut history) = storage.load().unwrap();
+        add(&mut todo, &mut history, &mut storage, "First");
+        add(&mut todo, &mut history, &mut storage, "Second");
+        todo.mark_finished(todo.get_root_tasks()[0].get_id(), Some(true))
+            .unwrap();
+        history.record(&mut todo);
+        let steps = history.take_steps();
+        storage.save(&todo, &history, &steps).unwrap();
+        history.undo(&mut todo).unwrap();
+        let steps = history.take_steps();
+        storage.save(&todo, &history, &steps).unwrap();
+        // Nothing has been compacted yet, it's all in the journal
+        assert!(!path.exists());
+
+        // The power going out part way through a save
+        let mut journal = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("todo.journal"))
+            .unwrap();
+        journal.write_all(b"{\"seq\":5,\"put\":[{\"na").unwrap();
+
+        let (loaded, loaded_history) = JsonFile::new(&path).load().unwrap();
+        assert_eq!(loaded, todo);
+        assert_eq!(loaded_history.get_done().len(), 2);
+        assert_eq!(loaded_history.get_undone().len(), 1);
+        // Loading folded the journal into a snapshot
+        assert!(path.exists());
+        assert_eq!(
+            std::fs::metadata(dir.join("todo.journal")).unwrap().len(),
+            0
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compaction() {
+        let dir = temp_dir();
+        let path = dir.join("todo.json");
+        let mut storage = JsonFile::new(&path);
+        let (mut todo, mut history) = storage.load().unwrap();
+        for i in 0..=COMPACT_AFTER {
+            add(&mut todo, &mut history, &mut storage, &i.to_string());
+        }
+        assert!(path.exists());
+        assert!(!dir.join("todo.json.1").exists());
+        add(&mut todo, &mut history, &mut storage, "Journaled");
+
+        // A new snapshot pushes the old one back into the backups
+        let mut storage = JsonFile::new(&path);
+        let (mut todo, mut history) = storage.load().unwrap();
+        assert_eq!(todo.num_tasks(), COMPACT_AFTER + 2);
+        assert!(dir.join("todo.json.1").exists());
+        assert!(dir.join("todo.journal.1").exists());
+        add(&mut todo, &mut history, &mut storage, "Last");
+
+        // Without the journal that went with it, the backup is missing a save and isn't used
+        std::fs::write(&path, "not json").unwrap();
+        std::fs::rename(dir.join("todo.journal.1"), dir.join("moved")).unwrap();
+        assert!(JsonFile::new(&path).load().is_err());
+
+        // Losing the snapshot falls back to the newest backup, along with the journals after it
+        std::fs::rename(dir.join("moved"), dir.join("todo.journal.1")).unwrap();
+        let (todo, _) = JsonFile::new(&path).load().unwrap();
+        let names: Vec<String> = todo
+            .get_root_tasks()
+            .iter()
+            .map(|task| task.get_name())
+            .collect();
+        assert!(names.contains(&"Journaled".to_string()));
+        assert!(names.contains(&"Last".to_string()));
+        assert_eq!(todo.num_tasks(), COMPACT_AFTER + 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn json_file_before_journal() {
+        let dir = temp_dir();
+        let mut todo = ToDo::new();
+        let mut history = History::new();
+        todo.add_task(Task::new("Old", "Old", None, 0, None, None, None));
+        history.record(&mut todo);
+        std::fs::write(dir.join("todo.json"), serde_json::to_string(&todo).unwrap()).unwrap();
+        std::fs::write(
+            dir.join("todo_history.json"),
+            serde_json::to_string(&history).unwrap(),
+        )
+        .unwrap();
+
+        let (loaded, loaded_history) = JsonFile::new(dir.join("todo.json")).load().unwrap();
+        assert_eq!(loaded, todo);
+        assert_eq!(loaded_history.get_done().len(), 1);
+        assert!(dir.join("todo.json.v0.bak").exists());
+
+        // Something saved by a newer build is left alone rather than replaced
+        std::fs::write(dir.join("todo.json"), r#"{"version": 1000}"#).unwrap();
+        assert!(JsonFile::new(dir.join("todo.json")).load().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,730 @@
+use crate::backend::history::{ChangeRecord, Step, MAX_RECORDS};
+use crate::backend::storage::{Storage, TaskFilter};
+use crate::backend::{CompletionStatus, EstTime, History, Task, ToDo};
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use uuid::Uuid;
+
+// Bumped whenever the tables change, kept in the database's user_version
+const SCHEMA_VERSION: i64 = 2;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS tasks (
+        id TEXT PRIMARY KEY,
+        parent TEXT,
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        description TEXT NOT NULL,
+        due INTEGER,
+        est_minutes INTEGER NOT NULL,
+        complete INTEGER NOT NULL,
+        priority INTEGER,
+        category TEXT,
+        task TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS tasks_parent ON tasks (parent);
+    CREATE INDEX IF NOT EXISTS tasks_due ON tasks (due);
+    CREATE INDEX IF NOT EXISTS tasks_category ON tasks (category);
+    CREATE INDEX IF NOT EXISTS tasks_priority ON tasks (priority);
+    CREATE TABLE IF NOT EXISTS tags (
+        task TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (task, tag)
+    );
+    CREATE INDEX IF NOT EXISTS tags_tag ON tags (tag);
+    CREATE TABLE IF NOT EXISTS changes (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        undone INTEGER NOT NULL,
+        record TEXT NOT NULL
+    );";
+
+/// Every task is a row, sub-tasks pointing at their parent, with the fields searches go by in indexed columns
+/// next to the whole task as JSON. Saves only touch the rows of the tasks that changed,
+/// and the history is kept a change per row, moved along by the steps it took
+pub struct Sqlite {
+    conn: Connection,
+    // The columns each task was last saved with, to find the ones that changed
+    saved: HashMap<Uuid, String>,
+    // Whether the tables are known to match `saved`, if not the next save rewrites everything
+    synced: bool,
+}
+
+impl Sqlite {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Sqlite::with_connection(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> anyhow::Result<Self> {
+        Sqlite::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut conn: Connection) -> anyhow::Result<Self> {
+        let version: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+        let tx = conn.transaction()?;
+        // The first version kept each top level task as a single JSON row, and the history as one more
+        let legacy = if version == 0 && has_table(&tx, "tasks")? {
+            Some(read_legacy(&tx)?)
+        } else {
+            None
+        };
+        if legacy.is_some() {
+            tx.execute_batch("DROP TABLE tasks; DROP TABLE history;")?;
+        }
+        tx.execute_batch(SCHEMA)?;
+        // Due dates were nanoseconds in the first version of these tables, which can't reach past 2262
+        if version == 1 {
+            tx.execute_batch("UPDATE tasks SET due = due / 1000000 WHERE due IS NOT NULL")?;
+        }
+        if let Some((todo, history)) = legacy {
+            write_tasks(&tx, None, &task_rows(&todo)?)?;
+            write_history(&tx, &history)?;
+        }
+        tx.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
+        tx.commit()?;
+        Ok(Sqlite {
+            conn,
+            saved: HashMap::new(),
+            synced: false,
+        })
+    }
+}
+
+impl Storage for Sqlite {
+    fn load(&mut self) -> anyhow::Result<(ToDo, History)> {
+        let mut roots = vec![];
+        let mut children: HashMap<Uuid, Vec<Task>> = HashMap::new();
+        let mut select = self
+            .conn
+            .prepare("SELECT parent, task FROM tasks ORDER BY position")?;
+        let rows = select.query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, Option<String>>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (parent, task) = row?;
+            let task: Task = serde_json::from_str(&task)?;
+            match parent {
+                Some(parent) => children
+                    .entry(Uuid::parse_str(&parent)?)
+                    .or_default()
+                    .push(task),
+                None => roots.push(task),
+            }
+        }
+        let roots = roots
+            .into_iter()
+            .map(|root| attach_sub_tasks(root, &mut children))
+            .collect();
+
+        let mut done = vec![];
+        let mut undone = vec![];
+        let mut select = self
+            .conn
+            .prepare("SELECT undone, record FROM changes ORDER BY id")?;
+        let rows = select.query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, bool>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (is_undone, record) = row?;
+            let record: ChangeRecord = serde_json::from_str(&record)?;
+            if is_undone {
+                undone.push(record);
+            } else {
+                done.push(record);
+            }
+        }
+
+        let todo = ToDo::from_vec(roots);
+        self.saved = signatures(&task_rows(&todo)?);
+        self.synced = true;
+        Ok((todo, History::from_stacks(done, undone)))
+    }
+
+    fn save(&mut self, todo: &ToDo, history: &History, steps: &[Step]) -> anyhow::Result<()> {
+        let rows = task_rows(todo)?;
+        let result = if self.synced {
+            write(
+                &mut self.conn,
+                Some(&self.saved),
+                &rows,
+                history,
+                Some(steps),
+            )
+        } else {
+            write(&mut self.conn, None, &rows, history, None)
+        };
+        // A failed save gets rolled back, leaving the tables as they were
+        // but the steps it was given are gone, so the next one starts over
+        self.synced = result.is_ok();
+        if self.synced {
+            self.saved = signatures(&rows);
+        }
+        result
+    }
+
+    fn search(&mut self, filter: &TaskFilter) -> anyhow::Result<Option<Vec<Uuid>>> {
+        let mut sql = String::from("SELECT id FROM tasks WHERE 1");
+        let mut args = vec![];
+        if let Some(name) = &filter.name {
+            sql.push_str(" AND instr(name, ?) > 0");
+            args.push(Value::Text(name.clone()));
+        }
+        if let Some(desc) = &filter.desc {
+            sql.push_str(" AND instr(description, ?) > 0");
+            args.push(Value::Text(desc.clone()));
+        }
+        // Tasks without a due date or priority sort before any that have one
+        if let Some(due_date) = filter.due_after {
+            sql.push_str(" AND due >= ?");
+            args.push(Value::Integer(due_date.timestamp_millis()));
+        }
+        if let Some(due_date) = filter.due_before {
+            sql.push_str(" AND (due IS NULL OR due <= ?)");
+            args.push(Value::Integer(due_date.timestamp_millis()));
+        }
+        if let Some(est_time) = filter.est_low {
+            sql.push_str(" AND est_minutes >= ?");
+            args.push(Value::Integer(est_time as i64));
+        }
+        if let Some(est_time) = filter.est_high {
+            sql.push_str(" AND est_minutes <= ?");
+            args.push(Value::Integer(est_time as i64));
+        }
+        if filter.complete == Some(true) {
+            sql.push_str(" AND complete");
+        }
+        if let Some(priority) = filter.priority_low {
+            sql.push_str(" AND priority >= ?");
+            args.push(Value::Integer(priority.val() as i64));
+        }
+        if let Some(priority) = filter.priority_high {
+            sql.push_str(" AND (priority IS NULL OR priority <= ?)");
+            args.push(Value::Integer(priority.val() as i64));
+        }
+        match &filter.category {
+            Some(Some(category)) => {
+                sql.push_str(" AND category = ?");
+                args.push(Value::Text(category.clone()));
+            }
+            Some(None) => sql.push_str(" AND category IS NULL"),
+            None => {}
+        }
+        if !filter.tags_any.is_empty() {
+            let places = vec!["?"; filter.tags_any.len()].join(", ");
+            sql.push_str(&format!(
+                " AND EXISTS (SELECT 1 FROM tags WHERE tags.task = tasks.id AND tag IN ({}))",
+                places
+            ));
+            args.extend(filter.tags_any.iter().cloned().map(Value::Text));
+        }
+        for tag in &filter.tags_all {
+            sql.push_str(" AND EXISTS (SELECT 1 FROM tags WHERE tags.task = tasks.id AND tag = ?)");
+            args.push(Value::Text(tag.clone()));
+        }
+
+        let mut select = self.conn.prepare(&sql)?;
+        let ids = select
+            .query_map(&args, |row| row.get::<_, String>(0))?
+            .map(|id| Ok(Uuid::parse_str(&id?)?))
+            .collect::<anyhow::Result<Vec<Uuid>>>()?;
+        Ok(Some(ids))
+    }
+}
+
+// A task's columns, ready to be written
+#[derive(Debug)]
+struct TaskRow {
+    id: Uuid,
+    parent: Option<Uuid>,
+    position: i64,
+    name: String,
+    desc: String,
+    // Milliseconds since the epoch
+    due: Option<i64>,
+    // Estimate and completion are rolled up from the sub-tasks, the same as searches in memory see them
+    est_minutes: i64,
+    complete: bool,
+    priority: Option<i64>,
+    category: Option<String>,
+    tags: Vec<String>,
+    // The task itself, less its sub-tasks, which have rows of their own
+    task: String,
+}
+
+fn task_rows(todo: &ToDo) -> anyhow::Result<Vec<TaskRow>> {
+    let mut rows = vec![];
+    // Top level tasks aren't kept in any order
+    for task in todo.get_root_tasks() {
+        push_rows(task, None, 0, &mut rows)?;
+    }
+    Ok(rows)
+}
+
+fn push_rows(
+    task: &Task,
+    parent: Option<Uuid>,
+    position: i64,
+    rows: &mut Vec<TaskRow>,
+) -> anyhow::Result<()> {
+    let mut json = serde_json::to_value(task)?;
+    if let Some(fields) = json.as_object_mut() {
+        fields.remove("sub_tasks");
+    }
+    let mut tags: Vec<String> = task.get_tags().into_iter().collect();
+    tags.sort();
+    rows.push(TaskRow {
+        id: task.get_id(),
+        parent,
+        position,
+        name: task.get_name(),
+        desc: task.get_desc(),
+        due: task
+            .get_due_date()
+            .map(|due_date| due_date.timestamp_millis()),
+        est_minutes: task.est_time() as i64,
+        complete: task.complete(),
+        priority: task.get_priority().map(|priority| priority.val() as i64),
+        category: task.get_category(),
+        tags,
+        task: json.to_string(),
+    });
+    for (position, sub_task) in task.get_sub_tasks().iter().enumerate() {
+        push_rows(sub_task, Some(task.get_id()), position as i64, rows)?;
+    }
+    Ok(())
+}
+
+impl TaskRow {
+    fn signature(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+fn signatures(rows: &[TaskRow]) -> HashMap<Uuid, String> {
+    rows.iter().map(|row| (row.id, row.signature())).collect()
+}
+
+fn attach_sub_tasks(mut task: Task, children: &mut HashMap<Uuid, Vec<Task>>) -> Task {
+    for sub_task in children.remove(&task.get_id()).unwrap_or_default() {
+        task.add_sub_task(attach_sub_tasks(sub_task, children));
+    }
+    task
+}
+
+fn write(
+    conn: &mut Connection,
+    saved: Option<&HashMap<Uuid, String>>,
+    rows: &[TaskRow],
+    history: &History,
+    steps: Option<&[Step]>,
+) -> anyhow::Result<()> {
+    let tx = conn.transaction()?;
+    write_tasks(&tx, saved, rows)?;
+    match steps {
+        Some(steps) => {
+            for step in steps {
+                write_step(&tx, step)?;
+            }
+        }
+        None => write_history(&tx, history)?,
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Only the rows that differ from `saved`, or all of them without it
+fn write_tasks(
+    conn: &Connection,
+    saved: Option<&HashMap<Uuid, String>>,
+    rows: &[TaskRow],
+) -> anyhow::Result<()> {
+    let mut delete = conn.prepare_cached("DELETE FROM tasks WHERE id = ?1")?;
+    let mut delete_tags = conn.prepare_cached("DELETE FROM tags WHERE task = ?1")?;
+    let mut insert = conn.prepare_cached(
+        "INSERT OR REPLACE INTO tasks
+            (id, parent, position, name, description, due, est_minutes, complete, priority, category, task)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+    )?;
+    let mut insert_tag = conn.prepare_cached("INSERT INTO tags (task, tag) VALUES (?1, ?2)")?;
+
+    let nothing_saved = HashMap::new();
+    let saved = match saved {
+        Some(saved) => {
+            let ids: HashSet<Uuid> = rows.iter().map(|row| row.id).collect();
+            for id in saved.keys().filter(|id| !ids.contains(id)) {
+                delete.execute(params![id.to_string()])?;
+                delete_tags.execute(params![id.to_string()])?;
+            }
+            saved
+        }
+        None => {
+            conn.execute_batch("DELETE FROM tasks; DELETE FROM tags;")?;
+            &nothing_saved
+        }
+    };
+    for row in rows {
+        if saved.get(&row.id) == Some(&row.signature()) {
+            continue;
+        }
+        let id = row.id.to_string();
+        insert.execute(params![
+            id,
+            row.parent.map(|parent| parent.to_string()),
+            row.position,
+            row.name,
+            row.desc,
+            row.due,
+            row.est_minutes,
+            row.complete,
+            row.priority,
+            row.category,
+            row.task
+        ])?;
+        delete_tags.execute(params![id])?;
+        for tag in &row.tags {
+            insert_tag.execute(params![id, tag])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_history(conn: &Connection, history: &History) -> anyhow::Result<()> {
+    conn.execute("DELETE FROM changes", NO_PARAMS)?;
+    for record in history.get_done() {
+        insert_change(conn, record, false)?;
+    }
+    for record in history.get_undone() {
+        insert_change(conn, record, true)?;
+    }
+    Ok(())
+}
+
+fn insert_change(conn: &Connection, record: &ChangeRecord, undone: bool) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT INTO changes (undone, record) VALUES (?1, ?2)",
+        params![undone, serde_json::to_string(record)?],
+    )?;
+    Ok(())
+}
+
+// The same as `History::replay`, on the rows
+fn write_step(conn: &Connection, step: &Step) -> anyhow::Result<()> {
+    match step {
+        Step::Recorded(records) => {
+            conn.execute("DELETE FROM changes WHERE undone = 1", NO_PARAMS)?;
+            for record in records {
+                insert_change(conn, record, false)?;
+            }
+            conn.execute(
+                "DELETE FROM changes WHERE undone = 0 AND id NOT IN
+                    (SELECT id FROM changes WHERE undone = 0 ORDER BY id DESC LIMIT ?1)",
+                params![MAX_RECORDS as i64],
+            )?;
+        }
+        Step::Undone { reverted } => move_latest(conn, false, *reverted)?,
+        Step::Redone { applied } => move_latest(conn, true, *applied)?,
+    }
+    Ok(())
+}
+
+// Takes the latest change off one stack, putting it on top of the other one if it's kept
+fn move_latest(conn: &Connection, from_undone: bool, keep: bool) -> anyhow::Result<()> {
+    let latest = conn
+        .query_row(
+            "SELECT id, record FROM changes WHERE undone = ?1 ORDER BY id DESC LIMIT 1",
+            params![from_undone],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()?;
+    if let Some((id, record)) = latest {
+        conn.execute("DELETE FROM changes WHERE id = ?1", params![id])?;
+        if keep {
+            conn.execute(
+                "INSERT INTO changes (undone, record) VALUES (?1, ?2)",
+                params![!from_undone, record],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn has_table(conn: &Connection, table: &str) -> anyhow::Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            params![table],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+fn read_legacy(conn: &Connection) -> anyhow::Result<(ToDo, History)> {
+    let mut select = conn.prepare("SELECT task FROM tasks")?;
+    let tasks = select
+        .query_map(NO_PARAMS, |row| row.get::<_, String>(0))?
+        .map(|task| Ok(serde_json::from_str::<Task>(&task?)?))
+        .collect::<anyhow::Result<Vec<Task>>>()?;
+    let history = conn
+        .query_row(
+            "SELECT history FROM history WHERE id = 0",
+            NO_PARAMS,
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    let history = match history {
+        Some(history) => serde_json::from_str(&history)?,
+        None => History::new(),
+    };
+    Ok((ToDo::from_vec(tasks), history))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sqlite;
+    use crate::backend::storage::{temp_dir, Storage, TaskFilter};
+    use crate::backend::{History, Priority, Task, ToDo};
+    use chrono::{Duration, Local, TimeZone};
+    use rusqlite::{params, Connection};
+    use uuid::Uuid;
+
+    fn save(storage: &mut Sqlite, todo: &mut ToDo, history: &mut History) {
+        history.record(todo);
+        let steps = history.take_steps();
+        storage.save(todo, history, &steps).unwrap();
+    }
+
+    #[test]
+    fn sqlite_search() {
+        let now = Local::now();
+        let mut groceries = Task::new(
+            "Groceries",
+            "Milk and eggs",
+            Some(now + Duration::days(1)),
+            30,
+            Some(Priority::High),
+            None,
+            Some("home".to_string()),
+        );
+        groceries.set_tags(vec!["errand".to_string()].into_iter().collect());
+        let report = Task::new(
+            "Report",
+            "Quarterly",
+            Some(now + Duration::days(7)),
+            120,
+            Some(Priority::Medium),
+            None,
+            Some("work".to_string()),
+        );
+        let mut slides = Task::new("Slides", "For the report", None, 60, None, None, None);
+        slides.set_tags(
+            vec!["errand".to_string(), "work".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let (report_id, slides_id) = (report.get_id(), slides.get_id());
+        let mut todo = ToDo::from_vec(vec![groceries, report]);
+        todo.add_sub_task(report_id, slides).unwrap();
+        let mut history = History::new();
+        let mut storage = Sqlite::in_memory().unwrap();
+        storage.load().unwrap();
+        save(&mut storage, &mut todo, &mut history);
+
+        let filters = vec![
+            TaskFilter {
+                name: Some("Re".to_string()),
+                ..Default::default()
+            },
+            TaskFilter {
+                desc: Some("report".to_string()),
+                ..Default::default()
+            },
+            TaskFilter {
+                due_after: Some(now + Duration::days(2)),
+                ..Default::default()
+            },
+            TaskFilter {
+                due_before: Some(now + Duration::days(2)),
+                ..Default::default()
+            },
+            TaskFilter {
+                est_low: Some(100),
+                ..Default::default()
+            },
+            TaskFilter {
+                priority_low: Some(Priority::High),
+                ..Default::default()
+            },
+            TaskFilter {
+                priority_high: Some(Priority::Medium),
+                ..Default::default()
+            },
+            TaskFilter {
+                category: Some(None),
+                ..Default::default()
+            },
+            TaskFilter {
+                category: Some(Some("work".to_string())),
+                est_high: Some(200),
+                ..Default::default()
+            },
+            TaskFilter {
+                tags_any: vec!["errand".to_string(), "none".to_string()],
+                ..Default::default()
+            },
+            TaskFilter {
+                tags_all: vec!["errand".to_string(), "work".to_string()],
+                ..Default::default()
+            },
+        ];
+        let check = |storage: &mut Sqlite, todo: &ToDo, filter: &TaskFilter| {
+            let mut found = storage.search(filter).unwrap().unwrap();
+            let mut expected: Vec<Uuid> = todo
+                .get_all_tasks()
+                .into_iter()
+                .filter(|task| filter.matches(task))
+                .map(|task| task.get_id())
+                .collect();
+            found.sort();
+            expected.sort();
+            assert_eq!(found, expected, "{:?}", filter);
+            found.len()
+        };
+        for filter in &filters {
+            assert!(check(&mut storage, &todo, filter) > 0, "{:?}", filter);
+        }
+
+        // Finishing the sub-task finishes its parent along with it
+        let complete = TaskFilter {
+            complete: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(check(&mut storage, &todo, &complete), 0);
+        todo.mark_finished(slides_id, Some(true)).unwrap();
+        save(&mut storage, &mut todo, &mut history);
+        assert_eq!(check(&mut storage, &todo, &complete), 2);
+    }
+
+    #[test]
+    fn sqlite_history() {
+        let dir = temp_dir();
+        let path = dir.join("todo.db");
+        let mut storage = Sqlite::open(&path).unwrap();
+        let (mut todo, mut history) = storage.load().unwrap();
+        let parent = Task::new("Parent", "Parent", None, 0, None, None, None);
+        let parent_id = parent.get_id();
+        todo.add_task(parent);
+        for name in &["First", "Second", "Third"] {
+            todo.add_sub_task(parent_id, Task::new(name, name, None, 0, None, None, None))
+                .unwrap();
+        }
+        save(&mut storage, &mut todo, &mut history);
+        history.undo(&mut todo).unwrap();
+        history.undo(&mut todo).unwrap();
+        history.redo(&mut todo).unwrap();
+        save(&mut storage, &mut todo, &mut history);
+
+        let (loaded, loaded_history) = Sqlite::open(&path).unwrap().load().unwrap();
+        assert_eq!(loaded, todo);
+        let names: Vec<String> = loaded
+            .get_task(parent_id)
+            .unwrap()
+            .get_sub_tasks()
+            .iter()
+            .map(|task| task.get_name())
+            .collect();
+        assert_eq!(names, vec!["First", "Second"]);
+        assert_eq!(loaded_history.get_done().len(), 3);
+        assert_eq!(loaded_history.get_undone().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_before_columns() {
+        let dir = temp_dir();
+        let path = dir.join("todo.db");
+        let mut todo = ToDo::new();
+        let mut history = History::new();
+        let parent = Task::new("Parent", "Parent", None, 0, None, None, None);
+        let parent_id = parent.get_id();
+        todo.add_task(parent);
+        todo.add_sub_task(
+            parent_id,
+            Task::new("Child", "Child", None, 0, None, None, None),
+        )
+        .unwrap();
+        history.record(&mut todo);
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE tasks (id TEXT PRIMARY KEY, task TEXT NOT NULL);
+                CREATE TABLE history (id INTEGER PRIMARY KEY CHECK (id = 0), history TEXT NOT NULL);",
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO tasks (id, task) VALUES (?1, ?2)",
+                params![
+                    parent_id.to_string(),
+                    serde_json::to_string(todo.get_task(parent_id).unwrap()).unwrap()
+                ],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO history (id, history) VALUES (0, ?1)",
+                params![serde_json::to_string(&history).unwrap()],
+            )
+            .unwrap();
+        }
+
+        let (loaded, loaded_history) = Sqlite::open(&path).unwrap().load().unwrap();
+        assert_eq!(loaded, todo);
+        assert_eq!(loaded_history.get_done().len(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_far_future() {
+        let dir = temp_dir();
+        let path = dir.join("todo.db");
+        let far = Local.ymd(2300, 1, 1).and_hms(9, 0, 0);
+        let soon = Local::now() + Duration::days(1);
+        let distant = Task::new("Distant", "Distant", Some(far), 0, None, None, None);
+        let close = Task::new("Close", "Close", Some(soon), 0, None, None, None);
+        let (distant_id, close_id) = (distant.get_id(), close.get_id());
+        let mut todo = ToDo::from_vec(vec![distant, close]);
+        let mut history = History::new();
+        let mut storage = Sqlite::open(&path).unwrap();
+        storage.load().unwrap();
+        save(&mut storage, &mut todo, &mut history);
+
+        let after = |when| TaskFilter {
+            due_after: Some(when),
+            ..Default::default()
+        };
+        let found = storage.search(&after(far - Duration::days(1))).unwrap();
+        assert_eq!(found, Some(vec![distant_id]));
+        drop(storage);
+
+        // The way the first version of the tables had it, which couldn't hold the distant one at all
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute(
+                "DELETE FROM tasks WHERE id = ?1",
+                params![distant_id.to_string()],
+            )
+            .unwrap();
+            conn.execute(
+                "UPDATE tasks SET due = ?1 WHERE id = ?2",
+                params![soon.timestamp_nanos(), close_id.to_string()],
+            )
+            .unwrap();
+            conn.execute_batch("PRAGMA user_version = 1").unwrap();
+        }
+        let mut storage = Sqlite::open(&path).unwrap();
+        let found = storage.search(&after(soon - Duration::hours(1))).unwrap();
+        assert_eq!(found, Some(vec![close_id]));
+        assert_eq!(storage.search(&after(far)).unwrap(), Some(vec![]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
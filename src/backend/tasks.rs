@@ -1,10 +1,11 @@
 // TODO refactor function layout to make more sense
 
-use crate::backend::{CompletionStatus, EstTime, Priority};
-use chrono::prelude::{DateTime, Local, Weekday};
-use chrono::{Datelike, Duration};
+use crate::backend::source::Origin;
+use crate::backend::{CompletionStatus, EstTime, Priority, Recurrence, TrackedTime};
+use chrono::prelude::{DateTime, Local};
+use chrono::{Duration, Timelike};
 use serde::{Deserialize, Serialize};
-use std::cmp::Ordering;
+use std::collections::HashSet;
 use uuid::Uuid;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -16,9 +17,45 @@ pub struct Task {
     initial_date: DateTime<Local>,
     est_minutes: u32,
     priority: Option<Priority>,
-    repeat: Option<Vec<Weekday>>,
+    repeat: Option<Recurrence>,
     category: Option<String>,
     id: Uuid,
+    #[serde(default)]
+    sub_tasks: Vec<Task>,
+    // Tasks that have to be finished before this one can be started
+    #[serde(default)]
+    blocked_by: Vec<Uuid>,
+    #[serde(default)]
+    sessions: Vec<TimeSession>,
+    #[serde(default)]
+    tags: HashSet<String>,
+    // Hidden from the default views until this passes
+    #[serde(default)]
+    not_before: Option<DateTime<Local>>,
+    // When it was last marked finished, None while it isn't
+    #[serde(default)]
+    finished_at: Option<DateTime<Local>>,
+    // The Trello card, or checklist item for a sub-task, that this one mirrors
+    #[serde(default)]
+    trello_id: Option<String>,
+    // The source it was merged in from, None for ones made here
+    #[serde(default)]
+    origin: Option<Origin>,
+}
+
+/// One stretch of actually working on a task, `end` is None while the timer is running
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TimeSession {
+    pub start: DateTime<Local>,
+    pub end: Option<DateTime<Local>>,
+}
+
+impl TimeSession {
+    pub fn duration(&self) -> Duration {
+        self.end
+            .unwrap_or_else(Local::now)
+            .signed_duration_since(self.start)
+    }
 }
 
 impl Task {
@@ -28,9 +65,16 @@ impl Task {
         due_date: Option<DateTime<Local>>,
         est_minutes: u32,
         priority: Option<Priority>,
-        repeat: Option<Vec<Weekday>>,
+        repeat: Option<Recurrence>,
         category: Option<String>,
     ) -> Self {
+        // The first due date is where the recurrence starts counting from
+        let repeat = repeat.map(|mut rule| {
+            if let (None, Some(due_date)) = (rule.get_start(), due_date) {
+                rule.set_start(due_date.with_nanosecond(0).unwrap());
+            }
+            rule
+        });
         Task {
             name: name.to_string(),
             desc: desc.to_string(),
@@ -42,6 +86,14 @@ impl Task {
             repeat,
             category,
             id: Uuid::new_v4(),
+            sub_tasks: Vec::new(),
+            blocked_by: Vec::new(),
+            sessions: Vec::new(),
+            tags: HashSet::new(),
+            not_before: None,
+            finished_at: None,
+            trello_id: None,
+            origin: None,
         }
     }
 
@@ -53,36 +105,30 @@ impl Task {
         }
     }
 
-    // Returns false if there is  no repeat
+    /// Moves the due date up to the next occurrence of the task's recurrence rule.
+    /// Returns false if there is no repeat, or the rule has run out of occurrences
     pub fn repeat(&mut self) -> bool {
-        if self.repeat.is_some() && self.due_date.is_some() {
-            let today = Local::today().weekday();
-            let mut diffs: Vec<i64> = self
-                .repeat
-                .as_ref()
-                .unwrap()
-                .iter()
-                .map(|weekday| {
-                    let today_num = today.num_days_from_monday();
-                    let target_num = weekday.num_days_from_monday();
-                    match today_num.cmp(&target_num) {
-                        // Anything that requires passing Sunday
-                        Ordering::Greater => (7 - today_num + target_num) as i64,
-                        // Sometime this calendar week
-                        Ordering::Equal => (target_num - today_num) as i64,
-                        // Same day of the week
-                        Ordering::Less => 7 as i64,
-                    }
-                })
-                .collect();
-            diffs.sort();
-            // Only care about the day difference, not the number of hours or whatever
-            let new_due_date = self.due_date.unwrap() + Duration::days(diffs[0]);
-            self.due_date = Some(new_due_date);
-            self.set_done(false);
-            true
-        } else {
-            false
+        let due_date = match self.due_date {
+            Some(due_date) => due_date,
+            None => return false,
+        };
+        let rule = match self.repeat.as_mut() {
+            Some(rule) => rule,
+            None => return false,
+        };
+        // Rules loaded from before they had a start repeat from the current due date
+        if rule.get_start().is_none() {
+            rule.set_start(due_date);
+        }
+        // Skip over anything missed while nobody was around to repeat it
+        let now = Local::now();
+        match rule.next_after(if due_date > now { due_date } else { now }) {
+            Some(next_due_date) => {
+                self.due_date = Some(next_due_date);
+                self.set_done(false);
+                true
+            }
+            None => false,
         }
     }
 
@@ -98,10 +144,62 @@ impl Task {
         self.id
     }
 
+    // Imports that bring their own ids keep them, so the same task can be matched up again later
+    pub(crate) fn set_id(&mut self, id: Uuid) {
+        self.id = id;
+    }
+
+    // Edits that touch what the ToDo indexes, so it has to take the task back through replace_task
+    pub(crate) fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
     pub fn get_due_date(&self) -> Option<DateTime<Local>> {
         self.due_date
     }
 
+    pub(crate) fn set_due_date(&mut self, due_date: Option<DateTime<Local>>) {
+        self.due_date = due_date;
+    }
+
+    /// When the task was first made
+    pub fn get_initial_date(&self) -> DateTime<Local> {
+        self.initial_date
+    }
+
+    pub fn set_initial_date(&mut self, initial_date: DateTime<Local>) {
+        self.initial_date = initial_date;
+    }
+
+    pub fn get_not_before(&self) -> Option<DateTime<Local>> {
+        self.not_before
+    }
+
+    pub fn set_not_before(&mut self, not_before: Option<DateTime<Local>>) {
+        self.not_before = not_before
+    }
+
+    /// A snoozed task can't be acted on until its not before date
+    pub fn is_snoozed(&self) -> bool {
+        match self.not_before {
+            Some(not_before) => not_before > Local::now(),
+            None => false,
+        }
+    }
+
+    /// Pushes the not before date out by `duration`, counting from now if it has already passed.
+    /// Returns the new not before date, or None if it would be out of range
+    pub fn snooze(&mut self, duration: Duration) -> Option<DateTime<Local>> {
+        let now = Local::now();
+        let from = match self.not_before {
+            Some(not_before) if not_before > now => not_before,
+            _ => now,
+        };
+        let until = from.checked_add_signed(duration)?;
+        self.not_before = Some(until);
+        Some(until)
+    }
+
     pub fn get_priority(&self) -> Option<Priority> {
         self.priority
     }
@@ -110,28 +208,282 @@ impl Task {
         self.category.clone()
     }
 
-    pub fn get_repeats(&self) -> Option<Vec<Weekday>> {
+    pub(crate) fn set_category(&mut self, category: Option<String>) {
+        self.category = category;
+    }
+
+    pub fn get_trello_id(&self) -> Option<&str> {
+        self.trello_id.as_deref()
+    }
+
+    pub(crate) fn set_trello_id(&mut self, trello_id: Option<String>) {
+        self.trello_id = trello_id;
+    }
+
+    pub fn get_origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    pub(crate) fn set_origin(&mut self, origin: Option<Origin>) {
+        self.origin = origin;
+    }
+
+    pub fn get_tags(&self) -> HashSet<String> {
+        self.tags.clone()
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(tag)
+    }
+
+    /// Replaces the task's tags, use the ToDo to tag tasks that have already been added to it
+    pub fn set_tags(&mut self, tags: HashSet<String>) {
+        self.tags = tags
+    }
+
+    pub(crate) fn add_tag(&mut self, tag: &str) -> bool {
+        self.tags.insert(tag.to_string())
+    }
+
+    pub(crate) fn remove_tag(&mut self, tag: &str) -> bool {
+        self.tags.remove(tag)
+    }
+
+    pub fn get_repeats(&self) -> Option<Recurrence> {
         self.repeat.clone()
     }
 
+    /// Marking it finished when it wasn't already records when it was
     pub fn set_done(&mut self, finished: bool) {
+        if !finished {
+            self.finished_at = None;
+        } else if !self.finished {
+            self.finished_at = Some(Local::now());
+        }
         self.finished = finished
     }
+
+    /// Only this task's own flag, see `complete` for the rolled up status
+    pub fn is_done(&self) -> bool {
+        self.finished
+    }
+
+    pub fn get_finished_at(&self) -> Option<DateTime<Local>> {
+        self.finished_at
+    }
+
+    /// For imports that know when it was really finished, only sticks if it is
+    pub fn set_finished_at(&mut self, finished_at: Option<DateTime<Local>>) {
+        if self.finished {
+            self.finished_at = finished_at;
+        }
+    }
+
+    pub fn get_sub_tasks(&self) -> &[Task] {
+        &self.sub_tasks
+    }
+
+    /// Find a direct child of this task
+    pub fn get_sub_task(&self, id: Uuid) -> Option<&Task> {
+        self.sub_tasks.iter().find(|task| task.id == id)
+    }
+
+    /// Find a direct child of this task
+    pub fn get_sub_task_mut(&mut self, id: Uuid) -> Option<&mut Task> {
+        self.sub_tasks.iter_mut().find(|task| task.id == id)
+    }
+
+    pub fn add_sub_task(&mut self, task: Task) {
+        self.sub_tasks.push(task)
+    }
+
+    /// Removes a direct child of this task, along with all of its own children
+    pub fn remove_sub_task(&mut self, id: Uuid) -> Option<Task> {
+        match self.sub_tasks.iter().position(|task| task.id == id) {
+            Some(idx) => Some(self.sub_tasks.remove(idx)),
+            None => None,
+        }
+    }
+
+    /// Swaps a direct child of this task for a new copy with the same id, keeping its place among the others
+    pub(crate) fn replace_sub_task(&mut self, task: Task) -> Option<Task> {
+        let sub_task = self.get_sub_task_mut(task.id)?;
+        Some(std::mem::replace(sub_task, task))
+    }
+
+    /// Gives it and everything under it new ids, for a copy that has to sit alongside the original
+    pub(crate) fn renew_ids(&mut self) {
+        self.id = Uuid::new_v4();
+        for sub_task in self.sub_tasks.iter_mut() {
+            sub_task.renew_ids();
+        }
+    }
+
+    pub fn get_blocked_by(&self) -> Vec<Uuid> {
+        self.blocked_by.clone()
+    }
+
+    // Only the ToDo can add these, it has to make sure there's no cycles
+    pub(crate) fn add_blocker(&mut self, id: Uuid) {
+        if !self.blocked_by.contains(&id) {
+            self.blocked_by.push(id)
+        }
+    }
+
+    pub(crate) fn remove_blocker(&mut self, id: Uuid) -> bool {
+        let len = self.blocked_by.len();
+        self.blocked_by.retain(|blocker| *blocker != id);
+        len != self.blocked_by.len()
+    }
+
+    /// Takes what only this side keeps track of from an older copy of the same task,
+    /// for when it comes back from somewhere that can't hold onto the estimate, sub-tasks, blockers, tracked time,
+    /// what it's linked to in Trello or where it was merged in from
+    pub fn carry_over(&mut self, older: &Task) {
+        self.est_minutes = older.est_minutes;
+        self.trello_id = older.trello_id.clone();
+        self.origin = older.origin.clone();
+        self.sub_tasks = older.sub_tasks.clone();
+        self.blocked_by = older.blocked_by.clone();
+        self.sessions = older.sessions.clone();
+    }
+
+    /// Takes what only this side keeps track of from an older copy of the same task, for one fetched from a source
+    /// that can carry its own estimate, sub-tasks and blockers. Those stay as the source has them, unless it came without any.
+    /// Sub-tasks are matched up with the older ones by id, or failing that by their order, and keep their ids and tracked time
+    pub(crate) fn keep_local(&mut self, older: &Task) {
+        self.trello_id = older.trello_id.clone();
+        self.origin = older.origin.clone();
+        self.sessions = older.sessions.clone();
+        if self.est_minutes == 0 {
+            self.est_minutes = older.est_minutes;
+        }
+        if self.blocked_by.is_empty() {
+            self.blocked_by = older.blocked_by.clone();
+        }
+        if self.sub_tasks.is_empty() {
+            self.sub_tasks = older.sub_tasks.clone();
+            return;
+        }
+        let ids: HashSet<Uuid> = self.sub_tasks.iter().map(|task| task.id).collect();
+        let mut unmatched = older
+            .sub_tasks
+            .iter()
+            .filter(|task| !ids.contains(&task.id));
+        for sub_task in self.sub_tasks.iter_mut() {
+            let older = older.get_sub_task(sub_task.id).or_else(|| unmatched.next());
+            if let Some(older) = older {
+                sub_task.id = older.id;
+                sub_task.keep_local(older);
+            }
+        }
+    }
+
+    /// Gives new ids to the sub-tasks, and everything under them, whose ids `taken` says are already in use
+    pub(crate) fn renew_taken_ids(&mut self, taken: &dyn Fn(Uuid) -> bool) {
+        for sub_task in self.sub_tasks.iter_mut() {
+            if taken(sub_task.id) {
+                sub_task.renew_ids();
+            } else {
+                sub_task.renew_taken_ids(taken);
+            }
+        }
+    }
+
+    pub fn get_sessions(&self) -> Vec<TimeSession> {
+        self.sessions.clone()
+    }
+
+    pub fn is_tracking(&self) -> bool {
+        match self.sessions.last() {
+            Some(session) => session.end.is_none(),
+            None => false,
+        }
+    }
+
+    /// Starts a new time session, returns false if one is already running
+    pub fn start_timer(&mut self) -> bool {
+        if self.is_tracking() {
+            false
+        } else {
+            self.sessions.push(TimeSession {
+                start: Local::now(),
+                end: None,
+            });
+            true
+        }
+    }
+
+    /// Ends the running time session, returns false if there isn't one
+    pub fn stop_timer(&mut self) -> bool {
+        match self.sessions.last_mut() {
+            Some(session) if session.end.is_none() => {
+                session.end = Some(Local::now());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// This task followed by every task below it, depth first
+    pub fn walk(&self) -> Vec<&Task> {
+        let mut tasks = vec![self];
+        for sub_task in self.sub_tasks.iter() {
+            tasks.append(&mut sub_task.walk());
+        }
+        tasks
+    }
 }
 
+// A task with sub-tasks is tracked through its children, so it's done once all of them are
 impl CompletionStatus for Task {
     fn complete(&self) -> bool {
-        self.finished
+        if self.sub_tasks.is_empty() {
+            self.finished
+        } else {
+            self.finished || self.sub_tasks.iter().all(|task| task.complete())
+        }
     }
 
     fn completion_status(&self) -> (u32, u32) {
-        (if self.finished { 1 } else { 0 }, 1)
+        if self.sub_tasks.is_empty() {
+            (if self.finished { 1 } else { 0 }, 1)
+        } else {
+            let (done, total) = self
+                .sub_tasks
+                .iter()
+                .map(|task| task.completion_status())
+                .fold((0, 0), |acc, status| (acc.0 + status.0, acc.1 + status.1));
+            // Finishing the parent outright finishes everything under it
+            (if self.finished { total } else { done }, total)
+        }
+    }
+}
+
+impl TrackedTime for Task {
+    fn tracked_time(&self) -> u32 {
+        let seconds: i64 = self
+            .sessions
+            .iter()
+            .map(|session| session.duration().num_seconds())
+            .sum();
+        (seconds / 60) as u32
+            + self
+                .sub_tasks
+                .iter()
+                .map(|task| task.tracked_time())
+                .sum::<u32>()
     }
 }
 
 impl EstTime for Task {
     fn est_time(&self) -> u32 {
         self.est_minutes
+            + self
+                .sub_tasks
+                .iter()
+                .map(|task| task.est_time())
+                .sum::<u32>()
     }
 }
 
@@ -0,0 +1,396 @@
+use crate::backend::recurrence::{parse_date_time, Frequency};
+use crate::backend::{Priority, Recurrence, Task, ToDo};
+use chrono::prelude::{DateTime, TimeZone, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::str::FromStr;
+use uuid::Uuid;
+
+const DATE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+const WEEKDAYS: [Weekday; 5] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+];
+
+/// One task the way `task export` writes it and `task import` reads it.
+/// Anything else Taskwarrior puts in there, like the urgency or UDAs, is ignored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TwTask {
+    pub uuid: Uuid,
+    pub description: String,
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recur: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+    // The template a recurring instance was made from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Annotation {
+    pub entry: String,
+    pub description: String,
+}
+
+/// Reads the output of `task export`, either the JSON array it writes now
+/// or the one object per line older versions wrote.
+///
+/// Tasks keep their Taskwarrior uuid as their id. Deleted tasks are left out,
+/// and so are the instances of a recurring task whose template is in there too, the template stands in for them
+pub fn parse(text: &str) -> Result<Vec<Task>, String> {
+    let tw_tasks: Vec<TwTask> = if text.trim_start().starts_with('[') {
+        serde_json::from_str(text).map_err(|e| e.to_string())?
+    } else {
+        text.lines()
+            .enumerate()
+            .map(|(idx, line)| (idx, line.trim().trim_end_matches(',')))
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(idx, line)| {
+                serde_json::from_str(line).map_err(|e| format!("Line {}: {}", idx + 1, e))
+            })
+            .collect::<Result<_, _>>()?
+    };
+    let templates: HashSet<Uuid> = tw_tasks
+        .iter()
+        .filter(|tw_task| tw_task.status == "recurring")
+        .map(|tw_task| tw_task.uuid)
+        .collect();
+    tw_tasks
+        .iter()
+        .filter(|tw_task| tw_task.status != "deleted")
+        .filter(|tw_task| match tw_task.parent {
+            Some(parent) => !templates.contains(&parent),
+            None => true,
+        })
+        .map(|tw_task| {
+            to_task(tw_task)
+                .map_err(|e| format!("{} ({}): {}", tw_task.description, tw_task.uuid, e))
+        })
+        .collect()
+}
+
+/// Every task, sub-tasks included, as a JSON array for `task import`.
+/// Taskwarrior has no sub-tasks, so they all come out at the top level
+pub fn export(todo: &ToDo) -> String {
+    let now = Utc::now();
+    let lines: Vec<String> = todo
+        .get_all_tasks()
+        .into_iter()
+        .map(|task| serde_json::to_string(&from_task(task, now)).unwrap())
+        .collect();
+    format!("[\n{}\n]\n", lines.join(",\n"))
+}
+
+/// A task read from Taskwarrior, with the same id.
+///
+/// - the description is the name, and the annotations make up the description
+/// - the project is the category
+/// - H priority is High, M Medium and L Low
+/// - waiting tasks are hidden until their wait date
+/// - `recur` is how it repeats, from `daily` and `biweekly` to `3months`
+pub fn to_task(tw_task: &TwTask) -> Result<Task, &'static str> {
+    let priority = match tw_task.priority.as_deref() {
+        None | Some("") => None,
+        Some("H") => Some(Priority::High),
+        Some("M") => Some(Priority::Medium),
+        Some("L") => Some(Priority::Low),
+        Some(_) => return Err("Invalid priority"),
+    };
+    let repeat = match &tw_task.recur {
+        Some(recur) => {
+            let mut rule = parse_recur(recur)?;
+            if let Some(until) = &tw_task.until {
+                rule += &format!(";UNTIL={}", utc(parse_date_time(until)?));
+            }
+            Some(Recurrence::from_str(&rule)?)
+        }
+        None => None,
+    };
+    let desc: Vec<&str> = tw_task
+        .annotations
+        .iter()
+        .map(|annotation| annotation.description.as_str())
+        .collect();
+    let mut task = Task::new(
+        &tw_task.description,
+        &desc.join("\n"),
+        tw_task.due.as_deref().map(parse_date_time).transpose()?,
+        0,
+        priority,
+        repeat,
+        tw_task.project.clone(),
+    );
+    task.set_id(tw_task.uuid);
+    task.set_tags(tw_task.tags.iter().cloned().collect());
+    task.set_done(tw_task.status == "completed");
+    task.set_finished_at(tw_task.end.as_deref().map(parse_date_time).transpose()?);
+    task.set_not_before(tw_task.wait.as_deref().map(parse_date_time).transpose()?);
+    if let Some(entry) = &tw_task.entry {
+        task.set_initial_date(parse_date_time(entry)?);
+    }
+    Ok(task)
+}
+
+/// A task the way Taskwarrior keeps it, `now` being when it was last modified.
+/// Extreme priority comes out as H, and repeats Taskwarrior can't express are rounded to their frequency
+pub fn from_task(task: &Task, now: DateTime<Utc>) -> TwTask {
+    let finished = task.is_done();
+    // Taskwarrior only repeats tasks with a due date, from a template in the recurring status
+    let recur = match (task.get_repeats(), task.get_due_date()) {
+        (Some(rule), Some(_)) => Some(rule),
+        _ => None,
+    };
+    let status = if finished {
+        "completed"
+    } else if recur.is_some() {
+        "recurring"
+    } else if task.is_snoozed() {
+        "waiting"
+    } else {
+        "pending"
+    };
+    let mut tags: Vec<String> = task.get_tags().into_iter().collect();
+    tags.sort();
+    TwTask {
+        uuid: task.get_id(),
+        description: task.get_name(),
+        status: status.to_string(),
+        entry: Some(utc(task.get_initial_date())),
+        modified: Some(utc(now)),
+        due: task.get_due_date().map(utc),
+        // Taskwarrior won't take a finished task without one
+        end: if finished {
+            Some(utc(task
+                .get_finished_at()
+                .map_or(now, |at| at.with_timezone(&Utc))))
+        } else {
+            None
+        },
+        wait: task.get_not_before().map(utc),
+        project: task.get_category(),
+        priority: task.get_priority().map(|priority| {
+            match priority {
+                Priority::Extreme | Priority::High => "H",
+                Priority::Medium => "M",
+                Priority::Low => "L",
+            }
+            .to_string()
+        }),
+        until: recur.as_ref().and_then(|rule| rule.get_until()).map(utc),
+        recur: recur.as_ref().map(recur_name),
+        parent: None,
+        tags,
+        annotations: if task.get_desc().is_empty() {
+            vec![]
+        } else {
+            vec![Annotation {
+                entry: utc(task.get_initial_date()),
+                description: task.get_desc(),
+            }]
+        },
+    }
+}
+
+fn utc<Tz: TimeZone>(time: DateTime<Tz>) -> String {
+    time.with_timezone(&Utc).format(DATE_FORMAT).to_string()
+}
+
+// Taskwarrior's named periods and durations like 2wks or P3M into an RRULE,
+// nothing shorter than a day
+fn parse_recur(recur: &str) -> Result<String, &'static str> {
+    let recur = recur.trim().to_ascii_lowercase();
+    let (interval, freq) = match recur.as_str() {
+        "weekdays" => return Ok("FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR".to_string()),
+        "daily" | "day" => (1, Frequency::Daily),
+        "weekly" | "week" => (1, Frequency::Weekly),
+        "biweekly" | "fortnight" => (2, Frequency::Weekly),
+        "monthly" | "month" => (1, Frequency::Monthly),
+        "quarterly" => (3, Frequency::Monthly),
+        "semiannual" => (6, Frequency::Monthly),
+        "yearly" | "annual" | "year" => (1, Frequency::Yearly),
+        "biannual" | "biyearly" => (2, Frequency::Yearly),
+        _ => {
+            // ISO 8601 durations use m for months, where Taskwarrior's own would be minutes
+            let iso = recur.starts_with('p');
+            let recur = recur.trim_start_matches('p');
+            let split = recur
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or("Invalid recurrence")?;
+            let count = if split == 0 {
+                1
+            } else {
+                u32::from_str(&recur[..split]).map_err(|_| "Invalid recurrence")?
+            };
+            match (&recur[split..], iso) {
+                ("d", _) | ("day", false) | ("days", false) => (count, Frequency::Daily),
+                ("w", _) | ("wk", false) | ("wks", false) | ("week", false) | ("weeks", false) => {
+                    (count, Frequency::Weekly)
+                }
+                ("m", true)
+                | ("mo", false)
+                | ("mos", false)
+                | ("mth", false)
+                | ("mths", false)
+                | ("month", false)
+                | ("months", false) => (count, Frequency::Monthly),
+                ("q", false)
+                | ("qtr", false)
+                | ("qtrs", false)
+                | ("quarter", false)
+                | ("quarters", false) => (
+                    count.checked_mul(3).ok_or("Invalid recurrence")?,
+                    Frequency::Monthly,
+                ),
+                ("y", _) | ("yr", false) | ("yrs", false) | ("year", false) | ("years", false) => {
+                    (count, Frequency::Yearly)
+                }
+                _ => return Err("Unsupported recurrence, it has to be a day or longer"),
+            }
+        }
+    };
+    if interval == 0 {
+        return Err("Invalid recurrence");
+    }
+    Ok(format!("FREQ={};INTERVAL={}", freq, interval))
+}
+
+fn recur_name(rule: &Recurrence) -> String {
+    let weekdays: Vec<(Option<i32>, Weekday)> = WEEKDAYS.iter().map(|day| (None, *day)).collect();
+    match (rule.get_freq(), rule.get_interval()) {
+        (Frequency::Weekly, 1) if rule.get_by_day() == weekdays => "weekdays".to_string(),
+        (Frequency::Daily, 1) => "daily".to_string(),
+        (Frequency::Weekly, 1) => "weekly".to_string(),
+        (Frequency::Weekly, 2) => "biweekly".to_string(),
+        (Frequency::Monthly, 1) => "monthly".to_string(),
+        (Frequency::Monthly, 3) => "quarterly".to_string(),
+        (Frequency::Monthly, 6) => "semiannual".to_string(),
+        (Frequency::Yearly, 1) => "yearly".to_string(),
+        (Frequency::Daily, n) => format!("{}days", n),
+        (Frequency::Weekly, n) => format!("{}weeks", n),
+        (Frequency::Monthly, n) => format!("{}months", n),
+        (Frequency::Yearly, n) => format!("{}years", n),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export, parse, parse_recur};
+    use crate::backend::{CompletionStatus, Priority, Recurrence, Task, ToDo};
+    use chrono::prelude::{Local, TimeZone, Utc};
+    use std::str::FromStr;
+    use uuid::Uuid;
+
+    #[test]
+    fn taskwarrior() {
+        let text = r#"[
+{"id":1,"description":"Pay rent","due":"20201101T120000Z","entry":"20201001T080000Z","modified":"20201001T080000Z","project":"Home","priority":"H","status":"pending","tags":["bills","money"],"uuid":"6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e01","annotations":[{"entry":"20201001T080000Z","description":"Online"}],"urgency":9.2},
+{"id":0,"description":"Old","end":"20201002T080000Z","entry":"20201001T080000Z","status":"completed","uuid":"6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e02"},
+{"id":0,"description":"Gone","entry":"20201001T080000Z","status":"deleted","uuid":"6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e03"},
+{"id":0,"description":"Water plants","due":"20201005T170000Z","entry":"20201001T080000Z","recur":"biweekly","until":"20201231T000000Z","status":"recurring","uuid":"6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e04"},
+{"id":2,"description":"Water plants","due":"20201005T170000Z","entry":"20201001T080000Z","recur":"biweekly","parent":"6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e04","imask":0,"status":"pending","uuid":"6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e05"}
+]"#;
+        let tasks = parse(text).unwrap();
+        assert_eq!(tasks.len(), 3);
+        let rent = &tasks[0];
+        assert_eq!(
+            rent.get_id(),
+            Uuid::parse_str("6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e01").unwrap()
+        );
+        assert_eq!(rent.get_name(), "Pay rent");
+        assert_eq!(rent.get_desc(), "Online");
+        assert_eq!(rent.get_category(), Some("Home".to_string()));
+        assert_eq!(rent.get_priority(), Some(Priority::High));
+        assert!(rent.has_tag("bills") && rent.has_tag("money"));
+        assert_eq!(
+            rent.get_due_date(),
+            Some(Utc.ymd(2020, 11, 1).and_hms(12, 0, 0).with_timezone(&Local))
+        );
+        assert!(tasks[1].complete());
+        assert_eq!(
+            tasks[1].get_finished_at(),
+            Some(Utc.ymd(2020, 10, 2).and_hms(8, 0, 0).with_timezone(&Local))
+        );
+        let rule = tasks[2].get_repeats().unwrap();
+        assert_eq!(rule.get_interval(), 2);
+        assert!(rule.get_until().is_some());
+
+        // Everything comes back the same way, ids included
+        let todo = ToDo::from_vec(tasks.clone());
+        let again = parse(&export(&todo)).unwrap();
+        assert_eq!(again.len(), 3);
+        for task in tasks.iter() {
+            let other = again.iter().find(|t| t.get_id() == task.get_id()).unwrap();
+            assert_eq!(other.get_name(), task.get_name());
+            assert_eq!(other.get_desc(), task.get_desc());
+            assert_eq!(other.get_due_date(), task.get_due_date());
+            assert_eq!(other.get_initial_date(), task.get_initial_date());
+            assert_eq!(other.get_priority(), task.get_priority());
+            assert_eq!(other.get_tags(), task.get_tags());
+            assert_eq!(other.complete(), task.complete());
+            assert_eq!(other.get_finished_at(), task.get_finished_at());
+            assert_eq!(
+                other.get_repeats().map(|rule| rule.get_interval()),
+                task.get_repeats().map(|rule| rule.get_interval())
+            );
+        }
+    }
+
+    #[test]
+    fn taskwarrior_recur() {
+        assert_eq!(parse_recur("weekly").unwrap(), "FREQ=WEEKLY;INTERVAL=1");
+        assert_eq!(parse_recur("3wks").unwrap(), "FREQ=WEEKLY;INTERVAL=3");
+        assert_eq!(parse_recur("quarterly").unwrap(), "FREQ=MONTHLY;INTERVAL=3");
+        assert_eq!(parse_recur("2q").unwrap(), "FREQ=MONTHLY;INTERVAL=6");
+        assert_eq!(parse_recur("P2M").unwrap(), "FREQ=MONTHLY;INTERVAL=2");
+        assert!(parse_recur("5min").is_err());
+        assert!(parse_recur("0d").is_err());
+        assert!(parse_recur("2000000000q").is_err());
+        assert!(parse_recur("sometimes").is_err());
+
+        let mut todo = ToDo::new();
+        let due = Some(Local.ymd(2020, 10, 5).and_hms(9, 0, 0));
+        for rule in &["FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR", "FREQ=DAILY;INTERVAL=4"] {
+            let rule = Recurrence::from_str(rule).unwrap();
+            todo.add_task(Task::new("Repeat", "", due, 0, None, Some(rule), None));
+        }
+        let text = export(&todo);
+        assert!(text.contains(r#""recur":"weekdays""#));
+        assert!(text.contains(r#""recur":"4days""#));
+        assert!(text.contains(r#""status":"recurring""#));
+    }
+
+    #[test]
+    fn taskwarrior_errors() {
+        assert!(parse("[{\"description\":\"No uuid\",\"status\":\"pending\"}]").is_err());
+        let line = r#"{"uuid":"6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e01","description":"Ok","status":"pending"},"#;
+        assert_eq!(parse(line).unwrap().len(), 1);
+        assert!(parse(&format!("{}\nnot json", line))
+            .unwrap_err()
+            .starts_with("Line 2:"));
+        let bad = r#"[{"uuid":"6f1b0b1e-9c4f-4b9f-8c3e-0a7d4d5c6e01","description":"Bad","status":"pending","priority":"X"}]"#;
+        assert!(parse(bad).unwrap_err().contains("Invalid priority"));
+    }
+}
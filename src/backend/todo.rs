@@ -1,7 +1,9 @@
+use crate::backend::history::Change;
+use crate::backend::source::{Merged, Origin, TaskSource};
 use crate::backend::tasks::Task;
-use crate::backend::{CompletionStatus, EstTime};
-use chrono::Local;
-use serde::de::{Deserialize, Deserializer, Error, MapAccess, SeqAccess, Visitor};
+use crate::backend::{CompletionStatus, EstTime, TrackedTime};
+use chrono::{DateTime, Duration, Local};
+use serde::de::{Deserialize, Deserializer, Error, IgnoredAny, MapAccess, SeqAccess, Visitor};
 use serde::export::Formatter;
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::collections::{HashMap, HashSet};
@@ -10,11 +12,23 @@ use uuid::Uuid;
 
 #[derive(Default, Debug)]
 pub struct ToDo {
+    // Only the top level tasks, sub-tasks live inside of their parents
     tasks: HashMap<Uuid, Task>,
     // #[serde(skip)]
     categories: HashMap<Option<String>, Vec<Uuid>>,
     // #[serde(skip)]
     overdue: HashSet<Uuid>,
+    // #[serde(skip)]
+    // Sub-task id -> parent id
+    parents: HashMap<Uuid, Uuid>,
+    // #[serde(skip)]
+    // Blocking task id -> the tasks it blocks
+    dependents: HashMap<Uuid, HashSet<Uuid>>,
+    // #[serde(skip)]
+    tags: HashMap<String, HashSet<Uuid>>,
+    // #[serde(skip)]
+    // Changes made since the last take_changes, for the history log
+    changes: Vec<Change>,
 }
 
 impl EstTime for ToDo {
@@ -23,19 +37,22 @@ impl EstTime for ToDo {
     }
 }
 
+impl TrackedTime for ToDo {
+    fn tracked_time(&self) -> u32 {
+        self.tasks.values().map(|task| task.tracked_time()).sum()
+    }
+}
+
 impl CompletionStatus for ToDo {
     fn complete(&self) -> bool {
         self.tasks.values().all(|task| task.complete())
     }
 
     fn completion_status(&self) -> (u32, u32) {
-        (
-            self.tasks
-                .values()
-                .filter(|&task_box| task_box.complete())
-                .count() as u32,
-            self.tasks.values().len() as u32,
-        )
+        self.tasks
+            .values()
+            .map(|task| task.completion_status())
+            .fold((0, 0), |acc, status| (acc.0 + status.0, acc.1 + status.1))
     }
 }
 
@@ -54,6 +71,10 @@ impl ToDo {
             tasks: HashMap::new(),
             categories: HashMap::new(),
             overdue: HashSet::new(),
+            parents: HashMap::new(),
+            dependents: HashMap::new(),
+            tags: HashMap::new(),
+            changes: Vec::new(),
         }
     }
 
@@ -64,28 +85,12 @@ impl ToDo {
                 return Err("Key and value don't match");
             }
         }
-        let categories_list: Vec<Option<String>> = task_map
-            .iter()
-            .map(|(_key, value)| value.get_category())
-            .collect();
-        let mut categories: HashMap<Option<String>, Vec<Uuid>> = categories_list
-            .into_iter()
-            .map(|cat| (cat, vec![]))
-            .collect();
-        for (id, task) in task_map.iter() {
-            categories.get_mut(&task.get_category()).unwrap().push(*id);
+        let mut todo = ToDo::new();
+        for task in task_map.into_iter().map(|pair| pair.1) {
+            // Loading isn't a change
+            todo.insert_task(task);
         }
-
-        let overdue = task_map
-            .iter()
-            .filter(|pair| pair.1.overdue())
-            .map(|pair| pair.1.get_id())
-            .collect();
-        Ok(ToDo {
-            tasks: task_map,
-            categories,
-            overdue,
-        })
+        Ok(todo)
     }
 
     // Can return self, because we know that the id -> task relations will be valid
@@ -100,47 +105,243 @@ impl ToDo {
     }
 
     pub fn add_task(&mut self, task: Task) {
-        self.categories
-            .entry(task.get_category())
-            .or_insert_with(Vec::new);
-        self.categories
-            .get_mut(&task.get_category())
-            .unwrap()
-            .push(task.get_id());
-        if task.get_due_date().is_some() && Local::now() >= task.get_due_date().unwrap() {
-            self.overdue.insert(task.get_id());
+        self.changes.push(Change::Added {
+            task: task.clone(),
+            parent: None,
+        });
+        self.insert_task(task);
+    }
+
+    /// Adds a task underneath an existing one
+    pub fn add_sub_task(&mut self, parent_id: Uuid, task: Task) -> Result<(), &'static str> {
+        if self.get_task(parent_id).is_none() {
+            return Err("No task with that id");
         }
-        self.tasks.insert(task.get_id(), task);
+        self.changes.push(Change::Added {
+            task: task.clone(),
+            parent: Some(parent_id),
+        });
+        self.index_task(&task, Some(parent_id));
+        self.get_task_mut(parent_id).unwrap().add_sub_task(task);
+        Ok(())
     }
 
+    /// Removes a task along with all of its sub-tasks
     pub fn remove_task(&mut self, id: Uuid) -> Result<(), ()> {
-        if !self.tasks.contains_key(&id) {
-            Err(())
-        } else {
-            let category = self.tasks.get(&id).as_ref().unwrap().get_category();
-            let cat_ids = self.categories.get_mut(&category).unwrap();
-            for i in (0..cat_ids.len()).rev() {
-                if cat_ids[i] == id {
-                    cat_ids.remove(i);
+        let parent = self.parents.get(&id).copied();
+        let removed = match parent {
+            Some(parent_id) => self
+                .get_task_mut(parent_id)
+                .and_then(|parent| parent.remove_sub_task(id)),
+            None => self.tasks.remove(&id),
+        };
+        match removed {
+            Some(task) => {
+                // Unindexing drops these links, remember them so the removal can be undone
+                let mut blocking = vec![];
+                for blocker in task.walk() {
+                    if let Some(dependents) = self.dependents.get(&blocker.get_id()) {
+                        blocking.extend(
+                            dependents
+                                .iter()
+                                .map(|dependent| (*dependent, blocker.get_id())),
+                        );
+                    }
                 }
+                self.unindex_task(&task);
+                self.changes.push(Change::Removed {
+                    task,
+                    parent,
+                    blocking,
+                });
+                Ok(())
             }
-            self.overdue.remove(&id);
-            Ok(())
+            None => Err(()),
         }
     }
 
+    /// Swaps a task for a new copy of it with the same id, in the same place in the list.
+    /// Anything that was waiting on it, or on what's under it, still is if that task is still there
+    pub fn replace_task(&mut self, task: Task) -> Result<(), &'static str> {
+        let id = task.get_id();
+        let parent = self.get_parent(id);
+        let before = match self.get_task(id) {
+            Some(old) => old.clone(),
+            None => return Err("No task with that id"),
+        };
+        let blocking: Vec<(Uuid, Uuid)> = before
+            .walk()
+            .into_iter()
+            .flat_map(|blocker| {
+                self.dependents
+                    .get(&blocker.get_id())
+                    .into_iter()
+                    .flatten()
+                    .map(move |dependent| (*dependent, blocker.get_id()))
+            })
+            .collect();
+        self.unindex_task(&before);
+        self.index_task(&task, parent);
+        match parent {
+            Some(parent_id) => {
+                self.get_task_mut(parent_id)
+                    .unwrap()
+                    .replace_sub_task(task)
+                    .unwrap();
+            }
+            None => {
+                self.tasks.insert(id, task);
+            }
+        }
+        for (dependent, blocker) in blocking {
+            let _ = self.add_dependency(dependent, blocker);
+        }
+        let after = self.get_task(id).unwrap().clone();
+        self.changes.push(Change::Replaced { before, after });
+        Ok(())
+    }
+
+    /// Marks `id` as blocked until `blocker` is finished.
+    /// Fails if either task doesn't exist or if the link would create a cycle
+    pub fn add_dependency(&mut self, id: Uuid, blocker: Uuid) -> Result<(), &'static str> {
+        if self.get_task(id).is_none() || self.get_task(blocker).is_none() {
+            return Err("No task with that id");
+        }
+        if id == blocker || self.depends_on(blocker, id) {
+            return Err("Dependency would create a cycle");
+        }
+        self.get_task_mut(id).unwrap().add_blocker(blocker);
+        self.dependents
+            .entry(blocker)
+            .or_insert_with(HashSet::new)
+            .insert(id);
+        Ok(())
+    }
+
+    pub fn remove_dependency(&mut self, id: Uuid, blocker: Uuid) -> Result<(), &'static str> {
+        match self.get_task_mut(id) {
+            Some(task) => {
+                if !task.remove_blocker(blocker) {
+                    return Err("Task isn't blocked by that id");
+                }
+            }
+            None => return Err("No task with that id"),
+        }
+        if let Some(dependents) = self.dependents.get_mut(&blocker) {
+            dependents.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// A task is blocked while any of the tasks it depends on aren't complete
+    pub fn is_blocked(&self, id: Uuid) -> bool {
+        match self.get_task(id) {
+            Some(task) => task
+                .get_blocked_by()
+                .into_iter()
+                .filter_map(|blocker| self.get_task(blocker))
+                .any(|blocker| !blocker.complete()),
+            None => false,
+        }
+    }
+
+    /// The tasks that are waiting on this one
+    pub fn get_dependents(&self, id: Uuid) -> Vec<&Task> {
+        match self.dependents.get(&id) {
+            Some(dependents) => dependents
+                .iter()
+                .map(|id| self.get_task(*id).unwrap())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Every task, ordered so that nothing comes before the tasks blocking it
+    pub fn get_dependency_order(&self) -> Vec<&Task> {
+        let mut tasks = self.get_all_tasks();
+        // Keep the output stable between calls
+        tasks.sort_by_key(|task| task.get_id());
+        let mut waiting_on: HashMap<Uuid, usize> = tasks
+            .iter()
+            .map(|task| {
+                let blockers = task
+                    .get_blocked_by()
+                    .into_iter()
+                    .filter(|blocker| self.get_task(*blocker).is_some())
+                    .count();
+                (task.get_id(), blockers)
+            })
+            .collect();
+        let mut ready: Vec<&Task> = tasks
+            .iter()
+            .filter(|task| waiting_on[&task.get_id()] == 0)
+            .copied()
+            .collect();
+        let mut ordered = Vec::with_capacity(tasks.len());
+        while !ready.is_empty() {
+            let task = ready.remove(0);
+            let mut unblocked: Vec<&Task> = vec![];
+            for dependent in self.get_dependents(task.get_id()) {
+                let count = waiting_on.get_mut(&dependent.get_id()).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    unblocked.push(dependent);
+                }
+            }
+            unblocked.sort_by_key(|task| task.get_id());
+            ready.append(&mut unblocked);
+            ordered.push(task);
+        }
+        ordered
+    }
+
+    /// The unfinished tasks that aren't waiting on anything, in dependency order
+    pub fn get_startable(&self) -> Vec<&Task> {
+        self.get_dependency_order()
+            .into_iter()
+            .filter(|task| {
+                !task.complete()
+                    && !self.is_blocked(task.get_id())
+                    && !self.is_snoozed(task.get_id())
+            })
+            .collect()
+    }
+
     pub fn get_task(&self, id: Uuid) -> Option<&Task> {
-        self.tasks.get(&id)
+        let path = self.get_path(id);
+        let mut task = self.tasks.get(&path[0])?;
+        for sub_id in path[1..].iter() {
+            task = task.get_sub_task(*sub_id)?;
+        }
+        Some(task)
     }
 
     pub fn get_task_mut(&mut self, id: Uuid) -> Option<&mut Task> {
-        self.tasks.get_mut(&id)
+        let path = self.get_path(id);
+        let mut task = self.tasks.get_mut(&path[0])?;
+        for sub_id in path[1..].iter() {
+            task = task.get_sub_task_mut(*sub_id)?;
+        }
+        Some(task)
     }
 
+    /// Returns the id of the task that owns this one, if it is a sub-task
+    pub fn get_parent(&self, id: Uuid) -> Option<Uuid> {
+        self.parents.get(&id).copied()
+    }
+
+    /// Every task in the list, sub-tasks included
     pub fn get_all_tasks(&self) -> Vec<&Task> {
+        self.tasks.values().flat_map(|task| task.walk()).collect()
+    }
+
+    /// Only the top level tasks, sub-tasks can be reached through their parents
+    pub fn get_root_tasks(&self) -> Vec<&Task> {
         self.tasks.values().collect()
     }
 
+    /// The top level tasks, mutably. Unlike `get_all_tasks` sub-tasks aren't listed, since they
+    /// can't be borrowed alongside their parents
     pub fn get_all_tasks_mut(&mut self) -> Vec<&mut Task> {
         self.tasks.values_mut().collect()
     }
@@ -151,7 +352,7 @@ impl ToDo {
             Some(categories) => Some(
                 categories
                     .iter()
-                    .map(|id| self.tasks.get(id).unwrap())
+                    .map(|id| self.get_task(*id).unwrap())
                     .collect(),
             ),
             None => None,
@@ -163,7 +364,7 @@ impl ToDo {
             Some(category) => (
                 category
                     .iter()
-                    .map(|id| self.tasks.get(id).unwrap())
+                    .map(|id| self.get_task(*id).unwrap())
                     .filter(|task| task.complete())
                     .count(),
                 category.len(),
@@ -180,30 +381,120 @@ impl ToDo {
             .collect()
     }
 
+    pub fn get_tags(&self) -> Vec<String> {
+        self.tags
+            .iter()
+            .filter(|pair| !pair.1.is_empty())
+            .map(|pair| pair.0.clone())
+            .collect()
+    }
+
+    /// Get all the tasks with a tag
+    pub fn get_tagged(&self, tag: &str) -> Vec<&Task> {
+        match self.tags.get(tag) {
+            Some(ids) => ids.iter().map(|id| self.get_task(*id).unwrap()).collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_tag_completion(&self, tag: &str) -> (usize, usize) {
+        let tasks = self.get_tagged(tag);
+        (
+            tasks.iter().filter(|task| task.complete()).count(),
+            tasks.len(),
+        )
+    }
+
+    pub fn add_tag(&mut self, id: Uuid, tag: &str) -> Result<(), &'static str> {
+        match self.get_task_mut(id) {
+            Some(task) => {
+                task.add_tag(tag);
+                self.tags
+                    .entry(tag.to_string())
+                    .or_insert_with(HashSet::new)
+                    .insert(id);
+                Ok(())
+            }
+            None => Err("No task with that id"),
+        }
+    }
+
+    pub fn remove_tag(&mut self, id: Uuid, tag: &str) -> Result<(), &'static str> {
+        match self.get_task_mut(id) {
+            Some(task) => {
+                if !task.remove_tag(tag) {
+                    return Err("Task doesn't have that tag");
+                }
+                if let Some(ids) = self.tags.get_mut(tag) {
+                    ids.remove(&id);
+                }
+                Ok(())
+            }
+            None => Err("No task with that id"),
+        }
+    }
+
     pub fn get_ids(&self) -> Vec<Uuid> {
-        self.tasks.keys().copied().collect()
+        self.get_all_tasks()
+            .into_iter()
+            .map(|task| task.get_id())
+            .collect()
     }
 
-    /// Returns the number of tasks currently in the todo list
+    /// Returns the number of tasks currently in the todo list, sub-tasks included
     pub fn num_tasks(&self) -> usize {
-        self.tasks.len()
+        self.tasks.len() + self.parents.len()
     }
 
     pub fn mark_finished(&mut self, id: Uuid, finished: Option<bool>) -> Result<(), ()> {
-        match self.tasks.get_mut(&id) {
+        match self.get_task_mut(id) {
             Some(task) => {
-                task.set_done(match finished {
+                let before = task.is_done();
+                let after = match finished {
                     Some(b) => b,
                     None => false,
-                });
+                };
+                task.set_done(after);
+                self.changes.push(Change::Finished { id, before, after });
                 Ok(())
             }
             None => Err(()),
         }
     }
 
+    /// Hands over the changes made since the last call
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::replace(&mut self.changes, Vec::new())
+    }
+
+    pub fn start_timer(&mut self, id: Uuid) -> Result<(), &'static str> {
+        match self.get_task_mut(id) {
+            Some(task) => {
+                if task.start_timer() {
+                    Ok(())
+                } else {
+                    Err("Timer is already running")
+                }
+            }
+            None => Err("No task with that id"),
+        }
+    }
+
+    pub fn stop_timer(&mut self, id: Uuid) -> Result<(), &'static str> {
+        match self.get_task_mut(id) {
+            Some(task) => {
+                if task.stop_timer() {
+                    Ok(())
+                } else {
+                    Err("Timer isn't running")
+                }
+            }
+            None => Err("No task with that id"),
+        }
+    }
+
     pub fn set_overdue(&mut self, id: Uuid) -> Result<(), &'static str> {
-        match self.tasks.get(&id) {
+        match self.get_task(id) {
             Some(_) => {
                 self.overdue.insert(id);
                 Ok(())
@@ -212,12 +503,264 @@ impl ToDo {
         }
     }
 
+    /// Moves a repeating task on to its next due date, it won't be overdue anymore.
+    /// Returns the new due date, or None if the task doesn't exist or has no more repeats
+    pub fn repeat_task(&mut self, id: Uuid) -> Option<DateTime<Local>> {
+        let task = self.get_task_mut(id)?;
+        if task.repeat() {
+            let due_date = task.get_due_date();
+            self.overdue.remove(&id);
+            due_date
+        } else {
+            None
+        }
+    }
+
+    /// Snoozes a task for `duration`, returning when it shows up again
+    pub fn snooze(
+        &mut self,
+        id: Uuid,
+        duration: Duration,
+    ) -> Result<DateTime<Local>, &'static str> {
+        match self.get_task_mut(id) {
+            Some(task) => task.snooze(duration).ok_or("Can't snooze that far out"),
+            None => Err("No task with that id"),
+        }
+    }
+
+    /// Clears the not before date so the task shows up right away
+    pub fn unsnooze(&mut self, id: Uuid) -> Result<(), &'static str> {
+        match self.get_task_mut(id) {
+            Some(task) => {
+                task.set_not_before(None);
+                Ok(())
+            }
+            None => Err("No task with that id"),
+        }
+    }
+
+    /// A task is hidden while it or anything above it is snoozed
+    pub fn is_snoozed(&self, id: Uuid) -> bool {
+        self.get_path(id)
+            .into_iter()
+            .filter_map(|id| self.get_task(id))
+            .any(|task| task.is_snoozed())
+    }
+
+    /// Drops the snoozed tasks, for the views that should only show what can be acted on
+    pub fn visible<'a>(&self, tasks: Vec<&'a Task>) -> Vec<&'a Task> {
+        tasks
+            .into_iter()
+            .filter(|task| !self.is_snoozed(task.get_id()))
+            .collect()
+    }
+
     pub fn get_overdue(&self) -> Vec<&Task> {
         self.overdue
             .iter()
-            .map(|id| self.tasks.get(id).unwrap())
+            .map(|id| self.get_task(*id).unwrap())
             .collect()
     }
+
+    /// Every task marked finished from `from` up to and including `to`, the earliest first
+    pub fn get_finished_between(&self, from: DateTime<Local>, to: DateTime<Local>) -> Vec<&Task> {
+        let mut finished: Vec<&Task> = self
+            .get_all_tasks()
+            .into_iter()
+            .filter(|task| {
+                task.get_finished_at()
+                    .map_or(false, |at| from <= at && at <= to)
+            })
+            .collect();
+        finished.sort_by_key(|task| task.get_finished_at());
+        finished
+    }
+
+    /// Brings in tasks fetched from a source, keyed by their ids over there.
+    /// Ones that were merged in before are updated in place, keeping their tracked time, Trello link and origin,
+    /// along with their snooze and any tags the source doesn't have. The estimate, sub-tasks and blockers are
+    /// the source's whenever it has any.
+    /// With `listed`, every id the source still has, the tasks from it that aren't in there anymore are removed
+    pub fn merge(
+        &mut self,
+        source: &str,
+        fetched: Vec<(String, Task)>,
+        listed: Option<&[String]>,
+    ) -> Merged {
+        let mut merged = Merged::default();
+        let mut from_source: HashMap<String, Uuid> = self
+            .get_all_tasks()
+            .into_iter()
+            .filter_map(|task| match task.get_origin() {
+                Some(origin) if origin.source == source => Some((origin.id.clone(), task.get_id())),
+                _ => None,
+            })
+            .collect();
+        for (source_id, mut task) in fetched {
+            let existing = from_source
+                .get(&source_id)
+                .and_then(|id| self.get_task(*id))
+                .cloned();
+            match existing {
+                Some(existing) => {
+                    task.set_id(existing.get_id());
+                    task.keep_local(&existing);
+                    // Sub-tasks new to the source could have ids that something else here already has
+                    let own: HashSet<Uuid> =
+                        existing.walk().iter().map(|task| task.get_id()).collect();
+                    task.renew_taken_ids(&|id| !own.contains(&id) && self.get_task(id).is_some());
+                    task.set_initial_date(existing.get_initial_date());
+                    task.set_not_before(existing.get_not_before());
+                    if existing.is_done() {
+                        task.set_finished_at(existing.get_finished_at());
+                    }
+                    if task.get_tags().is_empty() {
+                        task.set_tags(existing.get_tags());
+                    }
+                    if serde_json::to_value(&task).ok() != serde_json::to_value(&existing).ok() {
+                        merged.updated.push(task.get_id());
+                        self.replace_task(task).unwrap();
+                    }
+                }
+                None => {
+                    // Like when a store is merged into the list it was copied from
+                    if task
+                        .walk()
+                        .iter()
+                        .any(|task| self.get_task(task.get_id()).is_some())
+                    {
+                        task.renew_ids();
+                    }
+                    task.set_origin(Some(Origin {
+                        source: source.to_string(),
+                        id: source_id.clone(),
+                    }));
+                    from_source.insert(source_id, task.get_id());
+                    merged.added.push(task.get_id());
+                    self.add_task(task);
+                }
+            }
+        }
+        if let Some(listed) = listed {
+            let listed: HashSet<&String> = listed.iter().collect();
+            for (source_id, id) in from_source {
+                if !listed.contains(&source_id) && self.remove_task(id).is_ok() {
+                    merged.removed.push(id);
+                }
+            }
+        }
+        merged
+    }
+
+    /// Merges in what changed in a source since the cursor, returning the cursor to carry on from next time.
+    /// Tasks it no longer lists are only removed when it's been listed, which is skipped if nothing changed
+    pub fn merge_from(
+        &mut self,
+        source: &mut dyn TaskSource,
+        cursor: Option<&str>,
+    ) -> Result<(Merged, Option<String>), String> {
+        let fetched = source.fetch(cursor)?;
+        let listed = if fetched.cursor.is_some() && fetched.cursor.as_deref() == cursor {
+            None
+        } else {
+            Some(source.list()?)
+        };
+        let merged = self.merge(&source.name(), fetched.tasks, listed.as_deref());
+        Ok((merged, fetched.cursor))
+    }
+
+    // Whether `id` is waiting on `target`, directly or further down the chain
+    fn depends_on(&self, id: Uuid, target: Uuid) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![id];
+        while let Some(cur) = stack.pop() {
+            if cur == target {
+                return true;
+            }
+            if visited.insert(cur) {
+                if let Some(task) = self.get_task(cur) {
+                    stack.extend(task.get_blocked_by());
+                }
+            }
+        }
+        false
+    }
+
+    // Chain of ids from the top level task down to the requested one
+    fn get_path(&self, id: Uuid) -> Vec<Uuid> {
+        let mut path = vec![id];
+        let mut cur = id;
+        while let Some(parent_id) = self.parents.get(&cur) {
+            path.push(*parent_id);
+            cur = *parent_id;
+        }
+        path.reverse();
+        path
+    }
+
+    fn insert_task(&mut self, task: Task) {
+        self.index_task(&task, None);
+        self.tasks.insert(task.get_id(), task);
+    }
+
+    // Adds a task and everything under it to the lookup tables
+    fn index_task(&mut self, task: &Task, parent_id: Option<Uuid>) {
+        self.categories
+            .entry(task.get_category())
+            .or_insert_with(Vec::new)
+            .push(task.get_id());
+        if task.overdue() {
+            self.overdue.insert(task.get_id());
+        }
+        if let Some(parent_id) = parent_id {
+            self.parents.insert(task.get_id(), parent_id);
+        }
+        for blocker in task.get_blocked_by() {
+            self.dependents
+                .entry(blocker)
+                .or_insert_with(HashSet::new)
+                .insert(task.get_id());
+        }
+        for tag in task.get_tags() {
+            self.tags
+                .entry(tag)
+                .or_insert_with(HashSet::new)
+                .insert(task.get_id());
+        }
+        for sub_task in task.get_sub_tasks() {
+            self.index_task(sub_task, Some(task.get_id()));
+        }
+    }
+
+    // Clears a task and everything under it out of the lookup tables
+    fn unindex_task(&mut self, task: &Task) {
+        for task in task.walk() {
+            let id = task.get_id();
+            if let Some(cat_ids) = self.categories.get_mut(&task.get_category()) {
+                cat_ids.retain(|cat_id| *cat_id != id);
+            }
+            self.overdue.remove(&id);
+            self.parents.remove(&id);
+            for tag in task.get_tags() {
+                if let Some(ids) = self.tags.get_mut(&tag) {
+                    ids.remove(&id);
+                }
+            }
+            for blocker in task.get_blocked_by() {
+                if let Some(dependents) = self.dependents.get_mut(&blocker) {
+                    dependents.remove(&id);
+                }
+            }
+            // Nothing can be waiting on a task that's gone
+            if let Some(dependents) = self.dependents.remove(&id) {
+                for dependent in dependents {
+                    if let Some(dependent) = self.get_task_mut(dependent) {
+                        dependent.remove_blocker(id);
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for ToDo {
@@ -227,6 +770,8 @@ impl<'de> Deserialize<'de> for ToDo {
     {
         enum Field {
             Tasks,
+            // Whatever a newer version saved that this one doesn't know about
+            Ignored,
         };
         impl<'de> Deserialize<'de> for Field {
             fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'de>>::Error>
@@ -247,7 +792,7 @@ impl<'de> Deserialize<'de> for ToDo {
                     {
                         match value {
                             "tasks" => Ok(Field::Tasks),
-                            _ => Err(E::unknown_field(value, FIELDS)),
+                            _ => Ok(Field::Ignored),
                         }
                     }
                 }
@@ -286,6 +831,9 @@ impl<'de> Deserialize<'de> for ToDo {
                             }
                             tasks = Some(map.next_value()?);
                         }
+                        Field::Ignored => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
                     }
                 }
                 let tasks = tasks.ok_or_else(|| Error::missing_field("tasks"))?;
@@ -318,7 +866,8 @@ impl Serialize for ToDo {
 
 #[cfg(test)]
 mod test {
-    use crate::backend::{Task, ToDo};
+    use crate::backend::{CompletionStatus, EstTime, History, Task, ToDo, TrackedTime};
+    use chrono::{Duration, Local};
 
     #[test]
     fn test_from_vec() {
@@ -421,4 +970,242 @@ mod test {
         let deserialized: ToDo = serde_json::from_str(ser_str.as_str()).unwrap();
         assert_eq!(deserialized, test_todo)
     }
+
+    #[test]
+    fn unknown_fields() {
+        let deserialized: ToDo = serde_json::from_str(r#"{"tasks": [], "future": 1}"#).unwrap();
+        assert_eq!(deserialized.num_tasks(), 0);
+    }
+
+    #[test]
+    fn sub_task_rollup() {
+        let parent = Task::new("Parent", "Parent", None, 10, None, None, None);
+        let child_a = Task::new("Child1", "Child1", None, 20, None, None, None);
+        let child_b = Task::new("Child2", "Child2", None, 30, None, None, None);
+        let grandchild = Task::new("Grandchild", "Grandchild", None, 40, None, None, None);
+        let parent_id = parent.get_id();
+        let child_a_id = child_a.get_id();
+        let child_b_id = child_b.get_id();
+        let grandchild_id = grandchild.get_id();
+
+        let mut todo = ToDo::new();
+        todo.add_task(parent);
+        todo.add_sub_task(parent_id, child_a).unwrap();
+        todo.add_sub_task(parent_id, child_b).unwrap();
+        todo.add_sub_task(child_b_id, grandchild).unwrap();
+        assert_eq!(todo.num_tasks(), 4);
+        assert_eq!(todo.get_parent(grandchild_id), Some(child_b_id));
+        assert_eq!(todo.get_task(parent_id).unwrap().est_time(), 100);
+        assert_eq!(
+            todo.get_task(parent_id).unwrap().completion_status(),
+            (0, 2)
+        );
+
+        todo.mark_finished(child_a_id, Some(true)).unwrap();
+        todo.mark_finished(grandchild_id, Some(true)).unwrap();
+        assert_eq!(todo.completion_status(), (2, 2));
+        assert!(todo.get_task(parent_id).unwrap().complete());
+
+        todo.remove_task(child_b_id).unwrap();
+        assert_eq!(todo.num_tasks(), 2);
+        assert!(todo.get_task(grandchild_id).is_none());
+        assert_eq!(todo.get_task(parent_id).unwrap().est_time(), 30);
+    }
+
+    #[test]
+    fn sub_task_serialize_deserialize() {
+        let parent = Task::new("Parent", "Parent", None, 0, None, None, None);
+        let child = Task::new("Child", "Child", None, 0, None, None, None);
+        let parent_id = parent.get_id();
+        let child_id = child.get_id();
+        let mut todo = ToDo::new();
+        todo.add_task(parent);
+        todo.add_sub_task(parent_id, child).unwrap();
+
+        let ser_str = serde_json::to_string(&todo).unwrap();
+        let deserialized: ToDo = serde_json::from_str(ser_str.as_str()).unwrap();
+        assert_eq!(deserialized, todo);
+        assert_eq!(deserialized.get_parent(child_id), Some(parent_id));
+        assert_eq!(deserialized.get_all_tasks().len(), 2);
+    }
+
+    #[test]
+    fn dependencies() {
+        let task_a = Task::new("Build", "Build", None, 0, None, None, None);
+        let task_b = Task::new("Test", "Test", None, 0, None, None, None);
+        let task_c = Task::new("Deploy", "Deploy", None, 0, None, None, None);
+        let (a, b, c) = (task_a.get_id(), task_b.get_id(), task_c.get_id());
+        let mut todo = ToDo::from_vec(vec![task_c, task_b, task_a]);
+        todo.add_dependency(b, a).unwrap();
+        todo.add_dependency(c, b).unwrap();
+        assert!(todo.add_dependency(a, c).is_err());
+        assert!(todo.add_dependency(a, a).is_err());
+
+        let order: Vec<_> = todo
+            .get_dependency_order()
+            .iter()
+            .map(|task| task.get_id())
+            .collect();
+        assert_eq!(order, vec![a, b, c]);
+        assert!(todo.is_blocked(b) && todo.is_blocked(c));
+        assert_eq!(todo.get_startable()[0].get_id(), a);
+
+        todo.mark_finished(a, Some(true)).unwrap();
+        assert!(!todo.is_blocked(b));
+        assert_eq!(todo.get_startable()[0].get_id(), b);
+
+        todo.remove_task(b).unwrap();
+        assert!(!todo.is_blocked(c));
+        assert!(todo.get_task(c).unwrap().get_blocked_by().is_empty());
+    }
+
+    #[test]
+    fn replace_task() {
+        let parent = Task::new("Move", "Move", None, 0, None, None, None);
+        let task = Task::new("Pack", "Pack", None, 30, None, None, None);
+        let waiting = Task::new("Drive", "Drive", None, 0, None, None, None);
+        let (parent_id, id, waiting_id) = (parent.get_id(), task.get_id(), waiting.get_id());
+        let sibling = Task::new("Label", "Label", None, 0, None, None, None);
+        let mut todo = ToDo::from_vec(vec![parent, waiting]);
+        todo.add_sub_task(parent_id, task.clone()).unwrap();
+        todo.add_sub_task(parent_id, sibling).unwrap();
+        todo.add_dependency(waiting_id, id).unwrap();
+        let mut history = History::new();
+        history.record(&mut todo);
+
+        let mut new = Task::new("Pack boxes", "", None, 0, None, None, None);
+        new.set_id(id);
+        new.carry_over(&task);
+        todo.replace_task(new).unwrap();
+        assert_eq!(todo.get_task(id).unwrap().get_name(), "Pack boxes");
+        assert_eq!(todo.get_task(id).unwrap().est_time(), 30);
+        assert_eq!(todo.get_parent(id), Some(parent_id));
+        // Still ahead of the sub-task that came after it
+        assert_eq!(
+            todo.get_task(parent_id).unwrap().get_sub_tasks()[0].get_id(),
+            id
+        );
+        assert_eq!(
+            todo.get_task(waiting_id).unwrap().get_blocked_by(),
+            vec![id]
+        );
+        assert!(todo.is_blocked(waiting_id));
+        assert_eq!(todo.num_tasks(), 4);
+
+        // One undo puts the old copy back, rather than only taking the new one out
+        history.record(&mut todo);
+        let recorded = history.get_done().len();
+        history.undo(&mut todo).unwrap();
+        assert_eq!(history.get_done().len(), recorded - 1);
+        assert_eq!(todo.get_task(id).unwrap().get_name(), "Pack");
+        assert_eq!(
+            todo.get_task(parent_id).unwrap().get_sub_tasks()[0].get_id(),
+            id
+        );
+        assert!(todo.is_blocked(waiting_id));
+        assert_eq!(todo.num_tasks(), 4);
+        history.redo(&mut todo).unwrap();
+        assert_eq!(todo.get_task(id).unwrap().get_name(), "Pack boxes");
+
+        let other = Task::new("Other", "", None, 0, None, None, None);
+        assert!(todo.replace_task(other).is_err());
+    }
+
+    #[test]
+    fn finished_between() {
+        let task_a = Task::new("Report", "Report", None, 0, None, None, None);
+        let task_b = Task::new("Review", "Review", None, 0, None, None, None);
+        let (a, b) = (task_a.get_id(), task_b.get_id());
+        let mut todo = ToDo::from_vec(vec![task_a, task_b]);
+        let before = Local::now();
+        todo.mark_finished(a, Some(true)).unwrap();
+        let at = todo.get_task(a).unwrap().get_finished_at().unwrap();
+        assert!(at >= before);
+        // Marking it again keeps when it was first finished
+        todo.mark_finished(a, Some(true)).unwrap();
+        assert_eq!(todo.get_task(a).unwrap().get_finished_at(), Some(at));
+
+        let week = Duration::days(7);
+        assert_eq!(todo.get_finished_between(at - week, at).len(), 1);
+        assert!(todo
+            .get_finished_between(at - week, at - Duration::seconds(1))
+            .is_empty());
+        todo.mark_finished(b, Some(true)).unwrap();
+        let finished: Vec<_> = todo
+            .get_finished_between(at, at + week)
+            .iter()
+            .map(|task| task.get_id())
+            .collect();
+        assert_eq!(finished, vec![a, b]);
+
+        todo.mark_finished(a, Some(false)).unwrap();
+        assert_eq!(todo.get_task(a).unwrap().get_finished_at(), None);
+    }
+
+    #[test]
+    fn time_tracking() {
+        let task = Task::new("Track", "Track", None, 30, None, None, None);
+        let id = task.get_id();
+        let mut todo = ToDo::from_vec(vec![task]);
+        assert!(todo.stop_timer(id).is_err());
+        todo.start_timer(id).unwrap();
+        assert!(todo.start_timer(id).is_err());
+        assert!(todo.get_task(id).unwrap().is_tracking());
+        todo.stop_timer(id).unwrap();
+        assert!(!todo.get_task(id).unwrap().is_tracking());
+        assert_eq!(todo.get_task(id).unwrap().get_sessions().len(), 1);
+        assert_eq!(todo.tracked_time(), 0);
+    }
+
+    #[test]
+    fn tags() {
+        let mut task_a = Task::new("Groceries", "Groceries", None, 0, None, None, None);
+        task_a.set_tags(
+            vec!["home".to_string(), "errands".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let task_b = Task::new("Dishes", "Dishes", None, 0, None, None, None);
+        let (a, b) = (task_a.get_id(), task_b.get_id());
+        let mut todo = ToDo::from_vec(vec![task_a, task_b]);
+        todo.add_tag(b, "home").unwrap();
+        assert_eq!(todo.get_tagged("home").len(), 2);
+        assert_eq!(todo.get_tagged("errands").len(), 1);
+
+        todo.remove_tag(a, "errands").unwrap();
+        assert!(todo.remove_tag(a, "errands").is_err());
+        assert_eq!(todo.get_tags(), vec!["home".to_string()]);
+
+        todo.remove_task(b).unwrap();
+        assert_eq!(todo.get_tag_completion("home"), (0, 1));
+    }
+
+    #[test]
+    fn snooze() {
+        let task = Task::new("Taxes", "Taxes", None, 0, None, None, None);
+        let sub_task = Task::new("Forms", "Forms", None, 0, None, None, None);
+        let (id, sub_id) = (task.get_id(), sub_task.get_id());
+        let mut todo = ToDo::from_vec(vec![task]);
+        todo.add_sub_task(id, sub_task).unwrap();
+        assert_eq!(todo.get_startable().len(), 2);
+
+        let not_before = todo.snooze(id, Duration::hours(1)).unwrap();
+        assert!(todo.is_snoozed(id));
+        // Hiding a task hides everything under it too
+        assert!(todo.is_snoozed(sub_id));
+        assert!(todo.get_startable().is_empty());
+        assert!(todo.visible(todo.get_all_tasks()).is_empty());
+
+        // Snoozing again counts from the current not before date
+        let later = todo.snooze(id, Duration::hours(1)).unwrap();
+        assert_eq!(later, not_before + Duration::hours(1));
+
+        // Going past the last representable date leaves the task as it was
+        assert!(todo.snooze(id, Duration::max_value()).is_err());
+        assert_eq!(todo.get_task(id).unwrap().get_not_before(), Some(later));
+
+        todo.unsnooze(id).unwrap();
+        assert!(!todo.is_snoozed(sub_id));
+        assert_eq!(todo.visible(todo.get_all_tasks()).len(), 2);
+    }
 }
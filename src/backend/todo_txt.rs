@@ -0,0 +1,263 @@
+use crate::backend::quick_add::at_local;
+use crate::backend::{CompletionStatus, Priority, Task, ToDo};
+use chrono::prelude::{NaiveDate, NaiveTime};
+use std::collections::HashSet;
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Reads a whole todo.txt file, a task for every line that isn't blank
+pub fn parse(text: &str) -> Result<Vec<Task>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| parse_line(line).map_err(|e| format!("Line {}: {}", idx + 1, e)))
+        .collect()
+}
+
+/// Turns a todo.txt line like `(A) 2020-10-01 Call mom +family @phone due:2020-10-20` into a task.
+///
+/// - a leading `x` marks it finished, followed by the date it was finished on if there is one
+/// - `(A)` is Extreme priority, `(B)` High, `(C)` Medium and anything lower Low.
+///   Finished tasks keep theirs as `pri:A` instead
+/// - the date before the name is when the task was made
+/// - the first `+project` is the category, any others become tags along with every `@context`
+/// - `due:2020-10-20` is due at the end of that day, `t:2020-10-18` hides it until the start of that day
+///
+/// Everything else makes up the name.
+pub fn parse_line(line: &str) -> Result<Task, &'static str> {
+    let mut words = line.split_whitespace().peekable();
+    let finished = words.peek() == Some(&"x");
+    let mut finished_on = None;
+    if finished {
+        words.next();
+        // Only ever a finished date here, a made date can't be given without one
+        finished_on = words.peek().and_then(|word| parse_date(word));
+        if finished_on.is_some() {
+            words.next();
+        }
+    }
+    let mut priority = words.peek().and_then(|word| parse_priority(word));
+    if priority.is_some() {
+        words.next();
+    }
+    let made = words.peek().and_then(|word| parse_date(word));
+    if made.is_some() {
+        words.next();
+    }
+
+    let mut name = vec![];
+    let mut category = None;
+    let mut tags = HashSet::new();
+    let mut due_date = None;
+    let mut not_before = None;
+    for word in words {
+        if word.len() > 1 && word.starts_with('+') {
+            if category.is_none() {
+                category = Some(word[1..].to_string());
+            } else {
+                tags.insert(word[1..].to_string());
+            }
+        } else if word.len() > 1 && word.starts_with('@') {
+            tags.insert(word[1..].to_string());
+        } else if let Some(due) = word.strip_prefix("due:") {
+            let day = parse_date(due).ok_or("Invalid due date")?;
+            due_date = Some(at_local(day, NaiveTime::from_hms(23, 59, 59))?);
+        } else if let Some(threshold) = word.strip_prefix("t:") {
+            let day = parse_date(threshold).ok_or("Invalid threshold date")?;
+            not_before = Some(at_local(day, NaiveTime::from_hms(0, 0, 0))?);
+        } else if let (Some(letter), true) = (word.strip_prefix("pri:"), finished) {
+            priority = Some(priority_from_letter(letter).ok_or("Invalid priority")?);
+        } else {
+            name.push(word);
+        }
+    }
+    if name.is_empty() {
+        return Err("Task needs a name");
+    }
+
+    let mut task = Task::new(
+        name.join(" ").as_str(),
+        "",
+        due_date,
+        0,
+        priority,
+        None,
+        category,
+    );
+    task.set_tags(tags);
+    task.set_not_before(not_before);
+    task.set_done(finished);
+    // Finished some time nobody knows when without a date
+    task.set_finished_at(
+        finished_on
+            .map(|day| at_local(day, NaiveTime::from_hms(0, 0, 0)))
+            .transpose()?,
+    );
+    if let Some(made) = made {
+        task.set_initial_date(at_local(made, NaiveTime::from_hms(0, 0, 0))?);
+    }
+    Ok(task)
+}
+
+/// Writes out every task, sub-tasks included, in the usual todo.txt order
+pub fn export(todo: &ToDo) -> String {
+    let mut lines: Vec<String> = todo.get_all_tasks().into_iter().map(to_line).collect();
+    // Sorting the lines as they are puts the prioritized tasks first and the finished ones last
+    lines.sort();
+    lines.into_iter().map(|line| line + "\n").collect()
+}
+
+/// A single task as a todo.txt line, the description, estimate and repeats have nowhere to go
+pub fn to_line(task: &Task) -> String {
+    let finished = task.complete();
+    let mut words = vec![];
+    if finished {
+        words.push("x".to_string());
+        // The made date can only follow a finished date
+        if let Some(finished_at) = task.get_finished_at() {
+            words.push(finished_at.format(DATE_FORMAT).to_string());
+            words.push(task.get_initial_date().format(DATE_FORMAT).to_string());
+        }
+    } else {
+        if let Some(priority) = task.get_priority() {
+            words.push(format!("({})", priority_letter(priority)));
+        }
+        words.push(task.get_initial_date().format(DATE_FORMAT).to_string());
+    }
+    words.extend(task.get_name().split_whitespace().map(String::from));
+    if let Some(category) = task.get_category() {
+        words.push(format!("+{}", no_spaces(&category)));
+    }
+    let mut tags: Vec<String> = task.get_tags().into_iter().collect();
+    tags.sort();
+    words.extend(tags.iter().map(|tag| format!("@{}", no_spaces(tag))));
+    if let Some(due_date) = task.get_due_date() {
+        words.push(format!("due:{}", due_date.format(DATE_FORMAT)));
+    }
+    if let Some(not_before) = task.get_not_before() {
+        words.push(format!("t:{}", not_before.format(DATE_FORMAT)));
+    }
+    if let (true, Some(priority)) = (finished, task.get_priority()) {
+        words.push(format!("pri:{}", priority_letter(priority)));
+    }
+    words.join(" ")
+}
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(s, DATE_FORMAT).ok()
+}
+
+// (A) through (Z)
+fn parse_priority(s: &str) -> Option<Priority> {
+    if s.len() == 3 && s.starts_with('(') && s.ends_with(')') {
+        priority_from_letter(&s[1..2])
+    } else {
+        None
+    }
+}
+
+fn priority_from_letter(letter: &str) -> Option<Priority> {
+    match letter {
+        "A" => Some(Priority::Extreme),
+        "B" => Some(Priority::High),
+        "C" => Some(Priority::Medium),
+        _ if letter.len() == 1 && letter.chars().all(|c| c.is_ascii_uppercase()) => {
+            Some(Priority::Low)
+        }
+        _ => None,
+    }
+}
+
+fn priority_letter(priority: Priority) -> char {
+    match priority {
+        Priority::Extreme => 'A',
+        Priority::High => 'B',
+        Priority::Medium => 'C',
+        Priority::Low => 'D',
+    }
+}
+
+// Projects and contexts end at the first space
+fn no_spaces(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<&str>>().join("_")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export, parse, parse_line, to_line};
+    use crate::backend::{CompletionStatus, Priority, Task, ToDo};
+    use chrono::prelude::{Local, TimeZone};
+
+    #[test]
+    fn todo_txt() {
+        let task =
+            parse_line("(A) 2020-10-01 Call mom +family +phone @home due:2020-10-20").unwrap();
+        assert_eq!(task.get_name(), "Call mom");
+        assert_eq!(task.get_priority(), Some(Priority::Extreme));
+        assert_eq!(task.get_category(), Some("family".to_string()));
+        assert!(task.has_tag("phone") && task.has_tag("home"));
+        assert_eq!(
+            task.get_due_date(),
+            Some(Local.ymd(2020, 10, 20).and_hms(23, 59, 59))
+        );
+        assert_eq!(
+            task.get_initial_date(),
+            Local.ymd(2020, 10, 1).and_hms(0, 0, 0)
+        );
+        assert!(!task.complete());
+        // Writing it back out gives the same line
+        assert_eq!(
+            to_line(&task),
+            "(A) 2020-10-01 Call mom +family @home @phone due:2020-10-20"
+        );
+
+        let task = parse_line("x 2020-10-14 Pay rent url:http://bank pri:E t:2020-10-10").unwrap();
+        assert!(task.complete());
+        assert_eq!(
+            task.get_finished_at(),
+            Some(Local.ymd(2020, 10, 14).and_hms(0, 0, 0))
+        );
+        assert_eq!(task.get_name(), "Pay rent url:http://bank");
+        assert_eq!(task.get_priority(), Some(Priority::Low));
+        assert_eq!(
+            task.get_not_before(),
+            Some(Local.ymd(2020, 10, 10).and_hms(0, 0, 0))
+        );
+        assert!(to_line(&task).starts_with("x 2020-10-14 "));
+        assert!(to_line(&task).ends_with(" Pay rent url:http://bank t:2020-10-10 pri:D"));
+        let task = parse_line("x Pay rent").unwrap();
+        assert_eq!(to_line(&task), "x Pay rent");
+
+        let mut todo = ToDo::new();
+        let parent = Task::new("Parent", "", None, 0, Some(Priority::High), None, None);
+        let parent_id = parent.get_id();
+        todo.add_task(parent);
+        todo.add_sub_task(parent_id, Task::new("Child", "", None, 0, None, None, None))
+            .unwrap();
+        todo.add_task(Task::new("Done", "", None, 0, None, None, None));
+        let done_id = todo
+            .get_root_tasks()
+            .iter()
+            .find(|t| t.get_name() == "Done")
+            .unwrap()
+            .get_id();
+        todo.mark_finished(done_id, Some(true)).unwrap();
+        let text = export(&todo);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("(B)"));
+        assert!(lines[2].starts_with("x ") && lines[2].ends_with(" Done"));
+        assert_eq!(parse(&text).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn todo_txt_errors() {
+        assert!(parse_line("(A) 2020-10-01 +family").is_err());
+        assert!(parse_line("Call mom due:tomorrow").is_err());
+        assert!(parse_line("x pri:AA Something").is_err());
+        assert_eq!(
+            parse("Fine\n\nCall mom due:someday").unwrap_err(),
+            "Line 3: Invalid due date"
+        );
+    }
+}
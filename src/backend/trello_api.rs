@@ -1,19 +1,66 @@
 use chrono::prelude::{DateTime, Utc};
 use reqwest::blocking::Client;
-use serde::de::{Deserialize, Deserializer, Error, MapAccess, SeqAccess, Visitor};
+use reqwest::{Method, StatusCode};
+use serde::de::{
+    Deserialize, DeserializeOwned, Deserializer, Error, MapAccess, SeqAccess, Visitor,
+};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use serde::Deserialize as DeserializeMacro;
 use serde::Serialize as SerializeMacro;
 use std::env;
 use std::fmt;
 
-#[derive(Default, Clone)]
+const BASE_URL: &str = "https://api.trello.com/1";
+
+#[derive(Clone)]
 pub struct TrelloApi {
     key: Option<String>,
     token: Option<String>,
+    base_url: String,
     client: Client,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum TrelloError {
+    // No key or token, or Trello turned them down
+    Auth,
+    // Seconds to wait before trying again, if Trello said
+    RateLimited(Option<u64>),
+    NotFound,
+    // Trello answered with something that isn't what was asked for
+    Decode(String),
+    // Couldn't get an answer at all, or got some other error status
+    Http(String),
+}
+
+impl fmt::Display for TrelloError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrelloError::Auth => write!(f, "Trello didn't accept the key and token"),
+            TrelloError::RateLimited(Some(seconds)) => {
+                write!(f, "Rate limited by Trello, try again in {}s", seconds)
+            }
+            TrelloError::RateLimited(None) => write!(f, "Rate limited by Trello"),
+            TrelloError::NotFound => write!(f, "Not found on Trello"),
+            TrelloError::Decode(err) => write!(f, "Couldn't read Trello's response: {}", err),
+            TrelloError::Http(err) => write!(f, "Trello request failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TrelloError {}
+
+/// What to change on a card, None leaves it as it is
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CardUpdate {
+    pub name: Option<String>,
+    // Some(None) takes the due date off
+    pub due: Option<Option<DateTime<Utc>>>,
+    pub due_complete: Option<bool>,
+    pub list_id: Option<String>,
+    pub closed: Option<bool>,
+}
+
 pub trait ID {
     fn get_id(&self) -> &str;
 }
@@ -27,12 +74,15 @@ pub struct Board {
     desc: String,
 }
 
-#[derive(SerializeMacro, DeserializeMacro, Debug)]
+// Webhook actions only have the id and name
+#[derive(SerializeMacro, DeserializeMacro, Debug, Clone)]
 #[allow(non_snake_case)]
 pub struct List {
     id: String,
     name: String,
+    #[serde(default)]
     closed: bool,
+    #[serde(default)]
     idBoard: String,
 }
 
@@ -43,6 +93,7 @@ pub struct Card {
     name: String,
     due: Option<DateTime<Utc>>,
     dueComplete: bool,
+    idList: String,
     badges: Badge,
     pub idChecklists: Vec<String>,
 }
@@ -75,10 +126,72 @@ pub struct CheckItem {
     name: String,
 }
 
+impl List {
+    pub fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
 impl Card {
     pub fn get_checklist_id(&self, idx: usize) -> &str {
         self.idChecklists[idx].as_str()
     }
+
+    pub fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn get_due(&self) -> Option<DateTime<Utc>> {
+        self.due
+    }
+
+    pub fn is_due_complete(&self) -> bool {
+        self.dueComplete
+    }
+
+    /// The list the card is in
+    pub fn get_list_id(&self) -> &str {
+        self.idList.as_str()
+    }
+
+    pub fn get_badges(&self) -> &Badge {
+        &self.badges
+    }
+}
+
+impl Badge {
+    /// Items on all of the card's checklists
+    pub fn get_check_items(&self) -> i32 {
+        self.checkItems
+    }
+
+    pub fn get_check_items_checked(&self) -> i32 {
+        self.checkItemsChecked
+    }
+}
+
+impl Checklist {
+    pub fn get_check_items(&self) -> &[CheckItem] {
+        &self.checkItems
+    }
+}
+
+impl CheckItem {
+    pub fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state
+    }
+
+    pub fn get_checklist_id(&self) -> &str {
+        self.idChecklist.as_str()
+    }
 }
 
 impl ID for Board {
@@ -111,123 +224,277 @@ impl ID for CheckItem {
     }
 }
 
+impl Default for TrelloApi {
+    fn default() -> Self {
+        TrelloApi {
+            key: None,
+            token: None,
+            base_url: BASE_URL.to_string(),
+            client: Client::new(),
+        }
+    }
+}
+
 impl TrelloApi {
+    /// Takes the key and token from TRELLO_KEY and TRELLO_TOKEN
     pub fn new() -> Self {
-        let new_key: Option<String>;
-        let new_token: Option<String>;
-        match env::var("TRELLO_KEY") {
-            Ok(val) => {
-                new_key = Some(val);
-            }
+        let var = |name: &str| match env::var(name) {
+            Ok(val) => Some(val),
             Err(err) => {
-                new_key = None;
-                eprintln!(
-                    "Error TRELLO_KEY {:?}\nSet environment variable with token",
-                    err
-                );
-            }
-        }
-        match env::var("TRELLO_TOKEN") {
-            Ok(val) => {
-                new_token = Some(val);
-            }
-            Err(err) => {
-                new_token = None;
-                eprintln!(
-                    "Error TRELLO_TOKEN {:?}\nSet environment variable with token",
-                    err
-                );
+                warn!("No Trello credentials in {}: {}", name, err);
+                None
             }
+        };
+        TrelloApi {
+            key: var("TRELLO_KEY"),
+            token: var("TRELLO_TOKEN"),
+            ..TrelloApi::default()
         }
+    }
+
+    pub fn with_credentials(key: &str, token: &str) -> Self {
         TrelloApi {
-            key: new_key,
-            token: new_token,
-            client: Client::new(),
+            key: Some(key.to_string()),
+            token: Some(token.to_string()),
+            ..TrelloApi::default()
         }
     }
 
+    /// Talks to somewhere other than api.trello.com, like a mock server in tests
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
     pub fn is_valid(&self) -> bool {
         self.key.is_some() && self.token.is_some()
     }
 
-    pub fn get_boards(&self) -> Option<Vec<Board>> {
-        let request_uri: String = format!(
-            "https://api.trello.com/1/members/me/boards?\
-        &key={key}&token={token}",
-            key = self.key.as_ref().unwrap(),
-            token = &self.token.as_ref().unwrap()
-        );
+    pub fn get_boards(&self) -> Result<Vec<Board>, TrelloError> {
+        self.request(Method::GET, "/members/me/boards", &[])
+    }
 
-        let resp = self.client.get(&request_uri).send().unwrap();
-        let code = resp.status();
-        let resp = resp.text().unwrap();
-        match code {
-            reqwest::StatusCode::OK => {
-                let boards: Vec<Board> = serde_json::from_str(resp.as_str()).unwrap();
-                Some(boards)
-            }
-            _ => None,
+    /// When anything on the board last changed, as Trello writes it
+    pub fn get_last_activity(&self, board_id: &str) -> Result<String, TrelloError> {
+        #[derive(DeserializeMacro)]
+        #[allow(non_snake_case)]
+        struct Activity {
+            dateLastActivity: Option<String>,
         }
+        let activity: Activity = self.request(
+            Method::GET,
+            &format!("/boards/{}", board_id),
+            &[("fields", "dateLastActivity".to_string())],
+        )?;
+        Ok(activity.dateLastActivity.unwrap_or_default())
     }
 
-    pub fn get_lists(&self, board_id: &str) -> Option<Vec<List>> {
-        let request_uri: String = format!(
-            "https://api.trello.com/1/boards/{id}/lists?key={key}&token={token}",
-            id = board_id,
-            key = self.key.as_ref().unwrap(),
-            token = self.token.as_ref().unwrap()
-        );
+    pub fn get_lists(&self, board_id: &str) -> Result<Vec<List>, TrelloError> {
+        self.request(Method::GET, &format!("/boards/{}/lists", board_id), &[])
+    }
 
-        let resp = self.client.get(&request_uri).send().unwrap();
-        let code = resp.status();
-        let resp = resp.text().unwrap();
-        match code {
-            reqwest::StatusCode::OK => {
-                let lists: Vec<List> = serde_json::from_str(resp.as_str()).unwrap();
-                Some(lists)
-            }
-            _ => None,
+    pub fn get_cards(&self, list_id: &str) -> Result<Vec<Card>, TrelloError> {
+        self.request(Method::GET, &format!("/lists/{}/cards", list_id), &[])
+    }
+
+    pub fn get_checklists(&self, card_id: &str) -> Result<Vec<Checklist>, TrelloError> {
+        self.request(Method::GET, &format!("/cards/{}/checklists", card_id), &[])
+    }
+
+    pub fn create_card(
+        &self,
+        list_id: &str,
+        name: &str,
+        due: Option<DateTime<Utc>>,
+        due_complete: bool,
+    ) -> Result<Card, TrelloError> {
+        let mut params = vec![
+            ("idList", list_id.to_string()),
+            ("name", name.to_string()),
+            ("dueComplete", due_complete.to_string()),
+        ];
+        if let Some(due) = due {
+            params.push(("due", due.to_rfc3339()));
         }
+        self.request(Method::POST, "/cards", &params)
     }
 
-    pub fn get_cards(&self, list_id: &str) -> Option<Vec<Card>> {
-        let request_uri: String = format!(
-            "https://api.trello.com/1/lists/{id}/cards?key={key}&token={token}",
-            id = list_id,
-            key = self.key.as_ref().unwrap(),
-            token = self.token.as_ref().unwrap()
-        );
+    pub fn update_card(&self, card_id: &str, update: &CardUpdate) -> Result<Card, TrelloError> {
+        let mut params = vec![];
+        if let Some(name) = &update.name {
+            params.push(("name", name.clone()));
+        }
+        if let Some(due) = update.due {
+            params.push((
+                "due",
+                due.map(|due| due.to_rfc3339())
+                    .unwrap_or_else(|| "null".to_string()),
+            ));
+        }
+        if let Some(due_complete) = update.due_complete {
+            params.push(("dueComplete", due_complete.to_string()));
+        }
+        if let Some(list_id) = &update.list_id {
+            params.push(("idList", list_id.clone()));
+        }
+        if let Some(closed) = update.closed {
+            params.push(("closed", closed.to_string()));
+        }
+        self.request(Method::PUT, &format!("/cards/{}", card_id), &params)
+    }
 
-        let resp = self.client.get(&request_uri).send().unwrap();
-        let code = resp.status();
-        let resp = resp.text().unwrap();
-        match code {
-            reqwest::StatusCode::OK => {
-                let cards: Vec<Card> = serde_json::from_str(resp.as_str()).unwrap();
-                Some(cards)
-            }
-            _ => None,
+    pub fn set_due_complete(&self, card_id: &str, due_complete: bool) -> Result<Card, TrelloError> {
+        self.update_card(
+            card_id,
+            &CardUpdate {
+                due_complete: Some(due_complete),
+                ..CardUpdate::default()
+            },
+        )
+    }
+
+    pub fn move_card(&self, card_id: &str, list_id: &str) -> Result<Card, TrelloError> {
+        self.update_card(
+            card_id,
+            &CardUpdate {
+                list_id: Some(list_id.to_string()),
+                ..CardUpdate::default()
+            },
+        )
+    }
+
+    /// Closes the card, Trello keeps it around in the archive
+    pub fn archive_card(&self, card_id: &str) -> Result<Card, TrelloError> {
+        self.update_card(
+            card_id,
+            &CardUpdate {
+                closed: Some(true),
+                ..CardUpdate::default()
+            },
+        )
+    }
+
+    pub fn create_checklist(&self, card_id: &str, name: &str) -> Result<Checklist, TrelloError> {
+        self.request(
+            Method::POST,
+            &format!("/cards/{}/checklists", card_id),
+            &[("name", name.to_string())],
+        )
+    }
+
+    pub fn create_check_item(
+        &self,
+        checklist_id: &str,
+        name: &str,
+        complete: bool,
+    ) -> Result<CheckItem, TrelloError> {
+        self.request(
+            Method::POST,
+            &format!("/checklists/{}/checkItems", checklist_id),
+            &[
+                ("name", name.to_string()),
+                ("checked", complete.to_string()),
+            ],
+        )
+    }
+
+    /// Renames the item if there's a name, and checks or unchecks it if there's a state
+    pub fn update_check_item(
+        &self,
+        card_id: &str,
+        item_id: &str,
+        name: Option<&str>,
+        complete: Option<bool>,
+    ) -> Result<CheckItem, TrelloError> {
+        let mut params = vec![];
+        if let Some(name) = name {
+            params.push(("name", name.to_string()));
+        }
+        if let Some(complete) = complete {
+            params.push((
+                "state",
+                if complete { "complete" } else { "incomplete" }.to_string(),
+            ));
         }
+        self.request(
+            Method::PUT,
+            &format!("/cards/{}/checkItem/{}", card_id, item_id),
+            &params,
+        )
     }
 
-    pub fn get_checklists(&self, list_id: &str) -> Option<Vec<Checklist>> {
-        let request_uri: String = format!(
-            "https://api.trello.com/1/cards/{id}/checklists?key={key}&token={token}",
-            id = list_id,
-            key = self.key.as_ref().unwrap(),
-            token = self.token.as_ref().unwrap()
-        );
+    pub fn set_check_item_state(
+        &self,
+        card_id: &str,
+        item_id: &str,
+        complete: bool,
+    ) -> Result<CheckItem, TrelloError> {
+        self.update_check_item(card_id, item_id, None, Some(complete))
+    }
+
+    pub fn delete_check_item(&self, card_id: &str, item_id: &str) -> Result<(), TrelloError> {
+        self.request::<serde_json::Value>(
+            Method::DELETE,
+            &format!("/cards/{}/checkItem/{}", card_id, item_id),
+            &[],
+        )
+        .map(|_| ())
+    }
 
-        let resp = self.client.get(&request_uri).send().unwrap();
+    /// Has Trello POST every change to the board, or anything else with an id, to the callback url.
+    /// Trello checks the url answers a HEAD first, and errors if the webhook's already there
+    pub fn create_webhook(&self, callback_url: &str, model_id: &str) -> Result<(), TrelloError> {
+        self.request::<serde_json::Value>(
+            Method::POST,
+            "/webhooks",
+            &[
+                ("callbackURL", callback_url.to_string()),
+                ("idModel", model_id.to_string()),
+                ("description", "Desktopper".to_string()),
+            ],
+        )
+        .map(|_| ())
+    }
+
+    // Everything goes through here, the parameters are sent in the query string like Trello's docs do it
+    fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        params: &[(&str, String)],
+    ) -> Result<T, TrelloError> {
+        let (key, token) = match (&self.key, &self.token) {
+            (Some(key), Some(token)) => (key, token),
+            _ => return Err(TrelloError::Auth),
+        };
+        let resp = self
+            .client
+            .request(method, &format!("{}{}", self.base_url, path))
+            .query(&[("key", key), ("token", token)])
+            .query(params)
+            .send()
+            .map_err(|err| TrelloError::Http(err.to_string()))?;
         let code = resp.status();
-        let resp = resp.text().unwrap();
         match code {
-            reqwest::StatusCode::OK => {
-                let checklists: Vec<Checklist> = serde_json::from_str(resp.as_str()).unwrap();
-                Some(checklists)
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => return Err(TrelloError::Auth),
+            StatusCode::NOT_FOUND => return Err(TrelloError::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = resp
+                    .headers()
+                    .get("retry-after")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok());
+                return Err(TrelloError::RateLimited(retry_after));
             }
-            _ => None,
+            _ => {}
+        }
+        let resp = resp
+            .text()
+            .map_err(|err| TrelloError::Http(err.to_string()))?;
+        if !code.is_success() {
+            return Err(TrelloError::Http(format!("{} {}", code, resp)));
         }
+        serde_json::from_str(resp.as_str()).map_err(|err| TrelloError::Decode(err.to_string()))
     }
 }
 
@@ -384,8 +651,118 @@ impl Serialize for CheckItem {
 
 #[cfg(test)]
 mod test {
-    use super::{CheckItem, TrelloApi, ID};
+    use super::{CheckItem, TrelloApi, TrelloError, ID};
     use serde_json;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Answers a request with each of the canned statuses and bodies in turn,
+    // then hands back the request lines it got
+    fn mock(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/1", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|(status, body)| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request = String::new();
+                    reader.read_line(&mut request).unwrap();
+                    // Nothing sends a body, so the request's over after the headers
+                    let mut header = String::new();
+                    while reader.read_line(&mut header).unwrap() > 2 {
+                        header.clear();
+                    }
+                    write!(
+                        stream,
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    )
+                    .unwrap();
+                    request.trim_end().to_string()
+                })
+                .collect()
+        });
+        (url, server)
+    }
+
+    fn mock_api(url: &str) -> TrelloApi {
+        TrelloApi::with_credentials("k", "t").with_base_url(url)
+    }
+
+    #[test]
+    fn write_requests() {
+        let card = r#"{"id": "c1", "name": "Mail letter", "due": null, "dueComplete": false, "idList": "l2",
+            "badges": {"due": null, "dueComplete": false, "checkItems": 0, "checkItemsChecked": 0},
+            "idChecklists": []}"#;
+        let item = r#"{"id": "i1", "name": "Stamp", "idChecklist": "k1", "state": "complete"}"#;
+        let (url, server) = mock(vec![
+            ("200 OK", card),
+            ("200 OK", card),
+            ("200 OK", item),
+            ("200 OK", "{}"),
+        ]);
+        let api = mock_api(&url);
+        let created = api.create_card("l1", "Mail letter", None, false).unwrap();
+        assert_eq!(created.get_id(), "c1");
+        assert_eq!(api.move_card("c1", "l2").unwrap().get_list_id(), "l2");
+        assert!(api
+            .set_check_item_state("c1", "i1", true)
+            .unwrap()
+            .is_complete());
+        api.delete_check_item("c1", "i1").unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests[0],
+            "POST /1/cards?key=k&token=t&idList=l1&name=Mail+letter&dueComplete=false HTTP/1.1"
+        );
+        assert_eq!(
+            requests[1],
+            "PUT /1/cards/c1?key=k&token=t&idList=l2 HTTP/1.1"
+        );
+        assert_eq!(
+            requests[2],
+            "PUT /1/cards/c1/checkItem/i1?key=k&token=t&state=complete HTTP/1.1"
+        );
+        assert_eq!(
+            requests[3],
+            "DELETE /1/cards/c1/checkItem/i1?key=k&token=t HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let (url, server) = mock(vec![
+            ("401 Unauthorized", "invalid token"),
+            ("404 Not Found", "model not found"),
+            ("429 Too Many Requests\r\nRetry-After: 10", "{}"),
+            ("200 OK", r#"[{"id": 3}]"#),
+            ("500 Internal Server Error", "oops"),
+        ]);
+        let api = mock_api(&url);
+        assert_eq!(api.get_boards().unwrap_err(), TrelloError::Auth);
+        assert_eq!(api.get_lists("b1").unwrap_err(), TrelloError::NotFound);
+        assert_eq!(
+            api.get_cards("l1").unwrap_err(),
+            TrelloError::RateLimited(Some(10))
+        );
+        assert!(matches!(api.get_cards("l1"), Err(TrelloError::Decode(_))));
+        assert!(matches!(api.archive_card("c1"), Err(TrelloError::Http(_))));
+        server.join().unwrap();
+
+        // Nothing is sent without a key and token
+        assert_eq!(
+            TrelloApi::default().get_boards().unwrap_err(),
+            TrelloError::Auth
+        );
+    }
 
     #[test]
     fn check_item_complete_deserialization() {
@@ -503,8 +880,8 @@ mod test {
         }
 
         let boards = match api.get_boards() {
-            Some(boards) => boards,
-            None => {
+            Ok(boards) => boards,
+            Err(_) => {
                 assert!(false);
                 return;
             }
@@ -512,8 +889,8 @@ mod test {
 
         let board_id = boards[10].get_id();
         let lists = match api.get_lists(board_id) {
-            Some(lists) => lists,
-            None => {
+            Ok(lists) => lists,
+            Err(_) => {
                 assert!(false);
                 return;
             }
@@ -521,8 +898,8 @@ mod test {
 
         let list_id = lists[0].get_id();
         let cards = match api.get_cards(list_id) {
-            Some(cards) => cards,
-            None => {
+            Ok(cards) => cards,
+            Err(_) => {
                 assert!(false);
                 return;
             }
@@ -530,8 +907,8 @@ mod test {
 
         let card_id = cards[0].get_id();
         match api.get_checklists(card_id) {
-            Some(_) => assert!(true),
-            None => {
+            Ok(_) => assert!(true),
+            Err(_) => {
                 assert!(false);
                 return;
             }
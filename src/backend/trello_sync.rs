@@ -0,0 +1,1431 @@
+use crate::backend::trello_api::{
+    Card, CardUpdate, CheckItem, Checklist, List, TrelloApi, TrelloError, ID,
+};
+use crate::backend::trello_webhook::Event;
+use crate::backend::{Task, ToDo};
+use chrono::prelude::{DateTime, Local, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// A board's open lists, their cards and the cards' checklists, as they were when it was fetched
+pub struct BoardSnapshot {
+    pub lists: Vec<List>,
+    pub cards: Vec<CardSnapshot>,
+}
+
+pub struct CardSnapshot {
+    pub card: Card,
+    pub checklists: Vec<Checklist>,
+}
+
+impl BoardSnapshot {
+    pub fn fetch(api: &TrelloApi, board_id: &str) -> Result<Self, TrelloError> {
+        let lists: Vec<List> = api
+            .get_lists(board_id)?
+            .into_iter()
+            .filter(|list| !list.is_closed())
+            .collect();
+        let mut cards = vec![];
+        for list in lists.iter() {
+            for card in api.get_cards(list.get_id())? {
+                let checklists = if card.idChecklists.is_empty() {
+                    vec![]
+                } else {
+                    api.get_checklists(card.get_id())?
+                };
+                cards.push(CardSnapshot { card, checklists });
+            }
+        }
+        Ok(BoardSnapshot { lists, cards })
+    }
+}
+
+/// What a card or checklist item looked like the last time both sides agreed on it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Synced {
+    name: String,
+    due: Option<DateTime<Utc>>,
+    done: bool,
+    // The list a card is in, always None for checklist items
+    list: Option<String>,
+}
+
+impl Synced {
+    fn card(card: &Card) -> Self {
+        Synced {
+            name: card.get_name().to_string(),
+            due: card.get_due().map(whole_seconds),
+            done: card.is_due_complete(),
+            list: Some(card.get_list_id().to_string()),
+        }
+    }
+
+    fn item(item: &CheckItem) -> Self {
+        Synced {
+            name: item.get_name().to_string(),
+            due: None,
+            done: item.is_complete(),
+            list: None,
+        }
+    }
+
+    fn task(task: &Task, list: Option<String>) -> Self {
+        Synced {
+            name: task.get_name(),
+            due: task
+                .get_due_date()
+                .map(|due| whole_seconds(due.with_timezone(&Utc))),
+            done: task.is_done(),
+            list,
+        }
+    }
+
+    fn update(&mut self, fields: &Fields) {
+        if let Some(name) = &fields.name {
+            self.name = name.clone();
+        }
+        if let Some(due) = fields.due {
+            self.due = due;
+        }
+        if let Some(done) = fields.done {
+            self.done = done;
+        }
+        if let Some(list) = &fields.list {
+            self.list = Some(list.clone());
+        }
+    }
+}
+
+// Trello only keeps milliseconds, and nobody sets a due date that precisely anyways
+fn whole_seconds(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.with_nanosecond(0).unwrap()
+}
+
+/// The parts of a card or checklist item that changed, None where they didn't
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fields {
+    pub name: Option<String>,
+    pub due: Option<Option<DateTime<Utc>>>,
+    pub done: Option<bool>,
+    pub list: Option<String>,
+}
+
+impl Fields {
+    pub fn is_empty(&self) -> bool {
+        *self == Fields::default()
+    }
+}
+
+/// Something that has to be done on Trello to catch it up with the ToDo.
+/// Once it has been, tell the sync with `TrelloSync::pushed`
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteChange {
+    CreateCard {
+        task: Uuid,
+        list: String,
+        name: String,
+        due: Option<DateTime<Utc>>,
+        done: bool,
+    },
+    UpdateCard {
+        card: String,
+        fields: Fields,
+    },
+    ArchiveCard {
+        card: String,
+    },
+    // The checklist is None when the card doesn't have one yet
+    CreateCheckItem {
+        task: Uuid,
+        card: String,
+        checklist: Option<String>,
+        name: String,
+        done: bool,
+    },
+    UpdateCheckItem {
+        card: String,
+        item: String,
+        fields: Fields,
+    },
+    DeleteCheckItem {
+        card: String,
+        item: String,
+    },
+}
+
+/// Both sides changed the same thing differently since the last sync.
+/// Neither side's copy is touched until they agree again.
+/// A card or item that was removed from Trello but changed here shows up as the `deleted` field,
+/// the task is unlinked and goes back up as a new one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub task: Uuid,
+    pub trello_id: String,
+    pub field: &'static str,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub remote: Vec<RemoteChange>,
+    pub conflicts: Vec<Conflict>,
+    // The tasks that were added, changed or removed here
+    pub local: Vec<Uuid>,
+}
+
+/// What the sync remembers between runs, save it alongside the ToDo
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncState {
+    synced: HashMap<String, Synced>,
+}
+
+/// Keeps a ToDo and a Trello board in step.
+/// Lists are categories, cards are tasks and the items on a card's checklists are its sub-tasks
+pub struct TrelloSync {
+    board_id: String,
+    // List name or id -> category, lists that aren't in here use their name
+    categories: HashMap<String, String>,
+    state: SyncState,
+    // As of the last sync
+    lists: Vec<List>,
+    events: u64,
+}
+
+impl TrelloSync {
+    pub fn new(board_id: &str, categories: HashMap<String, String>, state: SyncState) -> Self {
+        TrelloSync {
+            board_id: board_id.to_string(),
+            categories,
+            state,
+            lists: vec![],
+            events: 0,
+        }
+    }
+
+    pub fn get_board_id(&self) -> &str {
+        self.board_id.as_str()
+    }
+
+    pub fn get_state(&self) -> &SyncState {
+        &self.state
+    }
+
+    /// How many webhook events have been applied, a board fetched before the count last went up is out of date
+    pub fn get_events(&self) -> u64 {
+        self.events
+    }
+
+    /// The category a list's cards go into
+    pub fn category(&self, list: &List) -> String {
+        self.categories
+            .get(list.get_id())
+            .or_else(|| self.categories.get(list.get_name()))
+            .cloned()
+            .unwrap_or_else(|| list.get_name().to_string())
+    }
+
+    // The list a card in the category belongs in. Several lists can share a category,
+    // so a card already in one of them stays there rather than going back to the first
+    fn list_for(
+        &self,
+        category: Option<String>,
+        lists: &[List],
+        current: Option<&str>,
+    ) -> Option<String> {
+        let category = category?;
+        let matching: Vec<&List> = lists
+            .iter()
+            .filter(|list| self.category(list) == category)
+            .collect();
+        matching
+            .iter()
+            .find(|list| Some(list.get_id()) == current)
+            .or_else(|| matching.first())
+            .map(|list| list.get_id().to_string())
+    }
+
+    // The list a card was in when both sides last agreed on it
+    fn synced_list(&self, trello_id: &str) -> Option<&str> {
+        self.state.synced.get(trello_id)?.list.as_deref()
+    }
+
+    /// Brings what changed on the board since the last sync into the ToDo,
+    /// and works out what has to go the other way.
+    /// New tasks here only go up to Trello if their category is one of the board's lists
+    pub fn sync(
+        &mut self,
+        todo: &mut ToDo,
+        board: &BoardSnapshot,
+    ) -> Result<SyncReport, &'static str> {
+        self.lists = board.lists.clone();
+        let mut report = SyncReport::default();
+        let mut linked: HashMap<String, Uuid> = todo
+            .get_all_tasks()
+            .into_iter()
+            .filter_map(|task| Some((task.get_trello_id()?.to_string(), task.get_id())))
+            .collect();
+        let mut seen = HashSet::new();
+
+        for snapshot in board.cards.iter() {
+            let card = &snapshot.card;
+            let card_id = card.get_id().to_string();
+            seen.insert(card_id.clone());
+            let remote = Synced::card(card);
+            let task_id = match linked.get(&card_id).copied() {
+                Some(task_id) => {
+                    let category = todo.get_task(task_id).unwrap().get_category();
+                    let current = self
+                        .synced_list(&card_id)
+                        .or_else(|| Some(card.get_list_id()));
+                    let list = self.list_for(category, &board.lists, current);
+                    let local = Synced::task(todo.get_task(task_id).unwrap(), list);
+                    let fields = self.merge(
+                        todo,
+                        task_id,
+                        &card_id,
+                        local,
+                        &remote,
+                        &board.lists,
+                        &mut report,
+                    )?;
+                    if !fields.is_empty() {
+                        report.remote.push(RemoteChange::UpdateCard {
+                            card: card_id.clone(),
+                            fields,
+                        });
+                    }
+                    task_id
+                }
+                // Removed here, and nobody has touched it on Trello since
+                None if self.state.synced.get(&card_id) == Some(&remote) => {
+                    report
+                        .remote
+                        .push(RemoteChange::ArchiveCard { card: card_id });
+                    continue;
+                }
+                None => {
+                    let category = board
+                        .lists
+                        .iter()
+                        .find(|list| list.get_id() == card.get_list_id())
+                        .map(|list| self.category(list));
+                    let task = new_task(&remote, category, &card_id);
+                    let task_id = task.get_id();
+                    todo.add_task(task);
+                    linked.insert(card_id.clone(), task_id);
+                    self.state.synced.insert(card_id.clone(), remote);
+                    report.local.push(task_id);
+                    task_id
+                }
+            };
+
+            for item in snapshot
+                .checklists
+                .iter()
+                .flat_map(|checklist| checklist.get_check_items())
+            {
+                let item_id = item.get_id().to_string();
+                seen.insert(item_id.clone());
+                let remote = Synced::item(item);
+                match linked.get(&item_id).copied() {
+                    Some(sub_id) => {
+                        let local = Synced::task(todo.get_task(sub_id).unwrap(), None);
+                        let fields = self.merge(
+                            todo,
+                            sub_id,
+                            &item_id,
+                            local,
+                            &remote,
+                            &board.lists,
+                            &mut report,
+                        )?;
+                        if !fields.is_empty() {
+                            report.remote.push(RemoteChange::UpdateCheckItem {
+                                card: card_id.clone(),
+                                item: item_id,
+                                fields,
+                            });
+                        }
+                    }
+                    None if self.state.synced.get(&item_id) == Some(&remote) => {
+                        report.remote.push(RemoteChange::DeleteCheckItem {
+                            card: card_id.clone(),
+                            item: item_id,
+                        });
+                    }
+                    None => {
+                        let sub_task = new_task(&remote, None, &item_id);
+                        let sub_id = sub_task.get_id();
+                        todo.add_sub_task(task_id, sub_task)?;
+                        linked.insert(item_id.clone(), sub_id);
+                        self.state.synced.insert(item_id, remote);
+                        report.local.push(sub_id);
+                    }
+                }
+            }
+
+            // Sub-tasks made here since the last sync
+            let checklist = snapshot
+                .checklists
+                .first()
+                .map(|checklist| checklist.get_id().to_string());
+            for sub_task in todo.get_task(task_id).unwrap().get_sub_tasks() {
+                if sub_task.get_trello_id().is_none() {
+                    report.remote.push(RemoteChange::CreateCheckItem {
+                        task: sub_task.get_id(),
+                        card: card_id.clone(),
+                        checklist: checklist.clone(),
+                        name: sub_task.get_name(),
+                        done: sub_task.is_done(),
+                    });
+                }
+            }
+        }
+
+        // Synced before but gone from the board now.
+        // Tasks linked to other boards aren't in this one's state, so they're left alone
+        let gone: Vec<(String, Uuid)> = linked
+            .into_iter()
+            .filter(|(trello_id, _)| {
+                !seen.contains(trello_id) && self.state.synced.contains_key(trello_id)
+            })
+            .collect();
+        for (trello_id, task_id) in gone {
+            self.gone(todo, &trello_id, task_id, &board.lists, &mut report);
+        }
+
+        // Tasks made here since the last sync
+        let mut new_tasks: Vec<&Task> = todo
+            .get_root_tasks()
+            .into_iter()
+            .filter(|task| task.get_trello_id().is_none())
+            .collect();
+        new_tasks.sort_by_key(|task| task.get_initial_date());
+        for task in new_tasks {
+            if let Some(list) = self.list_for(task.get_category(), &board.lists, None) {
+                let synced = Synced::task(task, Some(list.clone()));
+                report.remote.push(RemoteChange::CreateCard {
+                    task: task.get_id(),
+                    list,
+                    name: synced.name,
+                    due: synced.due,
+                    done: synced.done,
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    /// Applies one change that Trello sent to a webhook, like a sync of just that card or item.
+    /// Whatever changed here since the last sync is left for the next one to push up
+    pub fn apply_event(
+        &mut self,
+        todo: &mut ToDo,
+        event: &Event,
+    ) -> Result<SyncReport, &'static str> {
+        self.events += 1;
+        let mut report = SyncReport::default();
+        let mut lists = self.lists.clone();
+        match event {
+            Event::Card { id, list, fields } => {
+                // A list made since the last sync
+                if let Some(list) = list {
+                    if !lists.iter().any(|known| known.get_id() == list.get_id()) {
+                        lists.push(list.clone());
+                    }
+                }
+                match (find_linked(todo, id), self.state.synced.get(id).cloned()) {
+                    (Some(task_id), Some(mut remote)) => {
+                        remote.update(fields);
+                        let task = todo.get_task(task_id).unwrap();
+                        let list = self.list_for(task.get_category(), &lists, self.synced_list(id));
+                        let local = Synced::task(task, list);
+                        self.merge(todo, task_id, id, local, &remote, &lists, &mut report)?;
+                    }
+                    (None, None) => {
+                        if let Some(name) = &fields.name {
+                            let mut remote = Synced {
+                                name: name.clone(),
+                                due: None,
+                                done: false,
+                                list: None,
+                            };
+                            remote.update(fields);
+                            let category = lists
+                                .iter()
+                                .find(|list| Some(list.get_id()) == remote.list.as_deref())
+                                .map(|list| self.category(list));
+                            let task = new_task(&remote, category, id);
+                            report.local.push(task.get_id());
+                            todo.add_task(task);
+                            self.state.synced.insert(id.clone(), remote);
+                        }
+                    }
+                    // Linked to another board, or removed here since the last sync which is left to the next one
+                    _ => {}
+                }
+            }
+            Event::CheckItem { card, id, fields } => {
+                match (find_linked(todo, id), self.state.synced.get(id).cloned()) {
+                    (Some(sub_id), Some(mut remote)) => {
+                        remote.update(fields);
+                        let local = Synced::task(todo.get_task(sub_id).unwrap(), None);
+                        self.merge(todo, sub_id, id, local, &remote, &lists, &mut report)?;
+                    }
+                    (None, None) => {
+                        let parent = find_linked(todo, card)
+                            .filter(|_| self.state.synced.contains_key(card));
+                        if let (Some(parent), Some(name)) = (parent, &fields.name) {
+                            let remote = Synced {
+                                name: name.clone(),
+                                due: None,
+                                done: fields.done.unwrap_or(false),
+                                list: None,
+                            };
+                            let sub_task = new_task(&remote, None, id);
+                            report.local.push(sub_task.get_id());
+                            todo.add_sub_task(parent, sub_task)?;
+                            self.state.synced.insert(id.clone(), remote);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::CardGone { id } | Event::CheckItemGone { id } => {
+                if let Some(task_id) = find_linked(todo, id) {
+                    self.gone(todo, id, task_id, &lists, &mut report);
+                }
+            }
+            Event::Other => {}
+        }
+        Ok(report)
+    }
+
+    // Synced before but gone from Trello now, it goes here too unless it's been changed since
+    fn gone(
+        &mut self,
+        todo: &mut ToDo,
+        trello_id: &str,
+        task_id: Uuid,
+        lists: &[List],
+        report: &mut SyncReport,
+    ) {
+        // Tasks linked to other boards aren't in this one's state, so they're left alone
+        let base = match self.state.synced.remove(trello_id) {
+            Some(base) => base,
+            None => return,
+        };
+        // Already went along with its card
+        let task = match todo.get_task(task_id) {
+            Some(task) => task,
+            None => return,
+        };
+        let list = match base.list {
+            Some(_) => self
+                .list_for(task.get_category(), lists, base.list.as_deref())
+                .or_else(|| base.list.clone()),
+            None => None,
+        };
+        if Synced::task(task, list) == base {
+            for sub_task in task.walk() {
+                if let Some(sub_trello_id) = sub_task.get_trello_id() {
+                    self.state.synced.remove(sub_trello_id);
+                }
+            }
+            todo.remove_task(task_id).unwrap();
+            report.local.push(task_id);
+        } else {
+            todo.get_task_mut(task_id).unwrap().set_trello_id(None);
+            report.conflicts.push(Conflict {
+                task: task_id,
+                trello_id: trello_id.to_string(),
+                field: "deleted",
+            });
+        }
+    }
+
+    /// Records that a change from `sync` made it to Trello,
+    /// `created` being the id Trello gave a new card or checklist item
+    pub fn pushed(
+        &mut self,
+        todo: &mut ToDo,
+        change: &RemoteChange,
+        created: Option<&str>,
+    ) -> Result<(), &'static str> {
+        match change {
+            RemoteChange::CreateCard {
+                task,
+                list,
+                name,
+                due,
+                done,
+            } => self.link(
+                todo,
+                *task,
+                created,
+                Synced {
+                    name: name.clone(),
+                    due: *due,
+                    done: *done,
+                    list: Some(list.clone()),
+                },
+            ),
+            RemoteChange::CreateCheckItem {
+                task, name, done, ..
+            } => self.link(
+                todo,
+                *task,
+                created,
+                Synced {
+                    name: name.clone(),
+                    due: None,
+                    done: *done,
+                    list: None,
+                },
+            ),
+            RemoteChange::UpdateCard { card: id, fields }
+            | RemoteChange::UpdateCheckItem {
+                item: id, fields, ..
+            } => {
+                if let Some(base) = self.state.synced.get_mut(id) {
+                    base.update(fields);
+                }
+                Ok(())
+            }
+            RemoteChange::ArchiveCard { card: id }
+            | RemoteChange::DeleteCheckItem { item: id, .. } => {
+                self.state.synced.remove(id);
+                Ok(())
+            }
+        }
+    }
+
+    fn link(
+        &mut self,
+        todo: &mut ToDo,
+        task_id: Uuid,
+        created: Option<&str>,
+        synced: Synced,
+    ) -> Result<(), &'static str> {
+        let trello_id = created.ok_or("No id for what was made on Trello")?;
+        todo.get_task_mut(task_id)
+            .ok_or("No task with that id")?
+            .set_trello_id(Some(trello_id.to_string()));
+        self.state.synced.insert(trello_id.to_string(), synced);
+        Ok(())
+    }
+
+    // Three way merge of one card or item against what both sides were at the last sync.
+    // What changed on Trello is applied here, what changed here is returned to go up to Trello
+    #[allow(clippy::too_many_arguments)]
+    fn merge(
+        &mut self,
+        todo: &mut ToDo,
+        task_id: Uuid,
+        trello_id: &str,
+        local: Synced,
+        remote: &Synced,
+        lists: &[List],
+        report: &mut SyncReport,
+    ) -> Result<Fields, &'static str> {
+        // Linked without ever being synced, like after the state was lost, Trello wins
+        let mut base = self
+            .state
+            .synced
+            .get(trello_id)
+            .cloned()
+            .unwrap_or_else(|| local.clone());
+        // A category that isn't one of the board's lists leaves the card where it is
+        let local = Synced {
+            list: local.list.or_else(|| base.list.clone()),
+            ..local
+        };
+        let mut to_local = Fields::default();
+        let mut to_remote = Fields::default();
+        let mut conflicts = vec![];
+        if merge_field(
+            &mut base.name,
+            &local.name,
+            &remote.name,
+            &mut to_local.name,
+            &mut to_remote.name,
+        ) {
+            conflicts.push("name");
+        }
+        if merge_field(
+            &mut base.due,
+            &local.due,
+            &remote.due,
+            &mut to_local.due,
+            &mut to_remote.due,
+        ) {
+            conflicts.push("due");
+        }
+        if merge_field(
+            &mut base.done,
+            &local.done,
+            &remote.done,
+            &mut to_local.done,
+            &mut to_remote.done,
+        ) {
+            conflicts.push("done");
+        }
+        let (mut list_here, mut list_there) = (None, None);
+        if merge_field(
+            &mut base.list,
+            &local.list,
+            &remote.list,
+            &mut list_here,
+            &mut list_there,
+        ) {
+            conflicts.push("list");
+        }
+        to_local.list = list_here.flatten();
+        to_remote.list = list_there.flatten();
+
+        report
+            .conflicts
+            .extend(conflicts.into_iter().map(|field| Conflict {
+                task: task_id,
+                trello_id: trello_id.to_string(),
+                field,
+            }));
+        if !to_local.is_empty() {
+            self.apply(todo, task_id, &to_local, lists)?;
+            report.local.push(task_id);
+        }
+        self.state.synced.insert(trello_id.to_string(), base);
+        Ok(to_remote)
+    }
+
+    fn apply(
+        &self,
+        todo: &mut ToDo,
+        task_id: Uuid,
+        fields: &Fields,
+        lists: &[List],
+    ) -> Result<(), &'static str> {
+        if fields.name.is_some() || fields.due.is_some() || fields.list.is_some() {
+            let mut task = todo
+                .get_task(task_id)
+                .ok_or("No task with that id")?
+                .clone();
+            if let Some(name) = &fields.name {
+                task.set_name(name);
+            }
+            if let Some(due) = fields.due {
+                task.set_due_date(due.map(|due| due.with_timezone(&Local)));
+            }
+            if let Some(list) = &fields.list {
+                task.set_category(
+                    lists
+                        .iter()
+                        .find(|other| other.get_id() == list)
+                        .map(|list| self.category(list)),
+                );
+            }
+            todo.replace_task(task)?;
+        }
+        if let Some(done) = fields.done {
+            todo.mark_finished(task_id, Some(done))
+                .map_err(|_| "No task with that id")?;
+        }
+        Ok(())
+    }
+}
+
+/// The `[trello]` section of the config file.
+/// Without a key and token in there they come from TRELLO_KEY and TRELLO_TOKEN
+#[derive(Deserialize, Debug, Clone)]
+pub struct TrelloConfig {
+    pub key: Option<String>,
+    pub token: Option<String>,
+    pub base_url: Option<String>,
+    // Seconds between syncs
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    // Where what's been synced is kept between runs
+    pub state_path: Option<String>,
+    #[serde(default)]
+    pub boards: Vec<BoardConfig>,
+    // The app's secret that Trello signs webhook callbacks with
+    pub webhook_secret: Option<String>,
+    // Where Trello can reach the api server's `/todo/trello/webhook`, exactly as it was registered
+    pub callback_url: Option<String>,
+}
+
+fn default_interval() -> u64 {
+    300
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BoardConfig {
+    pub id: String,
+    // List name or id -> category, the rest of the lists are categories named after them
+    #[serde(default)]
+    pub categories: HashMap<String, String>,
+}
+
+impl TrelloConfig {
+    pub fn api(&self) -> TrelloApi {
+        let api = match (&self.key, &self.token) {
+            (Some(key), Some(token)) => TrelloApi::with_credentials(key, token),
+            _ => TrelloApi::new(),
+        };
+        match &self.base_url {
+            Some(base_url) => api.with_base_url(base_url),
+            None => api,
+        }
+    }
+}
+
+impl BoardConfig {
+    pub fn sync(&self, state: SyncState) -> TrelloSync {
+        TrelloSync::new(&self.id, self.categories.clone(), state)
+    }
+}
+
+/// Makes a change from `TrelloSync::sync` on Trello.
+/// Returns the id of the card or checklist item it made, to hand to `TrelloSync::pushed`
+pub fn push(api: &TrelloApi, change: &RemoteChange) -> Result<Option<String>, TrelloError> {
+    let created = match change {
+        RemoteChange::CreateCard {
+            list,
+            name,
+            due,
+            done,
+            ..
+        } => Some(
+            api.create_card(list, name, *due, *done)?
+                .get_id()
+                .to_string(),
+        ),
+        RemoteChange::UpdateCard { card, fields } => {
+            api.update_card(
+                card,
+                &CardUpdate {
+                    name: fields.name.clone(),
+                    due: fields.due,
+                    due_complete: fields.done,
+                    list_id: fields.list.clone(),
+                    closed: None,
+                },
+            )?;
+            None
+        }
+        RemoteChange::CreateCheckItem {
+            card,
+            checklist,
+            name,
+            done,
+            ..
+        } => {
+            let checklist = match checklist {
+                Some(checklist) => checklist.clone(),
+                None => api
+                    .create_checklist(card, "Checklist")?
+                    .get_id()
+                    .to_string(),
+            };
+            Some(
+                api.create_check_item(&checklist, name, *done)?
+                    .get_id()
+                    .to_string(),
+            )
+        }
+        RemoteChange::UpdateCheckItem { card, item, fields } => {
+            api.update_check_item(card, item, fields.name.as_deref(), fields.done)?;
+            None
+        }
+        // Already gone is as good as gone
+        RemoteChange::ArchiveCard { card } => match api.archive_card(card) {
+            Ok(_) | Err(TrelloError::NotFound) => None,
+            Err(err) => return Err(err),
+        },
+        RemoteChange::DeleteCheckItem { card, item } => match api.delete_check_item(card, item) {
+            Ok(_) | Err(TrelloError::NotFound) => None,
+            Err(err) => return Err(err),
+        },
+    };
+    Ok(created)
+}
+
+// Whichever side changed since the base wins, and the base moves up to it if that was Trello.
+// Returns true if they both changed it and don't agree
+fn merge_field<T: PartialEq + Clone>(
+    base: &mut T,
+    local: &T,
+    remote: &T,
+    to_local: &mut Option<T>,
+    to_remote: &mut Option<T>,
+) -> bool {
+    if local == remote || local == base {
+        if local != remote {
+            *to_local = Some(remote.clone());
+        }
+        *base = remote.clone();
+        false
+    } else if remote == base {
+        *to_remote = Some(local.clone());
+        false
+    } else {
+        true
+    }
+}
+
+fn find_linked(todo: &ToDo, trello_id: &str) -> Option<Uuid> {
+    todo.get_all_tasks()
+        .into_iter()
+        .find(|task| task.get_trello_id() == Some(trello_id))
+        .map(|task| task.get_id())
+}
+
+fn new_task(synced: &Synced, category: Option<String>, trello_id: &str) -> Task {
+    let mut task = Task::new(
+        &synced.name,
+        "",
+        synced.due.map(|due| due.with_timezone(&Local)),
+        0,
+        None,
+        None,
+        category,
+    );
+    task.set_trello_id(Some(trello_id.to_string()));
+    task.set_done(synced.done);
+    task
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        BoardSnapshot, CardSnapshot, Conflict, Fields, RemoteChange, SyncState, TrelloConfig,
+        TrelloSync,
+    };
+    use crate::backend::trello_api::{Card, Checklist, List};
+    use crate::backend::trello_webhook;
+    use crate::backend::{Task, ToDo};
+    use chrono::prelude::{TimeZone, Utc};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn list(id: &str, name: &str) -> List {
+        serde_json::from_value(json!({"id": id, "name": name, "closed": false, "idBoard": "b1"}))
+            .unwrap()
+    }
+
+    fn card(id: &str, name: &str, list: &str, due: Option<&str>, done: bool) -> Card {
+        serde_json::from_value(json!({
+            "id": id,
+            "name": name,
+            "due": due,
+            "dueComplete": done,
+            "idList": list,
+            "badges": {"due": due, "dueComplete": done, "checkItems": 0, "checkItemsChecked": 0},
+            "idChecklists": []
+        }))
+        .unwrap()
+    }
+
+    fn checklist(id: &str, items: &[(&str, &str, bool)]) -> Checklist {
+        let items: Vec<_> = items
+            .iter()
+            .map(|(item_id, name, done)| {
+                json!({
+                    "id": item_id,
+                    "name": name,
+                    "idChecklist": id,
+                    "state": if *done { "complete" } else { "incomplete" }
+                })
+            })
+            .collect();
+        serde_json::from_value(json!({"id": id, "name": "Checklist", "checkItems": items})).unwrap()
+    }
+
+    // Mail the letter with a checklist in To Do, and a finished card in the list that goes to "done"
+    fn board(letter: Card, items: &[(&str, &str, bool)], rest: Vec<Card>) -> BoardSnapshot {
+        let mut cards = vec![CardSnapshot {
+            card: letter,
+            checklists: vec![checklist("k1", items)],
+        }];
+        cards.extend(rest.into_iter().map(|card| CardSnapshot {
+            card,
+            checklists: vec![],
+        }));
+        BoardSnapshot {
+            lists: vec![list("l1", "To Do"), list("l2", "Finished")],
+            cards,
+        }
+    }
+
+    const ITEMS: [(&str, &str, bool); 2] = [("i1", "Stamp", false), ("i2", "Envelope", true)];
+
+    fn first_sync() -> (TrelloSync, ToDo) {
+        let mut categories = HashMap::new();
+        categories.insert("Finished".to_string(), "done".to_string());
+        let mut sync = TrelloSync::new("b1", categories, SyncState::default());
+        let mut todo = ToDo::new();
+        let report = sync
+            .sync(
+                &mut todo,
+                &board(
+                    card(
+                        "c1",
+                        "Mail letter",
+                        "l1",
+                        Some("2020-10-20T17:00:00.000Z"),
+                        false,
+                    ),
+                    &ITEMS,
+                    vec![card("c2", "Pay rent", "l2", None, true)],
+                ),
+            )
+            .unwrap();
+        assert_eq!(report.local.len(), 4);
+        assert!(report.remote.is_empty() && report.conflicts.is_empty());
+        (sync, todo)
+    }
+
+    fn linked<'a>(todo: &'a ToDo, trello_id: &str) -> Option<&'a Task> {
+        todo.get_all_tasks()
+            .into_iter()
+            .find(|task| task.get_trello_id() == Some(trello_id))
+    }
+
+    #[test]
+    fn trello_import() {
+        let (mut sync, mut todo) = first_sync();
+        assert_eq!(todo.num_tasks(), 4);
+        let letter = linked(&todo, "c1").unwrap();
+        assert_eq!(letter.get_category(), Some("To Do".to_string()));
+        assert_eq!(
+            letter.get_due_date().unwrap(),
+            Utc.ymd(2020, 10, 20).and_hms(17, 0, 0)
+        );
+        assert_eq!(letter.get_sub_tasks().len(), 2);
+        assert!(!linked(&todo, "i1").unwrap().is_done());
+        assert!(linked(&todo, "i2").unwrap().is_done());
+        let rent = linked(&todo, "c2").unwrap();
+        assert_eq!(rent.get_category(), Some("done".to_string()));
+        assert!(rent.is_done());
+
+        // Nothing changed on either side
+        let report = sync
+            .sync(
+                &mut todo,
+                &board(
+                    card(
+                        "c1",
+                        "Mail letter",
+                        "l1",
+                        Some("2020-10-20T17:00:00.000Z"),
+                        false,
+                    ),
+                    &ITEMS,
+                    vec![card("c2", "Pay rent", "l2", None, true)],
+                ),
+            )
+            .unwrap();
+        assert!(report.local.is_empty());
+        assert!(report.remote.is_empty() && report.conflicts.is_empty());
+    }
+
+    #[test]
+    fn trello_both_ways() {
+        let (mut sync, mut todo) = first_sync();
+        let mut letter = linked(&todo, "c1").unwrap().clone();
+        letter.set_name("Mail the letter");
+        todo.replace_task(letter.clone()).unwrap();
+        let mut rent = linked(&todo, "c2").unwrap().clone();
+        rent.set_name("Pay the rent");
+        todo.replace_task(rent.clone()).unwrap();
+        let stamp = linked(&todo, "i1").unwrap().get_id();
+        todo.mark_finished(stamp, Some(true)).unwrap();
+
+        let report = sync
+            .sync(
+                &mut todo,
+                &board(
+                    card(
+                        "c1",
+                        "Mail letter",
+                        "l2",
+                        Some("2020-10-21T17:00:00.000Z"),
+                        false,
+                    ),
+                    &ITEMS,
+                    vec![card("c2", "Pay rent!", "l2", None, true)],
+                ),
+            )
+            .unwrap();
+        // Moved and pushed back on Trello, renamed here
+        let letter = todo.get_task(letter.get_id()).unwrap();
+        assert_eq!(letter.get_name(), "Mail the letter");
+        assert_eq!(letter.get_category(), Some("done".to_string()));
+        assert_eq!(
+            letter.get_due_date().unwrap(),
+            Utc.ymd(2020, 10, 21).and_hms(17, 0, 0)
+        );
+        assert_eq!(report.local.len(), 1);
+        let rename = RemoteChange::UpdateCard {
+            card: "c1".to_string(),
+            fields: Fields {
+                name: Some("Mail the letter".to_string()),
+                ..Fields::default()
+            },
+        };
+        let check = RemoteChange::UpdateCheckItem {
+            card: "c1".to_string(),
+            item: "i1".to_string(),
+            fields: Fields {
+                done: Some(true),
+                ..Fields::default()
+            },
+        };
+        assert_eq!(report.remote, vec![rename.clone(), check.clone()]);
+        // Renamed on both sides
+        assert_eq!(
+            report.conflicts,
+            vec![Conflict {
+                task: rent.get_id(),
+                trello_id: "c2".to_string(),
+                field: "name"
+            }]
+        );
+        assert_eq!(
+            todo.get_task(rent.get_id()).unwrap().get_name(),
+            "Pay the rent"
+        );
+
+        // Once the changes are up, and the rent's been settled on, they agree again
+        sync.pushed(&mut todo, &rename, None).unwrap();
+        sync.pushed(&mut todo, &check, None).unwrap();
+        let report = sync
+            .sync(
+                &mut todo,
+                &board(
+                    card(
+                        "c1",
+                        "Mail the letter",
+                        "l2",
+                        Some("2020-10-21T17:00:00.000Z"),
+                        false,
+                    ),
+                    &[("i1", "Stamp", true), ("i2", "Envelope", true)],
+                    vec![card("c2", "Pay the rent", "l2", None, true)],
+                ),
+            )
+            .unwrap();
+        assert!(report.local.is_empty());
+        assert!(report.remote.is_empty() && report.conflicts.is_empty());
+    }
+
+    #[test]
+    fn trello_added_and_removed() {
+        let (mut sync, mut todo) = first_sync();
+        let letter = linked(&todo, "c1").unwrap().get_id();
+        let rent = linked(&todo, "c2").unwrap().get_id();
+        let groceries = Task::new(
+            "Groceries",
+            "",
+            None,
+            0,
+            None,
+            None,
+            Some("To Do".to_string()),
+        );
+        let groceries_id = groceries.get_id();
+        todo.add_task(groceries);
+        // Categories that aren't on the board stay here
+        todo.add_task(Task::new(
+            "Laundry",
+            "",
+            None,
+            0,
+            None,
+            None,
+            Some("home".to_string()),
+        ));
+        let glue = Task::new("Glue", "", None, 0, None, None, None);
+        let glue_id = glue.get_id();
+        todo.add_sub_task(letter, glue).unwrap();
+        todo.remove_task(linked(&todo, "i2").unwrap().get_id())
+            .unwrap();
+
+        // The rent card is gone from Trello
+        let report = sync
+            .sync(
+                &mut todo,
+                &board(
+                    card(
+                        "c1",
+                        "Mail letter",
+                        "l1",
+                        Some("2020-10-20T17:00:00.000Z"),
+                        false,
+                    ),
+                    &ITEMS,
+                    vec![],
+                ),
+            )
+            .unwrap();
+        assert!(todo.get_task(rent).is_none());
+        assert_eq!(report.local.len(), 1);
+        let new_card = RemoteChange::CreateCard {
+            task: groceries_id,
+            list: "l1".to_string(),
+            name: "Groceries".to_string(),
+            due: None,
+            done: false,
+        };
+        let new_item = RemoteChange::CreateCheckItem {
+            task: glue_id,
+            card: "c1".to_string(),
+            checklist: Some("k1".to_string()),
+            name: "Glue".to_string(),
+            done: false,
+        };
+        let deleted = RemoteChange::DeleteCheckItem {
+            card: "c1".to_string(),
+            item: "i2".to_string(),
+        };
+        assert_eq!(
+            report.remote,
+            vec![deleted.clone(), new_item.clone(), new_card.clone()]
+        );
+
+        sync.pushed(&mut todo, &new_card, Some("c3")).unwrap();
+        sync.pushed(&mut todo, &new_item, Some("i3")).unwrap();
+        sync.pushed(&mut todo, &deleted, None).unwrap();
+        assert_eq!(linked(&todo, "c3").unwrap().get_id(), groceries_id);
+
+        // The letter's done with here, but was renamed on Trello in the meantime
+        todo.remove_task(letter).unwrap();
+        let report = sync
+            .sync(
+                &mut todo,
+                &BoardSnapshot {
+                    lists: vec![list("l1", "To Do"), list("l2", "Finished")],
+                    cards: vec![
+                        CardSnapshot {
+                            card: card("c1", "Mail letters", "l1", None, false),
+                            checklists: vec![],
+                        },
+                        CardSnapshot {
+                            card: card("c3", "Groceries", "l1", None, false),
+                            checklists: vec![],
+                        },
+                    ],
+                },
+            )
+            .unwrap();
+        assert!(report.remote.is_empty());
+        assert_eq!(linked(&todo, "c1").unwrap().get_name(), "Mail letters");
+
+        // Then is removed here again, which archives it.
+        // Groceries is gone from Trello, but was renamed here
+        todo.remove_task(linked(&todo, "c1").unwrap().get_id())
+            .unwrap();
+        let mut groceries = todo.get_task(groceries_id).unwrap().clone();
+        groceries.set_name("Groceries and milk");
+        todo.replace_task(groceries).unwrap();
+        let report = sync
+            .sync(
+                &mut todo,
+                &BoardSnapshot {
+                    lists: vec![list("l1", "To Do")],
+                    cards: vec![CardSnapshot {
+                        card: card("c1", "Mail letters", "l1", None, false),
+                        checklists: vec![],
+                    }],
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            report.conflicts,
+            vec![Conflict {
+                task: groceries_id,
+                trello_id: "c3".to_string(),
+                field: "deleted"
+            }]
+        );
+        // So it's kept, and goes back up as a new card
+        assert_eq!(todo.get_task(groceries_id).unwrap().get_trello_id(), None);
+        assert_eq!(
+            report.remote,
+            vec![
+                RemoteChange::ArchiveCard {
+                    card: "c1".to_string()
+                },
+                RemoteChange::CreateCard {
+                    task: groceries_id,
+                    list: "l1".to_string(),
+                    name: "Groceries and milk".to_string(),
+                    due: None,
+                    done: false,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn trello_webhook_events() {
+        let (mut sync, mut todo) = first_sync();
+        let event = |kind: &str, data: serde_json::Value| {
+            let body = json!({"model": {"id": "b1"}, "action": {"type": kind, "data": data}});
+            trello_webhook::parse(body.to_string().as_bytes())
+                .unwrap()
+                .event
+        };
+
+        // Moved to a list made since the sync, and a new card
+        let moved = event(
+            "updateCard",
+            json!({
+                "card": {"id": "c1", "name": "Mail letter", "idList": "l3"},
+                "listBefore": {"id": "l1", "name": "To Do"},
+                "listAfter": {"id": "l3", "name": "Waiting"}
+            }),
+        );
+        let report = sync.apply_event(&mut todo, &moved).unwrap();
+        let letter = linked(&todo, "c1").unwrap().get_id();
+        assert_eq!(report.local, vec![letter]);
+        assert!(report.remote.is_empty() && report.conflicts.is_empty());
+        assert_eq!(
+            todo.get_task(letter).unwrap().get_category(),
+            Some("Waiting".to_string())
+        );
+        let created = event(
+            "createCard",
+            json!({"card": {"id": "c3", "name": "Groceries"}, "list": {"id": "l1", "name": "To Do"}}),
+        );
+        sync.apply_event(&mut todo, &created).unwrap();
+        assert_eq!(
+            linked(&todo, "c3").unwrap().get_category(),
+            Some("To Do".to_string())
+        );
+
+        // Checked off, and a new item
+        let checked = event(
+            "updateCheckItemStateOnCard",
+            json!({"card": {"id": "c1"}, "checkItem": {"id": "i1", "name": "Stamp", "state": "complete"}}),
+        );
+        sync.apply_event(&mut todo, &checked).unwrap();
+        assert!(linked(&todo, "i1").unwrap().is_done());
+        let added = event(
+            "createCheckItem",
+            json!({"card": {"id": "c1"}, "checkItem": {"id": "i3", "name": "Address", "state": "incomplete"}}),
+        );
+        sync.apply_event(&mut todo, &added).unwrap();
+        assert_eq!(todo.get_task(letter).unwrap().get_sub_tasks().len(), 3);
+
+        // Gone from Trello
+        let removed = event("deleteCheckItem", json!({"checkItem": {"id": "i2"}}));
+        sync.apply_event(&mut todo, &removed).unwrap();
+        assert!(linked(&todo, "i2").is_none());
+        let archived = event(
+            "updateCard",
+            json!({"card": {"id": "c2", "name": "Pay rent", "closed": true}}),
+        );
+        sync.apply_event(&mut todo, &archived).unwrap();
+        assert!(linked(&todo, "c2").is_none());
+        assert!(matches!(
+            sync.apply_event(&mut todo, &event("commentCard", json!({}))),
+            Ok(report) if report.local.is_empty()
+        ));
+        assert_eq!(sync.get_events(), 7);
+        assert_eq!(todo.num_tasks(), 4);
+    }
+
+    #[test]
+    fn trello_shared_category() {
+        let mut categories = HashMap::new();
+        categories.insert("To Do".to_string(), "work".to_string());
+        categories.insert("Doing".to_string(), "work".to_string());
+        let mut sync = TrelloSync::new("b1", categories, SyncState::default());
+        let mut todo = ToDo::new();
+        let board = |list_id: &str| BoardSnapshot {
+            lists: vec![list("l1", "To Do"), list("l2", "Doing")],
+            cards: vec![CardSnapshot {
+                card: card("c1", "Write report", list_id, None, false),
+                checklists: vec![],
+            }],
+        };
+        sync.sync(&mut todo, &board("l2")).unwrap();
+        let report = linked(&todo, "c1").unwrap().get_id();
+        assert_eq!(
+            todo.get_task(report).unwrap().get_category(),
+            Some("work".to_string())
+        );
+
+        // A card in the second list for a category isn't sent back to the first
+        let again = sync.sync(&mut todo, &board("l2")).unwrap();
+        assert!(again.local.is_empty());
+        assert!(again.remote.is_empty() && again.conflicts.is_empty());
+
+        // Nor is one moved between them, by a webhook or a sync
+        let moved = trello_webhook::parse(
+            json!({"model": {"id": "b1"}, "action": {"type": "updateCard", "data": {
+                "card": {"id": "c1", "name": "Write report", "idList": "l1"},
+                "listBefore": {"id": "l2", "name": "Doing"},
+                "listAfter": {"id": "l1", "name": "To Do"}
+            }}})
+            .to_string()
+            .as_bytes(),
+        )
+        .unwrap()
+        .event;
+        let applied = sync.apply_event(&mut todo, &moved).unwrap();
+        assert!(applied.remote.is_empty() && applied.conflicts.is_empty());
+        let again = sync.sync(&mut todo, &board("l1")).unwrap();
+        assert!(again.remote.is_empty() && again.conflicts.is_empty());
+        let again = sync.sync(&mut todo, &board("l2")).unwrap();
+        assert!(again.remote.is_empty() && again.conflicts.is_empty());
+        assert_eq!(
+            todo.get_task(report).unwrap().get_category(),
+            Some("work".to_string())
+        );
+
+        // New tasks still go in the first one
+        todo.add_task(Task::new(
+            "Plan",
+            "",
+            None,
+            0,
+            None,
+            None,
+            Some("work".to_string()),
+        ));
+        let created = sync.sync(&mut todo, &board("l2")).unwrap();
+        assert!(matches!(
+            created.remote.as_slice(),
+            [RemoteChange::CreateCard { list, .. }] if list == "l1"
+        ));
+    }
+
+    #[test]
+    fn trello_config() {
+        let config: TrelloConfig = toml::from_str(
+            r#"
+            key = "k"
+            token = "t"
+
+            [[boards]]
+            id = "b1"
+                [boards.categories]
+                "Finished" = "done"
+
+            [[boards]]
+            id = "b2"
+            "#,
+        )
+        .unwrap();
+        assert!(config.api().is_valid());
+        assert_eq!(config.interval, 300);
+        assert_eq!(config.boards.len(), 2);
+        let sync = config.boards[0].sync(SyncState::default());
+        assert_eq!(sync.get_board_id(), "b1");
+        assert_eq!(sync.category(&list("l2", "Finished")), "done");
+        assert_eq!(sync.category(&list("l1", "To Do")), "To Do");
+        assert!(config.boards[1].categories.is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+use crate::backend::trello_api::{List, ID};
+use crate::backend::trello_sync::Fields;
+use chrono::prelude::{DateTime, Utc};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::{Deserialize, Deserializer};
+
+/// One change on a board, as far as the sync cares
+#[derive(Debug, Clone)]
+pub enum Event {
+    // Only what changed about the card is filled in, along with the name
+    Card {
+        id: String,
+        list: Option<List>,
+        fields: Fields,
+    },
+    // Deleted, archived or moved off the board
+    CardGone {
+        id: String,
+    },
+    CheckItem {
+        card: String,
+        id: String,
+        fields: Fields,
+    },
+    CheckItemGone {
+        id: String,
+    },
+    // Comments, labels, members and everything else that isn't synced
+    Other,
+}
+
+/// What Trello POSTs to a webhook's callback url
+#[derive(Debug)]
+pub struct Webhook {
+    // The board the webhook was made for
+    pub board: String,
+    // The action's type, like `updateCard`
+    pub kind: String,
+    pub event: Event,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    action: Action,
+    model: Model,
+}
+
+#[derive(Deserialize)]
+struct Model {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Action {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    data: Data,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+#[allow(non_snake_case)]
+struct Data {
+    card: Option<CardData>,
+    list: Option<List>,
+    listAfter: Option<List>,
+    checkItem: Option<CheckItemData>,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct CardData {
+    id: String,
+    name: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    due: Option<Option<DateTime<Utc>>>,
+    dueComplete: Option<bool>,
+    idList: Option<String>,
+    closed: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct CheckItemData {
+    id: String,
+    name: Option<String>,
+    state: Option<String>,
+}
+
+// Tells a null apart from the field being left out
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Checks the `X-Trello-Webhook` header, the base64 HMAC-SHA1 of the body and then the callback url,
+/// keyed with the app's secret
+pub fn verify(secret: &str, callback_url: &str, body: &[u8], signature: &str) -> bool {
+    match sign(secret, callback_url, body) {
+        Ok(expected) => {
+            expected.len() == signature.len()
+                && memcmp::eq(expected.as_bytes(), signature.as_bytes())
+        }
+        Err(_) => false,
+    }
+}
+
+fn sign(secret: &str, callback_url: &str, body: &[u8]) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(body)?;
+    signer.update(callback_url.as_bytes())?;
+    Ok(openssl::base64::encode_block(&signer.sign_to_vec()?))
+}
+
+pub fn parse(body: &[u8]) -> Result<Webhook, String> {
+    let payload: Payload = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let data = payload.action.data;
+    let event = match (&payload.action.kind[..], data.card, data.checkItem) {
+        ("deleteCard", Some(card), _) | ("moveCardFromBoard", Some(card), _) => {
+            Event::CardGone { id: card.id }
+        }
+        ("createCard", Some(card), _)
+        | ("copyCard", Some(card), _)
+        | ("updateCard", Some(card), _)
+        | ("moveCardToBoard", Some(card), _)
+        | ("convertToCardFromCheckItem", Some(card), _) => {
+            if card.closed == Some(true) {
+                Event::CardGone { id: card.id }
+            } else {
+                let list = data.listAfter.or(data.list);
+                Event::Card {
+                    fields: Fields {
+                        name: card.name,
+                        due: card.due,
+                        done: card.dueComplete,
+                        list: card
+                            .idList
+                            .or_else(|| list.as_ref().map(|list| list.get_id().to_string())),
+                    },
+                    id: card.id,
+                    list,
+                }
+            }
+        }
+        ("deleteCheckItem", _, Some(item)) => Event::CheckItemGone { id: item.id },
+        ("createCheckItem", Some(card), Some(item))
+        | ("updateCheckItem", Some(card), Some(item))
+        | ("updateCheckItemStateOnCard", Some(card), Some(item)) => Event::CheckItem {
+            card: card.id,
+            id: item.id,
+            fields: Fields {
+                name: item.name,
+                done: item.state.map(|state| state == "complete"),
+                ..Fields::default()
+            },
+        },
+        _ => Event::Other,
+    };
+    Ok(Webhook {
+        board: payload.model.id,
+        kind: payload.action.kind,
+        event,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, verify, Event};
+    use crate::backend::trello_api::ID;
+    use chrono::prelude::{TimeZone, Utc};
+    use serde_json::json;
+
+    const CALLBACK: &str = "https://example.com/todo/trello/webhook";
+
+    // Cut down from what Trello sends, which has the member, the board and a lot more besides
+    fn payload(kind: &str, data: serde_json::Value) -> Vec<u8> {
+        json!({
+            "model": {"id": "b1", "name": "Chores"},
+            "action": {
+                "id": "a1",
+                "idMemberCreator": "m1",
+                "type": kind,
+                "date": "2020-10-20T17:00:00.000Z",
+                "data": data
+            }
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn webhook_signature() {
+        let body = br#"{"action":{"type":"updateCard"},"model":{"id":"b1"}}"#;
+        // From python's hmac and base64
+        let signature = "Zl5y4XFoI0mYC4Zr7u2WAt6xU7I=";
+        assert!(verify("secret", CALLBACK, body, signature));
+        assert!(!verify("other", CALLBACK, body, signature));
+        assert!(!verify("secret", "https://example.com/", body, signature));
+        assert!(!verify("secret", CALLBACK, b"{}", signature));
+        assert!(!verify("secret", CALLBACK, body, ""));
+    }
+
+    #[test]
+    fn webhook_cards() {
+        let moved = parse(&payload(
+            "updateCard",
+            json!({
+                "card": {"id": "c1", "name": "Mail letter", "idList": "l2", "idShort": 4},
+                "old": {"idList": "l1"},
+                "listBefore": {"id": "l1", "name": "To Do"},
+                "listAfter": {"id": "l2", "name": "Finished"}
+            }),
+        ))
+        .unwrap();
+        assert_eq!(moved.board, "b1");
+        assert_eq!(moved.kind, "updateCard");
+        match moved.event {
+            Event::Card { id, list, fields } => {
+                assert_eq!(id, "c1");
+                assert_eq!(list.unwrap().get_id(), "l2");
+                assert_eq!(fields.name.unwrap(), "Mail letter");
+                assert_eq!(fields.list.unwrap(), "l2");
+                assert_eq!(fields.due, None);
+                assert_eq!(fields.done, None);
+            }
+            event => panic!("{:?}", event),
+        }
+
+        let due = parse(&payload(
+            "updateCard",
+            json!({
+                "card": {"id": "c1", "name": "Mail letter", "due": "2020-10-21T17:00:00.000Z", "dueComplete": true},
+                "old": {"due": null}
+            }),
+        ))
+        .unwrap();
+        match due.event {
+            Event::Card { fields, .. } => {
+                assert_eq!(
+                    fields.due,
+                    Some(Some(Utc.ymd(2020, 10, 21).and_hms(17, 0, 0)))
+                );
+                assert_eq!(fields.done, Some(true));
+            }
+            event => panic!("{:?}", event),
+        }
+        let undue = parse(&payload(
+            "updateCard",
+            json!({"card": {"id": "c1", "name": "Mail letter", "due": null}}),
+        ))
+        .unwrap();
+        assert!(matches!(undue.event, Event::Card { fields, .. } if fields.due == Some(None)));
+
+        let archived = parse(&payload(
+            "updateCard",
+            json!({"card": {"id": "c1", "name": "Mail letter", "closed": true}, "old": {"closed": false}}),
+        ))
+        .unwrap();
+        assert!(matches!(archived.event, Event::CardGone { id } if id == "c1"));
+        let deleted = parse(&payload("deleteCard", json!({"card": {"id": "c1"}}))).unwrap();
+        assert!(matches!(deleted.event, Event::CardGone { id } if id == "c1"));
+    }
+
+    #[test]
+    fn webhook_check_items() {
+        let checked = parse(&payload(
+            "updateCheckItemStateOnCard",
+            json!({
+                "card": {"id": "c1", "name": "Mail letter"},
+                "checklist": {"id": "k1", "name": "Checklist"},
+                "checkItem": {"id": "i1", "name": "Stamp", "state": "complete"}
+            }),
+        ))
+        .unwrap();
+        match checked.event {
+            Event::CheckItem { card, id, fields } => {
+                assert_eq!(card, "c1");
+                assert_eq!(id, "i1");
+                assert_eq!(fields.name.unwrap(), "Stamp");
+                assert_eq!(fields.done, Some(true));
+            }
+            event => panic!("{:?}", event),
+        }
+        let deleted = parse(&payload(
+            "deleteCheckItem",
+            json!({"card": {"id": "c1"}, "checkItem": {"id": "i1", "name": "Stamp"}}),
+        ))
+        .unwrap();
+        assert!(matches!(deleted.event, Event::CheckItemGone { id } if id == "i1"));
+
+        let comment = parse(&payload(
+            "commentCard",
+            json!({"card": {"id": "c1"}, "text": "Sent"}),
+        ))
+        .unwrap();
+        assert!(matches!(comment.event, Event::Other));
+        assert!(parse(b"{\"model\": {}}").is_err());
+    }
+}
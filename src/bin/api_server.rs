@@ -2,18 +2,43 @@
 extern crate log;
 extern crate pretty_env_logger;
 
-use std::fs::File;
-use std::io::BufReader;
-use std::ops::DerefMut;
 use std::process;
 
+use clap::{App, Arg};
+use desktopper::backend::storage::StorageConfig;
 use warp::Filter;
 
 use crate::data_model::DataStore;
 
-// TODO make into a user input
+// Where the list goes when the config doesn't say otherwise
 const SAVE_FILE_PATH: &str = "/etc/desktopper/todo.json";
 
+mod config {
+    use desktopper::backend::source::SourceConfig;
+    use desktopper::backend::storage::StorageConfig;
+    use desktopper::backend::trello_sync::TrelloConfig;
+    use serde::Deserialize;
+
+    /// The api server's part of the shared config file, the display's sections are ignored
+    #[derive(Deserialize, Default)]
+    pub struct Config {
+        pub storage: Option<StorageConfig>,
+        pub trello: Option<TrelloConfig>,
+        #[serde(default)]
+        pub sources: Vec<SourceConfig>,
+    }
+
+    pub fn parse_file(file_location: &str) -> Config {
+        match std::fs::read_to_string(file_location) {
+            Ok(contents) => toml::from_str(contents.as_str()).unwrap(),
+            Err(_) => {
+                warn!("Unable to read {}, using the defaults", file_location);
+                Config::default()
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     if std::env::var("RUST_LOG").is_err() {
@@ -23,48 +48,426 @@ async fn main() {
 
     info!("My pid is {}", process::id());
 
-    let data_store = DataStore::new();
-    match File::open(SAVE_FILE_PATH) {
-        Ok(file) => {
-            info!("Reading from {} file", SAVE_FILE_PATH);
-            // TODO: Fix deserialization --> might want to load just the hashmap, and rebuild the overdue and category segments
-            match serde_json::from_reader(BufReader::new(file)) {
-                Ok(todo) => {
-                    info!("Loaded todo from {}", SAVE_FILE_PATH);
-                    *data_store.todo_list.write().deref_mut() = todo;
-                }
-                Err(_) => {
-                    warn!("Unable to load from storage file, invalid data, will make a new one")
-                }
-            }
+    let matches = App::new("Desktopper task api")
+        .arg(
+            Arg::with_name("config_file")
+                .short("c")
+                .long("config")
+                .default_value("/etc/desktopper/config.toml"),
+        )
+        .get_matches();
+    let cfg = config::parse_file(matches.value_of("config_file").unwrap());
+    let storage_config = cfg.storage.unwrap_or(StorageConfig::Json {
+        path: SAVE_FILE_PATH.to_string(),
+    });
+    let storage = match storage_config.open() {
+        Ok(storage) => storage,
+        Err(e) => {
+            error!("Unable to open {:?}: {}", storage_config, e);
+            process::exit(1);
         }
-        Err(_) => warn!("Unable to open save file, will create new one."),
+    };
+
+    let data_store = DataStore::new(storage);
+    // Starting on an empty list would save over whatever couldn't be read
+    if let Err(e) = data_store.load() {
+        error!("Unable to load the todo list: {}", e);
+        process::exit(1);
+    }
+    data_store.watch_storage();
+    if let Some(trello) = cfg.trello {
+        data_store.sync_trello(trello);
+    }
+    if !cfg.sources.is_empty() {
+        data_store.sync_sources(&cfg.sources);
     }
     let task_routes = filters::task_master(data_store);
-    let todo_routes = task_routes.with(warp::log("todo"));
+    let todo_routes = task_routes
+        .recover(handlers::recover)
+        .with(warp::log("todo"));
     warp::serve(todo_routes).run(([0, 0, 0, 0], 3030)).await;
 }
 
 mod data_model {
+    use std::collections::HashMap;
+    use std::ops::DerefMut;
     use std::sync::Arc;
 
-    use parking_lot::RwLock;
+    use parking_lot::{Mutex, RwLock};
 
     use chrono::{DateTime, Local};
-    use desktopper::backend::{CompletionStatus, ToDo};
+    use desktopper::backend::source::{Merged, SourceConfig, SourceSync};
+    use desktopper::backend::storage::{Storage, TaskFilter};
+    use desktopper::backend::trello_api::{TrelloApi, TrelloError};
+    use desktopper::backend::trello_sync::{
+        push, BoardSnapshot, SyncReport, SyncState, TrelloConfig, TrelloSync,
+    };
+    use desktopper::backend::trello_webhook::{self, Webhook};
+    use desktopper::backend::{CompletionStatus, History, Task, ToDo};
+    use tokio::runtime::Handle;
     use tokio::task::JoinHandle;
     use tokio::{task, time};
     use uuid::Uuid;
 
+    // How often to check whether the storage has been changed by something else
+    const WATCH_INTERVAL_SECS: u64 = 2;
+    // How often the other task sources are merged in
+    const SOURCE_INTERVAL_SECS: u64 = 300;
+    // Where the Trello sync remembers what it's seen when the config doesn't say
+    const TRELLO_STATE_PATH: &str = "/etc/desktopper/trello.json";
+
     #[derive(Clone)]
     pub struct DataStore {
         pub todo_list: Arc<RwLock<ToDo>>,
+        pub history: Arc<RwLock<History>>,
+        storage: Arc<Mutex<Box<dyn Storage>>>,
+        // Locked before the list, shared by the sync thread and the webhook
+        trello: Arc<Mutex<TrelloBoards>>,
+    }
+
+    #[derive(Default)]
+    struct TrelloBoards {
+        syncs: Vec<TrelloSync>,
+        secret: Option<String>,
+        callback_url: Option<String>,
     }
 
     impl DataStore {
-        pub fn new() -> Self {
+        pub fn new(storage: Box<dyn Storage>) -> Self {
             DataStore {
                 todo_list: Arc::new(RwLock::new(ToDo::new())),
+                history: Arc::new(RwLock::new(History::new())),
+                storage: Arc::new(Mutex::new(storage)),
+                trello: Arc::new(Mutex::new(TrelloBoards::default())),
+            }
+        }
+
+        /// Replaces the list and its history with whatever is in storage
+        pub fn load(&self) -> anyhow::Result<()> {
+            let (todo, history) = self.storage.lock().load()?;
+            info!("Loaded {} tasks from storage", todo.num_tasks());
+            *self.todo_list.write() = todo;
+            *self.history.write() = history;
+            Ok(())
+        }
+
+        /// Loads the list again whenever it's changed outside of the server, like a checklist file edited by hand
+        pub fn watch_storage(&self) -> JoinHandle<()> {
+            let store = self.clone();
+            task::spawn(async move {
+                loop {
+                    time::delay_for(std::time::Duration::from_secs(WATCH_INTERVAL_SECS)).await;
+                    let changed = store.storage.lock().changed_elsewhere();
+                    if changed {
+                        info!("Storage was changed elsewhere, loading it again");
+                        if let Err(e) = store.load() {
+                            error!("Unable to load the changed todo list: {}", e);
+                        }
+                    }
+                }
+            })
+        }
+
+        /// Keeps the list in step with the configured Trello boards, syncing every `interval` seconds
+        /// and registering the webhook once the first round's done if there's a callback url.
+        /// It's on a thread of its own since the Trello client blocks
+        pub fn sync_trello(&self, config: TrelloConfig) -> std::thread::JoinHandle<()> {
+            let state_path = config
+                .state_path
+                .clone()
+                .unwrap_or_else(|| TRELLO_STATE_PATH.to_string());
+            let mut states: HashMap<String, SyncState> = match std::fs::read_to_string(&state_path)
+            {
+                Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                    warn!("Unable to read {}, syncing from scratch: {}", state_path, e);
+                    HashMap::new()
+                }),
+                Err(_) => HashMap::new(),
+            };
+            {
+                let mut trello = self.trello.lock();
+                trello.syncs = config
+                    .boards
+                    .iter()
+                    .map(|board| board.sync(states.remove(&board.id).unwrap_or_default()))
+                    .collect();
+                trello.secret = config.webhook_secret.clone();
+                trello.callback_url = config.callback_url.clone();
+                info!("Syncing {} Trello board(s)", trello.syncs.len());
+            }
+            let store = self.clone();
+            let runtime = Handle::current();
+            std::thread::spawn(move || {
+                let api = config.api();
+                let mut registered = false;
+                loop {
+                    for board in 0..config.boards.len() {
+                        if let Err(e) = store.sync_board(&api, board, &runtime) {
+                            warn!(
+                                "Unable to sync Trello board {}: {}",
+                                config.boards[board].id, e
+                            );
+                        }
+                    }
+                    store.save_trello(&state_path);
+                    if let (false, Some(callback_url)) = (registered, &config.callback_url) {
+                        for board in config.boards.iter() {
+                            match api.create_webhook(callback_url, &board.id) {
+                                Ok(()) => info!("Registered a Trello webhook for {}", board.id),
+                                // Most likely it's already there from an earlier run
+                                Err(e) => info!(
+                                    "Unable to register a Trello webhook for {}: {}",
+                                    board.id, e
+                                ),
+                            }
+                        }
+                        registered = true;
+                    }
+                    std::thread::sleep(std::time::Duration::from_secs(config.interval));
+                }
+            })
+        }
+
+        // One round for one board, fetching it, merging it in and then pushing up what changed here.
+        // The list is only locked for the merge, Trello's too slow to hold onto it through the requests
+        fn sync_board(
+            &self,
+            api: &TrelloApi,
+            board: usize,
+            runtime: &Handle,
+        ) -> anyhow::Result<()> {
+            let (board_id, events) = {
+                let trello = self.trello.lock();
+                let sync = &trello.syncs[board];
+                (sync.get_board_id().to_string(), sync.get_events())
+            };
+            let snapshot = BoardSnapshot::fetch(api, &board_id)?;
+            let report = {
+                let mut trello = self.trello.lock();
+                let sync = &mut trello.syncs[board];
+                // The snapshot could be from before whatever the webhook just applied
+                if sync.get_events() != events {
+                    debug!(
+                        "Trello board {} changed during the sync, merging it next time",
+                        board_id
+                    );
+                    return Ok(());
+                }
+                sync.sync(&mut self.todo_list.write(), &snapshot)
+                    .map_err(anyhow::Error::msg)?
+            };
+            self.trello_changed(&report, runtime);
+            let mut pushed = 0;
+            for change in report.remote.iter() {
+                match push(api, change) {
+                    Ok(created) => {
+                        // Only fails if the task went away in the meantime, the next sync sorts that out
+                        let _ = self.trello.lock().syncs[board].pushed(
+                            &mut self.todo_list.write(),
+                            change,
+                            created.as_deref(),
+                        );
+                        pushed += 1;
+                    }
+                    // The rest go up next time
+                    Err(e @ TrelloError::RateLimited(_)) => {
+                        warn!("{}, pushing the rest later", e);
+                        break;
+                    }
+                    Err(e) => warn!("Unable to push {:?} to Trello: {}", change, e),
+                }
+            }
+            if !report.local.is_empty() || pushed > 0 {
+                info!(
+                    "Synced Trello board {}, {} change(s) here and {} there",
+                    board_id,
+                    report.local.len(),
+                    pushed
+                );
+                self.save();
+            }
+            Ok(())
+        }
+
+        // Warns about the conflicts and watches the tasks that changed here for going overdue
+        fn trello_changed(&self, report: &SyncReport, runtime: &Handle) {
+            for conflict in report.conflicts.iter() {
+                warn!(
+                    "Task {} and Trello's {} both changed its {}, leaving them until they agree",
+                    conflict.task, conflict.trello_id, conflict.field
+                );
+            }
+            for id in report.local.iter() {
+                let due_date = self
+                    .todo_list
+                    .read()
+                    .get_task(*id)
+                    .and_then(Task::get_due_date);
+                if let Some(due_date) = due_date {
+                    runtime.enter(|| self.schedule_overdue_check(*id, due_date));
+                }
+            }
+        }
+
+        fn save_trello(&self, state_path: &str) {
+            let saved = {
+                let trello = self.trello.lock();
+                let states: HashMap<&str, &SyncState> = trello
+                    .syncs
+                    .iter()
+                    .map(|sync| (sync.get_board_id(), sync.get_state()))
+                    .collect();
+                serde_json::to_string(&states)
+            };
+            let saved = saved
+                .map_err(anyhow::Error::from)
+                .and_then(|states| Ok(std::fs::write(state_path, states)?));
+            if let Err(e) = saved {
+                error!(
+                    "Unable to save the Trello sync state to {}: {}",
+                    state_path, e
+                );
+            }
+        }
+
+        /// Whether a webhook callback was signed with the configured secret
+        pub fn verify_trello(&self, signature: &str, body: &[u8]) -> bool {
+            let trello = self.trello.lock();
+            match (&trello.secret, &trello.callback_url) {
+                (Some(secret), Some(callback_url)) => {
+                    trello_webhook::verify(secret, callback_url, body, signature)
+                }
+                _ => {
+                    warn!("Got a Trello webhook without a webhook_secret and callback_url to check it");
+                    false
+                }
+            }
+        }
+
+        /// Applies what a webhook callback says changed to the tasks mirroring its board.
+        /// Anything Trello needs to hear back about goes up with the next sync
+        pub fn apply_trello(&self, webhook: &Webhook) -> Result<(), &'static str> {
+            let report = {
+                let mut trello = self.trello.lock();
+                let sync = trello
+                    .syncs
+                    .iter_mut()
+                    .find(|sync| sync.get_board_id() == webhook.board);
+                match sync {
+                    Some(sync) => sync.apply_event(&mut self.todo_list.write(), &webhook.event)?,
+                    None => {
+                        debug!("Ignoring a Trello webhook for board {}", webhook.board);
+                        return Ok(());
+                    }
+                }
+            };
+            self.trello_changed(&report, &Handle::current());
+            if !report.local.is_empty() {
+                info!(
+                    "Applied Trello's {} on board {}, {} change(s) here",
+                    webhook.kind,
+                    webhook.board,
+                    report.local.len()
+                );
+                self.save();
+            }
+            Ok(())
+        }
+
+        /// Merges in each of the other task sources every few minutes, after pushing back what changed here.
+        /// Like the Trello sync it's on a thread of its own, the sources can block
+        pub fn sync_sources(&self, configs: &[SourceConfig]) -> std::thread::JoinHandle<()> {
+            let mut syncs: Vec<SourceSync> = configs
+                .iter()
+                .filter_map(|config| match config.open() {
+                    Ok(source) => Some(SourceSync::new(source)),
+                    Err(e) => {
+                        error!("Unable to open the task source {:?}: {}", config, e);
+                        None
+                    }
+                })
+                .collect();
+            info!("Merging in {} task source(s)", syncs.len());
+            let store = self.clone();
+            let runtime = Handle::current();
+            std::thread::spawn(move || loop {
+                for sync in syncs.iter_mut() {
+                    match store.sync_source(sync) {
+                        Ok(merged) => {
+                            for id in merged.added.iter().chain(merged.updated.iter()) {
+                                let due_date = store
+                                    .todo_list
+                                    .read()
+                                    .get_task(*id)
+                                    .and_then(Task::get_due_date);
+                                if let Some(due_date) = due_date {
+                                    runtime.enter(|| store.schedule_overdue_check(*id, due_date));
+                                }
+                            }
+                        }
+                        Err(e) => warn!("Unable to merge in {}: {}", sync.get_name(), e),
+                    }
+                }
+                std::thread::sleep(std::time::Duration::from_secs(SOURCE_INTERVAL_SECS));
+            })
+        }
+
+        // The list is only locked to see what changed and for the merge,
+        // the merge leaves anything edited in between for the next round to push
+        fn sync_source(&self, sync: &mut SourceSync) -> Result<Merged, String> {
+            let changed = sync.changed(&self.todo_list.read());
+            sync.push(&changed)?;
+            let (fetched, listed) = sync.fetch()?;
+            let merged = sync.merge(&mut self.todo_list.write(), fetched, listed);
+            if !merged.is_empty() {
+                info!(
+                    "Merged in {}, {} added, {} updated and {} removed",
+                    sync.get_name(),
+                    merged.added.len(),
+                    merged.updated.len(),
+                    merged.removed.len()
+                );
+                self.save();
+            }
+            Ok(merged)
+        }
+
+        /// Logs whatever changed since the last save to the history, then saves both
+        pub fn save(&self) {
+            if let Err(e) = self.try_save() {
+                error!("Unable to save: {}", e);
+            }
+        }
+
+        /// The same as `save`, for when whoever asked for the change has to hear that it wasn't kept.
+        /// If the storage was changed by something else it's loaded in place of the list,
+        /// so the change can be made again on top of that
+        pub fn try_save(&self) -> anyhow::Result<()> {
+            let result = {
+                let mut todo_list = self.todo_list.write();
+                let mut history = self.history.write();
+                history.record(todo_list.deref_mut());
+                let steps = history.take_steps();
+                self.storage.lock().save(&todo_list, &history, &steps)
+            };
+            if result.is_err() && self.storage.lock().changed_elsewhere() {
+                info!("Storage was changed elsewhere, loading it again");
+                if let Err(e) = self.load() {
+                    error!("Unable to load the changed todo list: {}", e);
+                }
+            }
+            result
+        }
+
+        /// Ids of the tasks matching the filter if the storage can search them itself
+        pub fn search(&self, filter: &TaskFilter) -> Option<Vec<Uuid>> {
+            let found = self.storage.lock().search(filter);
+            match found {
+                Ok(ids) => ids,
+                Err(e) => {
+                    warn!("Storage search failed, checking in memory instead: {}", e);
+                    None
+                }
             }
         }
 
@@ -75,8 +478,9 @@ mod data_model {
         ) -> JoinHandle<()> {
             let task_todo_list = self.todo_list.clone();
             task::spawn(async move {
+                // Already passed due dates are overdue right away
                 let dur = due_date.signed_duration_since(Local::now());
-                time::delay_for(dur.to_std().unwrap()).await;
+                time::delay_for(dur.to_std().unwrap_or_default()).await;
                 let mut lock = task_todo_list.write();
                 if let Some(task) = lock.get_task(id) {
                     if !task.complete() {
@@ -86,18 +490,78 @@ mod data_model {
             })
         }
 
-        pub fn schedule_repeats(&self, id: Uuid, due_date: DateTime<Local>) {
-            let task_todo_list = self.todo_list.clone();
+        /// Adds a task, underneath another one if a parent id is given,
+        /// and starts watching it if it has a due date or repeats
+        pub fn add_task(&self, task: Task, parent: Option<Uuid>) -> Result<(), &'static str> {
+            match parent {
+                Some(parent) => self.todo_list.write().add_sub_task(parent, task.clone())?,
+                None => self.todo_list.write().add_task(task.clone()),
+            }
+            if let Some(due_date) = task.get_due_date() {
+                self.schedule_overdue_check(task.get_id(), due_date);
+            }
+            if task.get_repeats().is_some() {
+                self.schedule_repeats(task.get_id());
+            }
+            Ok(())
+        }
+
+        /// Adds a task from an import at the top level, or if it has the id of one that's already here
+        /// takes that one's place, keeping what the import couldn't carry
+        pub fn import_task(&self, mut task: Task) -> Result<(), &'static str> {
+            let id = task.get_id();
+            let replaced = {
+                let mut todo_list = self.todo_list.write();
+                let existing = todo_list.get_task(id).cloned();
+                match existing {
+                    Some(existing) => {
+                        task.carry_over(&existing);
+                        todo_list.replace_task(task.clone())?;
+                        Some(existing)
+                    }
+                    None => None,
+                }
+            };
+            match replaced {
+                Some(existing) => {
+                    if let Some(due_date) = task.get_due_date() {
+                        self.schedule_overdue_check(id, due_date);
+                    }
+                    // One's already running if the old copy repeated
+                    if task.get_repeats().is_some() && existing.get_repeats().is_none() {
+                        self.schedule_repeats(id);
+                    }
+                    Ok(())
+                }
+                None => self.add_task(task, None),
+            }
+        }
+
+        /// Keeps moving a repeating task's due date along its recurrence rule
+        /// until the task is removed or the rule runs out
+        pub fn schedule_repeats(&self, id: Uuid) {
+            let store = self.clone();
             task::spawn(async move {
-                let mut keep_rep = true;
-                while keep_rep {
+                loop {
+                    let due_date = match store.todo_list.read().get_task(id) {
+                        Some(task) => task.get_due_date(),
+                        None => None,
+                    };
+                    let due_date = match due_date {
+                        Some(due_date) => due_date,
+                        None => break,
+                    };
+                    // Already passed due dates just repeat right away
                     let dur = due_date.signed_duration_since(Local::now());
-                    time::delay_for(dur.to_std().unwrap()).await;
-                    let mut lock = task_todo_list.write();
-                    match lock.get_task_mut(id) {
-                        // Task may have been removed
-                        Some(task) => keep_rep = task.repeat(),
-                        None => keep_rep = false,
+                    time::delay_for(dur.to_std().unwrap_or_default()).await;
+                    let next_due_date = store.todo_list.write().repeat_task(id);
+                    match next_due_date {
+                        Some(next_due_date) => {
+                            store.save();
+                            store.schedule_overdue_check(id, next_due_date);
+                        }
+                        // Task may have been removed, or has no more repeats
+                        None => break,
                     }
                 }
             });
@@ -109,13 +573,13 @@ mod filters {
     use std::collections::HashMap;
     use std::str::FromStr;
 
-    use chrono::{DateTime, Local, TimeZone, Weekday};
+    use chrono::{DateTime, Local, NaiveDate, TimeZone};
     use serde::Deserialize;
     use uuid::Uuid;
     use warp::Filter;
 
     use crate::{handlers, DataStore};
-    use desktopper::backend::{Priority, Task};
+    use desktopper::backend::{Priority, Recurrence, Task};
 
     pub fn task_master(
         storage: DataStore,
@@ -123,11 +587,32 @@ mod filters {
         warp::path("todo").and(
             get_task(storage.clone())
                 .or(add_task(storage.clone()))
+                .or(quick_add(storage.clone()))
                 .or(remove_task(storage.clone()))
                 .or(estimate_time(storage.clone()))
                 .or(complete(storage.clone()))
                 .or(completion_status(storage.clone()))
                 .or(mark_finished(storage.clone()))
+                .or(add_dependency(storage.clone()))
+                .or(remove_dependency(storage.clone()))
+                .or(startable(storage.clone()))
+                .or(next(storage.clone()))
+                .or(start_timer(storage.clone()))
+                .or(stop_timer(storage.clone()))
+                .or(tracked_time(storage.clone()))
+                .or(add_tag(storage.clone()))
+                .or(remove_tag(storage.clone()))
+                .or(snooze(storage.clone()))
+                .or(unsnooze(storage.clone()))
+                .or(history(storage.clone()))
+                .or(undo(storage.clone()))
+                .or(redo(storage.clone()))
+                .or(export(storage.clone()))
+                .or(calendar(storage.clone()))
+                .or(report(storage.clone()))
+                .or(import(storage.clone()))
+                .or(trello_handshake())
+                .or(trello_webhook(storage.clone()))
                 .or(search(storage)),
         )
     }
@@ -150,10 +635,25 @@ mod filters {
             .and(warp::path("add"))
             .and(warp::path::end())
             .and(json_body())
+            .and(option_extractor::<Uuid>("parent"))
             .and(with_store(storage))
             .and_then(handlers::add_task)
     }
 
+    /// Takes a plain text line like `Pay rent fri 9am !high #home ~30m every month`
+    pub fn quick_add(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(warp::path("quick"))
+            .and(warp::path::end())
+            .and(warp::body::content_length_limit(1024 * 4))
+            .and(warp::body::bytes())
+            .and(option_extractor::<Uuid>("parent"))
+            .and(with_store(storage))
+            .and_then(handlers::quick_add)
+    }
+
     pub fn remove_task(
         storage: DataStore,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -187,6 +687,39 @@ mod filters {
             .and_then(handlers::complete)
     }
 
+    pub fn tracked_time(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("tracked"))
+            .and(warp::path::end())
+            .and(option_extractor::<Uuid>("uuid"))
+            .and(with_store(storage))
+            .and_then(handlers::tracked_time)
+    }
+
+    pub fn start_timer(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("start_timer"))
+            .and(warp::path::end())
+            .and(option_extractor::<Uuid>("uuid"))
+            .and(with_store(storage))
+            .and_then(handlers::start_timer)
+    }
+
+    pub fn stop_timer(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("stop_timer"))
+            .and(warp::path::end())
+            .and(option_extractor::<Uuid>("uuid"))
+            .and(with_store(storage))
+            .and_then(handlers::stop_timer)
+    }
+
     pub fn completion_status(
         storage: DataStore,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -216,10 +749,256 @@ mod filters {
             .and(option_extractor::<Priority>("priority_low"))
             .and(option_extractor::<Priority>("priority_high"))
             .and(option_extractor::<String>("category"))
+            .and(option_extractor::<bool>("blocked"))
+            .and(warp::query::<SearchQuery>())
             .and(with_store(storage))
             .and_then(handlers::search)
     }
 
+    pub fn add_dependency(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("block"))
+            .and(warp::path::end())
+            .and(warp::query::<Dependency>())
+            .and(with_store(storage))
+            .and_then(handlers::add_dependency)
+    }
+
+    pub fn remove_dependency(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("unblock"))
+            .and(warp::path::end())
+            .and(warp::query::<Dependency>())
+            .and(with_store(storage))
+            .and_then(handlers::remove_dependency)
+    }
+
+    pub fn startable(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("startable"))
+            .and(warp::path::end())
+            .and(with_store(storage))
+            .and_then(handlers::startable)
+    }
+
+    pub fn next(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("next"))
+            .and(warp::path::end())
+            .and(option_extractor::<u32>("minutes"))
+            .and(with_store(storage))
+            .and_then(handlers::next)
+    }
+
+    pub fn add_tag(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("tag"))
+            .and(warp::path::end())
+            .and(warp::query::<Tag>())
+            .and(with_store(storage))
+            .and_then(handlers::add_tag)
+    }
+
+    pub fn remove_tag(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("untag"))
+            .and(warp::path::end())
+            .and(warp::query::<Tag>())
+            .and(with_store(storage))
+            .and_then(handlers::remove_tag)
+    }
+
+    /// The rest of the search parameters. Comma separated tag filters, matching any of the tags or all of them,
+    /// and `q`, a query in the language described on `desktopper::backend::Query`
+    #[derive(Deserialize)]
+    pub struct SearchQuery {
+        pub tags_any: Option<String>,
+        pub tags_all: Option<String>,
+        pub q: Option<String>,
+    }
+
+    /// Which format to export or import in, `todotxt`, `ics`, `taskwarrior` or, only for exports, `csv`.
+    /// Left out it's todo.txt, unless an imported file starts like an iCalendar file or JSON
+    #[derive(Deserialize)]
+    pub struct Format {
+        pub format: Option<String>,
+    }
+
+    /// Comma separated columns for a csv export, see `desktopper::backend::csv::Column`,
+    /// and `q` to only export the tasks matching a query
+    #[derive(Deserialize)]
+    pub struct CsvQuery {
+        pub columns: Option<String>,
+        pub q: Option<String>,
+    }
+
+    pub fn export(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("export"))
+            .and(warp::path::end())
+            .and(warp::query::<Format>())
+            .and(warp::query::<CsvQuery>())
+            .and(with_store(storage))
+            .and_then(handlers::export)
+    }
+
+    /// Whole days like `2020-10-12`, both ends included, and `json` or `csv` with the same columns as an export
+    #[derive(Deserialize)]
+    pub struct Report {
+        pub from: Option<NaiveDate>,
+        pub to: Option<NaiveDate>,
+        pub format: Option<String>,
+        pub columns: Option<String>,
+    }
+
+    /// The tasks finished over a range of days
+    pub fn report(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("report"))
+            .and(warp::path::end())
+            .and(warp::query::<Report>())
+            .and(with_store(storage))
+            .and_then(handlers::report)
+    }
+
+    /// The whole list as an iCalendar feed to subscribe to
+    pub fn calendar(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("calendar.ics"))
+            .and(warp::path::end())
+            .and(with_store(storage))
+            .and_then(handlers::calendar)
+    }
+
+    /// Takes a whole file in the body
+    pub fn import(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(warp::path("import"))
+            .and(warp::path::end())
+            .and(warp::query::<Format>())
+            .and(warp::body::content_length_limit(1024 * 1024))
+            .and(warp::body::bytes())
+            .and(with_store(storage))
+            .and_then(handlers::import)
+    }
+
+    /// Trello checks the callback url answers before it'll make a webhook
+    pub fn trello_handshake(
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::head()
+            .and(warp::path("trello"))
+            .and(warp::path("webhook"))
+            .and(warp::path::end())
+            .map(warp::reply)
+    }
+
+    /// Where Trello POSTs each change to a synced board, signed in the `X-Trello-Webhook` header
+    pub fn trello_webhook(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(warp::path("trello"))
+            .and(warp::path("webhook"))
+            .and(warp::path::end())
+            .and(warp::header::optional::<String>("x-trello-webhook"))
+            .and(warp::body::content_length_limit(1024 * 64))
+            .and(warp::body::bytes())
+            .and(with_store(storage))
+            .and_then(handlers::trello_webhook)
+    }
+
+    pub fn snooze(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("snooze"))
+            .and(warp::path::end())
+            .and(warp::query::<Snooze>())
+            .and(with_store(storage))
+            .and_then(handlers::snooze)
+    }
+
+    pub fn unsnooze(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("unsnooze"))
+            .and(warp::path::end())
+            .and(option_extractor::<Uuid>("uuid"))
+            .and(with_store(storage))
+            .and_then(handlers::unsnooze)
+    }
+
+    pub fn history(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("history"))
+            .and(warp::path::end())
+            .and(with_store(storage))
+            .and_then(handlers::history)
+    }
+
+    pub fn undo(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("undo"))
+            .and(warp::path::end())
+            .and(with_store(storage))
+            .and_then(handlers::undo)
+    }
+
+    pub fn redo(
+        storage: DataStore,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("redo"))
+            .and(warp::path::end())
+            .and(with_store(storage))
+            .and_then(handlers::redo)
+    }
+
+    #[derive(Deserialize)]
+    pub struct Tag {
+        pub uuid: Uuid,
+        pub tag: String,
+    }
+
+    /// Query for hiding a task for a number of minutes
+    #[derive(Deserialize)]
+    pub struct Snooze {
+        pub uuid: Uuid,
+        pub minutes: i64,
+    }
+
+    /// Query for linking a task to the one it's blocked by
+    #[derive(Deserialize)]
+    pub struct Dependency {
+        pub uuid: Uuid,
+        pub blocked_by: Uuid,
+    }
+
     pub fn mark_finished(
         storage: DataStore,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -264,14 +1043,16 @@ mod filters {
             name: String,
             desc: String,
             due_date: Option<String>,
+            not_before: Option<String>,
             est_time: u32,
             priority: Option<Priority>,
-            repeat: Option<Vec<Weekday>>,
+            repeat: Option<Recurrence>,
             category: Option<String>,
+            tags: Option<Vec<String>>,
         }
         warp::body::content_length_limit(1024 * 16).and(warp::body::json::<ApiTask>().map(
             |x: ApiTask| -> Task {
-                let new_due_date = match x.due_date {
+                let parse_date = |date: Option<String>| match date {
                     Some(d) => {
                         match chrono::NaiveDateTime::parse_from_str(d.as_str(), "%Y-%m-%d %H:%M:%S")
                         {
@@ -281,7 +1062,8 @@ mod filters {
                     }
                     _ => None,
                 };
-                Task::new(
+                let new_due_date = parse_date(x.due_date);
+                let mut task = Task::new(
                     x.name.as_str(),
                     x.desc.as_str(),
                     new_due_date,
@@ -289,39 +1071,48 @@ mod filters {
                     x.priority,
                     x.repeat,
                     x.category,
-                )
+                );
+                if let Some(tags) = x.tags {
+                    task.set_tags(tags.into_iter().collect());
+                }
+                task.set_not_before(parse_date(x.not_before));
+                task
             },
         ))
     }
 }
 
 mod handlers {
-    use std::fs::OpenOptions;
-
     use uuid::Uuid;
-    use warp::{http, Rejection};
+    use warp::{http, Rejection, Reply};
 
     use crate::data_model::DataStore;
-    use crate::SAVE_FILE_PATH;
-    use chrono::{DateTime, Local};
-    use desktopper::backend::{CompletionStatus, EstTime, Priority, Task};
-    use std::ops::Deref;
+    use crate::filters::{CsvQuery, Dependency, Format, Report, SearchQuery, Snooze, Tag};
+    use chrono::{DateTime, Duration, Local, TimeZone};
+    use desktopper::backend::csv::{self, Column};
+    use desktopper::backend::history::{Change, ChangeRecord};
+    use desktopper::backend::storage::TaskFilter;
+    use desktopper::backend::{ical, quick_add, scoring, taskwarrior, todo_txt, trello_webhook};
+    use desktopper::backend::{
+        CompletionStatus, EstTime, Priority, Query, Task, ToDo, TrackedTime,
+    };
+    use std::collections::HashMap;
+    use std::ops::{Deref, DerefMut};
+    use std::str::FromStr;
+
+    // Ten years, plenty for putting something off
+    const MAX_SNOOZE_MINUTES: i64 = 10 * 366 * 24 * 60;
 
+    /// Adds a task to the todo list, or underneath another task if a parent id is given
     pub async fn add_task(
         task: Task,
+        parent: Option<Uuid>,
         store: DataStore,
     ) -> Result<impl warp::Reply, warp::Rejection> {
-        store.todo_list.write().add_task(task.clone());
-        if task.get_due_date().is_some() {
-            store.schedule_overdue_check(task.get_id(), task.get_due_date().unwrap());
+        if store.add_task(task, parent).is_err() {
+            return Err(warp::reject::not_found());
         }
-        if task.get_repeats().is_some() {
-            store.schedule_repeats(
-                task.get_id(),
-                task.get_due_date().unwrap_or_else(Local::now),
-            );
-        }
-        update_file(store);
+        store.try_save().map_err(save_failed)?;
 
         Ok(warp::reply::with_status(
             "Added task to todo list",
@@ -329,6 +1120,32 @@ mod handlers {
         ))
     }
 
+    /// Parses a one line task and adds it, replying with what it was understood as
+    pub async fn quick_add(
+        body: warp::hyper::body::Bytes,
+        parent: Option<Uuid>,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let parsed = match std::str::from_utf8(&body) {
+            Ok(line) => quick_add::parse(line, Local::now()),
+            Err(_) => Err("Body isn't valid utf-8"),
+        };
+        let result = parsed.and_then(|task| store.add_task(task.clone(), parent).map(|_| task));
+        match result {
+            Ok(task) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    warp::reply::json(&task),
+                    http::StatusCode::CREATED,
+                ))
+            }
+            Err(e) => Ok(warp::reply::with_status(
+                warp::reply::json(&e),
+                http::StatusCode::BAD_REQUEST,
+            )),
+        }
+    }
+
     pub async fn get_task(
         uuid: Option<Uuid>,
         store: DataStore,
@@ -356,7 +1173,7 @@ mod handlers {
             },
             None => Err(warp::reject::reject()),
         };
-        update_file(store);
+        store.try_save().map_err(save_failed)?;
         ret_val
     }
 
@@ -373,6 +1190,67 @@ mod handlers {
         }
     }
 
+    pub async fn tracked_time(
+        id: Option<Uuid>,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        match id {
+            Some(id) => match store.todo_list.read().get_task(id) {
+                Some(task) => Ok(warp::reply::json(&task.tracked_time())),
+                None => Err(warp::reject()),
+            },
+            None => Ok(warp::reply::json(&store.todo_list.read().tracked_time())),
+        }
+    }
+
+    pub async fn start_timer(
+        id: Option<Uuid>,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let id = match id {
+            Some(id) => id,
+            None => return Err(warp::reject()),
+        };
+        let result = store.todo_list.write().start_timer(id);
+        match result {
+            Ok(()) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    format!("Started timer for {}", id),
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(e) => Ok(warp::reply::with_status(
+                e.to_string(),
+                http::StatusCode::BAD_REQUEST,
+            )),
+        }
+    }
+
+    pub async fn stop_timer(
+        id: Option<Uuid>,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let id = match id {
+            Some(id) => id,
+            None => return Err(warp::reject()),
+        };
+        let result = store.todo_list.write().stop_timer(id);
+        match result {
+            Ok(()) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    format!("Stopped timer for {}", id),
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(e) => Ok(warp::reply::with_status(
+                e.to_string(),
+                http::StatusCode::BAD_REQUEST,
+            )),
+        }
+    }
+
     pub async fn complete(
         id: Option<Uuid>,
         store: DataStore,
@@ -440,82 +1318,272 @@ mod handlers {
         priority_low: Option<Priority>,
         priority_high: Option<Priority>,
         category: Option<String>,
+        blocked: Option<bool>,
+        search_query: SearchQuery,
         storage: DataStore,
     ) -> Result<impl warp::Reply, Rejection> {
+        let query = match search_query.q.as_deref().map(Query::from_str) {
+            Some(Ok(query)) => Some(query),
+            Some(Err(e)) => {
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&e),
+                    http::StatusCode::BAD_REQUEST,
+                ))
+            }
+            None => None,
+        };
+        let filter = TaskFilter {
+            name,
+            desc,
+            due_after: due_date_start,
+            due_before: due_date_end,
+            est_low: est_time_low,
+            est_high: est_time_high,
+            complete,
+            priority_low,
+            priority_high,
+            // An empty category means the tasks without one
+            category: category.map(|category| Some(category).filter(|c| !c.is_empty())),
+            tags_any: split_tags(search_query.tags_any),
+            tags_all: split_tags(search_query.tags_all),
+        };
+        // Storage that indexes the tasks does the field filters itself
+        let found = match id {
+            Some(_) => None,
+            None => storage.search(&filter),
+        };
         let todo_list = storage.todo_list.read();
-        let search_results; // Pre-declare
-        if let Some(id) = id {
+        let search_results: Vec<&Task> = if let Some(id) = id {
             // fast path
-            search_results = match todo_list.get_task(id) {
+            match todo_list.get_task(id) {
                 Some(task) => vec![task],
                 None => vec![], // dummy fast
             }
         } else {
-            // ALL THE FILTERS
-            search_results = todo_list
-                .get_all_tasks()
+            let candidates = match found {
+                Some(ids) => ids
+                    .into_iter()
+                    .filter_map(|id| todo_list.get_task(id))
+                    .collect(),
+                None => todo_list
+                    .get_all_tasks()
+                    .into_iter()
+                    .filter(|task| filter.matches(task))
+                    .collect::<Vec<&Task>>(),
+            };
+            // Blocking and queries need the rest of the list, so those stay in memory
+            candidates
                 .into_iter()
-                .filter(|task| match &name {
-                    Some(name) => task.get_name().contains(name),
-                    _ => true,
-                })
-                .filter(|task| match &desc {
-                    Some(desc) => task.get_desc().contains(desc),
-                    _ => true,
-                })
-                .filter(|task| match due_date_start {
-                    // Gotta re-wrap to get the behavior from the match being none, while having an ez compare to the get on the task
-                    Some(due_start) => task.get_due_date() >= Some(due_start),
-                    _ => true,
-                })
-                .filter(|task| match due_date_end {
-                    // Gotta re-wrap to get the behavior from the match being none, while having an ez compare to the get on the task
-                    Some(due_end) => task.get_due_date() <= Some(due_end),
-                    _ => true,
-                })
-                .filter(|task| match est_time_low {
-                    Some(est_low) => task.est_time() >= est_low,
-                    _ => true,
-                })
-                .filter(|task| match est_time_high {
-                    Some(est_high) => task.est_time() <= est_high,
+                .filter(|task| match blocked {
+                    Some(blocked) => todo_list.is_blocked(task.get_id()) == blocked,
                     _ => true,
                 })
-                .filter(|task| match complete {
-                    Some(complete) => {
-                        if complete {
-                            task.complete()
-                        } else {
-                            true
-                        }
-                    }
-                    _ => true,
-                })
-                .filter(|task| match priority_low {
-                    // Gotta re-wrap to get the behavior from the match being none, while having an ez compare to the get on the task
-                    Some(priority) => task.get_priority() >= Some(priority),
+                .filter(|task| match &query {
+                    Some(query) => query.matches(task, &todo_list),
                     _ => true,
                 })
-                .filter(|task| match priority_high {
-                    // Gotta re-wrap to get the behavior from the match being none, while having an ez compare to the get on the task
-                    Some(priority) => task.get_priority() <= Some(priority),
-                    _ => true,
-                })
-                .filter(|task| match &category {
-                    Some(category) => {
-                        let wrapped_category = if category.is_empty() {
-                            None
-                        } else {
-                            Some(category.clone())
-                        };
-                        task.get_category() == wrapped_category
+                .collect()
+        };
+        // Say hi
+        Ok(warp::reply::with_status(
+            warp::reply::json(&search_results),
+            http::StatusCode::OK,
+        ))
+    }
+
+    /// The whole list in another format, or as csv only the tasks matching the query
+    pub async fn export(
+        format: Format,
+        csv_query: CsvQuery,
+        store: DataStore,
+    ) -> Result<warp::reply::Response, Rejection> {
+        let todo_list = store.todo_list.read();
+        match format.format.as_deref().unwrap_or("todotxt") {
+            "csv" => {
+                let query = match csv_query.q.as_deref().map(Query::from_str) {
+                    Some(Ok(query)) => Some(query),
+                    Some(Err(e)) => return Ok(bad_request(e)),
+                    None => None,
+                };
+                let columns = match csv_columns(csv_query.columns.as_deref(), &csv::DEFAULT_COLUMNS)
+                {
+                    Ok(columns) => columns,
+                    Err(e) => return Ok(bad_request(&e)),
+                };
+                let mut tasks: Vec<&Task> = todo_list
+                    .get_all_tasks()
+                    .into_iter()
+                    .filter(|task| match &query {
+                        Some(query) => query.matches(task, &todo_list),
+                        None => true,
+                    })
+                    .collect();
+                tasks.sort_by_key(|task| task.get_initial_date());
+                Ok(csv_reply(csv::export(&tasks, &todo_list, &columns)).into_response())
+            }
+            "todotxt" => Ok(todo_txt::export(&todo_list).into_response()),
+            "ics" => Ok(calendar_reply(&todo_list).into_response()),
+            "taskwarrior" => Ok(warp::reply::with_header(
+                taskwarrior::export(&todo_list),
+                "content-type",
+                "application/json",
+            )
+            .into_response()),
+            _ => Ok(warp::reply::with_status(
+                warp::reply::json(&"Unknown format"),
+                http::StatusCode::BAD_REQUEST,
+            )
+            .into_response()),
+        }
+    }
+
+    /// What got finished between two days, the week up to today if they're left out
+    pub async fn report(
+        report: Report,
+        store: DataStore,
+    ) -> Result<warp::reply::Response, Rejection> {
+        let to = report.to.unwrap_or_else(|| Local::today().naive_local());
+        let from = report.from.unwrap_or_else(|| to - Duration::days(6));
+        let (start, end) = match (
+            Local.from_local_datetime(&from.and_hms(0, 0, 0)).earliest(),
+            Local.from_local_datetime(&to.and_hms(23, 59, 59)).latest(),
+        ) {
+            (Some(start), Some(end)) if start <= end => (start, end),
+            _ => return Ok(bad_request("Invalid date range")),
+        };
+        let todo_list = store.todo_list.read();
+        let finished = todo_list.get_finished_between(start, end);
+        match report.format.as_deref().unwrap_or("json") {
+            "json" => {
+                // The ones without a category are counted under ""
+                let mut by_category: HashMap<String, usize> = HashMap::new();
+                for task in finished.iter() {
+                    *by_category
+                        .entry(task.get_category().unwrap_or_default())
+                        .or_default() += 1;
+                }
+                Ok(warp::reply::json(&serde_json::json!({
+                    "from": from,
+                    "to": to,
+                    "finished": finished.len(),
+                    "by_category": by_category,
+                    "tasks": finished,
+                }))
+                .into_response())
+            }
+            "csv" => match csv_columns(report.columns.as_deref(), &csv::REPORT_COLUMNS) {
+                Ok(columns) => {
+                    Ok(csv_reply(csv::export(&finished, &todo_list, &columns)).into_response())
+                }
+                Err(e) => Ok(bad_request(&e)),
+            },
+            _ => Ok(bad_request("Unknown format")),
+        }
+    }
+
+    fn csv_columns(columns: Option<&str>, default: &[Column]) -> Result<Vec<Column>, String> {
+        match columns {
+            Some(columns) => csv::parse_columns(columns),
+            None => Ok(default.to_vec()),
+        }
+    }
+
+    fn csv_reply(rows: String) -> impl warp::Reply {
+        warp::reply::with_header(rows, "content-type", "text/csv; charset=utf-8")
+    }
+
+    fn bad_request(e: &str) -> warp::reply::Response {
+        warp::reply::with_status(warp::reply::json(&e), http::StatusCode::BAD_REQUEST)
+            .into_response()
+    }
+
+    pub async fn calendar(store: DataStore) -> Result<impl warp::Reply, Rejection> {
+        Ok(calendar_reply(&store.todo_list.read()))
+    }
+
+    fn calendar_reply(todo_list: &ToDo) -> impl warp::Reply {
+        warp::reply::with_header(
+            ical::export(todo_list),
+            "content-type",
+            "text/calendar; charset=utf-8",
+        )
+    }
+
+    /// Adds every task in a file at the top level, along with any sub-tasks the format can carry.
+    /// Ones with the id of a task that's already here replace it
+    pub async fn import(
+        format: Format,
+        body: warp::hyper::body::Bytes,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let parsed = match std::str::from_utf8(&body) {
+            Ok(text) => match format.format.as_deref().unwrap_or_else(|| {
+                let text = text.trim_start();
+                if text.starts_with("BEGIN:VCALENDAR") {
+                    "ics"
+                } else if text.starts_with('[') || text.starts_with('{') {
+                    "taskwarrior"
+                } else {
+                    "todotxt"
+                }
+            }) {
+                "todotxt" => todo_txt::parse(text),
+                "ics" => ical::parse(text),
+                "taskwarrior" => taskwarrior::parse(text),
+                _ => Err("Unknown format".to_string()),
+            },
+            Err(_) => Err("Body isn't valid utf-8".to_string()),
+        };
+        match parsed {
+            Ok(tasks) => {
+                // Only what made it in is sent back
+                let mut imported = vec![];
+                for task in tasks {
+                    match store.import_task(task.clone()) {
+                        Ok(()) => imported.push(task),
+                        Err(e) => warn!("Unable to import {}: {}", task.get_name(), e),
                     }
-                    _ => true,
-                })
-                .collect();
+                }
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    warp::reply::json(&imported),
+                    http::StatusCode::CREATED,
+                ))
+            }
+            Err(e) => Ok(warp::reply::with_status(
+                warp::reply::json(&e),
+                http::StatusCode::BAD_REQUEST,
+            )),
         }
-        // Say hi
-        Ok(warp::reply::json(&search_results))
+    }
+
+    /// Applies one change from Trello. Anything but a bad signature or body gets a 200,
+    /// Trello keeps retrying and eventually drops the webhook otherwise
+    pub async fn trello_webhook(
+        signature: Option<String>,
+        body: warp::hyper::body::Bytes,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, Rejection> {
+        if !store.verify_trello(signature.as_deref().unwrap_or_default(), &body) {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&"Bad signature"),
+                http::StatusCode::UNAUTHORIZED,
+            )
+            .into_response());
+        }
+        let webhook = match trello_webhook::parse(&body) {
+            Ok(webhook) => webhook,
+            Err(e) => return Ok(bad_request(&e)),
+        };
+        if let Err(e) = store.apply_trello(&webhook) {
+            warn!("Unable to apply Trello's {}: {}", webhook.kind, e);
+        }
+        Ok(warp::reply().into_response())
+    }
+
+    fn split_tags(tags: Option<String>) -> Vec<String> {
+        tags.map(|tags| tags.split(',').map(String::from).collect())
+            .unwrap_or_default()
     }
 
     pub async fn mark_finished(
@@ -523,37 +1591,248 @@ mod handlers {
         finished: Option<bool>,
         store: DataStore,
     ) -> Result<impl warp::Reply, Rejection> {
-        match store.todo_list.write().mark_finished(id, finished) {
-            Ok(()) => Ok(http::Response::builder().body(format!(
-                "Set task {} to {}",
-                id,
-                if finished.is_some() {
-                    finished.unwrap()
-                } else {
-                    true
-                }
-            ))),
+        let result = store.todo_list.write().mark_finished(id, finished);
+        match result {
+            Ok(()) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(http::Response::builder().body(format!(
+                    "Set task {} to {}",
+                    id,
+                    if finished.is_some() {
+                        finished.unwrap()
+                    } else {
+                        true
+                    }
+                )))
+            }
             Err(()) => Err(warp::reject()),
         }
     }
 
-    // TODO fix update_file to only serialize the hashmap that holds the tasks, not the categories or overdue as those are only to make searches and other features easier
-    pub fn update_file(store: DataStore) {
-        match serde_json::to_writer(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(SAVE_FILE_PATH)
-                .unwrap(),
-            store.todo_list.read().deref(),
-        ) {
-            Ok(_) => {}
-            Err(e) => error!("{}", e),
+    pub async fn add_dependency(
+        dependency: Dependency,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let result = store
+            .todo_list
+            .write()
+            .add_dependency(dependency.uuid, dependency.blocked_by);
+        match result {
+            Ok(()) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    format!(
+                        "{} is blocked by {}",
+                        dependency.uuid, dependency.blocked_by
+                    ),
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(e) => Ok(warp::reply::with_status(
+                e.to_string(),
+                http::StatusCode::BAD_REQUEST,
+            )),
+        }
+    }
+
+    pub async fn remove_dependency(
+        dependency: Dependency,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let result = store
+            .todo_list
+            .write()
+            .remove_dependency(dependency.uuid, dependency.blocked_by);
+        match result {
+            Ok(()) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    format!(
+                        "{} is no longer blocked by {}",
+                        dependency.uuid, dependency.blocked_by
+                    ),
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(_) => Err(warp::reject::not_found()),
+        }
+    }
+
+    pub async fn add_tag(tag: Tag, store: DataStore) -> Result<impl warp::Reply, Rejection> {
+        let result = store.todo_list.write().add_tag(tag.uuid, tag.tag.as_str());
+        match result {
+            Ok(()) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    format!("Tagged {} with {}", tag.uuid, tag.tag),
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(_) => Err(warp::reject::not_found()),
+        }
+    }
+
+    pub async fn remove_tag(tag: Tag, store: DataStore) -> Result<impl warp::Reply, Rejection> {
+        let result = store
+            .todo_list
+            .write()
+            .remove_tag(tag.uuid, tag.tag.as_str());
+        match result {
+            Ok(()) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    format!("Removed {} from {}", tag.tag, tag.uuid),
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(_) => Err(warp::reject::not_found()),
+        }
+    }
+
+    /// The unfinished, unblocked tasks in the order they should be worked on
+    pub async fn startable(store: DataStore) -> Result<impl warp::Reply, Rejection> {
+        let todo_list = store.todo_list.read();
+        Ok(warp::reply::json(&todo_list.get_startable()))
+    }
+
+    pub async fn snooze(snooze: Snooze, store: DataStore) -> Result<impl warp::Reply, Rejection> {
+        // Duration::minutes panics on large values, so they're turned away before getting there
+        if snooze.minutes <= 0 || snooze.minutes > MAX_SNOOZE_MINUTES {
+            return Ok(warp::reply::with_status(
+                format!("Minutes must be between 1 and {}", MAX_SNOOZE_MINUTES),
+                http::StatusCode::BAD_REQUEST,
+            ));
+        }
+        let result = {
+            let mut todo_list = store.todo_list.write();
+            if todo_list.get_task(snooze.uuid).is_none() {
+                return Err(warp::reject::not_found());
+            }
+            todo_list.snooze(snooze.uuid, Duration::minutes(snooze.minutes))
+        };
+        match result {
+            Ok(not_before) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    format!("Snoozed {} until {}", snooze.uuid, not_before),
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(e) => Ok(warp::reply::with_status(
+                e.to_string(),
+                http::StatusCode::BAD_REQUEST,
+            )),
+        }
+    }
+
+    pub async fn unsnooze(
+        id: Option<Uuid>,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let id = match id {
+            Some(id) => id,
+            None => return Err(warp::reject()),
+        };
+        let result = store.todo_list.write().unsnooze(id);
+        match result {
+            Ok(()) => {
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    format!("{} is no longer snoozed", id),
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(_) => Err(warp::reject::not_found()),
+        }
+    }
+
+    /// Open tasks ranked by what should be worked on next, best first.
+    /// `minutes` is how much time there is, tasks that won't fit in it rank lower
+    pub async fn next(
+        minutes: Option<u32>,
+        store: DataStore,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let todo_list = store.todo_list.read();
+        Ok(warp::reply::json(&scoring::rank(&todo_list, minutes)))
+    }
+
+    pub async fn history(store: DataStore) -> Result<impl warp::Reply, Rejection> {
+        let history = store.history.read();
+        Ok(warp::reply::json(history.deref()))
+    }
+
+    /// Reverts the most recent change to the todo list
+    pub async fn undo(store: DataStore) -> Result<impl warp::Reply, Rejection> {
+        let result = {
+            let mut todo_list = store.todo_list.write();
+            store.history.write().undo(todo_list.deref_mut())
+        };
+        step_history(result, store)
+    }
+
+    /// Re-applies the most recently undone change
+    pub async fn redo(store: DataStore) -> Result<impl warp::Reply, Rejection> {
+        let result = {
+            let mut todo_list = store.todo_list.write();
+            store.history.write().redo(todo_list.deref_mut())
+        };
+        step_history(result, store)
+    }
+
+    fn step_history(
+        result: Result<ChangeRecord, &'static str>,
+        store: DataStore,
+    ) -> Result<warp::reply::WithStatus<warp::reply::Json>, Rejection> {
+        match result {
+            Ok(record) => {
+                // A task that came back needs its overdue check again
+                if let Change::Added { task, .. }
+                | Change::Removed { task, .. }
+                | Change::Replaced { after: task, .. } = &record.change
+                {
+                    let due_date = match store.todo_list.read().get_task(task.get_id()) {
+                        Some(task) => task.get_due_date(),
+                        None => None,
+                    };
+                    if let Some(due_date) = due_date {
+                        store.schedule_overdue_check(task.get_id(), due_date);
+                    }
+                }
+                store.try_save().map_err(save_failed)?;
+                Ok(warp::reply::with_status(
+                    warp::reply::json(&record),
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(e) => Ok(warp::reply::with_status(
+                warp::reply::json(&e),
+                http::StatusCode::NOT_FOUND,
+            )),
         }
     }
 
     #[derive(Debug)]
     struct InvalidQuery;
     impl warp::reject::Reject for InvalidQuery {}
+
+    // The change was made to the list but couldn't be saved
+    #[derive(Debug)]
+    struct SaveFailed(String);
+    impl warp::reject::Reject for SaveFailed {}
+
+    fn save_failed(e: anyhow::Error) -> Rejection {
+        error!("Unable to save: {}", e);
+        warp::reject::custom(SaveFailed(e.to_string()))
+    }
+
+    /// Answers a failed save with a 500 and why, rather than leaving it to look like the change went through
+    pub async fn recover(err: Rejection) -> Result<impl warp::Reply, Rejection> {
+        match err.find::<SaveFailed>() {
+            Some(SaveFailed(e)) => Ok(warp::reply::with_status(
+                format!("Unable to save: {}", e),
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+            None => Err(err),
+        }
+    }
 }
@@ -16,6 +16,7 @@ use std::sync::mpsc::TryRecvError;
 use std::time::Instant;
 
 mod config {
+    use desktopper::backend::trello_sync::TrelloConfig;
     use serde::Deserialize;
 
     #[derive(Deserialize)]
@@ -23,6 +24,7 @@ mod config {
         pub gpio: GPIO,
         pub tasks: Tasks,
         pub spotify_auth: Option<SpotifyAuth>,
+        pub trello: Option<TrelloConfig>,
     }
 
     #[derive(Deserialize)]
@@ -152,6 +154,18 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
+    // Shows the first of the boards the api_server syncs
+    if let Some(trello) = &cfg.trello {
+        let api = trello.api();
+        if !api.is_valid() {
+            warn!("No Trello key and token in [trello], TRELLO_KEY or TRELLO_TOKEN");
+        }
+        match trello.boards.first() {
+            Some(board) => display_state.add(Box::new(TrelloScreen::new(api, &board.id))),
+            None => warn!("No Trello boards in [trello] to show"),
+        }
+    }
+
     display_state.add(Box::new(TestScreen {}));
     display_state.next();
     let mut button_state: Option<Buttons>;
@@ -2,5 +2,5 @@ pub mod buttons;
 pub mod screens;
 
 pub use buttons::{Button, Buttons, InputHandler};
-pub use screens::{clock::ClockScreen, tasks::TaskScreen};
+pub use screens::{clock::ClockScreen, tasks::TaskScreen, trello::TrelloScreen};
 pub use screens::{DisplayState, Screen, TestScreen};
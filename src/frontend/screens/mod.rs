@@ -7,6 +7,7 @@ use crate::frontend::buttons::Buttons;
 pub mod clock;
 pub mod music;
 pub mod tasks;
+pub mod trello;
 
 /// Defines generic behavior that each screens, to be displayed on an LCD/Character Display
 pub trait Screen {
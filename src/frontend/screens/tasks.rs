@@ -4,10 +4,17 @@ use gpio_lcd::scheduler::{Job, ThreadedLcd};
 use reqwest::blocking::Client;
 use uuid::Uuid;
 
-use crate::backend::{CompletionStatus, Task, ToDo};
+use crate::backend::history::ChangeRecord;
+use crate::backend::scoring;
+use crate::backend::{CompletionStatus, EstTime, Task, ToDo, TrackedTime};
 use crate::frontend::buttons::{Buttons, HELD, OPEN, RELEASED};
 use crate::frontend::screens::Screen;
 
+// How long the snooze button hides a task for
+const SNOOZE_MINUTES: i64 = 24 * 60;
+// How much each press changes the time available in the next task view
+const FREE_MINUTES_STEP: u32 = 15;
+
 pub struct TaskScreen {
     client: Client,
     cur_id: Option<Uuid>,
@@ -16,6 +23,9 @@ pub struct TaskScreen {
     view_flag: usize,
     todo: ToDo,
     cur_category: Option<String>,
+    cur_tag: Option<String>,
+    // Time to work with in the next task view, None for no limit
+    free_minutes: Option<u32>,
     api_root: String,
 }
 
@@ -27,6 +37,9 @@ enum TaskScreenState {
     AllTasks,
     Overdue,
     TaskInfo,
+    Tags,
+    TagTasks,
+    Next,
 }
 
 impl TaskScreenState {
@@ -38,11 +51,17 @@ impl TaskScreenState {
             TaskScreenState::AllTasks => 3,
             TaskScreenState::Overdue => 4,
             TaskScreenState::TaskInfo => 5,
+            TaskScreenState::Tags => 6,
+            TaskScreenState::TagTasks => 7,
+            TaskScreenState::Next => 8,
         }
     }
 
     pub fn get(val: usize) -> TaskScreenState {
-        match val % 6 {
+        match val % 9 {
+            8 => TaskScreenState::Next,
+            7 => TaskScreenState::TagTasks,
+            6 => TaskScreenState::Tags,
             5 => TaskScreenState::TaskInfo,
             4 => TaskScreenState::Overdue,
             3 => TaskScreenState::AllTasks,
@@ -73,6 +92,8 @@ impl TaskScreen {
             view_flag: 0,
             todo,
             cur_category: None,
+            cur_tag: None,
+            free_minutes: None,
             api_root,
         }
     }
@@ -95,15 +116,77 @@ impl TaskScreen {
         ));
         lcd.add_job(Job::new(
             format!(
-                "Overdue: {}, Categories: {}",
-                self.todo.get_overdue().len(),
-                self.todo.get_categories().len()
+                "Overdue: {}, Categories: {}, Snoozed: {}",
+                self.todo.visible(self.todo.get_overdue()).len(),
+                self.todo.get_categories().len(),
+                self.todo
+                    .get_all_tasks()
+                    .iter()
+                    .filter(|task| task.is_snoozed())
+                    .count()
             )
             .as_str(),
             1,
             Some(Duration::from_millis(250)),
         ));
     }
+
+    // Starts or stops the time tracking on a task through the api
+    fn toggle_timer(&mut self, id: Uuid) {
+        let action = match self.todo.get_task(id) {
+            Some(task) if task.is_tracking() => "stop_timer",
+            Some(_) => "start_timer",
+            None => return,
+        };
+        let url = format!("{}/todo/{}?uuid={}", &self.api_root, action, id);
+        match self.client.get(&url).send() {
+            Ok(_) => self.update_tasks(),
+            Err(e) => error!("Failed to {}: {}", action, e),
+        }
+    }
+
+    // Reverts the last change made to the todo list through the api and says what it was
+    fn undo(&mut self, lcd: &mut ThreadedLcd) {
+        let url = format!("{}/todo/undo", &self.api_root);
+        let resp = match self.client.get(&url).send() {
+            Ok(resp) => resp.text().unwrap_or_default(),
+            Err(e) => {
+                error!("Failed to undo: {}", e);
+                return;
+            }
+        };
+        let (title, line) = match serde_json::from_str::<ChangeRecord>(&resp) {
+            Ok(record) => ("Undid", record.change.to_string()),
+            Err(_) => ("Undo", "Nothing to undo".to_string()),
+        };
+        self.update_tasks();
+        lcd.clear_jobs();
+        lcd.add_job(Job::new(title, 0, None));
+        lcd.add_job(Job::new(line.as_str(), 1, Some(Duration::from_millis(250))));
+    }
+
+    // Hides a task for a day through the api
+    fn snooze(&mut self, id: Uuid) {
+        let url = format!(
+            "{}/todo/snooze?uuid={}&minutes={}",
+            &self.api_root, id, SNOOZE_MINUTES
+        );
+        match self.client.get(&url).send() {
+            Ok(_) => self.update_tasks(),
+            Err(e) => error!("Failed to snooze: {}", e),
+        }
+    }
+
+    // Blocked tasks get flagged, they can't be started yet. Snoozed ones can only be seen from their info
+    fn task_title(&self, task: &Task) -> String {
+        if self.todo.is_blocked(task.get_id()) {
+            format!("[B] {}", task.get_name())
+        } else if self.todo.is_snoozed(task.get_id()) {
+            format!("[Z] {}", task.get_name())
+        } else {
+            task.get_name()
+        }
+    }
 }
 
 impl Screen for TaskScreen {
@@ -117,13 +200,16 @@ impl Screen for TaskScreen {
         if buttons.cycle.state == RELEASED {
             // GO back to root
             self.root_view(lcd);
+        } else if buttons.cycle.state == HELD && buttons.f1.state == RELEASED {
+            // Letting go of cycle afterwards heads back to the root
+            self.undo(lcd);
         } else {
             match TaskScreenState::get(self.view_flag) {
                 TaskScreenState::Root => {
                     if buttons.f0.state == RELEASED {
-                        self.idx = if self.idx == 0 { 3 } else { self.idx - 1 };
+                        self.idx = if self.idx == 0 { 5 } else { self.idx - 1 };
                     } else if buttons.f2.state == RELEASED {
-                        self.idx = (self.idx + 1) % 4;
+                        self.idx = (self.idx + 1) % 6;
                     }
                     match self.idx {
                         1 => {
@@ -138,6 +224,18 @@ impl Screen for TaskScreen {
                                 self.idx = 0;
                             }
                         }
+                        4 => {
+                            if buttons.f1.state == RELEASED {
+                                self.view_flag = TaskScreenState::Tags.val();
+                                self.idx = 0;
+                            }
+                        }
+                        5 => {
+                            if buttons.f1.state == RELEASED {
+                                self.view_flag = TaskScreenState::Next.val();
+                                self.idx = 0;
+                            }
+                        }
                         _ => {
                             if buttons.f1.state == RELEASED {
                                 self.view_flag = TaskScreenState::AllTasks.val();
@@ -163,11 +261,17 @@ impl Screen for TaskScreen {
                     }
                 }
                 TaskScreenState::CategoryTasks => {
-                    match self.todo.get_category(self.cur_category.clone()) {
+                    match self
+                        .todo
+                        .get_category(self.cur_category.clone())
+                        .map(|tasks| self.todo.visible(tasks))
+                        .filter(|tasks| !tasks.is_empty())
+                    {
                         Some(tasks) => {
                             if buttons.f1.state == RELEASED {
                                 self.cur_category = None;
                                 self.cur_id = Some(tasks[self.idx].get_id());
+                                self.parent_id = self.todo.get_parent(tasks[self.idx].get_id());
                                 self.idx = 0;
                                 self.view_flag = TaskScreenState::TaskInfo.val();
                             } else if buttons.f0.state == RELEASED {
@@ -180,14 +284,18 @@ impl Screen for TaskScreen {
                                 self.idx = (self.idx + 1) % tasks.len()
                             }
                         }
-                        None => self.first_load(lcd), // This category disappeared somehow, go back to root
+                        None => self.first_load(lcd), // This category disappeared somehow, or all of it is snoozed, go back to root
                     }
                 }
                 TaskScreenState::AllTasks => {
-                    let tasks = self.todo.get_all_tasks();
-                    if buttons.f1.state == RELEASED {
+                    let tasks = self.todo.visible(self.todo.get_all_tasks());
+                    if tasks.is_empty() {
+                        // Everything is snoozed or done away with
+                        self.first_load(lcd);
+                    } else if buttons.f1.state == RELEASED {
                         self.cur_category = None;
                         self.cur_id = Some(tasks[self.idx].get_id());
+                        self.parent_id = self.todo.get_parent(tasks[self.idx].get_id());
                         self.idx = 0;
                         self.view_flag = TaskScreenState::TaskInfo.val();
                     } else if buttons.f0.state == RELEASED {
@@ -201,10 +309,54 @@ impl Screen for TaskScreen {
                     }
                 }
                 TaskScreenState::Overdue => {
-                    let tasks = self.todo.get_overdue();
-                    if buttons.f1.state == RELEASED {
+                    let tasks = self.todo.visible(self.todo.get_overdue());
+                    if tasks.is_empty() {
+                        self.first_load(lcd);
+                    } else if buttons.f1.state == RELEASED {
                         self.cur_category = None;
                         self.cur_id = Some(tasks[self.idx].get_id());
+                        self.parent_id = self.todo.get_parent(tasks[self.idx].get_id());
+                        self.idx = 0;
+                        self.view_flag = TaskScreenState::TaskInfo.val();
+                    } else if buttons.f0.state == RELEASED {
+                        self.idx = if self.idx == 0 {
+                            tasks.len() - 1
+                        } else {
+                            self.idx - 1
+                        };
+                    } else if buttons.f2.state == RELEASED {
+                        self.idx = (self.idx + 1) % tasks.len()
+                    }
+                }
+                TaskScreenState::Tags => {
+                    let tags = self.todo.get_tags();
+                    if tags.is_empty() {
+                        // Nothing to browse
+                        self.first_load(lcd);
+                    } else if buttons.f1.state == RELEASED {
+                        self.cur_tag = Some(tags[self.idx].clone());
+                        self.idx = 0;
+                        self.view_flag = TaskScreenState::TagTasks.val();
+                    } else if buttons.f0.state == RELEASED {
+                        self.idx = if self.idx == 0 {
+                            tags.len() - 1
+                        } else {
+                            self.idx - 1
+                        };
+                    } else if buttons.f2.state == RELEASED {
+                        self.idx = (self.idx + 1) % tags.len();
+                    }
+                }
+                TaskScreenState::TagTasks => {
+                    let tag = self.cur_tag.clone().unwrap_or_default();
+                    let tasks = self.todo.visible(self.todo.get_tagged(tag.as_str()));
+                    if tasks.is_empty() {
+                        // This tag disappeared somehow, or all of it is snoozed, go back to root
+                        self.first_load(lcd);
+                    } else if buttons.f1.state == RELEASED {
+                        self.cur_tag = None;
+                        self.cur_id = Some(tasks[self.idx].get_id());
+                        self.parent_id = self.todo.get_parent(tasks[self.idx].get_id());
                         self.idx = 0;
                         self.view_flag = TaskScreenState::TaskInfo.val();
                     } else if buttons.f0.state == RELEASED {
@@ -217,7 +369,77 @@ impl Screen for TaskScreen {
                         self.idx = (self.idx + 1) % tasks.len()
                     }
                 }
-                TaskScreenState::TaskInfo => {}
+                TaskScreenState::Next => {
+                    // f0 and f2 change how much time there is to work with
+                    if buttons.f1.state == RELEASED {
+                        match scoring::next(&self.todo, self.free_minutes) {
+                            Some(task) => {
+                                self.cur_id = Some(task.get_id());
+                                self.parent_id = self.todo.get_parent(task.get_id());
+                                self.idx = 0;
+                                self.view_flag = TaskScreenState::TaskInfo.val();
+                            }
+                            None => self.first_load(lcd),
+                        }
+                    } else if buttons.f0.state == RELEASED {
+                        self.free_minutes = match self.free_minutes {
+                            Some(minutes) if minutes > FREE_MINUTES_STEP => {
+                                Some(minutes - FREE_MINUTES_STEP)
+                            }
+                            _ => None,
+                        };
+                    } else if buttons.f2.state == RELEASED {
+                        self.free_minutes = match self.free_minutes {
+                            Some(minutes) => Some(minutes + FREE_MINUTES_STEP),
+                            None => Some(FREE_MINUTES_STEP),
+                        };
+                    }
+                }
+                TaskScreenState::TaskInfo => {
+                    // Hold f0 and press f1 to start or stop tracking time on the task
+                    let toggle_timer = buttons.f0.state == HELD && buttons.f1.state == RELEASED;
+                    if let (true, Some(id)) = (toggle_timer, self.cur_id) {
+                        self.toggle_timer(id);
+                    }
+                    // Hold f2 and press f1 to hide the task for a while
+                    let snooze = buttons.f2.state == HELD && buttons.f1.state == RELEASED;
+                    if let (true, Some(id)) = (snooze, self.cur_id) {
+                        self.snooze(id);
+                    }
+                    let task = match self.cur_id {
+                        Some(id) => self.todo.get_task(id),
+                        None => None,
+                    };
+                    match task {
+                        Some(task) => {
+                            let sub_tasks = task.get_sub_tasks();
+                            if buttons.f1.state == RELEASED && !toggle_timer && !snooze {
+                                if !sub_tasks.is_empty() {
+                                    // Step down into the selected sub-task
+                                    self.parent_id = self.cur_id;
+                                    self.cur_id = Some(sub_tasks[self.idx].get_id());
+                                    self.idx = 0;
+                                } else if let Some(parent_id) = self.parent_id {
+                                    // Nothing below this one, head back up
+                                    self.cur_id = Some(parent_id);
+                                    self.parent_id = self.todo.get_parent(parent_id);
+                                    self.idx = 0;
+                                }
+                            } else if !sub_tasks.is_empty() {
+                                if buttons.f0.state == RELEASED {
+                                    self.idx = if self.idx == 0 {
+                                        sub_tasks.len() - 1
+                                    } else {
+                                        self.idx - 1
+                                    };
+                                } else if buttons.f2.state == RELEASED {
+                                    self.idx = (self.idx + 1) % sub_tasks.len()
+                                }
+                            }
+                        }
+                        None => self.first_load(lcd), // Task got removed out from under us
+                    }
+                }
             }
 
             match TaskScreenState::get(self.view_flag) {
@@ -236,6 +458,23 @@ impl Screen for TaskScreen {
                         lcd.add_job(Job::new("Overdue", 0, None));
                         lcd.add_job(Job::empty(1));
                     }
+                    4 => {
+                        lcd.clear_jobs();
+                        lcd.add_job(Job::new("Tags", 0, None));
+                        lcd.add_job(Job::empty(1));
+                    }
+                    5 => {
+                        lcd.clear_jobs();
+                        lcd.add_job(Job::new("Next Task", 0, None));
+                        match scoring::next(&self.todo, None) {
+                            Some(task) => lcd.add_job(Job::new(
+                                task.get_name().as_str(),
+                                1,
+                                Some(Duration::from_millis(250)),
+                            )),
+                            None => lcd.add_job(Job::empty(1)),
+                        }
+                    }
                     _ => {
                         lcd.clear_jobs();
                         lcd.add_job(Job::new("All Tasks", 0, None));
@@ -260,60 +499,170 @@ impl Screen for TaskScreen {
                         None,
                     ));
                 }
-                TaskScreenState::CategoryTasks => {
-                    match self.todo.get_category(self.cur_category.clone()) {
-                        Some(tasks) => {
+                TaskScreenState::CategoryTasks => match self
+                    .todo
+                    .get_category(self.cur_category.clone())
+                    .map(|tasks| self.todo.visible(tasks))
+                    .filter(|tasks| !tasks.is_empty())
+                {
+                    Some(tasks) => {
+                        lcd.clear_jobs();
+                        lcd.add_job(Job::new(
+                            self.task_title(tasks[self.idx]).as_str(),
+                            0,
+                            Some(Duration::from_millis(250)),
+                        ));
+                        lcd.add_job(Job::new(
+                            tasks[self.idx].get_desc().as_str(),
+                            1,
+                            Some(Duration::from_millis(250)),
+                        ));
+                    }
+                    None => self.first_load(lcd),
+                },
+                TaskScreenState::AllTasks => {
+                    let tasks = self.todo.visible(self.todo.get_all_tasks());
+                    match tasks.get(self.idx) {
+                        Some(task) => {
                             lcd.clear_jobs();
                             lcd.add_job(Job::new(
-                                tasks[self.idx].get_name().as_str(),
+                                self.task_title(task).as_str(),
                                 0,
                                 Some(Duration::from_millis(250)),
                             ));
                             lcd.add_job(Job::new(
-                                tasks[self.idx].get_desc().as_str(),
+                                task.get_desc().as_str(),
                                 1,
                                 Some(Duration::from_millis(250)),
                             ));
                         }
+                        // Nothing in it, or all of it is snoozed
                         None => self.first_load(lcd),
                     }
                 }
-                TaskScreenState::AllTasks => {
-                    let tasks = self.todo.get_all_tasks();
-                    lcd.clear_jobs();
-                    lcd.add_job(Job::new(
-                        tasks[self.idx].get_name().as_str(),
-                        0,
-                        Some(Duration::from_millis(250)),
-                    ));
-                    lcd.add_job(Job::new(
-                        tasks[self.idx].get_desc().as_str(),
-                        1,
-                        Some(Duration::from_millis(250)),
-                    ));
-                }
                 TaskScreenState::Overdue => {
-                    let tasks = self.todo.get_overdue();
+                    let tasks = self.todo.visible(self.todo.get_overdue());
+                    match tasks.get(self.idx) {
+                        Some(task) => {
+                            lcd.clear_jobs();
+                            lcd.add_job(Job::new(
+                                self.task_title(task).as_str(),
+                                0,
+                                Some(Duration::from_millis(250)),
+                            ));
+                            lcd.add_job(Job::new(
+                                task.get_desc().as_str(),
+                                1,
+                                Some(Duration::from_millis(250)),
+                            ));
+                        }
+                        // Nothing in it, or all of it is snoozed
+                        None => self.first_load(lcd),
+                    }
+                }
+                TaskScreenState::Tags => match self.todo.get_tags().get(self.idx) {
+                    Some(tag) => {
+                        lcd.clear_jobs();
+                        lcd.add_job(Job::new(
+                            format!("#{}", tag).as_str(),
+                            0,
+                            Some(Duration::from_millis(250)),
+                        ));
+                        let completion_status = self.todo.get_tag_completion(tag.as_str());
+                        lcd.add_job(Job::new(
+                            format!("Completion {}/{}", completion_status.0, completion_status.1)
+                                .as_str(),
+                            1,
+                            None,
+                        ));
+                    }
+                    // Nothing tagged yet, there's nothing to browse
+                    None => self.first_load(lcd),
+                },
+                TaskScreenState::TagTasks => {
+                    let tag = self.cur_tag.clone().unwrap_or_default();
+                    let tasks = self.todo.visible(self.todo.get_tagged(tag.as_str()));
+                    match tasks.get(self.idx) {
+                        Some(task) => {
+                            lcd.clear_jobs();
+                            lcd.add_job(Job::new(
+                                self.task_title(task).as_str(),
+                                0,
+                                Some(Duration::from_millis(250)),
+                            ));
+                            lcd.add_job(Job::new(
+                                task.get_desc().as_str(),
+                                1,
+                                Some(Duration::from_millis(250)),
+                            ));
+                        }
+                        None => self.first_load(lcd),
+                    }
+                }
+                TaskScreenState::Next => {
+                    let ranked = scoring::rank(&self.todo, self.free_minutes);
+                    let free_time = match self.free_minutes {
+                        Some(minutes) => format!("{}m free", minutes),
+                        None => "any time".to_string(),
+                    };
                     lcd.clear_jobs();
-                    lcd.add_job(Job::new(
-                        tasks[self.idx].get_name().as_str(),
-                        0,
-                        Some(Duration::from_millis(250)),
-                    ));
-                    lcd.add_job(Job::new(
-                        tasks[self.idx].get_desc().as_str(),
-                        1,
-                        Some(Duration::from_millis(250)),
-                    ));
+                    match ranked.first() {
+                        Some(best) => {
+                            lcd.add_job(Job::new(
+                                self.task_title(best.task).as_str(),
+                                0,
+                                Some(Duration::from_millis(250)),
+                            ));
+                            lcd.add_job(Job::new(
+                                format!("Score {}, {}", best.score, free_time).as_str(),
+                                1,
+                                None,
+                            ));
+                        }
+                        None => {
+                            lcd.add_job(Job::new("Nothing to do", 0, None));
+                            lcd.add_job(Job::new(free_time.as_str(), 1, None));
+                        }
+                    }
                 }
                 TaskScreenState::TaskInfo => {
-                    lcd.clear_jobs();
-                    lcd.add_job(Job::new(
-                        "Feature not implemented, go back to root",
-                        0,
-                        Some(Duration::from_millis(250)),
-                    ));
-                    lcd.add_job(Job::empty(1));
+                    let task = match self.cur_id {
+                        Some(id) => self.todo.get_task(id),
+                        None => None,
+                    };
+                    if let Some(task) = task {
+                        let cs = task.completion_status();
+                        lcd.clear_jobs();
+                        lcd.add_job(Job::new(
+                            format!(
+                                "{} {}/{} {}/{}m{}",
+                                self.task_title(task),
+                                cs.0,
+                                cs.1,
+                                task.tracked_time(),
+                                task.est_time(),
+                                // Timer is running
+                                if task.is_tracking() { "*" } else { "" }
+                            )
+                            .as_str(),
+                            0,
+                            Some(Duration::from_millis(250)),
+                        ));
+                        let sub_tasks = task.get_sub_tasks();
+                        if sub_tasks.is_empty() {
+                            lcd.add_job(Job::new(
+                                task.get_desc().as_str(),
+                                1,
+                                Some(Duration::from_millis(250)),
+                            ));
+                        } else {
+                            lcd.add_job(Job::new(
+                                format!("> {}", sub_tasks[self.idx].get_name()).as_str(),
+                                1,
+                                Some(Duration::from_millis(250)),
+                            ));
+                        }
+                    }
                 }
             }
         }
@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use chrono::Local;
+use gpio_lcd::scheduler::{Job, ThreadedLcd};
+
+use crate::backend::trello_api::{Card, List, TrelloApi, TrelloError, ID};
+use crate::frontend::buttons::{Buttons, RELEASED};
+use crate::frontend::screens::Screen;
+
+/// Browses one Trello board, a list at a time.
+/// Cycle goes to the next list, f0 and f2 go back and forth through its cards
+/// and f1 marks the card due-complete, or not if it already was
+pub struct TrelloScreen {
+    api: TrelloApi,
+    board_id: String,
+    lists: Vec<List>,
+    cards: Vec<Card>,
+    list_idx: usize,
+    card_idx: usize,
+}
+
+impl TrelloScreen {
+    pub fn new(api: TrelloApi, board_id: &str) -> Self {
+        TrelloScreen {
+            api,
+            board_id: board_id.to_string(),
+            lists: vec![],
+            cards: vec![],
+            list_idx: 0,
+            card_idx: 0,
+        }
+    }
+
+    // Fetches the board's open lists again, staying on the same list if it's still there
+    fn update_lists(&mut self) -> Result<(), TrelloError> {
+        let cur_id = self
+            .lists
+            .get(self.list_idx)
+            .map(|list| list.get_id().to_string());
+        self.lists = self
+            .api
+            .get_lists(&self.board_id)?
+            .into_iter()
+            .filter(|list| !list.is_closed())
+            .collect();
+        self.list_idx = cur_id
+            .and_then(|id| self.lists.iter().position(|list| list.get_id() == id))
+            .unwrap_or(0);
+        self.update_cards()
+    }
+
+    fn update_cards(&mut self) -> Result<(), TrelloError> {
+        self.cards = match self.lists.get(self.list_idx) {
+            Some(list) => self.api.get_cards(list.get_id())?,
+            None => vec![],
+        };
+        if self.card_idx >= self.cards.len() {
+            self.card_idx = 0;
+        }
+        Ok(())
+    }
+
+    fn toggle_due_complete(&mut self) -> Result<(), TrelloError> {
+        if let Some(card) = self.cards.get(self.card_idx) {
+            let updated = self
+                .api
+                .set_due_complete(card.get_id(), !card.is_due_complete())?;
+            self.cards[self.card_idx] = updated;
+        }
+        Ok(())
+    }
+
+    fn show(&self, lcd: &mut ThreadedLcd) {
+        lcd.clear_jobs();
+        lcd.clear_row(0);
+        lcd.clear_row(1);
+        let list = match self.lists.get(self.list_idx) {
+            Some(list) => list,
+            None => {
+                lcd.add_job(Job::new("Trello", 0, None));
+                lcd.add_job(Job::new("No lists", 1, None));
+                return;
+            }
+        };
+        match self.cards.get(self.card_idx) {
+            Some(card) => {
+                let top = format!(
+                    "{} {}/{}: {}",
+                    list.get_name(),
+                    self.card_idx + 1,
+                    self.cards.len(),
+                    card.get_name()
+                );
+                lcd.add_job(Job::new(top.as_str(), 0, Some(Duration::from_millis(250))));
+                lcd.add_job(Job::new(
+                    card_status(card).as_str(),
+                    1,
+                    Some(Duration::from_millis(250)),
+                ));
+            }
+            None => {
+                lcd.add_job(Job::new(
+                    list.get_name(),
+                    0,
+                    Some(Duration::from_millis(250)),
+                ));
+                lcd.add_job(Job::new("No cards", 1, None));
+            }
+        }
+    }
+
+    fn show_error(&self, lcd: &mut ThreadedLcd, e: TrelloError) {
+        error!("Trello screen: {}", e);
+        lcd.clear_jobs();
+        lcd.clear_row(0);
+        lcd.clear_row(1);
+        lcd.add_job(Job::new("Trello error", 0, None));
+        lcd.add_job(Job::new(
+            e.to_string().as_str(),
+            1,
+            Some(Duration::from_millis(250)),
+        ));
+    }
+}
+
+// Whether it's done, when it's due and how far through its checklists it is, like `Done 10/20 5PM 2/3`
+fn card_status(card: &Card) -> String {
+    let mut status = vec![];
+    if card.is_due_complete() {
+        status.push("Done".to_string());
+    }
+    match card.get_due() {
+        Some(due) => status.push(due.with_timezone(&Local).format("%m/%d %-I%p").to_string()),
+        None if !card.is_due_complete() => status.push("No due date".to_string()),
+        None => {}
+    }
+    let badges = card.get_badges();
+    if badges.get_check_items() > 0 {
+        status.push(format!(
+            "{}/{}",
+            badges.get_check_items_checked(),
+            badges.get_check_items()
+        ));
+    }
+    status.join(" ")
+}
+
+impl Screen for TrelloScreen {
+    fn first_load(&mut self, lcd: &mut ThreadedLcd) {
+        match self.update_lists() {
+            Ok(()) => self.show(lcd),
+            Err(e) => self.show_error(lcd, e),
+        }
+    }
+
+    fn update_screen(&mut self, lcd: &mut ThreadedLcd, buttons: Buttons) {
+        let result = if buttons.cycle.state == RELEASED {
+            if !self.lists.is_empty() {
+                self.list_idx = (self.list_idx + 1) % self.lists.len();
+            }
+            self.card_idx = 0;
+            self.update_cards()
+        } else if buttons.f0.state == RELEASED {
+            if !self.cards.is_empty() {
+                self.card_idx = (self.card_idx + self.cards.len() - 1) % self.cards.len();
+            }
+            Ok(())
+        } else if buttons.f2.state == RELEASED {
+            if !self.cards.is_empty() {
+                self.card_idx = (self.card_idx + 1) % self.cards.len();
+            }
+            Ok(())
+        } else if buttons.f1.state == RELEASED {
+            self.toggle_due_complete()
+        } else {
+            return;
+        };
+        match result {
+            Ok(()) => self.show(lcd),
+            Err(e) => self.show_error(lcd, e),
+        }
+    }
+
+    fn get_name(&self) -> String {
+        "Trello".to_string()
+    }
+}